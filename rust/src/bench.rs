@@ -0,0 +1,682 @@
+// I/O workload generator for files and block devices, reporting IOPS, bandwidth
+// and latency percentiles
+use crate::trace::{TraceRecord, TraceWriter};
+use crate::{AlignedBuffer, DiskLatency};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const BENCH_ALIGNMENT: usize = 4096;
+pub const BENCH_PERCENTILES: [f64; 7] = [50.0, 90.0, 95.0, 99.0, 99.5, 99.9, 99.99];
+
+const HIST_SUB_BITS: u32 = 6;
+const HIST_SUB_COUNT: usize = 1 << HIST_SUB_BITS;
+const HIST_BUCKETS: usize = (64 - HIST_SUB_BITS as usize + 1) * HIST_SUB_COUNT;
+
+/// Log-linear latency histogram in nanoseconds, 64 buckets per power of two
+/// (under 1.6% error) so long runs do not keep every sample.
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub sum: u128,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; HIST_BUCKETS],
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+            sum: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn index(value: u64) -> usize {
+        if value < HIST_SUB_COUNT as u64 {
+            return value as usize;
+        }
+        let msb = 63 - value.leading_zeros();
+        let mantissa = (value >> (msb - HIST_SUB_BITS)) as usize;
+        (msb - HIST_SUB_BITS + 1) as usize * HIST_SUB_COUNT + mantissa - HIST_SUB_COUNT
+    }
+
+    /// Middle of the bucket at `index`
+    fn value(index: usize) -> u64 {
+        if index < HIST_SUB_COUNT {
+            return index as u64;
+        }
+        let shift = (index / HIST_SUB_COUNT - 1) as u32;
+        let mantissa = (index % HIST_SUB_COUNT + HIST_SUB_COUNT) as u64;
+        (mantissa << shift) + ((1u64 << shift) >> 1)
+    }
+
+    pub fn record(&mut self, nanos: u64) {
+        self.counts[Self::index(nanos)] += 1;
+        self.count += 1;
+        self.min = self.min.min(nanos);
+        self.max = self.max.max(nanos);
+        self.sum += nanos as u128;
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (a, b) in self.counts.iter_mut().zip(&other.counts) {
+            *a += b;
+        }
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+    }
+
+    pub fn mean(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.sum / self.count as u128) as u64
+        }
+    }
+
+    /// Number of samples below `nanos`, at bucket resolution
+    pub fn count_below(&self, nanos: u64) -> u64 {
+        self.counts[..Self::index(nanos)].iter().sum()
+    }
+
+    /// Latency at percentile `p` (0..=100) in nanoseconds
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        if p >= 100.0 {
+            return self.max;
+        }
+        let rank = ((p / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::value(index).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchPattern {
+    Sequential,
+    Random,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub path: String,
+    pub pattern: BenchPattern,
+    pub read_percent: u8, // 100 read only, 0 write only, anything else mixed
+    pub block_size: usize,
+    pub queue_depth: usize, // in-flight I/Os per job, one synchronous worker each
+    pub jobs: usize,
+    pub runtime: Option<Duration>,
+    pub size: Option<u64>, // total bytes to transfer over all jobs
+    pub offset: u64,
+    pub range: Option<u64>, // bytes from `offset`, the rest of the target by default
+    pub rate_iops: Option<u64>,
+    pub direct: bool,
+    pub seed: u64,
+    pub trace: Option<String>,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            pattern: BenchPattern::Random,
+            read_percent: 100,
+            block_size: 4096,
+            queue_depth: 1,
+            jobs: 1,
+            runtime: None,
+            size: None,
+            offset: 0,
+            range: None,
+            rate_iops: None,
+            direct: false,
+            seed: 0x5EED,
+            trace: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BenchOpStats {
+    pub ios: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub latency: LatencyHistogram,
+}
+
+impl BenchOpStats {
+    fn merge(&mut self, other: &BenchOpStats) {
+        self.ios += other.ios;
+        self.bytes += other.bytes;
+        self.errors += other.errors;
+        self.latency.merge(&other.latency);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BenchReport {
+    pub read: BenchOpStats,
+    pub write: BenchOpStats,
+    pub elapsed: Duration,
+}
+
+impl BenchReport {
+    pub fn iops(stats: &BenchOpStats, elapsed: Duration) -> f64 {
+        stats.ios as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn bandwidth(stats: &BenchOpStats, elapsed: Duration) -> f64 {
+        stats.bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+pub fn fmt_latency(nanos: u64) -> String {
+    match nanos {
+        n if n < 10_000 => format!("{}ns", n),
+        n if n < 10_000_000 => format!("{:.1}us", n as f64 / 1e3),
+        n => format!("{:.2}ms", n as f64 / 1e6),
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Runtime: {:.2}s", self.elapsed.as_secs_f64())?;
+        for (name, stats) in [("read", &self.read), ("write", &self.write)] {
+            if stats.ios == 0 && stats.errors == 0 {
+                continue;
+            }
+            let lat = &stats.latency;
+            writeln!(
+                f,
+                "{:<5}: IOPS={:.0} BW={:.2}MiB/s ios={} bytes={} errors={}",
+                name,
+                Self::iops(stats, self.elapsed),
+                Self::bandwidth(stats, self.elapsed) / crate::MI_BYTES as f64,
+                stats.ios,
+                stats.bytes,
+                stats.errors
+            )?;
+            writeln!(
+                f,
+                "  lat: min={} avg={} max={}",
+                fmt_latency(lat.min.min(lat.max)),
+                fmt_latency(lat.mean()),
+                fmt_latency(lat.max)
+            )?;
+            let percentiles: Vec<String> = BENCH_PERCENTILES
+                .iter()
+                .map(|&p| format!("p{}={}", p, fmt_latency(lat.percentile(p))))
+                .collect();
+            writeln!(f, "  {}", percentiles.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// xorshift64*, enough for offsets and read/write mixing
+#[derive(Debug, Clone)]
+pub struct BenchRng(u64);
+
+impl BenchRng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    pub fn below(&mut self, bound: u64) -> u64 {
+        if bound == 0 {
+            0
+        } else {
+            self.next_u64() % bound
+        }
+    }
+}
+
+/// Open the target; a write run creates an image file that does not exist yet
+pub fn bench_open(path: &str, write: bool, direct: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(write);
+    if write && !path.starts_with("/dev/") {
+        options.create(true);
+    }
+    if direct {
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_DIRECT);
+        #[cfg(windows)]
+        std::os::windows::fs::OpenOptionsExt::custom_flags(
+            &mut options,
+            windows_sys::Win32::Storage::FileSystem::FILE_FLAG_NO_BUFFERING,
+        );
+    }
+    options.open(path)
+}
+
+#[cfg(unix)]
+pub(crate) fn pread(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+pub(crate) fn pwrite(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn pread(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn pwrite(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+}
+
+/// Shared state of one job: the sequential cursor over its region and the
+/// bytes it has left when the run is size limited
+struct BenchJob {
+    start: u64,
+    blocks: u64,
+    cursor: AtomicU64,
+    budget: Option<AtomicU64>,
+}
+
+struct BenchShared {
+    config: BenchConfig,
+    stop: AtomicBool,
+    deadline: Option<Instant>,
+    trace: Option<TraceWriter>,
+}
+
+impl BenchShared {
+    /// Claim the next I/O of `job`, false once its size limit or the runtime
+    /// is reached
+    fn claim(&self, job: &BenchJob) -> bool {
+        if self.stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                self.stop.store(true, Ordering::Relaxed);
+                return false;
+            }
+        }
+        if let Some(budget) = &job.budget {
+            let bs = self.config.block_size as u64;
+            return budget
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                    left.checked_sub(bs)
+                })
+                .is_ok();
+        }
+        true
+    }
+}
+
+/// Job `index`'s part of `total` blocks split over `jobs`
+fn bench_share(total: u64, jobs: u64, index: u64) -> u64 {
+    total / jobs + u64::from(index < total % jobs)
+}
+
+fn bench_worker(
+    shared: &BenchShared,
+    job: &BenchJob,
+    file: &File,
+    worker: u32,
+    interval: Option<Duration>,
+) -> io::Result<(BenchOpStats, BenchOpStats)> {
+    let config = &shared.config;
+    let bs = config.block_size;
+    let seed = config
+        .seed
+        .wrapping_add((worker as u64).wrapping_mul(0x9E37));
+    let mut rng = BenchRng::new(seed);
+    let mut buffer = AlignedBuffer::new(bs, BENCH_ALIGNMENT);
+    for chunk in buffer.chunks_exact_mut(8) {
+        chunk.copy_from_slice(&rng.next_u64().to_le_bytes());
+    }
+    let (mut read, mut write) = (BenchOpStats::default(), BenchOpStats::default());
+    let mut trace = shared.trace.as_ref().map(|writer| writer.buffer());
+    let mut next_issue = Instant::now();
+
+    while shared.claim(job) {
+        if let Some(interval) = interval {
+            let now = Instant::now();
+            if next_issue > now {
+                thread::sleep(next_issue - now);
+            }
+            next_issue = next_issue.max(now) + interval;
+        }
+        let block = match config.pattern {
+            BenchPattern::Sequential => job.cursor.fetch_add(1, Ordering::Relaxed) % job.blocks,
+            BenchPattern::Random => rng.below(job.blocks),
+        };
+        let offset = job.start + block * bs as u64;
+        let is_read = match config.read_percent {
+            100 => true,
+            0 => false,
+            pct => rng.below(100) < pct as u64,
+        };
+
+        let mut latency = DiskLatency::new(bs);
+        let result = if is_read {
+            pread(file, &mut buffer, offset)
+        } else {
+            pwrite(file, &buffer, offset)
+        };
+        latency.end();
+        let (start, end, size) = latency.get();
+        let stats = if is_read { &mut read } else { &mut write };
+        let status = match result {
+            Ok(n) if n == size => {
+                stats.ios += 1;
+                stats.bytes += n as u64;
+                stats.latency.record((end - start).as_nanos() as u64);
+                0
+            }
+            Ok(_) => {
+                stats.errors += 1;
+                -1
+            }
+            Err(e) => {
+                stats.errors += 1;
+                e.raw_os_error().unwrap_or(-1)
+            }
+        };
+        if let (Some(trace), Some(writer)) = (&mut trace, &shared.trace) {
+            trace.record(&TraceRecord {
+                io_type: if is_read { "read" } else { "write" }.to_string(),
+                offset,
+                size: size as u64,
+                queue: worker,
+                start: writer.since_start(start),
+                end: writer.since_start(end),
+                status,
+            })?;
+        }
+    }
+    if let Some(mut trace) = trace {
+        trace.flush()?;
+    }
+    Ok((read, write))
+}
+
+/// Run the workload described by `config` and collect the merged statistics
+pub fn bench_run(config: &BenchConfig) -> io::Result<BenchReport> {
+    let bs = config.block_size as u64;
+    if bs == 0 || config.jobs == 0 || config.queue_depth == 0 || config.read_percent > 100 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "block size, jobs and queue depth must be non zero, read percent at most 100",
+        ));
+    }
+    let writes = config.read_percent < 100;
+    let mut file = bench_open(&config.path, writes, config.direct)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", config.path, e)))?;
+    let mut capacity = file.seek(SeekFrom::End(0))?;
+    if let Some(range) = config.range {
+        let end = config.offset + range;
+        if capacity < end && writes && file.metadata()?.is_file() {
+            file.set_len(end)?;
+            capacity = end;
+        }
+    }
+    let range = config
+        .range
+        .unwrap_or(capacity.saturating_sub(config.offset))
+        .min(capacity.saturating_sub(config.offset));
+    let total_blocks = range / bs;
+    if total_blocks == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no room for a {} byte block", config.path, bs),
+        ));
+    }
+
+    // sequential jobs each get their own slice, random jobs share the whole
+    // range; a size limit is split between the jobs
+    let job_count = config.jobs as u64;
+    let jobs: Vec<BenchJob> = (0..job_count)
+        .map(|index| {
+            let (start, blocks) = match config.pattern {
+                BenchPattern::Sequential => {
+                    let blocks = (total_blocks / job_count).max(1);
+                    (config.offset + (index * blocks % total_blocks) * bs, blocks)
+                }
+                BenchPattern::Random => (config.offset, total_blocks),
+            };
+            let budget = match (config.size, config.runtime, config.pattern) {
+                (Some(size), _, _) => Some(bench_share(size / bs, job_count, index) * bs),
+                // one pass over the range
+                (None, None, BenchPattern::Sequential) => Some(blocks * bs),
+                (None, None, BenchPattern::Random) => {
+                    Some(bench_share(total_blocks, job_count, index) * bs)
+                }
+                (None, Some(_), _) => None,
+            };
+            BenchJob {
+                start,
+                blocks,
+                cursor: AtomicU64::new(0),
+                budget: budget.map(AtomicU64::new),
+            }
+        })
+        .collect();
+    let workers = (config.jobs * config.queue_depth) as u64;
+    let interval = config
+        .rate_iops
+        .filter(|&iops| iops > 0)
+        .map(|iops| Duration::from_secs_f64(workers as f64 / iops as f64));
+    let start_time = Instant::now();
+    let trace = match &config.trace {
+        Some(filename) => Some(TraceWriter::create(filename, start_time)?),
+        None => None,
+    };
+    let shared = Arc::new(BenchShared {
+        config: config.clone(),
+        stop: AtomicBool::new(false),
+        deadline: config.runtime.map(|runtime| start_time + runtime),
+        trace,
+    });
+    let jobs = Arc::new(jobs);
+    let report = Arc::new(Mutex::new(BenchReport::default()));
+
+    let handles: Vec<_> = (0..workers)
+        .map(|worker| {
+            let shared = Arc::clone(&shared);
+            let jobs = Arc::clone(&jobs);
+            let report = Arc::clone(&report);
+            let file = file.try_clone();
+            thread::spawn(move || -> io::Result<()> {
+                let file = file?;
+                let job = &jobs[worker as usize / shared.config.queue_depth];
+                let (read, write) = bench_worker(&shared, job, &file, worker as u32, interval)?;
+                let mut report = report.lock().unwrap();
+                report.read.merge(&read);
+                report.write.merge(&write);
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle
+            .join()
+            .map_err(|_| io::Error::other("bench worker panicked"))??;
+    }
+    let elapsed = start_time.elapsed();
+
+    if let Some(trace) = &shared.trace {
+        trace.flush()?;
+    }
+    let mut report = Arc::try_unwrap(report)
+        .map_err(|_| io::Error::other("bench workers still running"))?
+        .into_inner()
+        .unwrap();
+    report.elapsed = elapsed;
+    Ok(report)
+}
+
+/// Parse sizes like 4096, 4k, 128K, 1m, 2G (binary units)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = value
+        .find(|c: char| !c.is_ascii_digit())
+        .map(|pos| value.split_at(pos))
+        .unwrap_or((value, ""));
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+    let shift = match unit.to_ascii_lowercase().trim_end_matches(['b', 'i']) {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        _ => return Err(format!("invalid size unit '{}'", unit)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{}' too large", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        // exact below 64, then 64 buckets per power of two
+        for value in 0..64 {
+            assert_eq!(LatencyHistogram::index(value), value as usize);
+            assert_eq!(LatencyHistogram::value(value as usize), value);
+        }
+        assert_eq!(LatencyHistogram::index(64), 64);
+        assert_eq!(LatencyHistogram::index(127), 127);
+        assert_eq!(LatencyHistogram::index(128), 128);
+        assert_eq!(LatencyHistogram::index(129), 128);
+        assert_eq!(LatencyHistogram::index(130), 129);
+        assert_eq!(LatencyHistogram::index(u64::MAX), HIST_BUCKETS - 1);
+        // every value lands in a bucket whose middle is within 1.6%
+        for value in [65, 1000, 4095, 4096, 123_456_789, u64::MAX / 3] {
+            let middle = LatencyHistogram::value(LatencyHistogram::index(value));
+            assert!(
+                middle.abs_diff(value) as f64 <= value as f64 / 64.0,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!((histogram.percentile(50.0), histogram.mean()), (0, 0));
+        for nanos in 1..=100 {
+            histogram.record(nanos * 1000);
+        }
+        assert_eq!((histogram.min, histogram.max), (1000, 100_000));
+        assert_eq!(histogram.mean(), 50_500);
+        assert_eq!(histogram.percentile(100.0), 100_000);
+        // the first rank stays within the minimum's bucket, never below it
+        assert!((1000..1000 + 1000 / 64).contains(&histogram.percentile(0.0)));
+        for (p, expected) in [(50.0, 50_000), (90.0, 90_000), (99.0, 99_000)] {
+            let value = histogram.percentile(p);
+            assert!(
+                value.abs_diff(expected) <= expected / 64,
+                "p{} = {}",
+                p,
+                value
+            );
+        }
+        assert_eq!(histogram.count_below(50_000), 49);
+
+        let mut other = LatencyHistogram::default();
+        other.record(10);
+        histogram.merge(&other);
+        assert_eq!((histogram.count, histogram.min), (101, 10));
+        assert_eq!(histogram.percentile(0.5), 10);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size(" 4k "), Ok(4096));
+        assert_eq!(parse_size("128K"), Ok(128 << 10));
+        assert_eq!(parse_size("1m"), Ok(1 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("3t"), Ok(3 << 40));
+        for (value, expected) in [("4ki", 4096), ("4KiB", 4096), ("4kb", 4096), ("8b", 8)] {
+            assert_eq!(parse_size(value), Ok(expected), "{}", value);
+        }
+        for value in ["", "k", "-1", "1.5k", "4x", "4kk", "0x10"] {
+            assert!(parse_size(value).is_err(), "{}", value);
+        }
+        // high bits are not dropped
+        assert_eq!(parse_size("16777215t"), Ok(16777215 << 40));
+        assert!(parse_size("16777216t").is_err());
+        assert!(parse_size("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn job_shares() {
+        let shares: Vec<u64> = (0..3).map(|index| bench_share(10, 3, index)).collect();
+        assert_eq!(shares, [4, 3, 3]);
+        assert_eq!(bench_share(2, 3, 2), 0);
+    }
+
+    #[test]
+    fn sequential_jobs_cover_the_range() {
+        let path = std::env::temp_dir().join(format!("bench-seq-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let bs = 4096;
+        let config = BenchConfig {
+            path: path.to_string_lossy().into_owned(),
+            pattern: BenchPattern::Sequential,
+            read_percent: 0,
+            block_size: bs,
+            jobs: 3,
+            range: Some(12 * bs as u64),
+            ..Default::default()
+        };
+        // the image file is created and grown to the range
+        let report = bench_run(&config);
+        let data = std::fs::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        let report = report.unwrap();
+        let data = data.unwrap();
+        assert_eq!(data.len(), 12 * bs);
+        assert_eq!((report.write.ios, report.write.errors), (12, 0));
+        for (block, chunk) in data.chunks(bs).enumerate() {
+            assert!(
+                chunk.iter().any(|&b| b != 0),
+                "block {} never written",
+                block
+            );
+        }
+    }
+}
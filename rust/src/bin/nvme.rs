@@ -1,12 +1,52 @@
 use clap::{Parser, Subcommand};
+use nvme::bench::{bench_run, parse_size, BenchConfig, BenchPattern};
+#[cfg(windows)]
 use nvme::dev::dev_utils::{NvmeController, NvmeControllerList, PhysicalDisk};
+#[cfg(windows)]
+use nvme::dev::nvme_aer::nvme_aer_inbox_error;
+use nvme::dev::nvme_aer::NvmeAerMonitor;
+use nvme::dev::nvme_commands::NvmeTransport;
+#[cfg(windows)]
+use nvme::dev::nvme_commands::{
+    nvme_apst_build_table, nvme_apst_set_entry, nvme_apst_supported, nvme_security_certificate,
+    nvme_security_protocol_list, nvme_zone_extension_data, ApstPolicy, NvmeIoOptions, NvmeNsFormat,
+};
+use nvme::dev::nvme_commands::{nvme_opcode_direction, NvmeOpcodeType};
+#[cfg(windows)]
+use nvme::dev::nvme_decode::NvmeSmartLog;
+use nvme::dev::nvme_decode::{nvme_decode, NvmeDecodeKind};
+#[cfg(windows)]
 use nvme::dev::nvme_define::{
-    NVME_CDW10_GET_FEATURES, NVME_CDW10_IDENTIFY, NVME_IDENTIFY_CNS_CODES,
+    NVME_CDW10_GET_FEATURES, NVME_CDW10_IDENTIFY, NVME_CDW11_DATASET_MANAGEMENT,
+    NVME_CDW13_READ_WRITE_DSM, NVME_DIRECTIVE_TYPES, NVME_IDENTIFY_CNS_CODES, NVME_LOG_PAGES,
 };
+use nvme::dev::nvme_define::{NVME_CDW11_FEATURE_ASYNC_EVENT_CONFIG, NVME_COMMAND};
+#[cfg(windows)]
+use nvme::dev::nvme_health::{NvmeAlertSink, NvmeHealthRules, NvmeHealthWatch};
+use nvme::dev::nvme_print::{print_hex_dump, print_nvme_sqe};
+#[cfg(windows)]
 use nvme::dev::nvme_print::{
-    print_nvme_get_feature, print_nvme_identify_controller_data,
-    print_nvme_identify_namespace_data, print_nvme_ns_list, print_nvme_set_feature,
+    print_nvme_apst_table, print_nvme_dir_identify, print_nvme_get_feature,
+    print_nvme_identify_controller_data, print_nvme_identify_namespace_data, print_nvme_ns_list,
+    print_nvme_resv_report, print_nvme_security_certificates, print_nvme_security_protocols,
+    print_nvme_set_feature, print_nvme_streams_params, print_nvme_streams_status,
+    print_nvme_zns_id_ctrl, print_nvme_zns_id_ns, print_nvme_zone_report, print_opal_locking_range,
 };
+#[cfg(windows)]
+use nvme::dev::nvme_record::NvmeRecorder;
+use nvme::dev::nvme_record::NvmeReplay;
+#[cfg(windows)]
+use nvme::dev::nvme_retry::NvmeTimeouts;
+use nvme::dev::nvme_retry::{NvmeRetry, NvmeRetryPolicy};
+use nvme::dev::nvme_vendor::{VendorCommand, VendorDefinition, VendorPhaseCommand, VendorRegistry};
+#[cfg(windows)]
+use nvme::dev::opal::OpalDevice;
+use nvme::dev::tcg::TcgLevel0Discovery;
+use nvme::integrity::{integrity_run, IntegrityConfig};
+use nvme::trace::{trace_analyze_file, TraceAnalyzeOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser, Default)]
 #[command(author, version, about)]
@@ -20,6 +60,32 @@ struct Args {
     /// pci bus number. ex) 3 -> "3:0.0"
     #[arg(short, long)]
     bus: Option<i32>,
+    /// append passthru and vendor run commands with their completions to a trace file;
+    /// identify, log, feature and the other built-in commands go to the driver
+    /// directly and are not recorded
+    #[arg(long)]
+    record: Option<String>,
+    /// answer passthru, vendor run and aer-monitor commands from a recorded trace instead of a device
+    #[arg(long, conflicts_with = "record")]
+    replay: Option<String>,
+    /// command timeout in seconds, for the commands without a longer default
+    #[arg(long, value_name = "SECS")]
+    default_timeout: Option<u32>,
+    /// Format NVM timeout in seconds
+    #[arg(long, value_name = "SECS")]
+    format_timeout: Option<u32>,
+    /// Sanitize timeout in seconds
+    #[arg(long, value_name = "SECS")]
+    sanitize_timeout: Option<u32>,
+    /// Firmware Commit timeout in seconds
+    #[arg(long, value_name = "SECS")]
+    fw_commit_timeout: Option<u32>,
+    /// times to resend an idempotent command that failed with a transient status and DNR clear
+    #[arg(long)]
+    retries: Option<u32>,
+    /// delay before the first retry in milliseconds, doubling after each one
+    #[arg(long, value_name = "MS")]
+    retry_backoff: Option<u64>,
 }
 
 #[derive(Subcommand)]
@@ -56,6 +122,9 @@ enum Commands {
         /// log id
         #[clap(short, long)]
         lid: String,
+        /// print decoded logs as JSON
+        #[clap(long)]
+        json: bool,
     },
     /// Get Feature
     GetFeature {
@@ -75,8 +144,778 @@ enum Commands {
         #[clap(short, long, default_value = "0")]
         value: u32,
     },
+    /// Autonomous Power State Transition table (FID 0Ch)
+    Apst {
+        /// build the table from the power state latencies
+        #[clap(short, long)]
+        generate: bool,
+        /// max exit latency (us) allowed for the generated table
+        #[clap(short, long, default_value = "100000")]
+        latency: u64,
+        /// edit an entry: <ps>:<itps>:<itpt ms>
+        #[clap(short, long)]
+        entry: Vec<String>,
+        /// APSTE to set (0 or 1)
+        #[clap(short = 'E', long)]
+        enable: Option<u8>,
+    },
+    /// Register, unregister or replace a reservation key
+    ResvRegister {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// current reservation key
+        #[clap(short, long, default_value = "0")]
+        crkey: u64,
+        /// new reservation key
+        #[clap(short = 'k', long, default_value = "0")]
+        nrkey: u64,
+        /// register action: 0 register, 1 unregister, 2 replace
+        #[clap(short, long, default_value = "0")]
+        rrega: u8,
+        /// change persist through power loss: 0 no change, 2 clear, 3 set
+        #[clap(short = 'p', long, default_value = "0")]
+        cptpl: u8,
+        /// ignore existing key
+        #[clap(short, long)]
+        iekey: bool,
+    },
+    /// Acquire or preempt a reservation
+    ResvAcquire {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// current reservation key
+        #[clap(short, long, default_value = "0")]
+        crkey: u64,
+        /// preempt reservation key
+        #[clap(short, long, default_value = "0")]
+        prkey: u64,
+        /// reservation type
+        #[clap(short, long, default_value = "1")]
+        rtype: u8,
+        /// acquire action: 0 acquire, 1 preempt, 2 preempt and abort
+        #[clap(short, long, default_value = "0")]
+        acquire: u8,
+        /// ignore existing key
+        #[clap(short, long)]
+        iekey: bool,
+    },
+    /// Release or clear a reservation
+    ResvRelease {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// current reservation key
+        #[clap(short, long, default_value = "0")]
+        crkey: u64,
+        /// reservation type
+        #[clap(short, long, default_value = "1")]
+        rtype: u8,
+        /// release action: 0 release, 1 clear
+        #[clap(short = 'a', long, default_value = "0")]
+        rrela: u8,
+        /// ignore existing key
+        #[clap(short, long)]
+        iekey: bool,
+    },
+    /// Reservation Report
+    ResvReport {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// extended data structure (128-bit host identifier)
+        #[clap(short, long)]
+        eds: bool,
+    },
+    /// Directive Receive (identify: 1 return parameters / streams: 1 return parameters, 2 get status, 3 allocate resources)
+    DirReceive {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// directive type: 0 identify, 1 streams
+        #[clap(short = 'D', long, default_value = "0")]
+        dtype: u8,
+        /// directive operation
+        #[clap(short = 'O', long, default_value = "1")]
+        doper: u8,
+        /// namespace streams requested (allocate resources)
+        #[clap(short, long, default_value = "0")]
+        req_resource: u16,
+    },
+    /// Directive Send (identify: 1 enable directive / streams: 1 release identifier, 2 release resources)
+    DirSend {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// directive type: 0 identify, 1 streams
+        #[clap(short = 'D', long, default_value = "0")]
+        dtype: u8,
+        /// directive operation
+        #[clap(short = 'O', long, default_value = "1")]
+        doper: u8,
+        /// directive specific (stream identifier to release)
+        #[clap(short = 'S', long, default_value = "0")]
+        dspec: u16,
+        /// directive type to enable or disable (enable directive)
+        #[clap(short = 'T', long, default_value = "1")]
+        target_dir: u8,
+        /// enable (1) or disable (0) the target directive
+        #[clap(short, long, default_value = "1")]
+        endir: u8,
+    },
+    /// NVMe Security Send (81h)
+    SecuritySend {
+        /// nsid
+        #[clap(short, long, default_value = "0")]
+        nsid: u32,
+        /// security protocol (SECP)
+        #[clap(short = 'p', long)]
+        secp: u8,
+        /// SP specific (SPSP)
+        #[clap(short, long, default_value = "0")]
+        spsp: u16,
+        /// NVMe security specific field (NSSF)
+        #[clap(short = 'N', long, default_value = "0")]
+        nssf: u8,
+        /// file holding the payload
+        #[clap(short, long)]
+        file: String,
+    },
+    /// NVMe Security Receive (82h)
+    SecurityRecv {
+        /// nsid
+        #[clap(short, long, default_value = "0")]
+        nsid: u32,
+        /// security protocol (SECP)
+        #[clap(short = 'p', long, default_value = "0")]
+        secp: u8,
+        /// SP specific (SPSP)
+        #[clap(short, long, default_value = "0")]
+        spsp: u16,
+        /// NVMe security specific field (NSSF)
+        #[clap(short = 'N', long, default_value = "0")]
+        nssf: u8,
+        /// allocation length
+        #[clap(short = 'x', long, default_value = "4096")]
+        size: usize,
+        /// write the received data to a file
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+    /// TCG Level 0 Discovery
+    Discovery0 {
+        /// parse a saved discovery response instead of querying the device
+        #[clap(short, long)]
+        file: Option<String>,
+    },
+    /// ZNS Identify Namespace (CNS 05h, CSI 02h)
+    ZnsIdNs {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+    },
+    /// ZNS Identify Controller (CNS 06h, CSI 02h)
+    ZnsIdCtrl {},
+    /// Zone Management Receive: Report Zones / Extended Report Zones
+    ReportZones {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// starting lba
+        #[clap(short, long, default_value = "0")]
+        slba: u64,
+        /// state filter: 0 all, 1 empty, 2 implicitly opened, 3 explicitly opened, 4 closed, 5 full, 6 read-only, 7 offline
+        #[clap(short = 'S', long, default_value = "0")]
+        state: u8,
+        /// number of zones to report
+        #[clap(short, long, default_value = "64")]
+        count: usize,
+        /// partial report: zone count only covers the returned zones
+        #[clap(short, long)]
+        partial: bool,
+        /// extended report with zone descriptor extensions
+        #[clap(short, long)]
+        extended: bool,
+    },
+    /// Zone Management Send
+    ZoneMgmtSend {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// zone start lba
+        #[clap(short, long, default_value = "0")]
+        slba: u64,
+        /// zone send action: 1 close, 2 finish, 3 open, 4 reset, 5 offline, 16 set descriptor extension
+        #[clap(short, long)]
+        zsa: u8,
+        /// apply to all zones, slba is ignored
+        #[clap(short, long)]
+        all: bool,
+        /// file holding the zone descriptor extension data
+        #[clap(short, long)]
+        file: Option<String>,
+    },
+    /// Zone Append
+    ZoneAppend {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// zone start lba
+        #[clap(short, long)]
+        zslba: u64,
+        /// file holding the data, padded to the lba size
+        #[clap(short, long)]
+        file: String,
+        /// force unit access
+        #[clap(long)]
+        fua: bool,
+    },
+    /// Dataset Management (TRIM)
+    Dsm {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// lba range as <slba>:<nlb>, repeatable; large ranges are split
+        #[clap(short, long, required_unless_present = "all")]
+        range: Vec<String>,
+        /// deallocate every block of the namespace
+        #[clap(long, conflicts_with = "range")]
+        all: bool,
+        /// attribute deallocate (AD), the default when no attribute is given
+        #[clap(short, long)]
+        ad: bool,
+        /// attribute integral dataset for read (IDR)
+        #[clap(long)]
+        idr: bool,
+        /// attribute integral dataset for write (IDW)
+        #[clap(long)]
+        idw: bool,
+        /// read back the first block and check it against DLFEAT, needs deallocate
+        #[clap(short, long)]
+        verify: bool,
+    },
+    /// NVM Read, data and metadata are written to files
+    Read {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// starting lba
+        #[clap(short, long, default_value = "0")]
+        slba: u64,
+        /// number of blocks
+        #[clap(short, long, default_value = "1")]
+        count: u32,
+        /// data output file, hex dump when omitted
+        #[clap(short, long)]
+        output: Option<String>,
+        /// metadata output file (extended LBA formats)
+        #[clap(short, long)]
+        metadata: Option<String>,
+        #[command(flatten)]
+        io: IoOptions,
+    },
+    /// NVM Write from a file, padded to the lba size
+    Write {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// starting lba
+        #[clap(short, long, default_value = "0")]
+        slba: u64,
+        /// data input file
+        #[clap(short, long)]
+        file: String,
+        /// metadata input file (extended LBA formats)
+        #[clap(short, long)]
+        metadata: Option<String>,
+        #[command(flatten)]
+        io: IoOptions,
+    },
+    /// NVM Compare against a file, padded to the lba size
+    Compare {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// starting lba
+        #[clap(short, long, default_value = "0")]
+        slba: u64,
+        /// data input file
+        #[clap(short, long)]
+        file: String,
+        /// metadata input file (extended LBA formats)
+        #[clap(short, long)]
+        metadata: Option<String>,
+        #[command(flatten)]
+        io: IoOptions,
+    },
+    /// NVM Write Zeroes
+    WriteZeroes {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// starting lba
+        #[clap(short, long, default_value = "0")]
+        slba: u64,
+        /// number of blocks
+        #[clap(short, long, default_value = "1")]
+        count: u32,
+        /// deallocate the blocks (DEAC)
+        #[clap(long)]
+        deac: bool,
+        #[command(flatten)]
+        io: IoOptions,
+    },
+    /// NVM Write Uncorrectable
+    WriteUncor {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// starting lba
+        #[clap(short, long, default_value = "0")]
+        slba: u64,
+        /// number of blocks
+        #[clap(short, long, default_value = "1")]
+        count: u32,
+    },
+    /// NVM Verify
+    Verify {
+        /// nsid
+        #[clap(short, long, default_value = "1")]
+        nsid: u32,
+        /// starting lba
+        #[clap(short, long, default_value = "0")]
+        slba: u64,
+        /// number of blocks
+        #[clap(short, long, default_value = "1")]
+        count: u32,
+        #[command(flatten)]
+        io: IoOptions,
+    },
+    /// NVM Flush
+    Flush {
+        /// nsid
+        #[clap(short, long, default_value = "0xFFFFFFFF")]
+        nsid: String,
+    },
+    /// I/O benchmark against a file or block device
+    Bench {
+        /// target file or block device
+        path: String,
+        /// sequential instead of random offsets
+        #[clap(long)]
+        seq: bool,
+        /// percentage of reads, 100 read only, 0 write only
+        #[clap(short, long, default_value = "100")]
+        read: u8,
+        /// block size, e.g. 4k, 128k
+        #[clap(short, long, default_value = "4k", value_parser = parse_size)]
+        bs: u64,
+        /// queue depth per job
+        #[clap(short, long, default_value = "1")]
+        qd: usize,
+        /// number of jobs
+        #[clap(short, long, default_value = "1")]
+        jobs: usize,
+        /// runtime in seconds
+        #[clap(short, long)]
+        time: Option<u64>,
+        /// total bytes to transfer, e.g. 1g
+        #[clap(short, long, value_parser = parse_size)]
+        size: Option<u64>,
+        /// start offset in bytes
+        #[clap(long, default_value = "0", value_parser = parse_size)]
+        offset: u64,
+        /// bytes from the offset to run on, the rest of the target by default
+        #[clap(long, value_parser = parse_size)]
+        range: Option<u64>,
+        /// IOPS cap over all jobs
+        #[clap(long)]
+        rate: Option<u64>,
+        /// bypass the page cache (O_DIRECT / FILE_FLAG_NO_BUFFERING)
+        #[clap(long)]
+        direct: bool,
+        /// random seed
+        #[clap(long, default_value = "24301")]
+        seed: u64,
+        /// stream per-I/O offset, size, queue, timings and status to a csv file
+        #[clap(long)]
+        trace: Option<String>,
+    },
+    /// Write-then-verify with LBA-stamped blocks on a file or block device
+    Integrity {
+        /// target file or block device
+        path: String,
+        /// stamped block size, usually the logical block size
+        #[clap(short, long, default_value = "512", value_parser = parse_size)]
+        bs: u64,
+        /// bytes per read or write
+        #[clap(long, default_value = "128k", value_parser = parse_size)]
+        io_size: u64,
+        /// start offset in bytes
+        #[clap(long, default_value = "0", value_parser = parse_size)]
+        offset: u64,
+        /// bytes from the offset to run on, the rest of the target by default
+        #[clap(long, value_parser = parse_size)]
+        range: Option<u64>,
+        /// number of write+verify passes
+        #[clap(short, long, default_value = "1")]
+        passes: u32,
+        /// pass number of the first pass, the expected pass with --verify-only
+        #[clap(long, default_value = "1")]
+        pass: u32,
+        /// seed mixed into every stamp
+        #[clap(long, default_value = "24301")]
+        seed: u64,
+        /// only read back and check a previously written region
+        #[clap(long)]
+        verify_only: bool,
+        /// progress file to resume from and checkpoint to; resuming needs the
+        /// same --bs, --offset, --range and --seed
+        #[clap(long)]
+        state: Option<String>,
+        /// bypass the page cache (O_DIRECT / FILE_FLAG_NO_BUFFERING)
+        #[clap(long)]
+        direct: bool,
+        /// mismatches to print in detail
+        #[clap(long, default_value = "32")]
+        max_report: usize,
+        /// stop at the first I/O with a mismatch
+        #[clap(long)]
+        stop_on_error: bool,
+    },
+    /// Summarize a bench or save_trace csv: throughput, latency histograms, outliers
+    AnalyzeTrace {
+        /// trace csv file
+        file: String,
+        /// throughput sample interval in milliseconds
+        #[clap(short, long, default_value = "1000")]
+        interval: u64,
+        /// number of slowest I/Os to list
+        #[clap(short, long, default_value = "10")]
+        outliers: usize,
+        /// also list every I/O slower than this many microseconds
+        #[clap(long)]
+        threshold: Option<u64>,
+        /// write the throughput series to a csv file
+        #[clap(long)]
+        series: Option<String>,
+    },
+    /// Decode a saved identify or log page binary without a device
+    Decode {
+        /// binary file
+        file: String,
+        /// structure in the file
+        #[clap(short = 't', long = "type", value_parser = [
+            "id-ctrl", "id-ns", "smart", "error", "fw-slot", "effects", "self-test",
+            "sanitize", "telemetry", "persistent-event", "changed-ns",
+        ])]
+        kind: String,
+        /// print JSON instead of text
+        #[clap(long)]
+        json: bool,
+    },
+    /// Send an admin command built from raw command dwords
+    AdminPassthru {
+        #[command(flatten)]
+        cmd: PassthruOptions,
+    },
+    /// Send an I/O command built from raw command dwords
+    IoPassthru {
+        #[command(flatten)]
+        cmd: PassthruOptions,
+    },
+    /// Vendor-specific commands from TOML/JSON description files
+    Vendor {
+        #[command(subcommand)]
+        action: VendorCommands,
+    },
+    /// Poll SMART / Health on every controller and alert when rules trip
+    Monitor {
+        #[command(flatten)]
+        options: MonitorOptions,
+    },
+    /// Wait for asynchronous events and read their log pages to clear them. The Windows
+    /// inbox driver keeps Asynchronous Event Requests to itself, so events come from --replay
+    AerMonitor {
+        /// Async Event Configuration (FID 0Bh) to set; every supported event by default
+        #[arg(long, value_parser = parse_u32)]
+        config: Option<u32>,
+        /// stop after this many events
+        #[arg(short, long)]
+        count: Option<u32>,
+        /// leave the log pages unread, so the events stay masked
+        #[arg(long)]
+        no_clear: bool,
+        /// print one JSON object per event
+        #[arg(long)]
+        json: bool,
+    },
+    /// TCG Opal ownership, activation and locking ranges
+    Opal {
+        #[command(subcommand)]
+        action: OpalCommands,
+    },
+}
+
+#[derive(clap::Args)]
+struct IoOptions {
+    /// force unit access
+    #[clap(long)]
+    fua: bool,
+    /// limited retry
+    #[clap(long)]
+    lr: bool,
+    /// protection information field (PRACT/PRCHK)
+    #[clap(long, default_value = "0")]
+    prinfo: u8,
+    /// dataset management hints (CDW13 bits 7:0)
+    #[clap(long, default_value = "0")]
+    dsm: u8,
+    /// directive type
+    #[clap(long, default_value = "0")]
+    dtype: u8,
+    /// directive specific
+    #[clap(long, default_value = "0")]
+    dspec: u16,
+    /// initial logical block reference tag
+    #[clap(long, default_value = "0")]
+    reftag: u32,
+    /// logical block application tag
+    #[clap(long, default_value = "0")]
+    apptag: u16,
+    /// logical block application tag mask
+    #[clap(long, default_value = "0")]
+    appmask: u16,
+}
+
+#[derive(clap::Args)]
+struct PassthruOptions {
+    /// opcode, e.g. 0x06
+    #[clap(short, long, value_parser = parse_u8)]
+    opcode: u8,
+    /// nsid
+    #[clap(short, long, default_value = "0", value_parser = parse_u32)]
+    nsid: u32,
+    #[clap(long, default_value = "0", value_parser = parse_u32)]
+    cdw2: u32,
+    #[clap(long, default_value = "0", value_parser = parse_u32)]
+    cdw3: u32,
+    #[clap(long, default_value = "0", value_parser = parse_u32)]
+    cdw10: u32,
+    #[clap(long, default_value = "0", value_parser = parse_u32)]
+    cdw11: u32,
+    #[clap(long, default_value = "0", value_parser = parse_u32)]
+    cdw12: u32,
+    #[clap(long, default_value = "0", value_parser = parse_u32)]
+    cdw13: u32,
+    #[clap(long, default_value = "0", value_parser = parse_u32)]
+    cdw14: u32,
+    #[clap(long, default_value = "0", value_parser = parse_u32)]
+    cdw15: u32,
+    /// data buffer length, e.g. 4k; the input file length when not given,
+    /// shorter input files are padded with zeros
+    #[clap(short = 'l', long, value_parser = parse_size)]
+    data_len: Option<u64>,
+    /// none, write, read or both; taken from the opcode's low bits by default
+    #[clap(short, long, value_parser = parse_direction)]
+    direction: Option<u8>,
+    /// data to send with a write command
+    #[clap(short, long)]
+    input_file: Option<String>,
+    /// write the returned data to a file instead of a hex dump
+    #[clap(long)]
+    output: Option<String>,
+    /// command timeout in seconds
+    #[clap(short, long)]
+    timeout: Option<u32>,
+    /// separate metadata buffer length
+    #[clap(short, long, default_value = "0", value_parser = parse_size)]
+    metadata_len: u64,
+    /// show the encoded submission queue entry without sending it
+    #[clap(long)]
+    dry_run: bool,
+}
+
+fn parse_u32(value: &str) -> Result<u32, String> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse::<u32>(),
+    }
+    .map_err(|e| format!("{}: {}", value, e))
+}
+
+fn parse_u8(value: &str) -> Result<u8, String> {
+    let number = parse_u32(value)?;
+    u8::try_from(number).map_err(|_| format!("{} does not fit in a byte", value))
+}
+
+fn parse_u16(value: &str) -> Result<u16, String> {
+    let number = parse_u32(value)?;
+    u16::try_from(number).map_err(|_| format!("{} does not fit in 16 bits", value))
+}
+
+/// Data direction in the NvmeOpcodeType encoding
+fn parse_direction(value: &str) -> Result<u8, String> {
+    match value {
+        "none" => Ok(0),
+        "write" => Ok(1),
+        "read" => Ok(2),
+        "both" => Ok(3),
+        _ => Err(format!("{}: expected none, write, read or both", value)),
+    }
+}
+
+#[derive(clap::Args)]
+struct MonitorOptions {
+    /// seconds between polls
+    #[arg(short, long, default_value_t = 60)]
+    interval: u64,
+    /// stop after this many polls
+    #[arg(short, long)]
+    count: Option<u32>,
+    /// percentage-used points between alerts, 0 to disable
+    #[arg(long, default_value_t = 1)]
+    used_step: u8,
+    /// skip the WCTEMP/CCTEMP temperature rule
+    #[arg(long)]
+    no_temperature: bool,
+    /// skip the available spare rule
+    #[arg(long)]
+    no_spare: bool,
+    /// skip the media and data integrity errors rule
+    #[arg(long)]
+    no_media_errors: bool,
+    /// skip the error information log entries rule
+    #[arg(long)]
+    no_error_log: bool,
+    /// append alerts as JSON lines to this file
+    #[arg(long)]
+    log: Option<String>,
+    /// POST each alert as JSON to this http:// URL
+    #[arg(long)]
+    webhook: Option<String>,
+    /// do not print alerts on stdout
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+#[derive(clap::Args)]
+struct VendorOptions {
+    /// description file or directory of .toml/.json files, repeatable; by default the
+    /// vendor directory next to the executable, in the source tree or the working directory
+    #[clap(short, long = "defs")]
+    defs: Vec<String>,
+    /// PCI vendor ID to match without a device, e.g. 0x1b36
+    #[clap(long, value_parser = parse_u16)]
+    vid: Option<u16>,
+    /// model number to match without a device
+    #[clap(long, default_value = "")]
+    model: String,
+}
+
+#[derive(Subcommand)]
+enum VendorCommands {
+    /// List the commands registered for the device, or for --vid/--model
+    List {
+        #[command(flatten)]
+        registry: VendorOptions,
+    },
+    /// Run a registered command by name
+    Run {
+        /// command name
+        name: String,
+        #[command(flatten)]
+        registry: VendorOptions,
+        /// parameter value as name=value, repeatable
+        #[clap(short, long)]
+        arg: Vec<String>,
+        /// nsid
+        #[clap(short, long, default_value = "0", value_parser = parse_u32)]
+        nsid: u32,
+        /// data for phases that take the input
+        #[clap(short, long)]
+        input_file: Option<String>,
+        /// write the returned data to a file
+        #[clap(long)]
+        output: Option<String>,
+        /// command timeout in seconds
+        #[clap(short, long)]
+        timeout: Option<u32>,
+        /// show the encoded phases without sending them
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum OpalCommands {
+    /// Set the SID password using the MSID
+    TakeOwnership {
+        /// new SID password
+        #[clap(short, long)]
+        password: String,
+    },
+    /// Activate the Locking SP
+    Activate {
+        /// SID password
+        #[clap(short, long)]
+        password: String,
+    },
+    /// Configure a locking range and enable read/write locking
+    SetupRange {
+        /// Admin1 password
+        #[clap(short, long)]
+        password: String,
+        /// locking range, 0 is the global range
+        #[clap(short, long, default_value = "0")]
+        range: u8,
+        /// range start lba
+        #[clap(short, long, default_value = "0")]
+        start: u64,
+        /// range length in lba
+        #[clap(short, long, default_value = "0")]
+        length: u64,
+    },
+    /// Lock a locking range
+    Lock {
+        /// Admin1 password
+        #[clap(short, long)]
+        password: String,
+        /// locking range, 0 is the global range
+        #[clap(short, long, default_value = "0")]
+        range: u8,
+    },
+    /// Unlock a locking range
+    Unlock {
+        /// Admin1 password
+        #[clap(short, long)]
+        password: String,
+        /// locking range, 0 is the global range
+        #[clap(short, long, default_value = "0")]
+        range: u8,
+    },
+    /// Show the state of a locking range
+    Range {
+        /// Admin1 password
+        #[clap(short, long)]
+        password: String,
+        /// locking range, 0 is the global range
+        #[clap(short, long, default_value = "0")]
+        range: u8,
+    },
+    /// Revert the TPer to factory state with the PSID from the label
+    PsidRevert {
+        /// PSID
+        #[clap(short, long)]
+        psid: String,
+    },
 }
 
+#[cfg(windows)]
 struct CliManager<'a> {
     args: Args,
     disk: Option<PhysicalDisk>,
@@ -84,6 +923,7 @@ struct CliManager<'a> {
     nvme_list: &'a mut NvmeControllerList, // Add this line to store the controller list
 }
 
+#[cfg(windows)]
 impl<'a> CliManager<'a> {
     fn new(nvme_list: &'a mut NvmeControllerList) -> Self {
         let args = Args::parse();
@@ -95,6 +935,10 @@ impl<'a> CliManager<'a> {
         }
     }
     fn open_device(&mut self) -> &mut Self {
+        if self.args.replay.is_some() {
+            // the trace stands in for the device
+            return self;
+        }
         if let Some(driveno) = self.args.disk {
             if let Some(disk) = self.nvme_list.by_num(driveno) {
                 disk.open();
@@ -106,6 +950,11 @@ impl<'a> CliManager<'a> {
                 ctrl.open();
                 self.ctrl = Some(ctrl.clone());
             }
+        } else if let Some(Commands::Monitor { .. }) = &self.args.command {
+            // without --bus every controller is watched
+            for controller in self.nvme_list.iter_mut() {
+                controller.open();
+            }
         }
         self
     }
@@ -119,6 +968,8 @@ impl<'a> CliManager<'a> {
     fn disk_manager(&self) {
         if let Some(disk) = &self.disk {
             let device = disk.get_driver();
+            device.set_timeouts(nvme_timeouts(&self.args));
+            device.set_retry_policy(nvme_retry_policy(&self.args));
             match &self.args.command {
                 Some(Commands::IdCtrl {}) => {
                     let info = device.nvme_identify_controller().unwrap();
@@ -146,14 +997,27 @@ impl<'a> CliManager<'a> {
                         .collect();
                     print_nvme_ns_list(&ns_list);
                 }
-                Some(Commands::GetLog { lid }) => {
+                Some(Commands::GetLog { lid, json }) => {
                     let lid = if lid.starts_with("0x") {
                         u32::from_str_radix(&lid[2..], 16).unwrap()
                     } else {
                         lid.parse::<u32>().unwrap()
                     };
                     let info = device.nvme_logpage_query(lid, 0).unwrap();
-                    println!("logid: {} - {} {:?}", lid, info.len(), &info[..20 as usize]);
+                    match NvmeDecodeKind::from_log_id(lid) {
+                        Some(kind) => decode_print(kind, &info, *json).unwrap(),
+                        None => {
+                            println!("logid: {} - {} {:?}", lid, info.len(), &info[..20 as usize])
+                        }
+                    }
+                }
+                Some(Commands::AerMonitor { .. }) => {
+                    // refused before the Async Event Configuration is touched
+                    eprintln!(
+                        "{}; pass --replay to monitor a recorded trace",
+                        nvme_aer_inbox_error()
+                    );
+                    std::process::exit(1);
                 }
                 Some(Commands::GetFeature { fid, sel }) => {
                     let cdw10 = NVME_CDW10_GET_FEATURES::new()
@@ -166,6 +1030,478 @@ impl<'a> CliManager<'a> {
                     let info = device.nvme_setfeature(*fid, *value).unwrap();
                     print_nvme_set_feature(*fid, info);
                 }
+                Some(Commands::Apst {
+                    generate,
+                    latency,
+                    entry,
+                    enable,
+                }) => {
+                    let ctrl = device.nvme_identify_controller().unwrap();
+                    let (mut apste, mut table) = device.nvme_get_apst(0).unwrap();
+                    if *generate {
+                        let policy = ApstPolicy {
+                            max_latency_us: *latency,
+                            ..Default::default()
+                        };
+                        table = nvme_apst_build_table(&ctrl, &policy);
+                    }
+                    for item in entry {
+                        let (ps, itps, itpt) =
+                            or_exit(sscanf::sscanf!(item, "{usize}:{u8}:{u32}").map_err(|_| {
+                                io::Error::new(
+                                    io::ErrorKind::InvalidInput,
+                                    format!("{}: expected <ps>:<itps>:<itpt>", item),
+                                )
+                            }));
+                        or_exit(nvme_apst_set_entry(&mut table, &ctrl, ps, itps, itpt));
+                    }
+                    if let Some(enable) = enable {
+                        apste = *enable != 0;
+                    }
+                    if *generate || !entry.is_empty() || enable.is_some() {
+                        if !nvme_apst_supported(&ctrl) {
+                            eprintln!("the controller does not support APST (APSTA is 0)");
+                            std::process::exit(1);
+                        }
+                        device.nvme_set_apst(apste, &table).unwrap();
+                        (apste, table) = device.nvme_get_apst(0).unwrap();
+                    }
+                    print_nvme_apst_table(apste, &table, &ctrl);
+                }
+                Some(Commands::ResvRegister {
+                    nsid,
+                    crkey,
+                    nrkey,
+                    rrega,
+                    cptpl,
+                    iekey,
+                }) => {
+                    device
+                        .nvme_resv_register(*nsid, *rrega, *cptpl, *iekey, *crkey, *nrkey)
+                        .and_then(|ncs| ncs.check("Reservation Register"))
+                        .unwrap();
+                    println!("Reservation Register: success");
+                }
+                Some(Commands::ResvAcquire {
+                    nsid,
+                    crkey,
+                    prkey,
+                    rtype,
+                    acquire,
+                    iekey,
+                }) => {
+                    device
+                        .nvme_resv_acquire(*nsid, *acquire, *rtype, *iekey, *crkey, *prkey)
+                        .and_then(|ncs| ncs.check("Reservation Acquire"))
+                        .unwrap();
+                    println!("Reservation Acquire: success");
+                }
+                Some(Commands::ResvRelease {
+                    nsid,
+                    crkey,
+                    rtype,
+                    rrela,
+                    iekey,
+                }) => {
+                    device
+                        .nvme_resv_release(*nsid, *rrela, *rtype, *iekey, *crkey)
+                        .and_then(|ncs| ncs.check("Reservation Release"))
+                        .unwrap();
+                    println!("Reservation Release: success");
+                }
+                Some(Commands::ResvReport { nsid, eds }) => {
+                    let status = device.nvme_resv_report(*nsid, *eds).unwrap();
+                    print_nvme_resv_report(&status);
+                }
+                Some(Commands::DirReceive {
+                    nsid,
+                    dtype,
+                    doper,
+                    req_resource,
+                }) => {
+                    const IDENTIFY: u8 = NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_IDENTIFY as u8;
+                    const STREAMS: u8 = NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_STREAMS as u8;
+                    match (*dtype, *doper) {
+                        (IDENTIFY, 1) => {
+                            print_nvme_dir_identify(&device.nvme_dir_identify(*nsid).unwrap())
+                        }
+                        (STREAMS, 1) => {
+                            print_nvme_streams_params(&device.nvme_streams_params(*nsid).unwrap())
+                        }
+                        (STREAMS, 2) => {
+                            print_nvme_streams_status(&device.nvme_streams_status(*nsid).unwrap())
+                        }
+                        (STREAMS, 3) => {
+                            let nsa = device.nvme_streams_allocate(*nsid, *req_resource).unwrap();
+                            println!("Namespace Streams Allocated (NSA): {}", nsa);
+                        }
+                        _ => println!("Unsupported directive type {} operation {}", dtype, doper),
+                    }
+                }
+                Some(Commands::DirSend {
+                    nsid,
+                    dtype,
+                    doper,
+                    dspec,
+                    target_dir,
+                    endir,
+                }) => {
+                    const IDENTIFY: u8 = NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_IDENTIFY as u8;
+                    const STREAMS: u8 = NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_STREAMS as u8;
+                    match (*dtype, *doper) {
+                        (IDENTIFY, 1) => {
+                            device
+                                .nvme_dir_enable(*nsid, *target_dir, *endir != 0)
+                                .unwrap();
+                            print_nvme_dir_identify(&device.nvme_dir_identify(*nsid).unwrap());
+                        }
+                        (STREAMS, 1) => {
+                            device.nvme_streams_release_id(*nsid, *dspec).unwrap();
+                            println!("Released stream identifier {}", dspec);
+                        }
+                        (STREAMS, 2) => {
+                            device.nvme_streams_release_resources(*nsid).unwrap();
+                            println!("Released stream resources");
+                        }
+                        _ => println!("Unsupported directive type {} operation {}", dtype, doper),
+                    }
+                }
+                Some(Commands::SecuritySend {
+                    nsid,
+                    secp,
+                    spsp,
+                    nssf,
+                    file,
+                }) => {
+                    let mut data = std::fs::read(file).unwrap();
+                    device
+                        .nvme_security_send(*nsid, *secp, *spsp, *nssf, &mut data)
+                        .unwrap();
+                    println!("Security Send: {} bytes", data.len());
+                }
+                Some(Commands::SecurityRecv {
+                    nsid,
+                    secp,
+                    spsp,
+                    nssf,
+                    size,
+                    output,
+                }) => {
+                    let mut data = vec![0u8; *size];
+                    device
+                        .nvme_security_recv(*nsid, *secp, *spsp, *nssf, &mut data)
+                        .unwrap();
+                    match (output, *secp, *spsp) {
+                        (Some(path), _, _) => std::fs::write(path, &data).unwrap(),
+                        (None, 0, 0) => {
+                            print_nvme_security_protocols(&nvme_security_protocol_list(&data))
+                        }
+                        (None, 0, 1) => {
+                            print_nvme_security_certificates(&nvme_security_certificate(&data))
+                        }
+                        (None, _, _) => print_hex_dump(&data),
+                    }
+                }
+                Some(Commands::Discovery0 { file: None }) => {
+                    let discovery = device.nvme_tcg_discovery0().unwrap();
+                    print!("{}", discovery);
+                }
+                Some(Commands::ZnsIdNs { nsid }) => {
+                    let ns = device.nvme_identify_namespace(*nsid).unwrap();
+                    let zns = device.nvme_zns_identify_ns(*nsid).unwrap();
+                    print_nvme_zns_id_ns(&zns, &ns);
+                }
+                Some(Commands::ZnsIdCtrl {}) => {
+                    print_nvme_zns_id_ctrl(&device.nvme_zns_identify_ctrl().unwrap());
+                }
+                Some(Commands::ReportZones {
+                    nsid,
+                    slba,
+                    state,
+                    count,
+                    partial,
+                    extended,
+                }) => {
+                    let report = device
+                        .nvme_zone_report(*nsid, *slba, *state, *partial, *extended, *count)
+                        .unwrap();
+                    print_nvme_zone_report(&report);
+                }
+                Some(Commands::ZoneMgmtSend {
+                    nsid,
+                    slba,
+                    zsa,
+                    all,
+                    file,
+                }) => {
+                    let mut data = match file {
+                        Some(path) => {
+                            let data =
+                                or_exit(std::fs::read(path).map_err(|e| {
+                                    io::Error::new(e.kind(), format!("{}: {}", path, e))
+                                }));
+                            let size = or_exit(device.nvme_zone_extension_size(*nsid));
+                            or_exit(nvme_zone_extension_data(data, size))
+                        }
+                        None => vec![],
+                    };
+                    device
+                        .nvme_zone_mgmt_send(*nsid, *slba, *zsa, *all, &mut data)
+                        .unwrap();
+                    println!("Zone Management Send: success");
+                }
+                Some(Commands::ZoneAppend {
+                    nsid,
+                    zslba,
+                    file,
+                    fua,
+                }) => {
+                    let ns = device.nvme_identify_namespace(*nsid).unwrap();
+                    let lba_shift = ns.LBAF[ns.FLBAS.LbaFormatIndex() as usize].LBADS() as u32;
+                    let mut data = std::fs::read(file).unwrap();
+                    let size = data.len().div_ceil(1 << lba_shift) << lba_shift;
+                    data.resize(size, 0);
+                    let alba = device
+                        .nvme_zone_append(*nsid, *zslba, lba_shift, *fua, &mut data)
+                        .unwrap();
+                    println!("Zone Append: success, ALBA 0x{:X}", alba);
+                }
+                Some(Commands::Dsm {
+                    nsid,
+                    range,
+                    all,
+                    ad,
+                    idr,
+                    idw,
+                    verify,
+                }) => {
+                    // a range with no attribute at all would be a no-op
+                    let ad = *ad || !(*idr || *idw);
+                    if *verify && !(*all || ad) {
+                        eprintln!("--verify checks deallocated blocks, add --ad");
+                        std::process::exit(1);
+                    }
+                    let summary = if *all {
+                        device.nvme_deallocate_namespace(*nsid).unwrap()
+                    } else {
+                        let extents: Vec<(u64, u64)> = range
+                            .iter()
+                            .map(|item| sscanf::sscanf!(item, "{u64}:{u64}").expect("<slba>:<nlb>"))
+                            .collect();
+                        let attributes = NVME_CDW11_DATASET_MANAGEMENT::new()
+                            .with_AD(ad as u8)
+                            .with_IDR(*idr as u8)
+                            .with_IDW(*idw as u8);
+                        device
+                            .nvme_dsm_extents(*nsid, attributes, &extents)
+                            .unwrap()
+                    };
+                    println!(
+                        "DSM: {} ranges in {} commands, {} blocks",
+                        summary.ranges, summary.commands, summary.blocks
+                    );
+                    if *verify {
+                        let lba = if *all {
+                            0
+                        } else {
+                            range
+                                .first()
+                                .map(|item| sscanf::sscanf!(item, "{u64}:{u64}").unwrap().0)
+                                .unwrap_or(0)
+                        };
+                        match device.nvme_dsm_check_deallocated(*nsid, lba).unwrap() {
+                            Some(true) => println!("LBA {}: reads back as DLFEAT reports", lba),
+                            Some(false) => {
+                                println!("LBA {}: does not match DLFEAT read behavior", lba)
+                            }
+                            None => {
+                                println!("DLFEAT does not report the deallocated read behavior")
+                            }
+                        }
+                    }
+                }
+                Some(Commands::Read {
+                    nsid,
+                    slba,
+                    count,
+                    output,
+                    metadata,
+                    io,
+                }) => {
+                    let format = device.nvme_ns_format(*nsid).unwrap();
+                    let mut data = vec![0u8; *count as usize * format.block_size()];
+                    let mut meta = match metadata {
+                        Some(_) => vec![0u8; *count as usize * format.ms as usize],
+                        None => vec![],
+                    };
+                    device
+                        .nvme_read(*nsid, *slba, &format, &io.options(), &mut data, &mut meta)
+                        .unwrap();
+                    match output {
+                        Some(path) => std::fs::write(path, &data).unwrap(),
+                        None => print_hex_dump(&data),
+                    }
+                    if let Some(path) = metadata {
+                        std::fs::write(path, &meta).unwrap();
+                    }
+                }
+                Some(Commands::Write {
+                    nsid,
+                    slba,
+                    file,
+                    metadata,
+                    io,
+                }) => {
+                    let format = device.nvme_ns_format(*nsid).unwrap();
+                    let (data, meta) = read_io_files(&format, file, metadata);
+                    device
+                        .nvme_write(*nsid, *slba, &format, &io.options(), &data, &meta)
+                        .unwrap();
+                    println!("Write: {} blocks", data.len() / format.block_size());
+                }
+                Some(Commands::Compare {
+                    nsid,
+                    slba,
+                    file,
+                    metadata,
+                    io,
+                }) => {
+                    let format = device.nvme_ns_format(*nsid).unwrap();
+                    let (data, meta) = read_io_files(&format, file, metadata);
+                    let equal = device
+                        .nvme_compare(*nsid, *slba, &format, &io.options(), &data, &meta)
+                        .unwrap();
+                    println!("Compare: {}", if equal { "match" } else { "miscompare" });
+                }
+                Some(Commands::WriteZeroes {
+                    nsid,
+                    slba,
+                    count,
+                    deac,
+                    io,
+                }) => {
+                    device
+                        .nvme_write_zeroes(*nsid, *slba, *count, *deac, &io.options())
+                        .unwrap();
+                    println!("Write Zeroes: {} blocks", count);
+                }
+                Some(Commands::WriteUncor { nsid, slba, count }) => {
+                    device
+                        .nvme_write_uncorrectable(*nsid, *slba, *count)
+                        .unwrap();
+                    println!("Write Uncorrectable: {} blocks", count);
+                }
+                Some(Commands::Verify {
+                    nsid,
+                    slba,
+                    count,
+                    io,
+                }) => {
+                    device
+                        .nvme_verify(*nsid, *slba, *count, &io.options())
+                        .unwrap();
+                    println!("Verify: {} blocks", count);
+                }
+                Some(Commands::Flush { nsid }) => {
+                    let nsid = if let Some(hex) = nsid.strip_prefix("0x") {
+                        u32::from_str_radix(hex, 16).unwrap()
+                    } else {
+                        nsid.parse::<u32>().unwrap()
+                    };
+                    device.nvme_flush(nsid).unwrap();
+                    println!("Flush: success");
+                }
+                Some(Commands::AdminPassthru { cmd }) if !cmd.dry_run => {
+                    if let Some(timeout) = cmd.timeout {
+                        device.set_timeout(timeout);
+                    }
+                    or_exit(transport_run(device, &self.args.record, |t| {
+                        passthru_run(t, true, cmd)
+                    }));
+                }
+                Some(Commands::IoPassthru { cmd }) if !cmd.dry_run => {
+                    if let Some(timeout) = cmd.timeout {
+                        device.set_timeout(timeout);
+                    }
+                    or_exit(transport_run(device, &self.args.record, |t| {
+                        passthru_run(t, false, cmd)
+                    }));
+                }
+                Some(Commands::Vendor { action }) => {
+                    let info = device.nvme_identify_controller().unwrap();
+                    let model = String::from_utf8_lossy(&info.MN).trim().to_string();
+                    match action {
+                        VendorCommands::List { registry } => {
+                            let registry = or_exit(vendor_registry(registry));
+                            vendor_list(&registry.commands(info.VID, &model));
+                        }
+                        VendorCommands::Run {
+                            name,
+                            registry,
+                            arg,
+                            nsid,
+                            input_file,
+                            output,
+                            timeout,
+                            dry_run,
+                        } => {
+                            let registry = or_exit(vendor_registry(registry));
+                            let (command, phases) = or_exit(vendor_build(
+                                &registry,
+                                Some(info.VID),
+                                &model,
+                                name,
+                                arg,
+                                *nsid,
+                                input_file,
+                            ));
+                            if *dry_run {
+                                vendor_dry_run(&phases);
+                            } else {
+                                if let Some(timeout) = timeout {
+                                    device.set_timeout(*timeout);
+                                }
+                                or_exit(transport_run(device, &self.args.record, |t| {
+                                    vendor_execute(t, &command, phases, output)
+                                }));
+                            }
+                        }
+                    }
+                }
+                Some(Commands::Opal { action }) => {
+                    let discovery = device.nvme_tcg_discovery0().unwrap();
+                    let mut opal = OpalDevice::from_discovery(device, &discovery).unwrap();
+                    match action {
+                        OpalCommands::TakeOwnership { password } => {
+                            opal.take_ownership(password.as_bytes()).unwrap()
+                        }
+                        OpalCommands::Activate { password } => {
+                            opal.activate(password.as_bytes()).unwrap()
+                        }
+                        OpalCommands::SetupRange {
+                            password,
+                            range,
+                            start,
+                            length,
+                        } => opal
+                            .setup_range(password.as_bytes(), *range, *start, *length)
+                            .unwrap(),
+                        OpalCommands::Lock { password, range } => {
+                            opal.lock_range(password.as_bytes(), *range, true).unwrap()
+                        }
+                        OpalCommands::Unlock { password, range } => {
+                            opal.lock_range(password.as_bytes(), *range, false).unwrap()
+                        }
+                        OpalCommands::Range { password, range } => {
+                            let columns = opal.get_range(password.as_bytes(), *range).unwrap();
+                            print_opal_locking_range(*range, &columns);
+                        }
+                        OpalCommands::PsidRevert { psid } => {
+                            opal.psid_revert(psid.as_bytes()).unwrap()
+                        }
+                    }
+                    println!("Opal: success");
+                }
                 _ => {}
             }
         };
@@ -174,6 +1510,8 @@ impl<'a> CliManager<'a> {
     fn ctrl_manager(&self) {
         if let Some(controller) = &self.ctrl {
             let device = controller.get_driver();
+            device.set_timeouts(nvme_timeouts(&self.args));
+            device.set_retry_policy(nvme_retry_policy(&self.args));
             match &self.args.command {
                 Some(Commands::ListNs { all }) => {
                     let ns_list = device.nvme_identify_ns_list(0, *all).unwrap();
@@ -205,16 +1543,740 @@ impl<'a> CliManager<'a> {
                     println!("{}", self.nvme_list);
                 }
             }
-            _ => {}
+            Some(Commands::Monitor { options }) => {
+                let controllers: Vec<&NvmeController> = match &self.ctrl {
+                    Some(ctrl) => vec![ctrl],
+                    None => self.nvme_list.iter().collect(),
+                };
+                or_exit(health_monitor(&controllers, options));
+            }
+            _ => cli_offline(&self.args),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl IoOptions {
+    fn options(&self) -> NvmeIoOptions {
+        NvmeIoOptions {
+            fua: self.fua,
+            lr: self.lr,
+            prinfo: self.prinfo,
+            dsm: NVME_CDW13_READ_WRITE_DSM::from(self.dsm),
+            dtype: self.dtype,
+            dspec: self.dspec,
+            reftag: self.reftag,
+            apptag: self.apptag,
+            appmask: self.appmask,
+        }
+    }
+}
+
+/// The value, or the error on stderr and exit status 1
+fn or_exit<T>(result: io::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    })
+}
+
+#[cfg(windows)]
+/// Read a data file padded to whole blocks and its optional metadata file
+fn read_io_files(
+    format: &NvmeNsFormat,
+    file: &str,
+    metadata: &Option<String>,
+) -> (Vec<u8>, Vec<u8>) {
+    let mut data = std::fs::read(file).unwrap();
+    let size = data.len().div_ceil(format.block_size()) * format.block_size();
+    data.resize(size, 0);
+    let mut meta = match metadata {
+        Some(path) => std::fs::read(path).unwrap(),
+        None => vec![],
+    };
+    if !meta.is_empty() {
+        meta.resize(size / format.block_size() * format.ms as usize, 0);
+    }
+    (data, meta)
+}
+
+/// Command, direction and data buffer for admin-passthru and io-passthru
+fn passthru_build(cmd: &PassthruOptions) -> io::Result<(NVME_COMMAND, u8, Vec<u8>)> {
+    let mut nc = NVME_COMMAND::default();
+    nc.opcode(cmd.opcode as u32)
+        .nsid(cmd.nsid)
+        .cdw2(cmd.cdw2)
+        .cdw3(cmd.cdw3)
+        .cdw10(cmd.cdw10)
+        .cdw11(cmd.cdw11)
+        .cdw12(cmd.cdw12)
+        .cdw13(cmd.cdw13)
+        .cdw14(cmd.cdw14)
+        .cdw15(cmd.cdw15);
+    let direction = cmd
+        .direction
+        .unwrap_or(nvme_opcode_direction(cmd.opcode) as u8);
+    let mut data = match &cmd.input_file {
+        Some(path) => {
+            std::fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?
         }
+        None => vec![],
+    };
+    let data_len = cmd.data_len.unwrap_or(data.len() as u64);
+    if data.len() as u64 > data_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "input file holds {} bytes, more than --data-len {}",
+                data.len(),
+                data_len
+            ),
+        ));
+    }
+    data.resize(data_len as usize, 0);
+    if direction == NvmeOpcodeType::NOBUFFER as u8 && !data.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "data given for a command without a data transfer, set --direction",
+        ));
+    }
+    Ok((nc, direction, data))
+}
+
+fn passthru_dry_run(cmd: &PassthruOptions) -> io::Result<()> {
+    let (nc, direction, data) = passthru_build(cmd)?;
+    print_nvme_sqe(&nc.to_bytes());
+    println!(
+        "direction: {:?}, data: {} bytes, metadata: {} bytes, timeout: {}",
+        nvme_opcode_direction(direction),
+        data.len(),
+        cmd.metadata_len,
+        cmd.timeout
+            .map_or("default".to_string(), |seconds| format!("{}s", seconds))
+    );
+    Ok(())
+}
+
+#[cfg(windows)]
+fn nvme_timeouts(args: &Args) -> NvmeTimeouts {
+    let defaults = NvmeTimeouts::default();
+    NvmeTimeouts {
+        default: args.default_timeout.unwrap_or(defaults.default),
+        format: args.format_timeout.unwrap_or(defaults.format),
+        sanitize: args.sanitize_timeout.unwrap_or(defaults.sanitize),
+        fw_commit: args.fw_commit_timeout.unwrap_or(defaults.fw_commit),
+        ..defaults
+    }
+}
+
+fn nvme_retry_policy(args: &Args) -> NvmeRetryPolicy {
+    let defaults = NvmeRetryPolicy::default();
+    NvmeRetryPolicy {
+        max_retries: args.retries.unwrap_or(defaults.max_retries),
+        backoff: args
+            .retry_backoff
+            .map_or(defaults.backoff, Duration::from_millis),
+        ..defaults
     }
 }
 
+/// `--record` and `--replay` only apply to the commands sent through a transport;
+/// the built-in commands call the inbox driver directly, so anything else would
+/// quietly run without the trace
+fn trace_check(args: &Args) -> io::Result<()> {
+    let flag = match (&args.record, &args.replay) {
+        (Some(_), _) => "--record",
+        (_, Some(_)) => "--replay",
+        _ => return Ok(()),
+    };
+    let traced = match &args.command {
+        Some(Commands::AdminPassthru { .. })
+        | Some(Commands::IoPassthru { .. })
+        | Some(Commands::Vendor {
+            action: VendorCommands::Run { .. },
+        }) => true,
+        // no device passes asynchronous events on, so there is nothing to record
+        Some(Commands::AerMonitor { .. }) => args.replay.is_some(),
+        _ => false,
+    };
+    if traced {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        if args.replay.is_some() {
+            format!(
+                "{} applies only to admin-passthru, io-passthru, vendor run and aer-monitor; \
+                 the other commands go to the driver directly",
+                flag
+            )
+        } else {
+            format!(
+                "{} applies only to admin-passthru, io-passthru and vendor run; \
+                 the other commands go to the driver directly",
+                flag
+            )
+        },
+    ))
+}
+
+/// The `--replay` trace, retried like a device would be
+fn replay_open(args: &Args) -> io::Result<NvmeRetry<NvmeReplay>> {
+    let path = args.replay.as_ref().unwrap();
+    let replay =
+        NvmeReplay::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    Ok(NvmeRetry::new(replay, nvme_retry_policy(args)))
+}
+
+/// Poll the SMART / Health log of each controller and send the alerts its
+/// watch raises to every sink
+#[cfg(windows)]
+fn health_monitor(controllers: &[&NvmeController], options: &MonitorOptions) -> io::Result<()> {
+    let rules = NvmeHealthRules {
+        temperature: !options.no_temperature,
+        spare: !options.no_spare,
+        percentage_used_step: options.used_step,
+        media_errors: !options.no_media_errors,
+        error_log_entries: !options.no_error_log,
+    };
+    let mut sinks = vec![];
+    if !options.quiet {
+        sinks.push(NvmeAlertSink::Stdout);
+    }
+    if let Some(path) = &options.log {
+        sinks.push(NvmeAlertSink::json_lines(path)?);
+    }
+    if let Some(url) = &options.webhook {
+        sinks.push(NvmeAlertSink::webhook(url)?);
+    }
+
+    let mut watches = vec![];
+    for controller in controllers {
+        // controllers that failed to open were reported by open()
+        let Some(driver) = controller.driver() else {
+            continue;
+        };
+        let (wctemp, cctemp) = match driver.nvme_identify_controller() {
+            Ok(data) => (data.WCTEMP, data.CCTEMP),
+            Err(e) => {
+                eprintln!("{}: Identify Controller: {}", controller.location(), e);
+                (0, 0)
+            }
+        };
+        let watch = NvmeHealthWatch::new(&controller.location(), rules, wctemp, cctemp);
+        watches.push((driver, watch));
+    }
+    if watches.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no NVMe controller to monitor",
+        ));
+    }
+    eprintln!(
+        "monitoring {} controller(s) every {}s",
+        watches.len(),
+        options.interval
+    );
+
+    let mut polls = 0;
+    loop {
+        for (driver, watch) in watches.iter_mut() {
+            let lid = NVME_LOG_PAGES::NVME_LOG_PAGE_HEALTH_INFO as u32;
+            let smart = match driver.nvme_logpage_query(lid, 0) {
+                Ok(data) if data.len() >= NvmeDecodeKind::Smart.min_len() => {
+                    NvmeSmartLog::parse(&data)
+                }
+                Ok(data) => {
+                    eprintln!("{}: short SMART log, {} bytes", watch.device, data.len());
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("{}: SMART log: {}", watch.device, e);
+                    continue;
+                }
+            };
+            for alert in watch.evaluate(&smart) {
+                for sink in sinks.iter_mut() {
+                    if let Err(e) = sink.emit(&alert) {
+                        eprintln!("alert not delivered: {}", e);
+                    }
+                }
+            }
+        }
+        polls += 1;
+        if options.count.is_some_and(|count| polls >= count) {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_secs(options.interval));
+    }
+}
+
+/// Configure asynchronous events, then report them until `count` have arrived.
+/// A request that times out is sent again, unless `stop_on_timeout` is set.
+fn aer_monitor<T: NvmeTransport>(
+    monitor: &NvmeAerMonitor<T>,
+    config: Option<u32>,
+    count: Option<u32>,
+    clear: bool,
+    json: bool,
+    stop_on_timeout: bool,
+) -> io::Result<()> {
+    let config = match config {
+        Some(value) => NVME_CDW11_FEATURE_ASYNC_EVENT_CONFIG::from(value),
+        None => monitor.supported_config()?,
+    };
+    monitor.configure(config)?;
+    eprintln!(
+        "Async Event Configuration: {:#010x}, waiting for events",
+        u32::from(config)
+    );
+
+    let mut seen = 0;
+    while count.is_none_or(|count| seen < count) {
+        let report = match monitor.next_event(clear) {
+            Ok(report) => report,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut && !stop_on_timeout => continue,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        };
+        seen += 1;
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string(&report).map_err(io::Error::other)?
+            );
+            continue;
+        }
+        println!("{}", report.event());
+        if let Some(log) = &report.log {
+            log.print();
+        } else if let Some(error) = &report.log_error {
+            println!("  Log page {:02x}h: {}", report.log_page, error);
+        } else if !report.log_data.is_empty() {
+            print_hex_dump(&report.log_data);
+        }
+    }
+    Ok(())
+}
+
+/// Run `run` on `device`, through a recorder writing `record` when given
+#[cfg(windows)]
+fn transport_run<T: NvmeTransport>(
+    device: T,
+    record: &Option<String>,
+    run: impl FnOnce(&dyn NvmeTransport) -> io::Result<()>,
+) -> io::Result<()> {
+    match record {
+        Some(path) => run(&NvmeRecorder::create(device, path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?),
+        None => run(&device),
+    }
+}
+
+/// Send the command and report DW0, the status and any returned data
+fn passthru_run<T: NvmeTransport + ?Sized>(
+    device: &T,
+    admin: bool,
+    cmd: &PassthruOptions,
+) -> io::Result<()> {
+    if cmd.metadata_len != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the inbox driver takes no separate metadata buffer, use an extended LBA format",
+        ));
+    }
+    let (nc, direction, mut data) = passthru_build(cmd)?;
+    let mut dw0 = 0;
+    let status = if admin {
+        device.admin_passthru(direction, &nc, &mut data, &mut dw0)?
+    } else {
+        device.io_passthru(direction, &nc, &mut data, &mut dw0)?
+    };
+    println!("DW0: {:#010x}", dw0);
+    println!("Status: {}", status);
+    if direction & NvmeOpcodeType::READ as u8 != 0 && !data.is_empty() {
+        match &cmd.output {
+            Some(path) => std::fs::write(path, &data)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?,
+            None => print_hex_dump(&data),
+        }
+    }
+    if !status.is_success() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Where the vendor definitions are looked for without --defs: installed
+/// alongside the binary, then the source tree it was built from for
+/// target/*/nvme, then the working directory
+fn vendor_default_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Some(dir) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    {
+        dirs.push(dir.join("vendor"));
+    }
+    dirs.push(Path::new(env!("CARGO_MANIFEST_DIR")).join("vendor"));
+    dirs.push(PathBuf::from("vendor"));
+    dirs
+}
+
+fn vendor_registry(options: &VendorOptions) -> io::Result<VendorRegistry> {
+    let mut registry = VendorRegistry::new();
+    if options.defs.is_empty() {
+        let dirs = vendor_default_dirs();
+        let defs = dirs.iter().find(|dir| dir.is_dir()).ok_or_else(|| {
+            let tried: Vec<String> = dirs.iter().map(|d| d.display().to_string()).collect();
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no vendor definitions in {}, pass --defs", tried.join(", ")),
+            )
+        })?;
+        registry.load_path(defs)?;
+    }
+    for path in &options.defs {
+        registry.load_path(Path::new(path))?;
+    }
+    Ok(registry)
+}
+
+/// Commands for `vid` and `model`, or every registered command without a VID
+fn vendor_commands<'a>(
+    registry: &'a VendorRegistry,
+    vid: Option<u16>,
+    model: &str,
+) -> Vec<(&'a VendorDefinition, &'a VendorCommand)> {
+    match vid {
+        Some(vid) => registry.commands(vid, model),
+        None => registry
+            .vendors
+            .iter()
+            .flat_map(|v| v.commands.iter().map(move |c| (v, c)))
+            .collect(),
+    }
+}
+
+fn vendor_list(commands: &[(&VendorDefinition, &VendorCommand)]) {
+    if commands.is_empty() {
+        println!("no vendor commands registered");
+    }
+    for (vendor, command) in commands {
+        println!(
+            "{:<24} {:<16} {:?}, {} phase(s)  {}",
+            command.name,
+            vendor.name,
+            command.queue,
+            command.phases.len(),
+            command.description
+        );
+        for param in &command.params {
+            let default = param
+                .default
+                .map_or("required".to_string(), |v| format!("default {:#x}", v));
+            println!("    {:<20} {}  {}", param.name, default, param.description);
+        }
+    }
+}
+
+/// The registered command called `name` and its phases
+fn vendor_build(
+    registry: &VendorRegistry,
+    vid: Option<u16>,
+    model: &str,
+    name: &str,
+    args: &[String],
+    nsid: u32,
+    input_file: &Option<String>,
+) -> io::Result<(VendorCommand, Vec<VendorPhaseCommand>)> {
+    let (_, command) = vendor_commands(registry, vid, model)
+        .into_iter()
+        .find(|(_, c)| c.name == name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no vendor command {} registered for this controller", name),
+            )
+        })?;
+    let input = match input_file {
+        Some(path) => {
+            std::fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?
+        }
+        None => vec![],
+    };
+    let phases = command.build(nsid, &command.arguments(args)?, &input)?;
+    Ok((command.clone(), phases))
+}
+
+fn vendor_dry_run(phases: &[VendorPhaseCommand]) {
+    for (i, phase) in phases.iter().enumerate() {
+        println!(
+            "phase {}: direction: {:?}, data: {} bytes",
+            i + 1,
+            nvme_opcode_direction(phase.direction),
+            phase.data.len()
+        );
+        print_nvme_sqe(&phase.command.to_bytes());
+    }
+}
+
+/// Send the phases and report the last completion with the decoded response
+fn vendor_execute<T: NvmeTransport + ?Sized>(
+    device: &T,
+    command: &VendorCommand,
+    phases: Vec<VendorPhaseCommand>,
+    output: &Option<String>,
+) -> io::Result<()> {
+    let count = phases.len();
+    let response = command.execute(device, phases)?;
+    println!("DW0: {:#010x}", response.dw0);
+    println!("Status: {}", response.status);
+    if !response.status.is_success() {
+        println!("stopped at phase {} of {}", response.phases, count);
+        std::process::exit(1);
+    }
+    if let Some(path) = output {
+        std::fs::write(path, &response.data)?;
+    } else if command.fields.is_empty() {
+        if !response.data.is_empty() {
+            print_hex_dump(&response.data);
+        }
+    } else {
+        for field in command.decode(&response.data)? {
+            println!("{:<24} : {}", field.name, field);
+        }
+    }
+    Ok(())
+}
+
+fn decode_print(kind: NvmeDecodeKind, data: &[u8], json: bool) -> io::Result<()> {
+    let decoded = nvme_decode(kind, data)?;
+    if json {
+        println!("{}", decoded.to_json()?);
+    } else {
+        decoded.print();
+    }
+    Ok(())
+}
+
+/// Commands that need no controller handle and run on every platform
+fn cli_offline(args: &Args) {
+    match &args.command {
+        Some(Commands::Discovery0 { file: Some(file) }) => {
+            let data = std::fs::read(file).unwrap();
+            let discovery = TcgLevel0Discovery::parse(&data).unwrap();
+            print!("{}", discovery);
+        }
+        Some(Commands::Bench {
+            path,
+            seq,
+            read,
+            bs,
+            qd,
+            jobs,
+            time,
+            size,
+            offset,
+            range,
+            rate,
+            direct,
+            seed,
+            trace,
+        }) => {
+            let config = BenchConfig {
+                path: path.clone(),
+                pattern: if *seq {
+                    BenchPattern::Sequential
+                } else {
+                    BenchPattern::Random
+                },
+                read_percent: *read,
+                block_size: *bs as usize,
+                queue_depth: *qd,
+                jobs: *jobs,
+                runtime: time.map(Duration::from_secs),
+                size: *size,
+                offset: *offset,
+                range: *range,
+                rate_iops: *rate,
+                direct: *direct,
+                seed: *seed,
+                trace: trace.clone(),
+            };
+            let report = or_exit(bench_run(&config));
+            print!("{}", report);
+        }
+        Some(Commands::Integrity {
+            path,
+            bs,
+            io_size,
+            offset,
+            range,
+            passes,
+            pass,
+            seed,
+            verify_only,
+            state,
+            direct,
+            max_report,
+            stop_on_error,
+        }) => {
+            let config = IntegrityConfig {
+                path: path.clone(),
+                block_size: *bs as usize,
+                io_size: *io_size as usize,
+                offset: *offset,
+                range: *range,
+                passes: *passes,
+                first_pass: *pass,
+                seed: *seed,
+                verify_only: *verify_only,
+                state: state.clone(),
+                direct: *direct,
+                max_report: *max_report,
+                stop_on_error: *stop_on_error,
+            };
+            let report = or_exit(integrity_run(&config));
+            print!("{}", report);
+            if report.errors() > 0 {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::AnalyzeTrace {
+            file,
+            interval,
+            outliers,
+            threshold,
+            series,
+        }) => {
+            let options = TraceAnalyzeOptions {
+                interval: Duration::from_millis((*interval).max(1)),
+                outliers: *outliers,
+                threshold: threshold.map(|us| us * 1000),
+            };
+            let analysis = or_exit(
+                trace_analyze_file(file, &options)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e))),
+            );
+            print!("{}", analysis);
+            if let Some(series) = series {
+                or_exit(
+                    std::fs::write(series, analysis.series_csv())
+                        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", series, e))),
+                );
+            }
+        }
+        Some(Commands::AdminPassthru { cmd }) | Some(Commands::IoPassthru { cmd })
+            if cmd.dry_run =>
+        {
+            or_exit(passthru_dry_run(cmd));
+        }
+        Some(Commands::AdminPassthru { cmd }) if args.replay.is_some() => {
+            let replay = or_exit(replay_open(args));
+            or_exit(passthru_run(&replay, true, cmd));
+        }
+        Some(Commands::IoPassthru { cmd }) if args.replay.is_some() => {
+            let replay = or_exit(replay_open(args));
+            or_exit(passthru_run(&replay, false, cmd));
+        }
+        Some(Commands::AerMonitor {
+            config,
+            count,
+            no_clear,
+            json,
+            ..
+        }) if args.replay.is_some() => {
+            let monitor = NvmeAerMonitor::new(or_exit(replay_open(args)));
+            or_exit(aer_monitor(
+                &monitor, *config, *count, !*no_clear, *json, true,
+            ));
+        }
+        Some(Commands::Decode { file, kind, json }) => {
+            let kind = or_exit(
+                kind.parse::<NvmeDecodeKind>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)),
+            );
+            let data = or_exit(
+                std::fs::read(file)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e))),
+            );
+            or_exit(decode_print(kind, &data, *json));
+        }
+        // with a disk open the device's VID and model select the commands
+        Some(Commands::Vendor { action }) if args.disk.is_none() || args.replay.is_some() => {
+            match action {
+                VendorCommands::List { registry } => {
+                    let commands = or_exit(vendor_registry(registry));
+                    vendor_list(&vendor_commands(&commands, registry.vid, &registry.model));
+                }
+                VendorCommands::Run {
+                    name,
+                    registry,
+                    arg,
+                    nsid,
+                    input_file,
+                    dry_run: true,
+                    ..
+                } => {
+                    let commands = or_exit(vendor_registry(registry));
+                    let (_, phases) = or_exit(vendor_build(
+                        &commands,
+                        registry.vid,
+                        &registry.model,
+                        name,
+                        arg,
+                        *nsid,
+                        input_file,
+                    ));
+                    vendor_dry_run(&phases);
+                }
+                VendorCommands::Run {
+                    name,
+                    registry,
+                    arg,
+                    nsid,
+                    input_file,
+                    output,
+                    ..
+                } if args.replay.is_some() => {
+                    let replay = or_exit(replay_open(args));
+                    let commands = or_exit(vendor_registry(registry));
+                    let (command, phases) = or_exit(vendor_build(
+                        &commands,
+                        registry.vid,
+                        &registry.model,
+                        name,
+                        arg,
+                        *nsid,
+                        input_file,
+                    ));
+                    or_exit(vendor_execute(&replay, &command, phases, output));
+                }
+                VendorCommands::Run { .. } => {
+                    eprintln!("vendor run needs a device (--disk), --replay or --dry-run");
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(windows)]
 fn main() {
     let mut controller_list = NvmeControllerList::new();
     controller_list.enumerate();
 
     let mut cli = CliManager::new(&mut controller_list);
+    or_exit(trace_check(&cli.args));
     cli.open_device();
     cli.run();
 }
+
+#[cfg(not(windows))]
+fn main() {
+    let args = Args::parse();
+    or_exit(trace_check(&args));
+    cli_offline(&args);
+}
@@ -522,6 +522,16 @@ impl NvmeController {
     pub fn get_driver(&self) -> &InboxDriver {
         self.driver.as_ref().unwrap()
     }
+
+    /// The driver, when `open` succeeded
+    pub fn driver(&self) -> Option<&InboxDriver> {
+        self.driver.as_ref()
+    }
+
+    /// PCI location, e.g. "0000:03:00:00"
+    pub fn location(&self) -> String {
+        self.bdf.to_string()
+    }
 }
 
 impl fmt::Display for NvmeController {
@@ -626,6 +636,14 @@ impl NvmeControllerList {
             .find_map(|controller| controller.by_num(driveno))
     }
 
+    pub fn iter(&self) -> std::slice::Iter<'_, NvmeController> {
+        self.controllers.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, NvmeController> {
+        self.controllers.iter_mut()
+    }
+
     pub fn by_bus(&mut self, bus: i32) -> Option<&mut NvmeController> {
         self.controllers
             .iter_mut()
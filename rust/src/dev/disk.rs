@@ -1,25 +1,36 @@
-use endian_codec::EncodeBE;
 use log::warn;
+#[cfg(windows)]
 use windows_sys::{
     Win32::Foundation::*, Win32::Storage::FileSystem::*, Win32::Storage::IscsiDisc::*,
     Win32::System::Ioctl::*, Win32::System::IO::*,
 };
 
+#[cfg(windows)]
 use std::{
     ffi::c_void,
-    fmt,
-    io::{self, Read, Write},
     mem::{size_of_val, zeroed},
     ptr::{null, null_mut},
 };
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
 
 use super::scsi::*;
-use crate::SECTOR_SIZE;
+#[cfg(target_os = "linux")]
+use super::sg::sg_io;
+use super::tcg::{TcgLevel0Discovery, TCG_LEVEL0_COMID, TCG_LEVEL0_PROTOCOL};
+use crate::{AlignedBuffer, SECTOR_SIZE};
+
+/// Buffers handed to an unbuffered handle are aligned to at least this
+pub const DISK_BUFFER_ALIGNMENT: usize = 4096;
 
+#[cfg(windows)]
 pub fn last_error() -> u32 {
     unsafe { GetLastError() }
 }
 
+#[cfg(windows)]
 pub fn open(path: &str, rw: char) -> isize {
     let filename = std::ffi::CString::new(path).unwrap();
     let handle = unsafe {
@@ -44,6 +55,7 @@ pub fn open(path: &str, rw: char) -> isize {
     handle
 }
 
+#[cfg(windows)]
 pub fn ioctl(
     handle: isize,
     control_code: u32,
@@ -75,6 +87,7 @@ pub fn ioctl(
     }
 }
 
+#[cfg(windows)]
 fn geometry(drive: &HANDLE) -> usize {
     let mut geo: DISK_GEOMETRY_EX = unsafe { zeroed() };
     if let Ok(_r) = {
@@ -91,6 +104,7 @@ fn geometry(drive: &HANDLE) -> usize {
     }
 }
 
+#[cfg(windows)]
 fn getfilesize(drive: &HANDLE) -> usize {
     let mut bytes_returned = 0;
     let r = unsafe { GetFileSizeEx(*drive, &mut bytes_returned) };
@@ -101,13 +115,113 @@ fn getfilesize(drive: &HANDLE) -> usize {
     }
 }
 
+/// Logical and physical sector size reported by the storage stack, None for
+/// image files
+#[cfg(windows)]
+fn sector_sizes(drive: &HANDLE) -> Option<(usize, usize)> {
+    let mut spq: STORAGE_PROPERTY_QUERY = unsafe { zeroed() };
+    spq.PropertyId = StorageAccessAlignmentProperty;
+    spq.QueryType = PropertyStandardQuery;
+    let mut alignment: STORAGE_ACCESS_ALIGNMENT_DESCRIPTOR = unsafe { zeroed() };
+    ioctl(
+        *drive,
+        IOCTL_STORAGE_QUERY_PROPERTY,
+        Some((&spq as *const _ as *const c_void, size_of_val(&spq))),
+        Some((
+            &mut alignment as *mut _ as *mut c_void,
+            size_of_val(&alignment),
+        )),
+    )
+    .ok()?;
+    Some((
+        alignment.BytesPerLogicalSector as usize,
+        alignment.BytesPerPhysicalSector as usize,
+    ))
+}
+
+/// Block sizes must be non-zero powers of two, they become a shift
+fn check_sector_sizes(logical: usize, physical: usize) -> io::Result<()> {
+    for (name, size) in [("logical", logical), ("physical", physical)] {
+        if !size.is_power_of_two() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {} sector size {}", name, size),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// _IOR(0x12, 114, size_t), libc does not export it
+#[cfg(target_os = "linux")]
+const BLKGETSIZE64: libc::c_ulong =
+    (2 << 30) | ((std::mem::size_of::<usize>() as libc::c_ulong) << 16) | (0x12 << 8) | 114;
+
+/// Logical and physical block size and capacity of a block device, None for
+/// regular files
+#[cfg(target_os = "linux")]
+fn block_device_geometry(file: &std::fs::File) -> Option<(usize, usize, u64)> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+    if !file.metadata().ok()?.file_type().is_block_device() {
+        return None;
+    }
+    let fd = file.as_raw_fd();
+    let mut logical: libc::c_int = 0;
+    let mut physical: libc::c_uint = 0;
+    let mut size: u64 = 0;
+    unsafe {
+        if libc::ioctl(fd, libc::BLKSSZGET, &mut logical) < 0
+            || libc::ioctl(fd, libc::BLKPBSZGET, &mut physical) < 0
+            || libc::ioctl(fd, BLKGETSIZE64 as _, &mut size) < 0
+        {
+            return None;
+        }
+    }
+    Some((logical as usize, physical as usize, size))
+}
+
+/// Open with O_DIRECT; filesystems without direct I/O support (tmpfs) fall back
+/// to buffered access so image files still work
+#[cfg(unix)]
+fn open_file(path: &str, rw: char) -> io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+    if rw == 'w' {
+        options.write(true);
+        if !path.starts_with("/dev/") {
+            options.create(true).truncate(true);
+        }
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let mut direct = options.clone();
+        std::os::unix::fs::OpenOptionsExt::custom_flags(
+            &mut direct,
+            libc::O_DIRECT | libc::O_DSYNC,
+        );
+        match direct.open(path) {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                warn!("'{}' does not support O_DIRECT, using buffered I/O", path);
+            }
+            result => return result,
+        }
+    }
+    options.open(path)
+}
+
 pub struct Disk {
     path: String,
     rw: char,
+    #[cfg(windows)]
     pub handle: HANDLE,
+    #[cfg(unix)]
+    pub file: std::fs::File,
     pub size: usize,
     pub lba_shift: u8,
-    write_offset: u64,
+    pub physical_block_size: usize,
+    position: u64, // cursor for Read, Write, Seek and scsi_write
+    #[cfg(windows)]
     sptdwb: SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER,
     pub fua: Option<bool>,
 }
@@ -122,30 +236,93 @@ impl fmt::Display for Disk {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(
             fmt,
-            "Disk path: \"{}\", rw: '{}', handle: {}, size: {}, fua: {:?}",
-            self.path, self.rw, self.handle, self.size, self.fua,
+            "Disk path: \"{}\", rw: '{}', size: {}, block: {}/{}, fua: {:?}",
+            self.path,
+            self.rw,
+            self.size,
+            self.block_size(),
+            self.physical_block_size,
+            self.fua,
         )
     }
 }
 
 impl Disk {
+    #[cfg(windows)]
     pub fn open(path: String, rw: char, fua: Option<bool>) -> Option<Disk> {
         let handle = open(&path, rw);
         if handle == INVALID_HANDLE_VALUE {
             warn!("Can't open file!! '{}'", path);
             None
         } else {
-            Some(Disk {
+            let (logical, physical) = sector_sizes(&handle).unwrap_or((SECTOR_SIZE, SECTOR_SIZE));
+            if let Err(e) = check_sector_sizes(logical, physical) {
+                warn!("Can't open file!! '{}': {}", path, e);
+                unsafe { CloseHandle(handle) };
+                return None;
+            }
+            let mut disk = Disk {
                 path,
                 rw,
                 handle,
                 size: getfilesize(&handle),
-                lba_shift: 9,
-                write_offset: 0,
+                lba_shift: logical.trailing_zeros() as u8,
+                physical_block_size: physical,
+                position: 0,
                 sptdwb: SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER::new(SCSI_IOCTL_DATA_OUT),
                 fua,
-            })
+            };
+            // files and volumes reject passthrough and keep the OS geometry
+            if let Ok(capacity) = disk.scsi_read_capacity() {
+                disk.set_capacity(&capacity);
+            }
+            Some(disk)
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn open(path: String, rw: char, fua: Option<bool>) -> Option<Disk> {
+        let file = match open_file(&path, rw) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Can't open file!! '{}': {}", path, e);
+                return None;
+            }
+        };
+        #[cfg(target_os = "linux")]
+        let geometry = block_device_geometry(&file);
+        #[cfg(not(target_os = "linux"))]
+        let geometry = None;
+        let (logical, physical, size) = match geometry {
+            Some(geometry) => geometry,
+            None => (
+                SECTOR_SIZE,
+                SECTOR_SIZE,
+                file.metadata().map(|m| m.len()).unwrap_or(0),
+            ),
+        };
+        if let Err(e) = check_sector_sizes(logical, physical) {
+            warn!("Can't open file!! '{}': {}", path, e);
+            return None;
         }
+        let device = path.starts_with("/dev/");
+        let mut disk = Disk {
+            path,
+            rw,
+            file,
+            size: size as usize,
+            lba_shift: logical.trailing_zeros() as u8,
+            physical_block_size: physical,
+            position: 0,
+            fua,
+        };
+        // sg nodes have no block geometry; devices without SCSI keep the kernel's
+        if device {
+            if let Ok(capacity) = disk.scsi_read_capacity() {
+                disk.set_capacity(&capacity);
+            }
+        }
+        Some(disk)
     }
 
     /// The size of the drive in bytes.
@@ -153,6 +330,226 @@ impl Disk {
         self.size
     }
 
+    /// Logical block size in bytes
+    pub fn block_size(&self) -> usize {
+        1 << self.lba_shift
+    }
+
+    /// Image files have no device geometry, let the caller pick the block size
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.lba_shift = block_size.trailing_zeros() as u8;
+        self.physical_block_size = self.physical_block_size.max(block_size);
+    }
+
+    /// Zeroed buffer of at least `len` bytes, rounded up to whole blocks and
+    /// aligned for unbuffered I/O
+    pub fn alloc_buffer(&self, len: usize) -> AlignedBuffer {
+        let block = self.block_size();
+        AlignedBuffer::new(
+            len.div_ceil(block).max(1) * block,
+            block.max(DISK_BUFFER_ALIGNMENT),
+        )
+    }
+
+    #[cfg(windows)]
+    fn raw_read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut overlapped: OVERLAPPED = unsafe { zeroed() };
+        overlapped.Anonymous.Anonymous.Offset = offset as u32;
+        overlapped.Anonymous.Anonymous.OffsetHigh = (offset >> 32) as u32;
+        let mut bytes_read = 0u32;
+        let res = unsafe {
+            ReadFile(
+                self.handle,
+                buf.as_mut_ptr(),
+                buf.len() as u32,
+                &mut bytes_read,
+                &mut overlapped,
+            )
+        };
+        match (res, last_error()) {
+            (0, ERROR_HANDLE_EOF) => Ok(0),
+            (0, error) => Err(io::Error::other(format!("Error code: {:#08x}", error))),
+            _ => Ok(bytes_read as usize),
+        }
+    }
+
+    #[cfg(windows)]
+    fn raw_write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut overlapped: OVERLAPPED = unsafe { zeroed() };
+        overlapped.Anonymous.Anonymous.Offset = offset as u32;
+        overlapped.Anonymous.Anonymous.OffsetHigh = (offset >> 32) as u32;
+        let mut bytes_write = 0u32;
+        let res = unsafe {
+            WriteFile(
+                self.handle,
+                buf.as_ptr(),
+                buf.len() as u32,
+                &mut bytes_write,
+                &mut overlapped,
+            )
+        };
+        if res == 0 {
+            Err(io::Error::other(format!(
+                "Error code: {:#08x}",
+                last_error()
+            )))
+        } else {
+            Ok(bytes_write as usize)
+        }
+    }
+
+    #[cfg(unix)]
+    fn raw_read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.file, buf, offset)
+    }
+
+    #[cfg(unix)]
+    fn raw_write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(&self.file, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn set_file_len(&self, len: u64) -> io::Result<()> {
+        let info = FILE_END_OF_FILE_INFO {
+            EndOfFile: len as i64,
+        };
+        let res = unsafe {
+            SetFileInformationByHandle(
+                self.handle,
+                FileEndOfFileInfo,
+                &info as *const _ as *const c_void,
+                size_of_val(&info) as u32,
+            )
+        };
+        if res == 0 {
+            Err(io::Error::other(format!(
+                "Error code: {:#08x}",
+                last_error()
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cfg(unix)]
+    fn set_file_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    /// Unbuffered handles need block aligned offset, length and memory
+    fn is_aligned(&self, ptr: *const u8, len: usize, offset: u64) -> bool {
+        let block = self.block_size();
+        offset.is_multiple_of(block as u64)
+            && len.is_multiple_of(block)
+            && (ptr as usize).is_multiple_of(block)
+    }
+
+    /// Read one block for read-modify-write, past the end of an image file the
+    /// block reads as zeros
+    fn read_block(&self, block: &mut [u8], offset: u64) -> io::Result<()> {
+        let n = self.raw_read_at(block, offset)?;
+        block[n..].fill(0);
+        Ok(())
+    }
+
+    /// Read at `offset` without moving the cursor. Unaligned requests go through
+    /// a bounce buffer; the count is what the device returned, not the padding.
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.is_aligned(buf.as_ptr(), buf.len(), offset) {
+            return self.raw_read_at(buf, offset);
+        }
+        let block = self.block_size();
+        let head = (offset % block as u64) as usize;
+        let start = offset - head as u64;
+        let span = (head + buf.len()).div_ceil(block) * block;
+        let mut bounce = self.alloc_buffer(span);
+        let n = self
+            .raw_read_at(&mut bounce[..span], start)?
+            .saturating_sub(head)
+            .min(buf.len());
+        buf[..n].copy_from_slice(&bounce[head..head + n]);
+        Ok(n)
+    }
+
+    /// Write at `offset` without moving the cursor. A partial first or last
+    /// block is read, patched and written back whole through a bounce buffer.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = if self.is_aligned(buf.as_ptr(), buf.len(), offset) {
+            self.raw_write_at(buf, offset)?
+        } else {
+            let block = self.block_size();
+            let head = (offset % block as u64) as usize;
+            let start = offset - head as u64;
+            let span = (head + buf.len()).div_ceil(block) * block;
+            let tail = (head + buf.len()) % block;
+            let mut bounce = self.alloc_buffer(span);
+            if head != 0 {
+                self.read_block(&mut bounce[..block], start)?;
+            }
+            if tail != 0 && !(head != 0 && span == block) {
+                self.read_block(
+                    &mut bounce[span - block..span],
+                    start + (span - block) as u64,
+                )?;
+            }
+            bounce[head..head + buf.len()].copy_from_slice(buf);
+            let n = self
+                .raw_write_at(&bounce[..span], start)?
+                .saturating_sub(head)
+                .min(buf.len());
+            // padding written past the end of an image file is cut off again
+            let end = (offset + n as u64).max(self.size as u64);
+            if start + (span as u64) > end {
+                self.set_file_len(end)?;
+            }
+            n
+        };
+        self.size = self.size.max((offset + n as u64) as usize);
+        Ok(n)
+    }
+
+    /// Take block sizes from READ CAPACITY data rather than the OS view, which
+    /// reports 512 byte sectors for some devices. READ CAPACITY describes the
+    /// whole device, so the size is only taken when the OS has none, as for sg
+    /// nodes; partitions and volumes keep their own.
+    pub fn set_capacity(&mut self, capacity: &ScsiReadCapacity) {
+        if !capacity.block_length.is_power_of_two() {
+            warn!("Ignoring {} byte logical block size", capacity.block_length);
+            return;
+        }
+        self.lba_shift = capacity.block_length.trailing_zeros() as u8;
+        self.physical_block_size = capacity.physical_block_size() as usize;
+        if self.size == 0 {
+            match capacity.capacity() {
+                Ok(size) => self.size = size as usize,
+                Err(e) => warn!("Ignoring READ CAPACITY size: {}", e),
+            }
+        }
+    }
+
+    /// Flush anything the OS still holds for this handle
+    pub fn sync(&mut self) -> io::Result<()> {
+        #[cfg(windows)]
+        if unsafe { FlushFileBuffers(self.handle) } == 0 {
+            return Err(io::Error::other(format!(
+                "Error code: {:#08x}",
+                last_error()
+            )));
+        }
+        #[cfg(unix)]
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+impl Disk {
     pub fn scsi_open(&mut self, path: String) {
         unsafe { CloseHandle(self.handle) };
         self.handle = INVALID_HANDLE_VALUE;
@@ -182,10 +579,10 @@ impl Disk {
             );
             Ok(scsi_addr.PathId)
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Error code: {:#08x}", last_error()),
-            ))
+            Err(io::Error::other(format!(
+                "Error code: {:#08x}",
+                last_error()
+            )))
         }
     }
 
@@ -221,8 +618,12 @@ impl Disk {
         }
     }
 
+    /// Send the prepared CDB; a status other than GOOD comes back as an
+    /// `io::Error` wrapping a `ScsiError` with the decoded sense data
     pub fn scsi_pass_through_direct(&mut self) -> io::Result<usize> {
-        ioctl(
+        self.sptdwb.sptd.ScsiStatus = SCSI_STATUS_GOOD;
+        self.sptdwb.ucSenseBuf = Default::default();
+        let returned = ioctl(
             self.handle,
             IOCTL_SCSI_PASS_THROUGH_DIRECT,
             Some((
@@ -233,21 +634,143 @@ impl Disk {
                 &mut self.sptdwb as *mut _ as *mut c_void,
                 size_of_val(&self.sptdwb),
             )),
-        )
+        )?;
+        let status = self.sptdwb.sptd.ScsiStatus;
+        if status != SCSI_STATUS_GOOD && status != SCSI_STATUS_CONDITION_MET {
+            let sense_len =
+                (self.sptdwb.sptd.SenseInfoLength as usize).min(self.sptdwb.ucSenseBuf.len());
+            return Err(ScsiError::new(
+                self.sptdwb.sptd.Cdb[0],
+                status,
+                &self.sptdwb.ucSenseBuf[..sense_len],
+            )
+            .into());
+        }
+        Ok(returned)
+    }
+
+    pub fn storage_set_property(&self) {
+        let mut sps: STORAGE_PROPERTY_SET = unsafe { zeroed() };
+        sps.PropertyId = StorageDeviceWriteCacheProperty;
+        sps.SetType = PropertyStandardSet;
+        let mut cache_info: STORAGE_WRITE_CACHE_PROPERTY = unsafe { zeroed() };
+
+        if let Ok(_r) = ioctl(
+            self.handle,
+            IOCTL_STORAGE_SET_PROPERTY,
+            Some((&sps as *const _ as *const c_void, size_of_val(&sps))),
+            Some((
+                &mut cache_info as *mut _ as *mut c_void,
+                size_of_val(&cache_info),
+            )),
+        ) {
+            println!("storage_set_property: {}", cache_info.WriteCacheEnabled);
+        }
+    }
+}
+
+impl ScsiTransport for Disk {
+    #[cfg(windows)]
+    fn scsi_command(&mut self, command: &ScsiCommand, data: &mut [u8]) -> io::Result<usize> {
+        self.sptdwb.set_command(command, data);
+        self.scsi_pass_through_direct()?;
+        Ok(self.sptdwb.sptd.DataTransferLength as usize)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn scsi_command(&mut self, command: &ScsiCommand, data: &mut [u8]) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        sg_io(self.file.as_raw_fd(), command, data)
+    }
+
+    #[cfg(not(any(windows, target_os = "linux")))]
+    fn scsi_command(&mut self, _command: &ScsiCommand, _data: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SCSI passthrough is not supported on this platform",
+        ))
+    }
+}
+
+impl Disk {
+    pub fn scsi_inquiry_raw(&mut self, page: Option<u8>, buf: &mut [u8]) -> io::Result<usize> {
+        let cdb = ScsiInquiryCdb6::new(page, buf.len().min(u16::MAX as usize) as u16);
+        self.scsi_command(&ScsiCommand::new(&cdb, ScsiDataDirection::In), buf)
+    }
+
+    pub fn scsi_inquiry(&mut self) -> io::Result<ScsiInquiry> {
+        let mut buff = vec![0x0u8; SCSI_INQUIRY_LENGTH];
+        self.scsi_inquiry_raw(None, &mut buff)?;
+        ScsiInquiry::parse(&buff)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "short INQUIRY data"))
+    }
+
+    /// VPD page `page`, header included and trimmed to the page length
+    pub fn scsi_vpd(&mut self, page: u8) -> io::Result<Vec<u8>> {
+        let mut buff = vec![0x0u8; 4096];
+        self.scsi_inquiry_raw(Some(page), &mut buff)?;
+        let len = match scsi_vpd_payload(&buff) {
+            Some((code, payload)) if code == page => 4 + payload.len(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("device returned no VPD page {:#04x}", page),
+                ))
+            }
+        };
+        buff.truncate(len);
+        Ok(buff)
+    }
+
+    pub fn scsi_vpd_pages(&mut self) -> io::Result<Vec<u8>> {
+        Ok(scsi_vpd_supported_pages(
+            &self.scsi_vpd(SCSI_VPD_SUPPORTED_PAGES)?,
+        ))
+    }
+
+    /// READ CAPACITY (16), falling back to READ CAPACITY (10) for devices
+    /// that reject the service action
+    pub fn scsi_read_capacity(&mut self) -> io::Result<ScsiReadCapacity> {
+        let mut buff = vec![0x0u8; SCSI_READ_CAPACITY_16_LENGTH];
+        let cdb = ScsiReadCapacityCdb16::new(buff.len() as u32);
+        match self.scsi_command(&ScsiCommand::new(&cdb, ScsiDataDirection::In), &mut buff) {
+            Ok(_) => {
+                return ScsiReadCapacity::parse_16(&buff).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "short READ CAPACITY data")
+                })
+            }
+            Err(e)
+                if ScsiError::from_io(&e).and_then(|e| e.sense_key())
+                    != Some(ScsiSenseKey::ILLEGAL_REQUEST) =>
+            {
+                return Err(e)
+            }
+            Err(_) => {}
+        }
+        let mut buff = vec![0x0u8; 8];
+        let mut cdb = [0u8; 10];
+        cdb[0] = ScsiOpcode::SCSI_OPCODE_READ_CAPACITY_10 as u8;
+        self.scsi_command(
+            &ScsiCommand::from_bytes(&cdb, ScsiDataDirection::In),
+            &mut buff,
+        )?;
+        ScsiReadCapacity::parse_10(&buff)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "short READ CAPACITY data"))
     }
 
-    pub fn security_recv(&mut self, protocol: u8, com_id: u16, buf: &[u8]) -> io::Result<usize> {
+    pub fn security_recv(
+        &mut self,
+        protocol: u8,
+        com_id: u16,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
         let cdb = ScsiSecCdb12::new(
             ScsiOpcode::SCSI_OPCODE_SECURITY_RECV,
             protocol,
             com_id,
             buf.len() as u32,
         );
-        self.sptdwb.set_buffer(SCSI_IOCTL_DATA_IN, buf);
-        cdb.encode_as_be_bytes(&mut self.sptdwb.sptd.Cdb);
-        self.sptdwb.sptd.CdbLength = 12;
-
-        self.scsi_pass_through_direct()
+        self.scsi_command(&ScsiCommand::new(&cdb, ScsiDataDirection::In), buf)
     }
 
     pub fn security_send(&mut self, protocol: u8, com_id: u16, buf: &[u8]) -> io::Result<usize> {
@@ -257,145 +780,110 @@ impl Disk {
             com_id,
             buf.len() as u32,
         );
-        self.sptdwb.set_buffer(SCSI_IOCTL_DATA_OUT, buf);
-        cdb.encode_as_be_bytes(&mut self.sptdwb.sptd.Cdb);
-        self.sptdwb.sptd.CdbLength = 12;
-
-        self.scsi_pass_through_direct()
+        self.scsi_command(
+            &ScsiCommand::new(&cdb, ScsiDataDirection::Out),
+            &mut buf.to_vec(),
+        )
     }
 
-    pub fn discovery0(&mut self) -> io::Result<usize> {
-        let buff = Box::new(vec![0x0u8; 4096]);
-        let res = self.security_recv(0x01, 0x0001, &buff);
-        println!("discovery0 {:?}", &buff[..512]);
-        res
+    pub fn discovery0(&mut self) -> io::Result<TcgLevel0Discovery> {
+        let mut buff = vec![0x0u8; 4096];
+        self.security_recv(TCG_LEVEL0_PROTOCOL, TCG_LEVEL0_COMID, &mut buff)?;
+        TcgLevel0Discovery::parse(&buff)
     }
 
-    pub fn storage_set_property(&self) {
-        let mut sps: STORAGE_PROPERTY_SET = unsafe { zeroed() };
-        sps.PropertyId = StorageDeviceWriteCacheProperty;
-        sps.SetType = PropertyStandardSet;
-        let mut cache_info: STORAGE_WRITE_CACHE_PROPERTY = unsafe { zeroed() };
-
-        if let Ok(_r) = ioctl(
-            self.handle,
-            IOCTL_STORAGE_SET_PROPERTY,
-            Some((&sps as *const _ as *const c_void, size_of_val(&sps))),
-            Some((
-                &mut cache_info as *mut _ as *mut c_void,
-                size_of_val(&cache_info),
-            )),
-        ) {
-            println!("storage_set_property: {}", cache_info.WriteCacheEnabled);
+    /// Whole blocks only, the device would transfer past the end of `buf` otherwise
+    fn check_scsi_length(&self, buf: &[u8]) -> io::Result<()> {
+        if !buf.len().is_multiple_of(self.block_size()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes is not a multiple of the {} byte block size",
+                    buf.len(),
+                    self.block_size()
+                ),
+            ));
         }
+        Ok(())
     }
 
-    pub fn scsi_read(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
-        if buf.len() <= 0 {
+    pub fn scsi_read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
             return Ok(0);
         }
-        let mut len = buf.len() - 1;
-        len += SECTOR_SIZE - (len % SECTOR_SIZE);
+        self.check_scsi_length(buf)?;
         let lba = offset >> self.lba_shift;
-        let nlb = (len as u32 >> self.lba_shift) - 1;
+        let nlb = buf.len() as u32 >> self.lba_shift;
         let cdb = ScsiRwCdb16::new(ScsiOpcode::SCSI_OPCODE_READ_16, lba, nlb, 0);
-        self.sptdwb.set_buffer(SCSI_IOCTL_DATA_IN, buf);
-        cdb.encode_as_be_bytes(&mut self.sptdwb.sptd.Cdb);
-        self.sptdwb.sptd.CdbLength = 16;
-
-        self.scsi_pass_through_direct()
+        self.scsi_command(&ScsiCommand::new(&cdb, ScsiDataDirection::In), buf)
     }
 
-    #[allow(unused_assignments)]
+    /// WRITE(16) at the cursor, which advances by the bytes transferred
     pub fn scsi_write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if buf.len() <= 0 {
+        if buf.is_empty() {
             return Ok(0);
         }
-        let mut len = buf.len() - 1;
-        len += SECTOR_SIZE - (len % SECTOR_SIZE);
-        let lba = self.write_offset >> self.lba_shift;
-        let nlb = (len as u32 >> self.lba_shift) - 1;
-        let mut flag = 0;
-        if let Some(fua) = self.fua {
-            flag = (fua as u8) << 3;
-        }
-        let cdb = ScsiRwCdb16::new(ScsiOpcode::SCSI_OPCODE_WRITE_16, lba, nlb, flag as u8);
-        self.sptdwb.set_buffer(SCSI_IOCTL_DATA_OUT, buf);
-        cdb.encode_as_be_bytes(&mut self.sptdwb.sptd.Cdb);
-        self.sptdwb.sptd.CdbLength = 16;
-
-        let res = self.scsi_pass_through_direct();
-        match res {
-            Err(_err) => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Error code: {:#08x}", last_error()),
-            )),
-            Ok(_wb) => {
-                self.write_offset += self.sptdwb.sptd.DataTransferLength as u64;
-                Ok(self.sptdwb.sptd.DataTransferLength as usize)
-            }
-        }
+        self.check_scsi_length(buf)?;
+        let lba = self.position >> self.lba_shift;
+        let nlb = buf.len() as u32 >> self.lba_shift;
+        let flag = match self.fua {
+            Some(true) => ScsiCdbFlag::SCSI_FL_FUA as u8,
+            _ => 0,
+        };
+        let cdb = ScsiRwCdb16::new(ScsiOpcode::SCSI_OPCODE_WRITE_16, lba, nlb, flag);
+        let n = self.scsi_command(
+            &ScsiCommand::new(&cdb, ScsiDataDirection::Out),
+            &mut buf.to_vec(),
+        )?;
+        self.position += n as u64;
+        Ok(n)
     }
 }
 
 impl Read for Disk {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut bytes_read = 0u32;
-        let res = unsafe {
-            ReadFile(
-                self.handle,
-                buf.as_mut_ptr() as *mut u8,
-                buf.len() as u32,
-                &mut bytes_read,
-                null_mut(),
-            )
-        };
-        if res == 0 {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Error code: {:#08x}", last_error()),
-            ))
-        } else {
-            Ok(bytes_read as usize)
-        }
+        let n = self.read_at(buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
     }
 }
 
 impl Write for Disk {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if buf.len() <= 0 {
-            return Ok(0);
-        }
-        let mut len = buf.len() - 1;
-        len += SECTOR_SIZE - (len % SECTOR_SIZE);
-        let mut bytes_write = 0u32;
-        let res = unsafe {
-            WriteFile(
-                self.handle,
-                buf.as_ptr() as *const u8,
-                len as u32,
-                &mut bytes_write,
-                null_mut(),
-            )
-        };
-        if res == 0 {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Error code: {:#08x}", last_error()),
-            ))
-        } else {
-            Ok(bytes_write as usize)
-        }
+        let n = self.write_at(buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        self.sync()
+    }
+}
+
+impl Seek for Disk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => (self.size as u64).checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )),
+        }
     }
 }
 
 unsafe impl Send for Disk {}
 unsafe impl Sync for Disk {}
 
+#[cfg(windows)]
 pub fn get_physical_drv_number_from_logical_drv(drive_name: String) -> i32 {
     let mut disk_number = -1;
     let path = format!("\\\\.\\{drive_name}");
@@ -418,3 +906,200 @@ pub fn get_physical_drv_number_from_logical_drv(drive_name: String) -> i32 {
     }
     disk_number
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(name: &str, len: usize) -> Disk {
+        let path = std::env::temp_dir().join(format!("disk-{}-{}", name, std::process::id()));
+        std::fs::write(&path, vec![0u8; len]).unwrap();
+        let disk = Disk::open(path.to_string_lossy().into_owned(), 'r', None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        disk
+    }
+
+    #[test]
+    fn set_capacity_keeps_os_size() {
+        let capacity = ScsiReadCapacity {
+            last_lba: 0xFFFF,
+            block_length: 4096,
+            physical_block_exponent: 1,
+            ..Default::default()
+        };
+        // a partition or volume: block sizes from the device, size from the OS
+        let mut disk = image("partition", 1 << 20);
+        disk.set_capacity(&capacity);
+        assert_eq!(disk.size(), 1 << 20);
+        assert_eq!(disk.block_size(), 4096);
+        assert_eq!(disk.physical_block_size, 8192);
+
+        // no OS size, as for an sg node
+        let mut disk = image("sg", 0);
+        disk.set_capacity(&capacity);
+        assert_eq!(disk.size(), 0x10000 * 4096);
+
+        let overflow = ScsiReadCapacity {
+            last_lba: u64::MAX,
+            ..capacity
+        };
+        let mut disk = image("overflow", 0);
+        disk.set_capacity(&overflow);
+        assert_eq!(disk.size(), 0);
+    }
+
+    /// Writable image holding `len` bytes of a pattern, and the same bytes in
+    /// memory to check it against
+    #[cfg(unix)]
+    fn image_rw(name: &str, len: usize) -> (Disk, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!("disk-{}-{}", name, std::process::id()));
+        let mut disk = Disk::open(path.to_string_lossy().into_owned(), 'w', None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut data = disk.alloc_buffer(len);
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        std::os::unix::fs::FileExt::write_all_at(&disk.file, &data, 0).unwrap();
+        disk.file.set_len(len as u64).unwrap();
+        disk.size = len;
+        let model = data[..len].to_vec();
+        (disk, model)
+    }
+
+    /// Apply a write to the in-memory model, growing it like a file
+    #[cfg(unix)]
+    fn model_write(model: &mut Vec<u8>, data: &[u8], offset: usize) {
+        if model.len() < offset + data.len() {
+            model.resize(offset + data.len(), 0);
+        }
+        model[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// The whole image read back, its size and the file length all match
+    #[cfg(unix)]
+    fn check(disk: &mut Disk, model: &[u8]) {
+        assert_eq!(disk.size(), model.len());
+        assert_eq!(disk.file.metadata().unwrap().len(), model.len() as u64);
+        let mut data = vec![0u8; model.len() + 1000];
+        let n = disk.read_at(&mut data, 0).unwrap();
+        assert_eq!(n, model.len());
+        assert!(data[..n] == *model, "contents differ from the model");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_modify_write() {
+        let (mut disk, mut model) = image_rw("rmw", 4096);
+        let cases: [(usize, usize); 6] = [
+            (100, 412),   // head only: ends on a block boundary
+            (1024, 100),  // tail only: starts on one
+            (1100, 50),   // head and tail in one block
+            (1500, 1300), // spanning blocks
+            (2048, 1024), // aligned offset and length, unaligned memory
+            (0, 4096),
+        ];
+        for (i, &(offset, len)) in cases.iter().enumerate() {
+            let data: Vec<u8> = (0..len).map(|b| (b * 7 + i) as u8 | 1).collect();
+            assert_eq!(disk.write_at(&data, offset as u64).unwrap(), len);
+            model_write(&mut model, &data, offset);
+            check(&mut disk, &model);
+        }
+
+        // aligned memory too
+        let mut data = disk.alloc_buffer(512);
+        data.fill(0xa5);
+        disk.write_at(&data, 512).unwrap();
+        model_write(&mut model, &data, 512);
+        check(&mut disk, &model);
+
+        // unaligned reads inside, across and past the end
+        for (offset, len, expected) in [(3, 10, 10), (510, 4, 4), (4000, 200, 96), (4096, 10, 0)] {
+            let mut data = vec![0u8; len];
+            let n = disk.read_at(&mut data, offset).unwrap();
+            assert_eq!(n, expected, "read at {}", offset);
+            assert_eq!(data[..n], model[offset as usize..offset as usize + n]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_past_end() {
+        let (mut disk, mut model) = image_rw("extend", 1000);
+        // into the padding of the last block, then well past it
+        for (offset, len) in [(990, 30), (1020, 4), (3000, 17), (8192, 512), (9000, 1)] {
+            let data = vec![offset as u8 | 0x80; len];
+            assert_eq!(disk.write_at(&data, offset as u64).unwrap(), len);
+            model_write(&mut model, &data, offset);
+            check(&mut disk, &model);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn random_io_matches_model() {
+        let (mut disk, mut model) = image_rw("random", 5000);
+        let mut rng = crate::bench::BenchRng::new(0x5EED);
+        for _ in 0..300 {
+            let offset = rng.below(12000) as usize;
+            let len = rng.below(1500) as usize + 1;
+            if rng.below(2) == 0 {
+                let data: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+                assert_eq!(disk.write_at(&data, offset as u64).unwrap(), len);
+                model_write(&mut model, &data, offset);
+            } else {
+                let mut data = vec![0u8; len];
+                let n = disk.read_at(&mut data, offset as u64).unwrap();
+                let expected = model.len().saturating_sub(offset).min(len);
+                assert_eq!(n, expected, "read {} at {}", len, offset);
+                if n != 0 {
+                    assert_eq!(data[..n], model[offset..offset + n]);
+                }
+            }
+        }
+        check(&mut disk, &model);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cursor() {
+        let (mut disk, mut model) = image_rw("seek", 2048);
+        assert_eq!(disk.seek(SeekFrom::End(-10)).unwrap(), 2038);
+        let mut data = [0u8; 32];
+        // a short read at the end
+        assert_eq!(disk.read(&mut data).unwrap(), 10);
+        assert_eq!(data[..10], model[2038..]);
+        assert_eq!(disk.read(&mut data).unwrap(), 0);
+
+        assert_eq!(disk.seek(SeekFrom::Current(-1000)).unwrap(), 1048);
+        disk.write_all(b"hello").unwrap();
+        model_write(&mut model, b"hello", 1048);
+        assert_eq!(disk.stream_position().unwrap(), 1053);
+        assert_eq!(disk.seek(SeekFrom::Start(1046)).unwrap(), 1046);
+        disk.read_exact(&mut data[..9]).unwrap();
+        assert_eq!(&data[..9], &model[1046..1055]);
+
+        // past the end grows the image from there
+        assert_eq!(disk.seek(SeekFrom::End(100)).unwrap(), 2148);
+        disk.write_all(b"end").unwrap();
+        model_write(&mut model, b"end", 2148);
+        check(&mut disk, &model);
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), 2151);
+
+        for pos in [SeekFrom::End(-3000), SeekFrom::Current(-3000)] {
+            let error = disk.seek(pos).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        // a failed seek leaves the cursor alone
+        assert_eq!(disk.stream_position().unwrap(), 2151);
+    }
+
+    #[test]
+    fn sector_sizes_are_powers_of_two() {
+        assert!(check_sector_sizes(512, 4096).is_ok());
+        assert!(check_sector_sizes(4096, 4096).is_ok());
+        assert!(check_sector_sizes(0, 4096).is_err());
+        assert!(check_sector_sizes(512, 0).is_err());
+        assert!(check_sector_sizes(520, 4096).is_err());
+        assert!(check_sector_sizes(512, 3072).is_err());
+    }
+}
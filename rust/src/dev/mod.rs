@@ -1,8 +1,22 @@
 // src/dev/mod.rs
+#[cfg(windows)]
 pub mod dev_utils;
 pub mod disk;
+pub mod nvme_aer;
 pub mod nvme_commands;
+pub mod nvme_decode;
 pub mod nvme_define;
+#[cfg(windows)]
 pub mod nvme_device;
+pub mod nvme_health;
 pub mod nvme_print;
+pub mod nvme_record;
+pub mod nvme_retry;
+pub mod nvme_sim;
+pub mod nvme_vendor;
+pub mod opal;
 pub mod scsi;
+#[cfg(target_os = "linux")]
+pub mod sg;
+pub mod sntl;
+pub mod tcg;
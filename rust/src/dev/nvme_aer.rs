@@ -0,0 +1,493 @@
+// Asynchronous Event Request monitoring. The Async Event Configuration feature
+// (FID 0Bh) selects which events the controller reports; each Asynchronous Event
+// Request completes with the event type, its information and the log page that
+// holds the details. Reading that log page with RAE clear re-arms the event.
+// The Windows inbox driver (stornvme) submits its own Asynchronous Event
+// Requests and fails any sent through IOCTL_STORAGE_PROTOCOL_COMMAND, so the
+// monitor only receives events from a transport that passes them on, such as
+// a replayed trace or the simulator.
+use crate::dev::nvme_commands::{NvmeOpcodeType, NvmeTransport};
+use crate::dev::nvme_decode::{nvme_decode, NvmeDecodeKind, NvmeDecoded};
+use crate::dev::nvme_define::NVME_ADMIN_COMMANDS::*;
+use crate::dev::nvme_define::NVME_ASYNC_EVENT_ERROR_STATUS_CODES::*;
+use crate::dev::nvme_define::NVME_ASYNC_EVENT_HEALTH_STATUS_CODES::*;
+use crate::dev::nvme_define::NVME_ASYNC_EVENT_IO_COMMAND_SET_STATUS_CODES::*;
+use crate::dev::nvme_define::NVME_ASYNC_EVENT_NOTICE_CODES::*;
+use crate::dev::nvme_define::NVME_ASYNC_EVENT_TYPES::*;
+use crate::dev::nvme_define::NVME_ASYNC_EVENT_TYPE_VENDOR_SPECIFIC_CODES::*;
+use crate::dev::nvme_define::NVME_FEATURES::*;
+use crate::dev::nvme_define::*;
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const NVME_NSID_ALL: u32 = 0xffff_ffff;
+// OAES and the Async Event Configuration share bit positions for the notices
+const NVME_AER_NOTICE_MASK: u32 = 0x7f00 | (1 << 27);
+const NVME_AER_TELEMETRY_NOTICE: u32 = 1 << 10;
+
+pub fn nvme_async_event_type_name(event_type: u8) -> &'static str {
+    match event_type {
+        x if x == NVME_ASYNC_EVENT_TYPE_ERROR_STATUS as u8 => "Error Status",
+        x if x == NVME_ASYNC_EVENT_TYPE_HEALTH_STATUS as u8 => "SMART / Health Status",
+        x if x == NVME_ASYNC_EVENT_TYPE_NOTICE as u8 => "Notice",
+        x if x == NVME_ASYNC_EVENT_TYPE_IO_COMMAND_SET_STATUS as u8 => "I/O Command Set Status",
+        x if x == NVME_ASYNC_EVENT_TYPE_VENDOR_SPECIFIC as u8 => "Vendor Specific",
+        _ => "Reserved",
+    }
+}
+
+pub fn nvme_async_event_info_name(event_type: u8, info: u8) -> &'static str {
+    match event_type {
+        x if x == NVME_ASYNC_EVENT_TYPE_ERROR_STATUS as u8 => match info {
+            x if x == NVME_ASYNC_ERROR_INVALID_SUBMISSION_QUEUE as u8 => {
+                "Write to Invalid Doorbell Register"
+            }
+            x if x == NVME_ASYNC_ERROR_INVALID_DOORBELL_WRITE_VALUE as u8 => {
+                "Invalid Doorbell Write Value"
+            }
+            x if x == NVME_ASYNC_ERROR_DIAG_FAILURE as u8 => "Diagnostic Failure",
+            x if x == NVME_ASYNC_ERROR_PERSISTENT_INTERNAL_DEVICE_ERROR as u8 => {
+                "Persistent Internal Error"
+            }
+            x if x == NVME_ASYNC_ERROR_TRANSIENT_INTERNAL_DEVICE_ERROR as u8 => {
+                "Transient Internal Error"
+            }
+            x if x == NVME_ASYNC_ERROR_FIRMWARE_IMAGE_LOAD_ERROR as u8 => {
+                "Firmware Image Load Error"
+            }
+            _ => "Reserved",
+        },
+        x if x == NVME_ASYNC_EVENT_TYPE_HEALTH_STATUS as u8 => match info {
+            x if x == NVME_ASYNC_HEALTH_NVM_SUBSYSTEM_RELIABILITY as u8 => {
+                "NVM Subsystem Reliability"
+            }
+            x if x == NVME_ASYNC_HEALTH_TEMPERATURE_THRESHOLD as u8 => "Temperature Threshold",
+            x if x == NVME_ASYNC_HEALTH_SPARE_BELOW_THRESHOLD as u8 => "Spare Below Threshold",
+            _ => "Reserved",
+        },
+        x if x == NVME_ASYNC_EVENT_TYPE_NOTICE as u8 => match info {
+            x if x == NVME_ASYNC_NOTICE_NAMESPACE_ATTRIBUTE_CHANGED as u8 => {
+                "Namespace Attribute Changed"
+            }
+            x if x == NVME_ASYNC_NOTICE_FIRMWARE_ACTIVATION_STARTING as u8 => {
+                "Firmware Activation Starting"
+            }
+            x if x == NVME_ASYNC_NOTICE_TELEMETRY_LOG_CHANGED as u8 => "Telemetry Log Changed",
+            x if x == NVME_ASYNC_NOTICE_ASYMMETRIC_ACCESS_CHANGE as u8 => {
+                "Asymmetric Namespace Access Change"
+            }
+            x if x == NVME_ASYNC_NOTICE_PREDICTABLE_LATENCY_EVENT_AGGREGATE_LOG_CHANGE as u8 => {
+                "Predictable Latency Event Aggregate Log Change"
+            }
+            x if x == NVME_ASYNC_NOTICE_LBA_STATUS_INFORMATION_ALERT as u8 => {
+                "LBA Status Information Alert"
+            }
+            x if x == NVME_ASYNC_NOTICE_ENDURANCE_GROUP_EVENT_AGGREGATE_LOG_CHANGE as u8 => {
+                "Endurance Group Event Aggregate Log Change"
+            }
+            x if x == NVME_ASYNC_NOTICE_ZONE_DESCRIPTOR_CHANGED as u8 => "Zone Descriptor Changed",
+            _ => "Reserved",
+        },
+        x if x == NVME_ASYNC_EVENT_TYPE_IO_COMMAND_SET_STATUS as u8 => match info {
+            x if x == NVME_ASYNC_IO_CMD_SET_RESERVATION_LOG_PAGE_AVAILABLE as u8 => {
+                "Reservation Log Page Available"
+            }
+            x if x == NVME_ASYNC_IO_CMD_SANITIZE_OPERATION_COMPLETED as u8 => {
+                "Sanitize Operation Completed"
+            }
+            x if x
+                == NVME_ASYNC_IO_CMD_SANITIZE_OPERATION_COMPLETED_WITH_UNEXPECTED_DEALLOCATION
+                    as u8 =>
+            {
+                "Sanitize Operation Completed With Unexpected Deallocation"
+            }
+            _ => "Reserved",
+        },
+        x if x == NVME_ASYNC_EVENT_TYPE_VENDOR_SPECIFIC as u8 => match info {
+            x if x == NVME_ASYNC_EVENT_TYPE_VENDOR_SPECIFIC_DEVICE_PANIC as u8 => "Device Panic",
+            _ => "Vendor Specific",
+        },
+        _ => "Reserved",
+    }
+}
+
+/// Why an Asynchronous Event Request cannot go through the Windows inbox driver
+pub fn nvme_aer_inbox_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the Windows inbox NVMe driver (stornvme) does not pass Asynchronous Event Requests through",
+    )
+}
+
+/// Asynchronous Event Request completion, decoded from DW0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvmeAsyncEvent {
+    pub event_type: u8,
+    pub info: u8,
+    pub log_page: u8,
+}
+
+impl From<u32> for NvmeAsyncEvent {
+    fn from(dw0: u32) -> Self {
+        let dw0 = NVME_COMPLETION_DW0_ASYNC_EVENT_REQUEST::from(dw0);
+        Self {
+            event_type: dw0.AsyncEventType(),
+            info: dw0.AsyncEventInfo(),
+            log_page: dw0.LogPage(),
+        }
+    }
+}
+
+impl NvmeAsyncEvent {
+    pub fn type_name(&self) -> &'static str {
+        nvme_async_event_type_name(self.event_type)
+    }
+
+    pub fn info_name(&self) -> &'static str {
+        nvme_async_event_info_name(self.event_type, self.info)
+    }
+}
+
+impl fmt::Display for NvmeAsyncEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} (type {}h, info {:02x}h, log page {:02x}h)",
+            self.type_name(),
+            self.info_name(),
+            self.event_type,
+            self.info,
+            self.log_page
+        )
+    }
+}
+
+/// An event with the log page read to clear it
+#[derive(Serialize)]
+pub struct NvmeAsyncEventReport {
+    /// seconds since the Unix epoch
+    pub timestamp: u64,
+    pub event_type: u8,
+    pub type_name: &'static str,
+    pub info: u8,
+    pub info_name: &'static str,
+    pub log_page: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<NvmeDecoded>,
+    /// why the log page could not be read or decoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_error: Option<String>,
+    #[serde(skip)]
+    pub log_data: Vec<u8>,
+}
+
+impl NvmeAsyncEventReport {
+    pub fn event(&self) -> NvmeAsyncEvent {
+        NvmeAsyncEvent {
+            event_type: self.event_type,
+            info: self.info,
+            log_page: self.log_page,
+        }
+    }
+}
+
+/// Async Event Configuration with every SMART critical warning and the notices
+/// the controller reports in OAES, plus telemetry when LPA says it is supported
+pub fn nvme_aer_supported_config(
+    data: &NVME_IDENTIFY_CONTROLLER_DATA,
+) -> NVME_CDW11_FEATURE_ASYNC_EVENT_CONFIG {
+    let mut config = u32::from(data.OAES) & NVME_AER_NOTICE_MASK;
+    if data.LPA.TelemetrySupport() != 0 {
+        config |= NVME_AER_TELEMETRY_NOTICE;
+    }
+    NVME_CDW11_FEATURE_ASYNC_EVENT_CONFIG::from(config).with_CriticalWarnings(0x1f)
+}
+
+/// Bytes to read for a log page: the whole structure for the ones with a
+/// decoder, a header's worth otherwise
+fn nvme_aer_log_len(kind: Option<NvmeDecodeKind>) -> usize {
+    match kind {
+        Some(NvmeDecodeKind::Error)
+        | Some(NvmeDecodeKind::ChangedNs)
+        | Some(NvmeDecodeKind::Effects) => 4096,
+        Some(kind) => kind.min_len().max(512),
+        None => 512,
+    }
+}
+
+pub struct NvmeAerMonitor<T: NvmeTransport> {
+    transport: T,
+}
+
+impl<T: NvmeTransport> NvmeAerMonitor<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Current Async Event Configuration
+    pub fn config(&self) -> io::Result<NVME_CDW11_FEATURE_ASYNC_EVENT_CONFIG> {
+        let mut command = NVME_COMMAND::default();
+        command
+            .opcode(NVME_ADMIN_COMMAND_GET_FEATURES as u32)
+            .cdw10(NVME_FEATURE_ASYNC_EVENT_CONFIG as u32);
+        let mut dw0 = 0;
+        self.transport
+            .admin_passthru(NvmeOpcodeType::NOBUFFER as u8, &command, &mut [], &mut dw0)?
+            .check("Get Features (Async Event Configuration)")?;
+        Ok(NVME_CDW11_FEATURE_ASYNC_EVENT_CONFIG::from(dw0))
+    }
+
+    pub fn configure(&self, config: NVME_CDW11_FEATURE_ASYNC_EVENT_CONFIG) -> io::Result<()> {
+        let mut command = NVME_COMMAND::default();
+        command
+            .opcode(NVME_ADMIN_COMMAND_SET_FEATURES as u32)
+            .cdw10(NVME_FEATURE_ASYNC_EVENT_CONFIG as u32)
+            .cdw11(config.into());
+        let mut dw0 = 0;
+        self.transport
+            .admin_passthru(NvmeOpcodeType::NOBUFFER as u8, &command, &mut [], &mut dw0)?
+            .check("Set Features (Async Event Configuration)")
+    }
+
+    /// Configuration enabling every event the controller supports
+    pub fn supported_config(&self) -> io::Result<NVME_CDW11_FEATURE_ASYNC_EVENT_CONFIG> {
+        let mut command = NVME_COMMAND::default();
+        command
+            .opcode(NVME_ADMIN_COMMAND_IDENTIFY as u32)
+            .cdw10(NVME_IDENTIFY_CNS_CODES::NVME_IDENTIFY_CNS_CONTROLLER as u32);
+        let mut data = vec![0u8; NVME_IDENTIFY_SIZE];
+        let mut dw0 = 0;
+        self.transport
+            .admin_passthru(NvmeOpcodeType::READ as u8, &command, &mut data, &mut dw0)?
+            .check("Identify Controller")?;
+        let identify = unsafe {
+            std::ptr::read_unaligned(data.as_ptr() as *const NVME_IDENTIFY_CONTROLLER_DATA)
+        };
+        Ok(nvme_aer_supported_config(&identify))
+    }
+
+    /// Submit an Asynchronous Event Request and wait for it to complete. How
+    /// long that may take is up to the transport's timeout.
+    pub fn wait(&self) -> io::Result<NvmeAsyncEvent> {
+        let mut command = NVME_COMMAND::default();
+        command.opcode(NVME_ADMIN_COMMAND_ASYNC_EVENT_REQUEST as u32);
+        let mut dw0 = 0;
+        self.transport
+            .admin_passthru(NvmeOpcodeType::NOBUFFER as u8, &command, &mut [], &mut dw0)?
+            .check("Asynchronous Event Request")?;
+        Ok(NvmeAsyncEvent::from(dw0))
+    }
+
+    /// Read the event's log page with RAE clear, which lets the controller
+    /// report events of this type again
+    pub fn read_log(&self, event: &NvmeAsyncEvent) -> io::Result<Vec<u8>> {
+        let len = nvme_aer_log_len(NvmeDecodeKind::from_log_id(event.log_page as u32));
+        let numd = (len / 4 - 1) as u32;
+        let cdw10 = NVME_CDW10_GET_LOG_PAGE_V13::new()
+            .with_LID(event.log_page)
+            .with_RAE(0)
+            .with_NUMDL(numd as u16);
+        let mut command = NVME_COMMAND::default();
+        command
+            .opcode(NVME_ADMIN_COMMAND_GET_LOG_PAGE as u32)
+            .nsid(NVME_NSID_ALL)
+            .cdw10(cdw10.into())
+            .cdw11(numd >> 16);
+        let mut data = vec![0u8; len];
+        let mut dw0 = 0;
+        self.transport
+            .admin_passthru(NvmeOpcodeType::READ as u8, &command, &mut data, &mut dw0)?
+            .check("Get Log Page")?;
+        Ok(data)
+    }
+
+    /// Wait for the next event and, when `clear` is set, read and decode its
+    /// log page. A log that cannot be read is reported rather than returned
+    /// as an error, so monitoring carries on.
+    pub fn next_event(&self, clear: bool) -> io::Result<NvmeAsyncEventReport> {
+        let event = self.wait()?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut report = NvmeAsyncEventReport {
+            timestamp,
+            event_type: event.event_type,
+            type_name: event.type_name(),
+            info: event.info,
+            info_name: event.info_name(),
+            log_page: event.log_page,
+            log: None,
+            log_error: None,
+            log_data: vec![],
+        };
+        if !clear {
+            return Ok(report);
+        }
+        match self.read_log(&event) {
+            Ok(data) => {
+                if let Some(kind) = NvmeDecodeKind::from_log_id(event.log_page as u32) {
+                    match nvme_decode(kind, &data) {
+                        Ok(decoded) => report.log = Some(decoded),
+                        Err(e) => report.log_error = Some(e.to_string()),
+                    }
+                }
+                report.log_data = data;
+            }
+            Err(e) => report.log_error = Some(e.to_string()),
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev::nvme_sim::NvmeSimulator;
+
+    const SMART: u8 = NVME_LOG_PAGES::NVME_LOG_PAGE_HEALTH_INFO as u8;
+    const CHANGED_NS: u8 = NVME_LOG_PAGES::NVME_LOG_PAGE_CHANGED_NAMESPACE_LIST as u8;
+
+    fn monitor() -> NvmeAerMonitor<NvmeSimulator> {
+        NvmeAerMonitor::new(NvmeSimulator::new(1 << 20, 9))
+    }
+
+    fn opcodes(sim: &NvmeSimulator) -> Vec<u8> {
+        sim.history().iter().map(|c| c.opcode).collect()
+    }
+
+    #[test]
+    fn configuration() {
+        let monitor = monitor();
+        let fid = NVME_FEATURE_ASYNC_EVENT_CONFIG as u8;
+        // OAES of the simulator: namespace attribute and firmware activation
+        let config = monitor.supported_config().unwrap();
+        assert_eq!(u32::from(config), 0x0300 | 0x1f);
+        monitor.configure(config).unwrap();
+        assert_eq!(monitor.transport().feature(fid), Some(0x031f));
+        assert_eq!(u32::from(monitor.config().unwrap()), 0x031f);
+    }
+
+    #[test]
+    fn health_event() {
+        let monitor = monitor();
+        let sim = monitor.transport();
+        sim.post_async_event(
+            NVME_ASYNC_EVENT_TYPE_HEALTH_STATUS as u8,
+            NVME_ASYNC_HEALTH_TEMPERATURE_THRESHOLD as u8,
+            SMART,
+        );
+        let report = monitor.next_event(true).unwrap();
+        assert_eq!(
+            report.event().to_string(),
+            "SMART / Health Status: Temperature Threshold (type 1h, info 01h, log page 02h)"
+        );
+        let Some(NvmeDecoded::Smart(smart)) = &report.log else {
+            panic!("SMART log not decoded: {:?}", report.log_error);
+        };
+        assert_eq!(smart.temperature, 313);
+        assert_eq!(report.log_data.len(), 512);
+
+        // the log page is read with RAE clear to re-arm the event
+        let history = sim.history();
+        assert_eq!(
+            opcodes(sim),
+            [
+                NVME_ADMIN_COMMAND_ASYNC_EVENT_REQUEST as u8,
+                NVME_ADMIN_COMMAND_GET_LOG_PAGE as u8
+            ]
+        );
+        let cdw10 = NVME_CDW10_GET_LOG_PAGE_V13::from(history[1].cdw10);
+        assert_eq!((cdw10.LID(), cdw10.RAE(), cdw10.NUMDL()), (SMART, 0, 127));
+        assert_eq!(history[1].nsid, NVME_NSID_ALL);
+    }
+
+    #[test]
+    fn changed_namespace_event() {
+        let monitor = monitor();
+        let sim = monitor.transport();
+        let mut list = vec![0u8; 4096];
+        list[..4].copy_from_slice(&1u32.to_le_bytes());
+        list[4..8].copy_from_slice(&3u32.to_le_bytes());
+        sim.set_log_page(CHANGED_NS, list);
+        sim.post_async_event(
+            NVME_ASYNC_EVENT_TYPE_NOTICE as u8,
+            NVME_ASYNC_NOTICE_NAMESPACE_ATTRIBUTE_CHANGED as u8,
+            CHANGED_NS,
+        );
+        let report = monitor.next_event(true).unwrap();
+        assert_eq!(report.info_name, "Namespace Attribute Changed");
+        let Some(NvmeDecoded::ChangedNs(nsids)) = &report.log else {
+            panic!("changed namespace list not decoded");
+        };
+        assert_eq!(nsids, &[1, 3]);
+        assert_eq!(report.log_data.len(), 4096);
+
+        // reading it cleared the list
+        sim.post_async_event(
+            NVME_ASYNC_EVENT_TYPE_NOTICE as u8,
+            NVME_ASYNC_NOTICE_NAMESPACE_ATTRIBUTE_CHANGED as u8,
+            CHANGED_NS,
+        );
+        let report = monitor.next_event(true).unwrap();
+        assert!(matches!(&report.log, Some(NvmeDecoded::ChangedNs(nsids)) if nsids.is_empty()));
+    }
+
+    #[test]
+    fn events_in_order() {
+        let monitor = monitor();
+        let sim = monitor.transport();
+        sim.post_async_event(NVME_ASYNC_EVENT_TYPE_ERROR_STATUS as u8, 0x05, 0x01);
+        sim.post_async_event(NVME_ASYNC_EVENT_TYPE_VENDOR_SPECIFIC as u8, 0x01, 0xc0);
+        let first = monitor.next_event(false).unwrap();
+        let second = monitor.next_event(false).unwrap();
+        assert_eq!(first.info_name, "Firmware Image Load Error");
+        assert_eq!((second.info_name, second.log_page), ("Device Panic", 0xc0));
+        // without clear no log page is read
+        assert!(first.log.is_none() && first.log_data.is_empty());
+        assert_eq!(sim.history().len(), 2);
+
+        let error = monitor.next_event(false).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn unreadable_log() {
+        let monitor = monitor();
+        let sim = monitor.transport();
+        // the simulator has no vendor log at c0h yet
+        sim.post_async_event(NVME_ASYNC_EVENT_TYPE_VENDOR_SPECIFIC as u8, 0x01, 0xc0);
+        let report = monitor.next_event(true).unwrap();
+        assert!(report.log.is_none());
+        assert!(report.log_error.unwrap().contains("Get Log Page"));
+
+        // one without a decoder is kept raw
+        sim.set_log_page(0xc0, vec![0xee; 512]);
+        sim.post_async_event(NVME_ASYNC_EVENT_TYPE_VENDOR_SPECIFIC as u8, 0x01, 0xc0);
+        let report = monitor.next_event(true).unwrap();
+        assert!(report.log.is_none() && report.log_error.is_none());
+        assert_eq!(report.log_data, vec![0xee; 512]);
+    }
+
+    #[test]
+    fn report_json() {
+        let monitor = monitor();
+        monitor.transport().post_async_event(
+            NVME_ASYNC_EVENT_TYPE_HEALTH_STATUS as u8,
+            NVME_ASYNC_HEALTH_SPARE_BELOW_THRESHOLD as u8,
+            SMART,
+        );
+        let report = monitor.next_event(true).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+        assert_eq!(json["type_name"], "SMART / Health Status");
+        assert_eq!(json["info_name"], "Spare Below Threshold");
+        assert_eq!(json["log"]["available_spare"], 100);
+        assert!(json.get("log_error").is_none() && json.get("log_data").is_none());
+    }
+}
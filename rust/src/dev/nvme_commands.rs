@@ -1,7 +1,11 @@
+#[cfg(windows)]
 use crate::dev::nvme_define::NVME_IDENTIFY_CNS_CODES::*;
 use crate::dev::nvme_define::*;
+#[cfg(windows)]
 use crate::dev::nvme_device::*;
-use std::{io, mem::size_of};
+#[cfg(windows)]
+use crate::dev::tcg::{TcgLevel0Discovery, TCG_LEVEL0_COMID, TCG_LEVEL0_PROTOCOL};
+use std::{fmt, io, mem::size_of};
 
 impl NVME_COMMAND {
     pub fn opcode(&mut self, opc: u32) -> &mut Self {
@@ -12,6 +16,14 @@ impl NVME_COMMAND {
         self.NSID = nsid;
         self
     }
+    pub fn cdw2(&mut self, value: u32) -> &mut Self {
+        self.Reserved0[0] = value;
+        self
+    }
+    pub fn cdw3(&mut self, value: u32) -> &mut Self {
+        self.Reserved0[1] = value;
+        self
+    }
     pub fn data(&mut self) -> &mut Self {
         self
     }
@@ -61,8 +73,222 @@ impl NVME_COMMAND {
     pub fn sanitize(&mut self) -> &mut Self {
         self
     }
+
+    /// The 64 byte submission queue entry, little endian as the controller
+    /// reads it
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let general = unsafe { self.u.GENERAL };
+        let dwords = [
+            self.CDW0.into(),
+            self.NSID,
+            self.Reserved0[0],
+            self.Reserved0[1],
+            self.MPTR as u32,
+            (self.MPTR >> 32) as u32,
+            self.PRP1 as u32,
+            (self.PRP1 >> 32) as u32,
+            self.PRP2 as u32,
+            (self.PRP2 >> 32) as u32,
+            general.CDW10,
+            general.CDW11,
+            general.CDW12,
+            general.CDW13,
+            general.CDW14,
+            general.CDW15,
+        ];
+        let mut bytes = [0u8; 64];
+        for (chunk, dword) in bytes.chunks_exact_mut(4).zip(dwords) {
+            chunk.copy_from_slice(&dword.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Data direction encoded in the low two bits of standard opcodes
+pub fn nvme_opcode_direction(opcode: u8) -> NvmeOpcodeType {
+    match opcode & 0x3 {
+        1 => NvmeOpcodeType::WRITE,
+        2 => NvmeOpcodeType::READ,
+        3 => NvmeOpcodeType::READWRITE,
+        _ => NvmeOpcodeType::NOBUFFER,
+    }
+}
+
+/// Parameter (F1h) and data (F0h | direction) phase commands of the two
+/// phase vendor-specific protocol; the data phase is only sent when
+/// `direction` is not zero and the parameter phase succeeds
+pub fn nvme_vsc2_commands(
+    sub_opcode: u32,
+    direction: u8,
+    param_len: usize,
+    data_len: usize,
+    nsid: u32,
+) -> (NVME_COMMAND, NVME_COMMAND) {
+    let mut param = NVME_COMMAND::default();
+    param
+        .opcode(NvmeVscOpcode::Write as u32)
+        .nsid(nsid)
+        .cdw10((param_len / size_of::<u32>()) as u32)
+        .cdw12(sub_opcode);
+    let mut data = param;
+    data.opcode(NvmeVscOpcode::None as u32 | direction as u32)
+        .cdw10((data_len / size_of::<u32>()) as u32)
+        .cdw14(1);
+    (param, data)
+}
+
+pub fn nvme_status_name(sct: u8, sc: u8) -> &'static str {
+    match (sct, sc) {
+        (0, 0x00) => "Successful Completion",
+        (0, 0x01) => "Invalid Command Opcode",
+        (0, 0x02) => "Invalid Field in Command",
+        (0, 0x03) => "Command ID Conflict",
+        (0, 0x04) => "Data Transfer Error",
+        (0, 0x05) => "Commands Aborted due to Power Loss Notification",
+        (0, 0x06) => "Internal Error",
+        (0, 0x07) => "Command Abort Requested",
+        (0, 0x08) => "Command Aborted due to SQ Deletion",
+        (0, 0x09) => "Command Aborted due to Failed Fused Command",
+        (0, 0x0a) => "Command Aborted due to Missing Fused Command",
+        (0, 0x0b) => "Invalid Namespace or Format",
+        (0, 0x0c) => "Command Sequence Error",
+        (0, 0x0d) => "Invalid SGL Segment Descriptor",
+        (0, 0x0e) => "Invalid Number of SGL Descriptors",
+        (0, 0x0f) => "Data SGL Length Invalid",
+        (0, 0x10) => "Metadata SGL Length Invalid",
+        (0, 0x11) => "SGL Descriptor Type Invalid",
+        (0, 0x12) => "Invalid Use of Controller Memory Buffer",
+        (0, 0x13) => "PRP Offset Invalid",
+        (0, 0x14) => "Atomic Write Unit Exceeded",
+        (0, 0x15) => "Operation Denied",
+        (0, 0x16) => "SGL Offset Invalid",
+        (0, 0x18) => "Host Identifier Inconsistent Format",
+        (0, 0x19) => "Keep Alive Timer Expired",
+        (0, 0x1a) => "Keep Alive Timeout Invalid",
+        (0, 0x1b) => "Command Aborted due to Preempt and Abort",
+        (0, 0x1c) => "Sanitize Failed",
+        (0, 0x1d) => "Sanitize In Progress",
+        (0, 0x1e) => "SGL Data Block Granularity Invalid",
+        (0, 0x1f) => "Command Not Supported for Queue in CMB",
+        (0, 0x20) => "Namespace is Write Protected",
+        (0, 0x21) => "Command Interrupted",
+        (0, 0x22) => "Transient Transport Error",
+        (0, 0x70) => "Directive Type Invalid",
+        (0, 0x71) => "Directive ID Invalid",
+        (0, 0x80) => "LBA Out of Range",
+        (0, 0x81) => "Capacity Exceeded",
+        (0, 0x82) => "Namespace Not Ready",
+        (0, 0x83) => "Reservation Conflict",
+        (0, 0x84) => "Format In Progress",
+        (1, 0x00) => "Completion Queue Invalid",
+        (1, 0x01) => "Invalid Queue Identifier",
+        (1, 0x02) => "Invalid Queue Size",
+        (1, 0x03) => "Abort Command Limit Exceeded",
+        (1, 0x05) => "Asynchronous Event Request Limit Exceeded",
+        (1, 0x06) => "Invalid Firmware Slot",
+        (1, 0x07) => "Invalid Firmware Image",
+        (1, 0x08) => "Invalid Interrupt Vector",
+        (1, 0x09) => "Invalid Log Page",
+        (1, 0x0a) => "Invalid Format",
+        (1, 0x0b) => "Firmware Activation Requires Conventional Reset",
+        (1, 0x0c) => "Invalid Queue Deletion",
+        (1, 0x0d) => "Feature Identifier Not Saveable",
+        (1, 0x0e) => "Feature Not Changeable",
+        (1, 0x0f) => "Feature Not Namespace Specific",
+        (1, 0x10) => "Firmware Activation Requires NVM Subsystem Reset",
+        (1, 0x11) => "Firmware Activation Requires Controller Level Reset",
+        (1, 0x12) => "Firmware Activation Requires Maximum Time Violation",
+        (1, 0x13) => "Firmware Activation Prohibited",
+        (1, 0x14) => "Overlapping Range",
+        (1, 0x15) => "Namespace Insufficient Capacity",
+        (1, 0x16) => "Namespace Identifier Unavailable",
+        (1, 0x18) => "Namespace Already Attached",
+        (1, 0x19) => "Namespace Is Private",
+        (1, 0x1a) => "Namespace Not Attached",
+        (1, 0x1b) => "Thin Provisioning Not Supported",
+        (1, 0x1c) => "Controller List Invalid",
+        (1, 0x1d) => "Device Self-test In Progress",
+        (1, 0x1e) => "Boot Partition Write Prohibited",
+        (1, 0x1f) => "Invalid Controller Identifier",
+        (1, 0x20) => "Invalid Secondary Controller State",
+        (1, 0x21) => "Invalid Number of Controller Resources",
+        (1, 0x22) => "Invalid Resource Identifier",
+        (1, 0x23) => "Sanitize Prohibited While Persistent Memory Region is Enabled",
+        (1, 0x24) => "ANA Group Identifier Invalid",
+        (1, 0x25) => "ANA Attach Failed",
+        (1, 0x29) => "I/O Command Set Not Supported",
+        (1, 0x2a) => "I/O Command Set Not Enabled",
+        (1, 0x2b) => "I/O Command Set Combination Rejected",
+        (1, 0x2c) => "Invalid I/O Command Set",
+        (1, 0x7f) => "Stream Resource Allocation Failed",
+        (1, 0x80) => "Conflicting Attributes",
+        (1, 0x81) => "Invalid Protection Information",
+        (1, 0x82) => "Attempted Write to Read Only Range",
+        (1, 0x83) => "Command Size Limit Exceeded",
+        (1, 0xb8) => "Zoned Boundary Error",
+        (1, 0xb9) => "Zone Is Full",
+        (1, 0xba) => "Zone Is Read Only",
+        (1, 0xbb) => "Zone Is Offline",
+        (1, 0xbc) => "Zone Invalid Write",
+        (1, 0xbd) => "Too Many Active Zones",
+        (1, 0xbe) => "Too Many Open Zones",
+        (1, 0xbf) => "Invalid Zone State Transition",
+        (2, 0x80) => "Write Fault",
+        (2, 0x81) => "Unrecovered Read Error",
+        (2, 0x82) => "End-to-end Guard Check Error",
+        (2, 0x83) => "End-to-end Application Tag Check Error",
+        (2, 0x84) => "End-to-end Reference Tag Check Error",
+        (2, 0x85) => "Compare Failure",
+        (2, 0x86) => "Access Denied",
+        (2, 0x87) => "Deallocated or Unwritten Logical Block",
+        (3, 0x00) => "Internal Path Error",
+        (3, 0x01) => "Asymmetric Access Persistent Loss",
+        (3, 0x02) => "Asymmetric Access Inaccessible",
+        (3, 0x03) => "Asymmetric Access Transition",
+        (3, 0x60) => "Controller Pathing Error",
+        (3, 0x70) => "Host Pathing Error",
+        (3, 0x71) => "Command Aborted By Host",
+        (7, _) => "Vendor Specific",
+        _ => "Unknown Status",
+    }
+}
+
+impl NVME_COMMAND_STATUS {
+    pub fn is_success(&self) -> bool {
+        self.SCT() == (NVME_STATUS_TYPES::NVME_STATUS_TYPE_GENERIC_COMMAND as u8)
+            && self.SC()
+                == (NVME_STATUS_GENERIC_COMMAND_CODES::NVME_STATUS_SUCCESS_COMPLETION as u8)
+    }
+
+    pub fn check(&self, command: &str) -> io::Result<()> {
+        if self.is_success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("{} failed: {}", command, self)))
+        }
+    }
 }
 
+impl fmt::Display for NVME_COMMAND_STATUS {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (SCT {:#x} SC {:#x})",
+            nvme_status_name(self.SCT(), self.SC()),
+            self.SCT(),
+            self.SC()
+        )?;
+        if self.M() != 0 {
+            write!(f, ", More")?;
+        }
+        if self.DNR() != 0 {
+            write!(f, ", Do Not Retry")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
 impl InboxDriver {
     pub fn nvme_send_vsc2_passthrough_command(
         &self,
@@ -76,15 +302,17 @@ impl InboxDriver {
         let mut default_completion_dw0 = 0;
         let completion_dw0 = p_completion_dw0.unwrap_or(&mut default_completion_dw0);
 
-        let mut nc = NVME_COMMAND::default();
-        nc.opcode(NvmeVscOpcode::Write as u32)
-            .nsid(nsid)
-            .cdw10((p_param_buf.len() / size_of::<u32>()) as u32)
-            .cdw12(sub_opcode);
+        let (param, data) = nvme_vsc2_commands(
+            sub_opcode,
+            direction,
+            p_param_buf.len(),
+            p_data_buf.len(),
+            nsid,
+        );
 
         let result = self.nvme_send_passthrough_command(
             NvmeOpcodeType::WRITE as u8,
-            &nc,
+            &param,
             p_param_buf,
             completion_dw0,
         );
@@ -99,15 +327,9 @@ impl InboxDriver {
             return result;
         }
 
-        // Data phase
-        nc.opcode(NvmeVscOpcode::None as u32 | direction as u32)
-            .cdw10((p_data_buf.len() / size_of::<u32>()) as u32)
-            .cdw12(sub_opcode)
-            .cdw14(1);
-
         self.nvme_send_passthrough_command(
             NvmeOpcodeType::NOBUFFER as u8 | direction,
-            &nc,
+            &data,
             p_data_buf,
             completion_dw0,
         )
@@ -227,6 +449,1274 @@ impl InboxDriver {
     }
 }
 
+pub const NVME_APST_ENTRY_COUNT: usize = 32;
+
+pub type NvmeApstTable = [NVME_AUTO_POWER_STATE_TRANSITION_ENTRY; NVME_APST_ENTRY_COUNT];
+
+/// Parameters for building an APST table, defaults follow the Linux nvme driver
+/// (`ps_max_latency_us`, `apst_primary_timeout_ms`, ...).
+#[derive(Debug, Clone, Copy)]
+pub struct ApstPolicy {
+    pub max_latency_us: u64,
+    pub primary_timeout_ms: u64,
+    pub primary_latency_tol_us: u64,
+    pub secondary_timeout_ms: u64,
+    pub secondary_latency_tol_us: u64,
+}
+
+impl Default for ApstPolicy {
+    fn default() -> Self {
+        Self {
+            max_latency_us: 100000,
+            primary_timeout_ms: 100,
+            primary_latency_tol_us: 15000,
+            secondary_timeout_ms: 2000,
+            secondary_latency_tol_us: 100000,
+        }
+    }
+}
+
+impl ApstPolicy {
+    fn transition_time(&self, total_latency_us: u64, last_index: &mut u32) -> Option<u64> {
+        if total_latency_us <= self.primary_latency_tol_us {
+            if *last_index == 1 {
+                return None;
+            }
+            *last_index = 1;
+            return Some(self.primary_timeout_ms);
+        }
+        if self.secondary_timeout_ms != 0 && total_latency_us <= self.secondary_latency_tol_us {
+            if *last_index <= 2 {
+                return None;
+            }
+            *last_index = 2;
+            return Some(self.secondary_timeout_ms);
+        }
+        None
+    }
+}
+
+/// Build an APST table from the power state descriptors of the controller.
+/// Walking from the deepest state up, every non-operational state whose exit latency
+/// fits in the budget becomes the idle target of all the higher power states.
+pub fn nvme_apst_build_table(
+    ctrl: &NVME_IDENTIFY_CONTROLLER_DATA,
+    policy: &ApstPolicy,
+) -> NvmeApstTable {
+    let mut table = [NVME_AUTO_POWER_STATE_TRANSITION_ENTRY::default(); NVME_APST_ENTRY_COUNT];
+    let mut target: Option<NVME_AUTO_POWER_STATE_TRANSITION_ENTRY> = None;
+    let mut last_index = u32::MAX;
+    let npss = (ctrl.NPSS as usize).min(NVME_APST_ENTRY_COUNT - 1);
+
+    for state in (0..=npss).rev() {
+        if let Some(entry) = target {
+            table[state] = entry;
+        }
+        let psd = &ctrl.PDS[state];
+        if psd.NOPS() == 0 {
+            continue;
+        }
+        let exit_latency_us = psd.EXLAT() as u64;
+        if exit_latency_us > policy.max_latency_us {
+            continue;
+        }
+        let total_latency_us = exit_latency_us + psd.ENLAT() as u64;
+        let transition_ms = if policy.primary_timeout_ms != 0 && policy.primary_latency_tol_us != 0
+        {
+            match policy.transition_time(total_latency_us, &mut last_index) {
+                Some(ms) => ms,
+                None => continue,
+            }
+        } else {
+            total_latency_us.div_ceil(20).min((1 << 24) - 1)
+        };
+        target = Some(
+            NVME_AUTO_POWER_STATE_TRANSITION_ENTRY::new()
+                .with_IdleTransitionPowerState(state as u8)
+                .with_IdleTimePriorToTransition(transition_ms as u32),
+        );
+    }
+    table
+}
+
+pub fn nvme_apst_supported(ctrl: &NVME_IDENTIFY_CONTROLLER_DATA) -> bool {
+    ctrl.APSTA.Supported() != 0
+}
+
+/// Point power state `ps` at the non-operational state `itps` after `itpt_ms`
+/// of idle time, checking both states against NPSS and ITPT against its 24 bits
+pub fn nvme_apst_set_entry(
+    table: &mut NvmeApstTable,
+    ctrl: &NVME_IDENTIFY_CONTROLLER_DATA,
+    ps: usize,
+    itps: u8,
+    itpt_ms: u32,
+) -> io::Result<()> {
+    let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    let npss = ctrl.NPSS as usize;
+    if ps > npss || ps >= NVME_APST_ENTRY_COUNT {
+        return invalid(format!("power state {} out of range, NPSS is {}", ps, npss));
+    }
+    if itps as usize > npss || itps as usize >= NVME_APST_ENTRY_COUNT {
+        return invalid(format!(
+            "idle transition power state {} out of range, NPSS is {}",
+            itps, npss
+        ));
+    }
+    if itpt_ms != 0 && ctrl.PDS[itps as usize].NOPS() == 0 {
+        return invalid(format!(
+            "idle transition power state {} is an operational state",
+            itps
+        ));
+    }
+    if itpt_ms >= 1 << 24 {
+        return invalid(format!(
+            "idle time {} ms does not fit the 24-bit ITPT field",
+            itpt_ms
+        ));
+    }
+    table[ps] = table[ps]
+        .with_IdleTransitionPowerState(itps)
+        .with_IdleTimePriorToTransition(itpt_ms);
+    Ok(())
+}
+
+pub fn nvme_apst_table_from_bytes(data: &[u8]) -> NvmeApstTable {
+    let mut table = [NVME_AUTO_POWER_STATE_TRANSITION_ENTRY::default(); NVME_APST_ENTRY_COUNT];
+    for (entry, chunk) in table.iter_mut().zip(data.chunks_exact(8)) {
+        *entry = u64::from_le_bytes(chunk.try_into().unwrap()).into();
+    }
+    table
+}
+
+pub fn nvme_apst_table_to_bytes(table: &NvmeApstTable) -> Vec<u8> {
+    table
+        .iter()
+        .flat_map(|&entry| u64::from(entry).to_le_bytes())
+        .collect()
+}
+
+#[cfg(windows)]
+impl InboxDriver {
+    pub fn nvme_get_apst(&self, sel: u32) -> io::Result<(bool, NvmeApstTable)> {
+        let fid = NVME_FEATURES::NVME_FEATURE_AUTONOMOUS_POWER_STATE_TRANSITION as u32;
+        let cdw10 = NVME_CDW10_GET_FEATURES::new()
+            .with_FID(fid as u8)
+            .with_SEL(sel as u8);
+        let (dw0, data) = self.nvme_getfeature_data_query(
+            cdw10.into(),
+            0,
+            NVME_APST_ENTRY_COUNT * size_of::<u64>(),
+        )?;
+        let apste = NVME_CDW11_FEATURE_AUTO_POWER_STATE_TRANSITION::from(dw0).APSTE() != 0;
+        Ok((apste, nvme_apst_table_from_bytes(&data)))
+    }
+
+    pub fn nvme_set_apst(&self, enable: bool, table: &NvmeApstTable) -> io::Result<u32> {
+        let fid = NVME_FEATURES::NVME_FEATURE_AUTONOMOUS_POWER_STATE_TRANSITION as u32;
+        let cdw10 = NVME_CDW10_SET_FEATURES::new()
+            .with_FID(fid as u8)
+            .with_SV(0);
+        let cdw11 = NVME_CDW11_FEATURE_AUTO_POWER_STATE_TRANSITION::new().with_APSTE(enable as u8);
+        self.nvme_set_features_data(cdw10.into(), cdw11.into(), &nvme_apst_table_to_bytes(table))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NvmeRegistrant {
+    pub cntlid: u16,
+    pub holds_reservation: bool,
+    pub host_id: Vec<u8>, // 64-bit, or 128-bit with the extended data structure
+    pub rkey: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NvmeReservationStatus {
+    pub generation: u32,
+    pub rtype: u8,
+    pub ptpls: u8,
+    pub extended: bool,
+    pub registrants: Vec<NvmeRegistrant>,
+}
+
+impl NvmeReservationStatus {
+    pub fn holder(&self) -> Option<&NvmeRegistrant> {
+        self.registrants.iter().find(|reg| reg.holds_reservation)
+    }
+}
+
+/// Decode a Reservation Report data structure, `eds` selects the extended format
+pub fn nvme_resv_report_parse(data: &[u8], eds: bool) -> NvmeReservationStatus {
+    let header_size = size_of::<NVME_RESERVATION_REPORT_STATUS_HEADER>();
+    if data.len() < header_size {
+        return NvmeReservationStatus::default();
+    }
+    let header = unsafe {
+        std::ptr::read_unaligned(data.as_ptr() as *const NVME_RESERVATION_REPORT_STATUS_HEADER)
+    };
+    let mut status = NvmeReservationStatus {
+        generation: header.GEN,
+        rtype: header.RTYPE,
+        ptpls: header.PTPLS,
+        extended: eds,
+        registrants: vec![],
+    };
+
+    let (offset, entry_size) = if eds {
+        (
+            size_of::<NVME_RESERVATION_REPORT_STATUS_EXTENDED_DATA_STRUCTURE>(),
+            size_of::<NVME_REGISTERED_CONTROLLER_EXTENDED_DATA>(),
+        )
+    } else {
+        (header_size, size_of::<NVME_REGISTERED_CONTROLLER_DATA>())
+    };
+    let regctl = header.REGCTL as usize;
+    for chunk in data[offset.min(data.len())..]
+        .chunks_exact(entry_size)
+        .take(regctl)
+    {
+        let registrant = if eds {
+            let entry = unsafe {
+                std::ptr::read_unaligned(
+                    chunk.as_ptr() as *const NVME_REGISTERED_CONTROLLER_EXTENDED_DATA
+                )
+            };
+            NvmeRegistrant {
+                cntlid: entry.CNTLID,
+                holds_reservation: entry.RCSTS.HoldReservation() != 0,
+                host_id: entry.HOSTID.to_vec(),
+                rkey: entry.RKEY,
+            }
+        } else {
+            let entry = unsafe {
+                std::ptr::read_unaligned(chunk.as_ptr() as *const NVME_REGISTERED_CONTROLLER_DATA)
+            };
+            NvmeRegistrant {
+                cntlid: entry.CNTLID,
+                holds_reservation: entry.RCSTS.HoldReservation() != 0,
+                host_id: entry.HOSTID.to_vec(),
+                rkey: entry.RKEY,
+            }
+        };
+        status.registrants.push(registrant);
+    }
+    status
+}
+
+#[cfg(windows)]
+impl InboxDriver {
+    pub fn nvme_resv_register(
+        &self,
+        nsid: u32,
+        rrega: u8,
+        cptpl: u8,
+        iekey: bool,
+        crkey: u64,
+        nrkey: u64,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        let cdw10 = NVME_CDW10_RESERVATION_REGISTER::new()
+            .with_RREGA(rrega)
+            .with_IEKEY(iekey as u8)
+            .with_CPTPL(cptpl);
+        let mut data = [crkey.to_le_bytes(), nrkey.to_le_bytes()].concat();
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMANDS::NVME_NVM_COMMAND_RESERVATION_REGISTER as u32)
+            .nsid(nsid)
+            .cdw10(cdw10.into());
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::WRITE as u8, &nc, &mut data, &mut 0)
+    }
+
+    pub fn nvme_resv_acquire(
+        &self,
+        nsid: u32,
+        racqa: u8,
+        rtype: u8,
+        iekey: bool,
+        crkey: u64,
+        prkey: u64,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        let cdw10 = NVME_CDW10_RESERVATION_ACQUIRE::new()
+            .with_RACQA(racqa)
+            .with_IEKEY(iekey as u8)
+            .with_RTYPE(rtype);
+        let mut data = [crkey.to_le_bytes(), prkey.to_le_bytes()].concat();
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMANDS::NVME_NVM_COMMAND_RESERVATION_ACQUIRE as u32)
+            .nsid(nsid)
+            .cdw10(cdw10.into());
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::WRITE as u8, &nc, &mut data, &mut 0)
+    }
+
+    pub fn nvme_resv_release(
+        &self,
+        nsid: u32,
+        rrela: u8,
+        rtype: u8,
+        iekey: bool,
+        crkey: u64,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        let cdw10 = NVME_CDW10_RESERVATION_RELEASE::new()
+            .with_RRELA(rrela)
+            .with_IEKEY(iekey as u8)
+            .with_RTYPE(rtype);
+        let mut data = crkey.to_le_bytes();
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMANDS::NVME_NVM_COMMAND_RESERVATION_RELEASE as u32)
+            .nsid(nsid)
+            .cdw10(cdw10.into());
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::WRITE as u8, &nc, &mut data, &mut 0)
+    }
+
+    pub fn nvme_resv_report(&self, nsid: u32, eds: bool) -> io::Result<NvmeReservationStatus> {
+        let mut buffer = vec![0u8; NVME_DATA_BUFFER_SIZE];
+        let cdw11 = NVME_CDW11_RESERVATION_REPORT::new().with_EDS(eds as u8);
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMANDS::NVME_NVM_COMMAND_RESERVATION_REPORT as u32)
+            .nsid(nsid)
+            .cdw10((buffer.len() / size_of::<u32>()) as u32 - 1)
+            .cdw11(cdw11.into());
+        self.nvme_send_io_passthrough_command(
+            NvmeOpcodeType::READ as u8,
+            &nc,
+            &mut buffer,
+            &mut 0,
+        )?
+        .check("Reservation Report")?;
+        Ok(nvme_resv_report_parse(&buffer, eds))
+    }
+}
+
+#[cfg(windows)]
+impl InboxDriver {
+    pub fn nvme_dir_receive(
+        &self,
+        nsid: u32,
+        dtype: u8,
+        doper: u8,
+        dspec: u16,
+        cdw12: u32,
+        data: &mut [u8],
+    ) -> io::Result<u32> {
+        let cdw11 = NVME_CDW11_DIRECTIVE_RECEIVE::new()
+            .with_DOPER(doper)
+            .with_DTYPE(dtype)
+            .with_DSPEC(dspec);
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_ADMIN_COMMANDS::NVME_ADMIN_COMMAND_DIRECTIVE_RECEIVE as u32)
+            .nsid(nsid)
+            .cdw10((data.len() / size_of::<u32>()).saturating_sub(1) as u32)
+            .cdw11(cdw11.into())
+            .cdw12(cdw12);
+        let direction = if data.is_empty() {
+            NvmeOpcodeType::NOBUFFER
+        } else {
+            NvmeOpcodeType::READ
+        };
+        let mut dw0 = 0;
+        self.nvme_send_passthrough_command(direction as u8, &nc, data, &mut dw0)?
+            .check("Directive Receive")?;
+        Ok(dw0)
+    }
+
+    pub fn nvme_dir_send(
+        &self,
+        nsid: u32,
+        dtype: u8,
+        doper: u8,
+        dspec: u16,
+        cdw12: u32,
+        data: &mut [u8],
+    ) -> io::Result<u32> {
+        let cdw11 = NVME_CDW11_DIRECTIVE_SEND::new()
+            .with_DOPER(doper)
+            .with_DTYPE(dtype)
+            .with_DSPEC(dspec);
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_ADMIN_COMMANDS::NVME_ADMIN_COMMAND_DIRECTIVE_SEND as u32)
+            .nsid(nsid)
+            .cdw10((data.len() / size_of::<u32>()).saturating_sub(1) as u32)
+            .cdw11(cdw11.into())
+            .cdw12(cdw12);
+        let direction = if data.is_empty() {
+            NvmeOpcodeType::NOBUFFER
+        } else {
+            NvmeOpcodeType::WRITE
+        };
+        let mut dw0 = 0;
+        self.nvme_send_passthrough_command(direction as u8, &nc, data, &mut dw0)?
+            .check("Directive Send")?;
+        Ok(dw0)
+    }
+
+    /// Identify directive, Return Parameters: supported and enabled directive types
+    pub fn nvme_dir_identify(
+        &self,
+        nsid: u32,
+    ) -> io::Result<NVME_DIRECTIVE_IDENTIFY_RETURN_PARAMETERS> {
+        let mut buffer = vec![0u8; NVME_DATA_BUFFER_SIZE];
+        self.nvme_dir_receive(
+            nsid,
+            NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_IDENTIFY as u8,
+            NVME_DIRECTIVE_RECEIVE_IDENTIFY_OPERATIONS::NVME_DIRECTIVE_RECEIVE_IDENTIFY_OPERATION_RETURN_PARAMETERS as u8,
+            0,
+            0,
+            &mut buffer,
+        )?;
+        Ok(unsafe {
+            std::ptr::read_unaligned(
+                buffer.as_ptr() as *const NVME_DIRECTIVE_IDENTIFY_RETURN_PARAMETERS
+            )
+        })
+    }
+
+    /// Identify directive, Enable Directive: enable or disable `dtype` (e.g. streams)
+    pub fn nvme_dir_enable(&self, nsid: u32, dtype: u8, enable: bool) -> io::Result<u32> {
+        let cdw12 = NVME_CDW12_DIRECTIVE_SEND_IDENTIFY_ENABLE_DIRECTIVE::new()
+            .with_ENDIR(enable as u8)
+            .with_DTYPE(dtype);
+        self.nvme_dir_send(
+            nsid,
+            NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_IDENTIFY as u8,
+            NVME_DIRECTIVE_SEND_IDENTIFY_OPERATIONS::NVME_DIRECTIVE_SEND_IDENTIFY_OPERATION_ENABLE_DIRECTIVE as u8,
+            0,
+            cdw12.into(),
+            &mut [],
+        )
+    }
+
+    pub fn nvme_streams_params(
+        &self,
+        nsid: u32,
+    ) -> io::Result<NVME_DIRECTIVE_STREAMS_RETURN_PARAMETERS> {
+        let mut buffer = vec![0u8; size_of::<NVME_DIRECTIVE_STREAMS_RETURN_PARAMETERS>()];
+        self.nvme_dir_receive(
+            nsid,
+            NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_STREAMS as u8,
+            NVME_DIRECTIVE_RECEIVE_STREAMS_OPERATIONS::NVME_DIRECTIVE_RECEIVE_STREAMS_OPERATION_RETURN_PARAMETERS as u8,
+            0,
+            0,
+            &mut buffer,
+        )?;
+        Ok(unsafe {
+            std::ptr::read_unaligned(
+                buffer.as_ptr() as *const NVME_DIRECTIVE_STREAMS_RETURN_PARAMETERS
+            )
+        })
+    }
+
+    /// Streams directive, Get Status: identifiers of the currently open streams
+    pub fn nvme_streams_status(&self, nsid: u32) -> io::Result<Vec<u16>> {
+        let mut buffer = vec![0u8; size_of::<u16>() * (u16::MAX as usize + 1)];
+        self.nvme_dir_receive(
+            nsid,
+            NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_STREAMS as u8,
+            NVME_DIRECTIVE_RECEIVE_STREAMS_OPERATIONS::NVME_DIRECTIVE_RECEIVE_STREAMS_OPERATION_GET_STATUS as u8,
+            0,
+            0,
+            &mut buffer,
+        )?;
+        let ids: Vec<u16> = buffer
+            .chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+            .collect();
+        let count = ids[0] as usize;
+        Ok(ids[1..=count].to_vec())
+    }
+
+    /// Streams directive, Allocate Resources: returns the number of streams allocated
+    pub fn nvme_streams_allocate(&self, nsid: u32, nsr: u16) -> io::Result<u16> {
+        let dw0 = self.nvme_dir_receive(
+            nsid,
+            NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_STREAMS as u8,
+            NVME_DIRECTIVE_RECEIVE_STREAMS_OPERATIONS::NVME_DIRECTIVE_RECEIVE_STREAMS_OPERATION_ALLOCATE_RESOURCES as u8,
+            0,
+            nsr as u32,
+            &mut [],
+        )?;
+        Ok(dw0 as u16)
+    }
+
+    pub fn nvme_streams_release_id(&self, nsid: u32, stream_id: u16) -> io::Result<u32> {
+        self.nvme_dir_send(
+            nsid,
+            NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_STREAMS as u8,
+            NVME_DIRECTIVE_SEND_STREAMS_OPERATIONS::NVME_DIRECTIVE_SEND_STREAMS_OPERATION_RELEASE_IDENTIFIER as u8,
+            stream_id,
+            0,
+            &mut [],
+        )
+    }
+
+    pub fn nvme_streams_release_resources(&self, nsid: u32) -> io::Result<u32> {
+        self.nvme_dir_send(
+            nsid,
+            NVME_DIRECTIVE_TYPES::NVME_DIRECTIVE_TYPE_STREAMS as u8,
+            NVME_DIRECTIVE_SEND_STREAMS_OPERATIONS::NVME_DIRECTIVE_SEND_STREAMS_OPERATION_RELEASE_RESOURCES as u8,
+            0,
+            0,
+            &mut [],
+        )
+    }
+}
+
+/// Security protocol 00h, SPSP 0000h: list of the supported security protocols
+pub fn nvme_security_protocol_list(data: &[u8]) -> Vec<u8> {
+    if data.len() < 8 {
+        return vec![];
+    }
+    let length = u16::from_be_bytes([data[6], data[7]]) as usize;
+    data[8..].iter().take(length).copied().collect()
+}
+
+/// Security protocol 00h, SPSP 0001h: certificate data
+pub fn nvme_security_certificate(data: &[u8]) -> Vec<u8> {
+    if data.len() < 4 {
+        return vec![];
+    }
+    let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+    data[4..].iter().take(length).copied().collect()
+}
+
+#[cfg(windows)]
+impl InboxDriver {
+    pub fn nvme_security_send(
+        &self,
+        nsid: u32,
+        secp: u8,
+        spsp: u16,
+        nssf: u8,
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_ADMIN_COMMANDS::NVME_ADMIN_COMMAND_SECURITY_SEND as u32)
+            .nsid(nsid);
+        nc.u.SECURITYSEND = NVME_COMMAND_SECURITYSEND {
+            CDW10: NVME_CDW10_SECURITY_SEND_RECEIVE::new()
+                .with_NSSF(nssf)
+                .with_SPSP(spsp)
+                .with_SECP(secp),
+            CDW11: NVME_CDW11_SECURITY_SEND {
+                TL: data.len() as u32,
+            },
+            ..Default::default()
+        };
+        self.nvme_send_passthrough_command(NvmeOpcodeType::WRITE as u8, &nc, data, &mut 0)?
+            .check("Security Send")
+    }
+
+    pub fn nvme_security_recv(
+        &self,
+        nsid: u32,
+        secp: u8,
+        spsp: u16,
+        nssf: u8,
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_ADMIN_COMMANDS::NVME_ADMIN_COMMAND_SECURITY_RECEIVE as u32)
+            .nsid(nsid);
+        nc.u.SECURITYRECEIVE = NVME_COMMAND_SECURITYRECEIVE {
+            CDW10: NVME_CDW10_SECURITY_SEND_RECEIVE::new()
+                .with_NSSF(nssf)
+                .with_SPSP(spsp)
+                .with_SECP(secp),
+            CDW11: NVME_CDW11_SECURITY_RECEIVE {
+                AL: data.len() as u32,
+            },
+            ..Default::default()
+        };
+        self.nvme_send_passthrough_command(NvmeOpcodeType::READ as u8, &nc, data, &mut 0)?
+            .check("Security Receive")
+    }
+
+    pub fn nvme_security_protocols(&self) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; NVME_DATA_BUFFER_SIZE];
+        self.nvme_security_recv(0, 0x00, 0x0000, 0, &mut buffer)?;
+        Ok(nvme_security_protocol_list(&buffer))
+    }
+
+    pub fn nvme_security_certificates(&self) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; NVME_DATA_BUFFER_SIZE];
+        self.nvme_security_recv(0, 0x00, 0x0001, 0, &mut buffer)?;
+        Ok(nvme_security_certificate(&buffer))
+    }
+
+    pub fn nvme_tcg_discovery0(&self) -> io::Result<TcgLevel0Discovery> {
+        let mut buffer = vec![0u8; NVME_DATA_BUFFER_SIZE];
+        self.nvme_security_recv(0, TCG_LEVEL0_PROTOCOL, TCG_LEVEL0_COMID, 0, &mut buffer)?;
+        TcgLevel0Discovery::parse(&buffer)
+    }
+}
+
+pub const NVME_ZONE_DESCRIPTOR_SIZE: usize = 64;
+pub const NVME_ZONE_REPORT_HEADER_SIZE: usize = 64;
+pub const NVME_ZONE_EXTENSION_UNIT: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+pub struct NvmeZone {
+    pub desc: NVME_ZONE_DESCRIPTOR,
+    pub extension: Vec<u8>, // only with the extended report and ZDEV set
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NvmeZoneReport {
+    pub zone_count: u64,
+    pub zones: Vec<NvmeZone>,
+}
+
+/// Decode a (Extended) Report Zones data structure, `ext_size` is 0 for the plain report
+pub fn nvme_zone_report_parse(data: &[u8], ext_size: usize) -> NvmeZoneReport {
+    if data.len() < NVME_ZONE_REPORT_HEADER_SIZE {
+        return NvmeZoneReport::default();
+    }
+    let zone_count = u64::from_le_bytes(data[0..8].try_into().unwrap());
+    let entry_size = NVME_ZONE_DESCRIPTOR_SIZE + ext_size;
+    let zones = data[NVME_ZONE_REPORT_HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .take(zone_count as usize)
+        .map(|chunk| {
+            let desc =
+                unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const NVME_ZONE_DESCRIPTOR) };
+            let extension = if ext_size > 0 && desc.ZA.ZDEV() != 0 {
+                chunk[NVME_ZONE_DESCRIPTOR_SIZE..].to_vec()
+            } else {
+                vec![]
+            };
+            NvmeZone { desc, extension }
+        })
+        .collect();
+    NvmeZoneReport { zone_count, zones }
+}
+
+/// Data of a Set Zone Descriptor Extension, padded with zeros to the
+/// extension size `size` of the namespace
+pub fn nvme_zone_extension_data(mut data: Vec<u8>, size: usize) -> io::Result<Vec<u8>> {
+    if size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the namespace has no zone descriptor extension (ZDES is 0)",
+        ));
+    }
+    if data.len() > size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} bytes of data do not fit the {} byte zone descriptor extension",
+                data.len(),
+                size
+            ),
+        ));
+    }
+    data.resize(size, 0);
+    Ok(data)
+}
+
+#[cfg(windows)]
+impl InboxDriver {
+    /// Identify with a Command Set Identifier (CNS 05h/06h)
+    pub fn nvme_identify_csi(&self, cns: u8, nsid: u32, csi: u8) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; NVME_DATA_BUFFER_SIZE];
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_ADMIN_COMMANDS::NVME_ADMIN_COMMAND_IDENTIFY as u32)
+            .nsid(nsid)
+            .cdw10(cns as u32)
+            .cdw11((csi as u32) << 24);
+        self.nvme_send_passthrough_command(NvmeOpcodeType::READ as u8, &nc, &mut buffer, &mut 0)?
+            .check("Identify")?;
+        Ok(buffer)
+    }
+
+    pub fn nvme_zns_identify_ns(
+        &self,
+        nsid: u32,
+    ) -> io::Result<NVME_IDENTIFY_SPECIFIC_NAMESPACE_IO_COMMAND_SET> {
+        let data = self.nvme_identify_csi(
+            NVME_IDENTIFY_CNS_SPECIFIC_NAMESPACE_IO_COMMAND_SET as u8,
+            nsid,
+            NVME_COMMAND_SET_IDENTIFIERS::NVME_COMMAND_SET_ZONED_NAMESPACE as u8,
+        )?;
+        Ok(unsafe {
+            std::ptr::read_unaligned(
+                data.as_ptr() as *const NVME_IDENTIFY_SPECIFIC_NAMESPACE_IO_COMMAND_SET
+            )
+        })
+    }
+
+    pub fn nvme_zns_identify_ctrl(
+        &self,
+    ) -> io::Result<NVME_IDENTIFY_ZNS_SPECIFIC_CONTROLLER_IO_COMMAND_SET> {
+        let data = self.nvme_identify_csi(
+            NVME_IDENTIFY_CNS_SPECIFIC_CONTROLLER_IO_COMMAND_SET as u8,
+            0,
+            NVME_COMMAND_SET_IDENTIFIERS::NVME_COMMAND_SET_ZONED_NAMESPACE as u8,
+        )?;
+        Ok(unsafe {
+            std::ptr::read_unaligned(
+                data.as_ptr() as *const NVME_IDENTIFY_ZNS_SPECIFIC_CONTROLLER_IO_COMMAND_SET
+            )
+        })
+    }
+
+    /// Zone Descriptor Extension size in bytes for the current LBA format
+    pub fn nvme_zone_extension_size(&self, nsid: u32) -> io::Result<usize> {
+        let ns = self.nvme_identify_namespace(nsid)?;
+        let zns = self.nvme_zns_identify_ns(nsid)?;
+        let format = ns.FLBAS.LbaFormatIndex() as usize;
+        Ok(zns.LBAEF[format].ZDES as usize * NVME_ZONE_EXTENSION_UNIT)
+    }
+
+    pub fn nvme_zone_mgmt_recv(
+        &self,
+        nsid: u32,
+        slba: u64,
+        zra: u8,
+        zras: u8,
+        partial: bool,
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        let cdw13 = NVME_CDW13_ZONE_MANAGEMENT_RECEIVE::new()
+            .with_ZRA(zra)
+            .with_ZRASpecific(zras)
+            .with_Partial(partial as u8);
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMANDS::NVME_NVM_COMMAND_ZONE_MANAGEMENT_RECEIVE as u32)
+            .nsid(nsid)
+            .cdw10(slba as u32)
+            .cdw11((slba >> 32) as u32)
+            .cdw12((data.len() / size_of::<u32>()) as u32 - 1)
+            .cdw13(cdw13.into());
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::READ as u8, &nc, data, &mut 0)?
+            .check("Zone Management Receive")
+    }
+
+    /// Report up to `max_zones` zones from `slba`, filtered by `zras`
+    /// (NVME_ZONE_RECEIVE_ACTION_SPECIFIC). With `partial` the zone count only
+    /// covers the returned descriptors.
+    pub fn nvme_zone_report(
+        &self,
+        nsid: u32,
+        slba: u64,
+        zras: u8,
+        partial: bool,
+        extended: bool,
+        max_zones: usize,
+    ) -> io::Result<NvmeZoneReport> {
+        let (zra, ext_size) = if extended {
+            (
+                NVME_ZONE_RECEIVE_ACTION::NVME_ZONE_RECEIVE_EXTENDED_REPORT_ZONES as u8,
+                self.nvme_zone_extension_size(nsid)?,
+            )
+        } else {
+            (
+                NVME_ZONE_RECEIVE_ACTION::NVME_ZONE_RECEIVE_REPORT_ZONES as u8,
+                0,
+            )
+        };
+        let size =
+            NVME_ZONE_REPORT_HEADER_SIZE + max_zones * (NVME_ZONE_DESCRIPTOR_SIZE + ext_size);
+        let mut buffer = vec![0u8; size];
+        self.nvme_zone_mgmt_recv(nsid, slba, zra, zras, partial, &mut buffer)?;
+        let mut report = nvme_zone_report_parse(&buffer, ext_size);
+        report.zones.truncate(max_zones);
+        Ok(report)
+    }
+
+    /// Zone Management Send, `data` carries the extension for Set Zone Descriptor Extension
+    pub fn nvme_zone_mgmt_send(
+        &self,
+        nsid: u32,
+        slba: u64,
+        zsa: u8,
+        select_all: bool,
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        let cdw13 = NVME_CDW13_ZONE_MANAGEMENT_SEND::new()
+            .with_ZSA(zsa)
+            .with_SelectAll(select_all as u8);
+        let direction = if data.is_empty() {
+            NvmeOpcodeType::NOBUFFER
+        } else {
+            NvmeOpcodeType::WRITE
+        };
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMANDS::NVME_NVM_COMMAND_ZONE_MANAGEMENT_SEND as u32)
+            .nsid(nsid)
+            .cdw10(slba as u32)
+            .cdw11((slba >> 32) as u32)
+            .cdw13(cdw13.into());
+        self.nvme_send_io_passthrough_command(direction as u8, &nc, data, &mut 0)?
+            .check("Zone Management Send")
+    }
+
+    /// Append `data` (a whole number of logical blocks) to the zone at `zslba` and
+    /// return the LBA the controller wrote it to.
+    pub fn nvme_zone_append(
+        &self,
+        nsid: u32,
+        zslba: u64,
+        lba_shift: u32,
+        fua: bool,
+        data: &mut [u8],
+    ) -> io::Result<u64> {
+        let nlb = data.len() >> lba_shift;
+        if nlb == 0 || nlb << lba_shift != data.len() || nlb > NVME_IO_MAX_BLOCKS as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid append length {}", data.len()),
+            ));
+        }
+        let cdw12 = NVME_CDW12_ZONE_APPEND::new()
+            .with_NLB((nlb - 1) as u16)
+            .with_FUA(fua as u8);
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMANDS::NVME_NVM_COMMAND_ZONE_APPEND as u32)
+            .nsid(nsid)
+            .cdw10(zslba as u32)
+            .cdw11((zslba >> 32) as u32)
+            .cdw12(cdw12.into());
+        let mut dw0 = 0;
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::WRITE as u8, &nc, data, &mut dw0)?
+            .check("Zone Append")?;
+        // only DW0 of the completion is returned, the upper half of the assigned LBA
+        // is the one of the zone start as long as zones do not span a 2^32 boundary
+        Ok((zslba & !0xFFFF_FFFF) | dw0 as u64)
+    }
+}
+
+pub const NVME_DSM_MAX_RANGES: usize = 256;
+pub const NVME_DSM_RANGE_SIZE: usize = 16;
+
+/// What DSM sent: commands issued, ranges and logical blocks covered
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeDsmSummary {
+    pub commands: usize,
+    pub ranges: usize,
+    pub blocks: u64,
+}
+
+/// Split `(slba, nlb)` extents into ranges of at most `max_range_blocks` blocks,
+/// grouped into commands of at most `max_ranges` ranges
+pub fn nvme_dsm_split_ranges(
+    extents: &[(u64, u64)],
+    max_range_blocks: u64,
+    max_ranges: usize,
+) -> Vec<Vec<(u64, u32)>> {
+    let max_range_blocks = max_range_blocks.clamp(1, u32::MAX as u64);
+    let max_ranges = max_ranges.clamp(1, NVME_DSM_MAX_RANGES);
+    let mut ranges = vec![];
+    for &(slba, nlb) in extents {
+        let mut offset = 0;
+        while offset < nlb {
+            let count = (nlb - offset).min(max_range_blocks);
+            ranges.push((slba + offset, count as u32));
+            offset += count;
+        }
+    }
+    ranges.chunks(max_ranges).map(|c| c.to_vec()).collect()
+}
+
+/// Build the DSM range list (context attributes, length in blocks, starting LBA)
+pub fn nvme_dsm_range_bytes(ranges: &[(u64, u32)]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(ranges.len() * NVME_DSM_RANGE_SIZE);
+    for &(slba, nlb) in ranges {
+        data.extend_from_slice(&u32::from(NVME_CONTEXT_ATTRIBUTES::new()).to_le_bytes());
+        data.extend_from_slice(&nlb.to_le_bytes());
+        data.extend_from_slice(&slba.to_le_bytes());
+    }
+    data
+}
+
+#[cfg(windows)]
+impl InboxDriver {
+    /// One Dataset Management command with up to 256 ranges
+    pub fn nvme_dsm(
+        &self,
+        nsid: u32,
+        attributes: NVME_CDW11_DATASET_MANAGEMENT,
+        ranges: &[(u64, u32)],
+    ) -> io::Result<()> {
+        if ranges.is_empty() || ranges.len() > NVME_DSM_MAX_RANGES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("DSM takes 1 to {} ranges", NVME_DSM_MAX_RANGES),
+            ));
+        }
+        let cdw10 = NVME_CDW10_DATASET_MANAGEMENT::new().with_NR((ranges.len() - 1) as u8);
+        let mut data = nvme_dsm_range_bytes(ranges);
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMANDS::NVME_NVM_COMMAND_DATASET_MANAGEMENT as u32)
+            .nsid(nsid)
+            .cdw10(cdw10.into())
+            .cdw11(attributes.into());
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::WRITE as u8, &nc, &mut data, &mut 0)?
+            .check("Dataset Management")
+    }
+
+    /// DMRL/DMRSL from the NVM command set Identify Controller, 0 when not reported
+    pub fn nvme_dsm_limits(&self) -> (usize, u64) {
+        match self.nvme_identify_csi(
+            NVME_IDENTIFY_CNS_SPECIFIC_CONTROLLER_IO_COMMAND_SET as u8,
+            0,
+            NVME_COMMAND_SET_IDENTIFIERS::NVME_COMMAND_SET_NVM as u8,
+        ) {
+            Ok(data) => {
+                let nvm = unsafe {
+                    std::ptr::read_unaligned(data.as_ptr()
+                        as *const NVME_IDENTIFY_NVM_SPECIFIC_CONTROLLER_IO_COMMAND_SET)
+                };
+                (nvm.DMRL as usize, nvm.DMRSL as u64)
+            }
+            Err(_) => (0, 0),
+        }
+    }
+
+    /// Send `(slba, nlb)` extents of any size, split to the controller limits
+    pub fn nvme_dsm_extents(
+        &self,
+        nsid: u32,
+        attributes: NVME_CDW11_DATASET_MANAGEMENT,
+        extents: &[(u64, u64)],
+    ) -> io::Result<NvmeDsmSummary> {
+        let (dmrl, dmrsl) = self.nvme_dsm_limits();
+        let max_ranges = if dmrl == 0 { NVME_DSM_MAX_RANGES } else { dmrl };
+        let max_blocks = if dmrsl == 0 { u32::MAX as u64 } else { dmrsl };
+        let mut summary = NvmeDsmSummary::default();
+        for ranges in nvme_dsm_split_ranges(extents, max_blocks, max_ranges) {
+            self.nvme_dsm(nsid, attributes, &ranges)?;
+            summary.commands += 1;
+            summary.ranges += ranges.len();
+            summary.blocks += ranges.iter().map(|&(_, nlb)| nlb as u64).sum::<u64>();
+        }
+        Ok(summary)
+    }
+
+    /// Deallocate every block of the namespace
+    pub fn nvme_deallocate_namespace(&self, nsid: u32) -> io::Result<NvmeDsmSummary> {
+        let ns = self.nvme_identify_namespace(nsid)?;
+        let attributes = NVME_CDW11_DATASET_MANAGEMENT::new().with_AD(1);
+        self.nvme_dsm_extents(nsid, attributes, &[(0, ns.NSZE)])
+    }
+
+    /// Read back the block at `lba` and compare it with the DLFEAT read behavior.
+    /// Returns None when the namespace does not report what deallocated blocks read as.
+    pub fn nvme_dsm_check_deallocated(&self, nsid: u32, lba: u64) -> io::Result<Option<bool>> {
+        let ns = self.nvme_identify_namespace(nsid)?;
+        let pattern = match ns.DLFEAT.ReadBehavior() {
+            0x1 => 0x00u8,
+            0x2 => 0xFFu8,
+            _ => return Ok(None),
+        };
+        let format = self.nvme_ns_format(nsid)?;
+        let mut buffer = vec![0x5Au8; format.block_size()];
+        self.nvme_read(
+            nsid,
+            lba,
+            &format,
+            &NvmeIoOptions::default(),
+            &mut buffer,
+            &mut [],
+        )?;
+        Ok(Some(buffer.iter().all(|&b| b == pattern)))
+    }
+}
+
+/// Largest NLB of one I/O command, the field is 16 bits and zero-based
+pub const NVME_IO_MAX_BLOCKS: u32 = 0x10000;
+
+/// Zero-based NLB field for `nlb` blocks
+pub fn nvme_io_nlb(nlb: u32) -> io::Result<u16> {
+    if nlb == 0 || nlb > NVME_IO_MAX_BLOCKS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("block count {} is not 1 to {}", nlb, NVME_IO_MAX_BLOCKS),
+        ));
+    }
+    Ok((nlb - 1) as u16)
+}
+
+/// Options shared by the NVM I/O commands
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeIoOptions {
+    pub fua: bool,
+    pub lr: bool,
+    pub prinfo: u8,
+    pub dsm: NVME_CDW13_READ_WRITE_DSM, // access frequency/latency hints
+    pub dtype: u8,                      // directive type, e.g. streams
+    pub dspec: u16,
+    pub reftag: u32, // initial logical block reference tag
+    pub apptag: u16,
+    pub appmask: u16,
+}
+
+/// Logical block format of a namespace as seen by the I/O commands
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeNsFormat {
+    pub lba_shift: u32,
+    pub ms: u16,        // metadata bytes per block
+    pub extended: bool, // metadata transferred at the end of each block
+}
+
+impl NvmeNsFormat {
+    pub fn block_size(&self) -> usize {
+        1 << self.lba_shift
+    }
+
+    /// Number of blocks covered by `len` bytes of data
+    pub fn blocks(&self, len: usize) -> io::Result<u32> {
+        let nlb = len >> self.lba_shift;
+        if nlb == 0 || nlb << self.lba_shift != len || nlb > NVME_IO_MAX_BLOCKS as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "data length {} is not 1 to 65536 blocks of {} bytes",
+                    len,
+                    self.block_size()
+                ),
+            ));
+        }
+        Ok(nlb as u32)
+    }
+
+    /// Metadata is either absent or `ms` bytes per block of an extended LBA format
+    pub fn check_metadata(&self, nlb: u32, metadata_len: usize) -> io::Result<()> {
+        if metadata_len != 0 && (!self.extended || metadata_len != nlb as usize * self.ms as usize)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "metadata needs an extended LBA format and ms bytes per block",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Build the transfer buffer: data alone, or data and metadata interleaved per block
+    pub fn interleave(&self, data: &[u8], metadata: &[u8]) -> io::Result<Vec<u8>> {
+        let nlb = self.blocks(data.len())?;
+        self.check_metadata(nlb, metadata.len())?;
+        if metadata.is_empty() {
+            return Ok(data.to_vec());
+        }
+        let ms = self.ms as usize;
+        Ok(data
+            .chunks_exact(self.block_size())
+            .zip(metadata.chunks_exact(ms))
+            .flat_map(|(block, meta)| block.iter().chain(meta.iter()).copied())
+            .collect())
+    }
+
+    /// Split a transfer buffer back into data and metadata
+    pub fn deinterleave(&self, buffer: &[u8], data: &mut [u8], metadata: &mut [u8]) {
+        if metadata.is_empty() {
+            data.copy_from_slice(&buffer[..data.len()]);
+            return;
+        }
+        let ms = self.ms as usize;
+        let bs = self.block_size();
+        for (index, chunk) in buffer.chunks_exact(bs + ms).enumerate() {
+            data[index * bs..(index + 1) * bs].copy_from_slice(&chunk[..bs]);
+            metadata[index * ms..(index + 1) * ms].copy_from_slice(&chunk[bs..]);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl InboxDriver {
+    pub fn nvme_ns_format(&self, nsid: u32) -> io::Result<NvmeNsFormat> {
+        let ns = self.nvme_identify_namespace(nsid)?;
+        let lbaf = ns.LBAF[ns.FLBAS.LbaFormatIndex() as usize];
+        Ok(NvmeNsFormat {
+            lba_shift: lbaf.LBADS() as u32,
+            ms: lbaf.MS(),
+            extended: ns.FLBAS.MetadataInExtendedDataLBA() != 0,
+        })
+    }
+
+    fn nvme_io_command(
+        opcode: NVME_NVM_COMMANDS,
+        nsid: u32,
+        slba: u64,
+        nlb: u32,
+        options: &NvmeIoOptions,
+    ) -> io::Result<NVME_COMMAND> {
+        let nlb = nvme_io_nlb(nlb)?;
+        let mut cdw13 = NVME_CDW13_READ_WRITE::default();
+        cdw13.DSM = options.dsm;
+        cdw13.DSPEC = options.dspec;
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(opcode as u32).nsid(nsid);
+        nc.u.READWRITE = NVME_COMMAND_READWRITE {
+            LBALOW: slba as u32,
+            LBAHIGH: (slba >> 32) as u32,
+            CDW12: NVME_CDW12_READ_WRITE::new()
+                .with_NLB(nlb)
+                .with_DTYPE(options.dtype)
+                .with_PRINFO(options.prinfo)
+                .with_FUA(options.fua as u8)
+                .with_LR(options.lr as u8),
+            CDW13: cdw13,
+            CDW14: options.reftag,
+            CDW15: NVME_CDW15_READ_WRITE {
+                ELBAT: options.apptag,
+                ELBATM: options.appmask,
+            },
+        };
+        Ok(nc)
+    }
+
+    /// Read `data.len()` bytes from `slba`, `metadata` is filled for extended LBA formats
+    pub fn nvme_read(
+        &self,
+        nsid: u32,
+        slba: u64,
+        format: &NvmeNsFormat,
+        options: &NvmeIoOptions,
+        data: &mut [u8],
+        metadata: &mut [u8],
+    ) -> io::Result<()> {
+        let nlb = format.blocks(data.len())?;
+        format.check_metadata(nlb, metadata.len())?;
+        let mut buffer = vec![0u8; data.len() + metadata.len()];
+        let nc = Self::nvme_io_command(
+            NVME_NVM_COMMANDS::NVME_NVM_COMMAND_READ,
+            nsid,
+            slba,
+            nlb,
+            options,
+        )?;
+        self.nvme_send_io_passthrough_command(
+            NvmeOpcodeType::READ as u8,
+            &nc,
+            &mut buffer,
+            &mut 0,
+        )?
+        .check("Read")?;
+        format.deinterleave(&buffer, data, metadata);
+        Ok(())
+    }
+
+    pub fn nvme_write(
+        &self,
+        nsid: u32,
+        slba: u64,
+        format: &NvmeNsFormat,
+        options: &NvmeIoOptions,
+        data: &[u8],
+        metadata: &[u8],
+    ) -> io::Result<()> {
+        let nlb = format.blocks(data.len())?;
+        let mut buffer = format.interleave(data, metadata)?;
+        let nc = Self::nvme_io_command(
+            NVME_NVM_COMMANDS::NVME_NVM_COMMAND_WRITE,
+            nsid,
+            slba,
+            nlb,
+            options,
+        )?;
+        self.nvme_send_io_passthrough_command(
+            NvmeOpcodeType::WRITE as u8,
+            &nc,
+            &mut buffer,
+            &mut 0,
+        )?
+        .check("Write")
+    }
+
+    /// Compare `data` with the media, Ok(false) on a Compare Failure status
+    pub fn nvme_compare(
+        &self,
+        nsid: u32,
+        slba: u64,
+        format: &NvmeNsFormat,
+        options: &NvmeIoOptions,
+        data: &[u8],
+        metadata: &[u8],
+    ) -> io::Result<bool> {
+        let nlb = format.blocks(data.len())?;
+        let mut buffer = format.interleave(data, metadata)?;
+        let nc = Self::nvme_io_command(
+            NVME_NVM_COMMANDS::NVME_NVM_COMMAND_COMPARE,
+            nsid,
+            slba,
+            nlb,
+            options,
+        )?;
+        let ncs = self.nvme_send_io_passthrough_command(
+            NvmeOpcodeType::WRITE as u8,
+            &nc,
+            &mut buffer,
+            &mut 0,
+        )?;
+        if ncs.SCT() == NVME_STATUS_TYPES::NVME_STATUS_TYPE_MEDIA_ERROR as u8
+            && ncs.SC() == NVME_STATUS_MEDIA_ERROR_CODES::NVME_STATUS_NVM_COMPARE_FAILURE as u8
+        {
+            return Ok(false);
+        }
+        ncs.check("Compare").map(|_| true)
+    }
+
+    /// Write Zeroes, `deac` asks the controller to deallocate the blocks
+    pub fn nvme_write_zeroes(
+        &self,
+        nsid: u32,
+        slba: u64,
+        nlb: u32,
+        deac: bool,
+        options: &NvmeIoOptions,
+    ) -> io::Result<()> {
+        let mut nc = Self::nvme_io_command(
+            NVME_NVM_COMMANDS::NVME_NVM_COMMAND_WRITE_ZEROES,
+            nsid,
+            slba,
+            nlb,
+            options,
+        )?;
+        unsafe {
+            nc.u.READWRITE.CDW12 =
+                NVME_CDW12_READ_WRITE::from(u32::from(nc.u.READWRITE.CDW12) | (deac as u32) << 25);
+        }
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::NOBUFFER as u8, &nc, &mut [], &mut 0)?
+            .check("Write Zeroes")
+    }
+
+    pub fn nvme_write_uncorrectable(&self, nsid: u32, slba: u64, nlb: u32) -> io::Result<()> {
+        let nc = Self::nvme_io_command(
+            NVME_NVM_COMMANDS::NVME_NVM_COMMAND_WRITE_UNCORRECTABLE,
+            nsid,
+            slba,
+            nlb,
+            &NvmeIoOptions::default(),
+        )?;
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::NOBUFFER as u8, &nc, &mut [], &mut 0)?
+            .check("Write Uncorrectable")
+    }
+
+    pub fn nvme_verify(
+        &self,
+        nsid: u32,
+        slba: u64,
+        nlb: u32,
+        options: &NvmeIoOptions,
+    ) -> io::Result<()> {
+        let nc = Self::nvme_io_command(
+            NVME_NVM_COMMANDS::NVME_NVM_COMMAND_VERIFY,
+            nsid,
+            slba,
+            nlb,
+            options,
+        )?;
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::NOBUFFER as u8, &nc, &mut [], &mut 0)?
+            .check("Verify")
+    }
+
+    pub fn nvme_flush(&self, nsid: u32) -> io::Result<()> {
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMANDS::NVME_NVM_COMMAND_FLUSH as u32)
+            .nsid(nsid);
+        self.nvme_send_io_passthrough_command(NvmeOpcodeType::NOBUFFER as u8, &nc, &mut [], &mut 0)?
+            .check("Flush")
+    }
+}
+
 // Example Enum Definitions (actual values and types may vary)
 #[repr(u8)]
 #[derive(Debug)]
@@ -237,6 +1727,71 @@ pub enum NvmeOpcodeType {
     READWRITE,
 }
 
+/// Anything that can execute NVMe admin and I/O commands: the inbox driver, a
+/// simulated controller or a recorded session. `direction` is an `NvmeOpcodeType`.
+pub trait NvmeTransport {
+    fn admin_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS>;
+
+    fn io_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS>;
+}
+
+impl<T: NvmeTransport + ?Sized> NvmeTransport for &T {
+    fn admin_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        (**self).admin_passthru(direction, command, data, dw0)
+    }
+
+    fn io_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        (**self).io_passthru(direction, command, data, dw0)
+    }
+}
+
+#[cfg(windows)]
+impl NvmeTransport for InboxDriver {
+    fn admin_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.nvme_send_passthrough_command(direction, command, data, dw0)
+    }
+
+    fn io_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.nvme_send_io_passthrough_command(direction, command, data, dw0)
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 pub enum NvmeVscOpcode {
@@ -251,7 +1806,263 @@ impl Default for NvmeVscOpcode {
     }
 }
 
+#[cfg(windows)]
 const NVME_DATA_BUFFER_SIZE: usize = 4096; // Example size, adjust as necessary
+#[cfg(windows)]
 const VS_STD_NVME_CMD_TYPE_READ: u32 = 0x83061400;
+#[cfg(windows)]
 const VS_STD_NVME_CMD_TYPE_WRITE: u32 = 0x83061401;
+#[cfg(windows)]
 const VS_STD_NVME_CMD_TYPE_NON_DATA: u32 = 0x83061402;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apst_controller() -> NVME_IDENTIFY_CONTROLLER_DATA {
+        let mut ctrl = NVME_IDENTIFY_CONTROLLER_DATA {
+            NPSS: 4,
+            ..Default::default()
+        };
+        ctrl.APSTA.set_Supported(1);
+        for state in 3..=4 {
+            ctrl.PDS[state].set_NOPS(1);
+        }
+        ctrl
+    }
+
+    #[test]
+    fn io_block_counts() {
+        assert!(nvme_io_nlb(0).is_err());
+        assert_eq!(nvme_io_nlb(1).unwrap(), 0);
+        assert_eq!(nvme_io_nlb(NVME_IO_MAX_BLOCKS).unwrap(), 0xFFFF);
+        assert!(nvme_io_nlb(NVME_IO_MAX_BLOCKS + 1).is_err());
+
+        let format = NvmeNsFormat {
+            lba_shift: 9,
+            ..Default::default()
+        };
+        assert_eq!(format.blocks(4096).unwrap(), 8);
+        assert!(format.blocks(0).is_err());
+        assert!(format.blocks(513).is_err());
+        assert!(format
+            .blocks((NVME_IO_MAX_BLOCKS as usize + 1) << 9)
+            .is_err());
+    }
+
+    #[test]
+    fn io_metadata_layout() {
+        let format = NvmeNsFormat {
+            lba_shift: 9,
+            ms: 8,
+            extended: true,
+        };
+        let data: Vec<u8> = (0..1024).map(|i| (i / 512) as u8).collect();
+        let metadata = [0xA0; 16];
+        let buffer = format.interleave(&data, &metadata).unwrap();
+        assert_eq!(buffer.len(), 2 * 520);
+        assert_eq!(&buffer[512..520], &[0xA0; 8]);
+        assert_eq!(buffer[520], 1);
+
+        let (mut data_back, mut metadata_back) = (vec![0; 1024], [0; 16]);
+        format.deinterleave(&buffer, &mut data_back, &mut metadata_back);
+        assert_eq!((data_back, metadata_back), (data.clone(), metadata));
+
+        // wrong metadata length, or metadata on a separate-buffer format
+        assert!(format.check_metadata(2, 15).is_err());
+        assert!(format.interleave(&data, &metadata[..8]).is_err());
+        let separate = NvmeNsFormat {
+            extended: false,
+            ..format
+        };
+        assert!(separate.check_metadata(2, 16).is_err());
+        assert!(separate.check_metadata(2, 0).is_ok());
+    }
+
+    #[test]
+    fn apst_entry_sets_fields() {
+        let ctrl = apst_controller();
+        let mut table = [NVME_AUTO_POWER_STATE_TRANSITION_ENTRY::default(); NVME_APST_ENTRY_COUNT];
+        nvme_apst_set_entry(&mut table, &ctrl, 0, 3, 100).unwrap();
+        assert_eq!(table[0].IdleTransitionPowerState(), 3);
+        assert_eq!(table[0].IdleTimePriorToTransition(), 100);
+        assert_eq!(u64::from(table[1]), 0);
+        assert!(nvme_apst_supported(&ctrl));
+    }
+
+    #[test]
+    fn apst_entry_rejects_out_of_range() {
+        let ctrl = apst_controller();
+        let mut table = [NVME_AUTO_POWER_STATE_TRANSITION_ENTRY::default(); NVME_APST_ENTRY_COUNT];
+        // beyond NPSS, beyond the 32-entry table, operational target, ITPT overflow
+        assert!(nvme_apst_set_entry(&mut table, &ctrl, 5, 3, 100).is_err());
+        assert!(nvme_apst_set_entry(&mut table, &ctrl, 32, 3, 100).is_err());
+        assert!(nvme_apst_set_entry(&mut table, &ctrl, 0, 5, 100).is_err());
+        assert!(nvme_apst_set_entry(&mut table, &ctrl, 0, 40, 100).is_err());
+        assert!(nvme_apst_set_entry(&mut table, &ctrl, 0, 1, 100).is_err());
+        assert!(nvme_apst_set_entry(&mut table, &ctrl, 0, 3, 1 << 24).is_err());
+        assert!(table.iter().all(|&entry| u64::from(entry) == 0));
+        // ITPT 0 disables the entry, whatever the target
+        assert!(nvme_apst_set_entry(&mut table, &ctrl, 0, 0, 0).is_ok());
+    }
+
+    #[test]
+    fn zone_extension_data() {
+        let data = nvme_zone_extension_data(vec![1, 2, 3], 64).unwrap();
+        assert_eq!(data.len(), 64);
+        assert_eq!(&data[..4], &[1, 2, 3, 0]);
+        assert_eq!(
+            nvme_zone_extension_data(vec![7; 128], 128).unwrap(),
+            vec![7; 128]
+        );
+        assert_eq!(nvme_zone_extension_data(vec![], 64).unwrap(), vec![0; 64]);
+        for (len, size) in [(65, 64), (1, 0), (0, 0)] {
+            let e = nvme_zone_extension_data(vec![0; len], size).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn dsm_split_ranges() {
+        let max = u32::MAX as u64;
+        assert!(nvme_dsm_split_ranges(&[], max, NVME_DSM_MAX_RANGES).is_empty());
+        assert!(nvme_dsm_split_ranges(&[(10, 0)], max, NVME_DSM_MAX_RANGES).is_empty());
+
+        // one range holds at most 0xFFFFFFFF blocks, whatever the device allows
+        let commands = nvme_dsm_split_ranges(&[(5, max + 2)], u64::MAX, NVME_DSM_MAX_RANGES);
+        assert_eq!(commands, [vec![(5, u32::MAX), (5 + max, 2)]]);
+        let commands = nvme_dsm_split_ranges(&[(0, 10), (100, 3)], 4, NVME_DSM_MAX_RANGES);
+        assert_eq!(commands, [vec![(0, 4), (4, 4), (8, 2), (100, 3)]]);
+
+        // and one command at most 256 ranges
+        let extents: Vec<(u64, u64)> = (0..600).map(|i| (i * 10, 1)).collect();
+        let commands = nvme_dsm_split_ranges(&extents, max, 1000);
+        let sizes: Vec<usize> = commands.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [256, 256, 88]);
+        assert_eq!(commands[1][0], (2560, 1));
+        assert_eq!(nvme_dsm_split_ranges(&extents[..5], max, 2).len(), 3);
+        assert_eq!(nvme_dsm_split_ranges(&extents[..5], 0, 0).len(), 5);
+
+        // a whole namespace, as nvme_deallocate_namespace sends it
+        let nsze = 3 * max + 7;
+        let commands = nvme_dsm_split_ranges(&[(0, nsze)], u32::MAX as u64, NVME_DSM_MAX_RANGES);
+        assert_eq!(
+            commands,
+            [vec![
+                (0, u32::MAX),
+                (max, u32::MAX),
+                (2 * max, u32::MAX),
+                (3 * max, 7)
+            ]]
+        );
+        let ranges: Vec<(u64, u32)> = commands.concat();
+        assert_eq!(ranges.iter().map(|&(_, n)| n as u64).sum::<u64>(), nsze);
+        // a DMRSL limit spreads it over several commands
+        let commands = nvme_dsm_split_ranges(&[(0, 1000 * 256 + 1)], 1000, NVME_DSM_MAX_RANGES);
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[1], [(256_000, 1)]);
+
+        let bytes = nvme_dsm_range_bytes(&[(0x1_0000_0002, 8)]);
+        assert_eq!(bytes.len(), NVME_DSM_RANGE_SIZE);
+        assert_eq!(&bytes[4..8], &8u32.to_le_bytes());
+        assert_eq!(&bytes[8..16], &0x1_0000_0002u64.to_le_bytes());
+    }
+
+    /// Reservation Report header as the controller returns it
+    fn resv_header(regctl: u16, eds: bool) -> Vec<u8> {
+        let mut data = vec![0u8; if eds { 64 } else { 24 }];
+        data[0..4].copy_from_slice(&7u32.to_le_bytes()); // GEN
+        data[4] = 2; // RTYPE: write exclusive, registrants only
+        data[5..7].copy_from_slice(&regctl.to_le_bytes());
+        data[9] = 1; // PTPLS
+        data
+    }
+
+    #[test]
+    fn resv_report() {
+        let mut data = resv_header(2, false);
+        for (cntlid, rcsts, rkey) in [(1u16, 0u8, 0x1111u64), (0x42, 1, 0xfeed_0000_0000_beef)] {
+            let mut entry = [0u8; 24];
+            entry[0..2].copy_from_slice(&cntlid.to_le_bytes());
+            entry[2] = rcsts;
+            entry[8..16].copy_from_slice(&[cntlid as u8; 8]); // HOSTID
+            entry[16..24].copy_from_slice(&rkey.to_le_bytes());
+            data.extend_from_slice(&entry);
+        }
+        let status = nvme_resv_report_parse(&data, false);
+        assert_eq!((status.generation, status.rtype, status.ptpls), (7, 2, 1));
+        assert!(!status.extended);
+        assert_eq!(status.registrants.len(), 2);
+        let first = &status.registrants[0];
+        assert_eq!(
+            (first.cntlid, first.holds_reservation, first.rkey),
+            (1, false, 0x1111)
+        );
+        assert_eq!(first.host_id, [1; 8]);
+        let holder = status.holder().unwrap();
+        assert_eq!((holder.cntlid, holder.rkey), (0x42, 0xfeed_0000_0000_beef));
+        assert_eq!(holder.host_id, [0x42; 8]);
+
+        // a buffer cut inside the second entry keeps only the whole ones
+        let status = nvme_resv_report_parse(&data[..24 + 24 + 23], false);
+        assert_eq!(status.registrants.len(), 1);
+        assert!(status.holder().is_none());
+        // REGCTL larger than the buffer holds
+        data[5..7].copy_from_slice(&100u16.to_le_bytes());
+        assert_eq!(nvme_resv_report_parse(&data, false).registrants.len(), 2);
+        // fewer than the buffer holds, the rest is padding
+        data[5..7].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(nvme_resv_report_parse(&data, false).registrants.len(), 1);
+
+        let status = nvme_resv_report_parse(&data[..23], false);
+        assert_eq!((status.generation, status.registrants.len()), (0, 0));
+        let status = nvme_resv_report_parse(&data[..24], false);
+        assert_eq!((status.generation, status.registrants.len()), (7, 0));
+    }
+
+    #[test]
+    fn resv_report_extended() {
+        let mut data = resv_header(3, true);
+        for cntlid in 1u16..=3 {
+            let mut entry = [0u8; 64];
+            entry[0..2].copy_from_slice(&cntlid.to_le_bytes());
+            entry[2] = (cntlid == 3) as u8;
+            entry[8..16].copy_from_slice(&(cntlid as u64 * 0x100).to_le_bytes()); // RKEY
+            for (i, byte) in entry[16..32].iter_mut().enumerate() {
+                *byte = cntlid as u8 * 0x10 + i as u8; // 128-bit HOSTID
+            }
+            entry[32..64].fill(0xee); // reserved
+            data.extend_from_slice(&entry);
+        }
+        let status = nvme_resv_report_parse(&data, true);
+        assert!(status.extended);
+        assert_eq!((status.generation, status.rtype, status.ptpls), (7, 2, 1));
+        let cntlids: Vec<u16> = status.registrants.iter().map(|r| r.cntlid).collect();
+        assert_eq!(cntlids, [1, 2, 3]);
+        let second = &status.registrants[1];
+        assert_eq!(second.rkey, 0x200);
+        assert_eq!(second.host_id, (0x20..0x30).collect::<Vec<u8>>());
+        assert_eq!(status.holder().unwrap().cntlid, 3);
+
+        // the entries start after the 64 byte header, not the 24 byte one
+        assert_eq!(
+            nvme_resv_report_parse(&data[..64 + 63], true)
+                .registrants
+                .len(),
+            0
+        );
+        assert_eq!(
+            nvme_resv_report_parse(&data[..64 + 64], true)
+                .registrants
+                .len(),
+            1
+        );
+        // a header without room for the reserved bytes has no entries
+        assert_eq!(
+            nvme_resv_report_parse(&data[..40], true).registrants.len(),
+            0
+        );
+        data[5..7].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(nvme_resv_report_parse(&data, true).registrants.len(), 3);
+    }
+}
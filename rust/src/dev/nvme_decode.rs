@@ -0,0 +1,993 @@
+// Identify data and log pages decoded from raw buffers, so saved dumps can be
+// printed or turned into JSON without a controller. Log pages are read by byte
+// offset as laid out in the NVMe base specification.
+use crate::dev::nvme_commands::nvme_status_name;
+use crate::dev::nvme_define::*;
+use crate::dev::nvme_print::*;
+use serde::{Serialize, Serializer};
+use serde_json::json;
+use std::{fmt, io, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeDecodeKind {
+    IdCtrl,
+    IdNs,
+    Smart,
+    Error,
+    FwSlot,
+    Effects,
+    SelfTest,
+    Sanitize,
+    Telemetry,
+    PersistentEvent,
+    ChangedNs,
+}
+
+impl NvmeDecodeKind {
+    pub const ALL: [NvmeDecodeKind; 11] = [
+        Self::IdCtrl,
+        Self::IdNs,
+        Self::Smart,
+        Self::Error,
+        Self::FwSlot,
+        Self::Effects,
+        Self::SelfTest,
+        Self::Sanitize,
+        Self::Telemetry,
+        Self::PersistentEvent,
+        Self::ChangedNs,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::IdCtrl => "id-ctrl",
+            Self::IdNs => "id-ns",
+            Self::Smart => "smart",
+            Self::Error => "error",
+            Self::FwSlot => "fw-slot",
+            Self::Effects => "effects",
+            Self::SelfTest => "self-test",
+            Self::Sanitize => "sanitize",
+            Self::Telemetry => "telemetry",
+            Self::PersistentEvent => "persistent-event",
+            Self::ChangedNs => "changed-ns",
+        }
+    }
+
+    /// The structure a Get Log Page for `lid` returns, when it has a decoder
+    pub fn from_log_id(lid: u32) -> Option<Self> {
+        use NVME_LOG_PAGES::*;
+        match lid {
+            x if x == NVME_LOG_PAGE_ERROR_INFO as u32 => Some(Self::Error),
+            x if x == NVME_LOG_PAGE_HEALTH_INFO as u32 => Some(Self::Smart),
+            x if x == NVME_LOG_PAGE_FIRMWARE_SLOT_INFO as u32 => Some(Self::FwSlot),
+            x if x == NVME_LOG_PAGE_CHANGED_NAMESPACE_LIST as u32 => Some(Self::ChangedNs),
+            x if x == NVME_LOG_PAGE_COMMAND_EFFECTS as u32 => Some(Self::Effects),
+            x if x == NVME_LOG_PAGE_DEVICE_SELF_TEST as u32 => Some(Self::SelfTest),
+            x if x == NVME_LOG_PAGE_TELEMETRY_HOST_INITIATED as u32
+                || x == NVME_LOG_PAGE_TELEMETRY_CTLR_INITIATED as u32 =>
+            {
+                Some(Self::Telemetry)
+            }
+            x if x == NVME_LOG_PAGE_PERSISTENT_EVENT_LOG as u32 => Some(Self::PersistentEvent),
+            x if x == NVME_LOG_PAGE_SANITIZE_STATUS as u32 => Some(Self::Sanitize),
+            _ => None,
+        }
+    }
+
+    /// Smallest buffer the decoder accepts
+    pub fn min_len(&self) -> usize {
+        match self {
+            Self::IdCtrl => size_of::<NVME_IDENTIFY_CONTROLLER_DATA>(),
+            Self::IdNs => size_of::<NVME_IDENTIFY_NAMESPACE_DATA>(),
+            Self::Error => NVME_ERROR_LOG_ENTRY_SIZE,
+            Self::Effects => 2048,
+            Self::SelfTest => 4 + NVME_SELF_TEST_RESULT_SIZE * 20,
+            Self::Sanitize => 32,
+            Self::ChangedNs => 4,
+            _ => 512,
+        }
+    }
+}
+
+impl FromStr for NvmeDecodeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|k| k.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|k| k.name()).collect();
+                format!("{}: expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for NvmeDecodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub const NVME_ERROR_LOG_ENTRY_SIZE: usize = 64;
+pub const NVME_SELF_TEST_RESULT_SIZE: usize = 28;
+const NVME_PERSISTENT_EVENT_LOG_HEADER_SIZE: usize = 512;
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn le128(data: &[u8], offset: usize) -> u128 {
+    u128::from_le_bytes(data[offset..offset + 16].try_into().unwrap())
+}
+
+fn text(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches([' ', '\0'])
+        .to_string()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Copy of a fixed layout structure from the start of `data`
+fn read_struct<T: Copy>(data: &[u8]) -> T {
+    assert!(data.len() >= size_of::<T>());
+    unsafe { std::ptr::read_unaligned(data.as_ptr() as *const T) }
+}
+
+/// SMART / Health Information (Log Identifier 02h)
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeSmartLog {
+    pub critical_warning: u8,
+    /// composite temperature in Kelvin
+    pub temperature: u16,
+    pub available_spare: u8,
+    pub available_spare_threshold: u8,
+    pub percentage_used: u8,
+    pub endurance_group_critical_warning: u8,
+    #[serde(serialize_with = "serialize_u128")]
+    pub data_units_read: u128,
+    #[serde(serialize_with = "serialize_u128")]
+    pub data_units_written: u128,
+    #[serde(serialize_with = "serialize_u128")]
+    pub host_read_commands: u128,
+    #[serde(serialize_with = "serialize_u128")]
+    pub host_write_commands: u128,
+    #[serde(serialize_with = "serialize_u128")]
+    pub controller_busy_time: u128,
+    #[serde(serialize_with = "serialize_u128")]
+    pub power_cycles: u128,
+    #[serde(serialize_with = "serialize_u128")]
+    pub power_on_hours: u128,
+    #[serde(serialize_with = "serialize_u128")]
+    pub unsafe_shutdowns: u128,
+    #[serde(serialize_with = "serialize_u128")]
+    pub media_errors: u128,
+    #[serde(serialize_with = "serialize_u128")]
+    pub error_log_entries: u128,
+    pub warning_temperature_time: u32,
+    pub critical_temperature_time: u32,
+    /// Kelvin, 0 for sensors that are not implemented
+    pub temperature_sensors: [u16; 8],
+    pub thermal_transitions: [u32; 2],
+    pub thermal_time: [u32; 2],
+}
+
+impl NvmeSmartLog {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut temperature_sensors = [0; 8];
+        for (i, sensor) in temperature_sensors.iter_mut().enumerate() {
+            *sensor = le16(data, 200 + i * 2);
+        }
+        Self {
+            critical_warning: data[0],
+            temperature: le16(data, 1),
+            available_spare: data[3],
+            available_spare_threshold: data[4],
+            percentage_used: data[5],
+            endurance_group_critical_warning: data[6],
+            data_units_read: le128(data, 32),
+            data_units_written: le128(data, 48),
+            host_read_commands: le128(data, 64),
+            host_write_commands: le128(data, 80),
+            controller_busy_time: le128(data, 96),
+            power_cycles: le128(data, 112),
+            power_on_hours: le128(data, 128),
+            unsafe_shutdowns: le128(data, 144),
+            media_errors: le128(data, 160),
+            error_log_entries: le128(data, 176),
+            warning_temperature_time: le32(data, 192),
+            critical_temperature_time: le32(data, 196),
+            temperature_sensors,
+            thermal_transitions: [le32(data, 216), le32(data, 220)],
+            thermal_time: [le32(data, 224), le32(data, 228)],
+        }
+    }
+}
+
+/// One Error Information log entry (Log Identifier 01h)
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeErrorLogEntry {
+    pub error_count: u64,
+    pub sqid: u16,
+    pub cmdid: u16,
+    pub sct: u8,
+    pub sc: u8,
+    pub status: &'static str,
+    pub more: bool,
+    pub dnr: bool,
+    pub parameter_byte: u8,
+    pub parameter_bit: u8,
+    pub lba: u64,
+    pub nsid: u32,
+    pub vendor_log_page: u8,
+    pub transport_type: u8,
+    pub command_specific: u64,
+}
+
+impl NvmeErrorLogEntry {
+    pub fn parse(data: &[u8]) -> Self {
+        let status = NVME_COMMAND_STATUS::from(le16(data, 12));
+        let location = NVME_PARAMETER_ERROR_LOCATION::from(le16(data, 14));
+        Self {
+            error_count: le64(data, 0),
+            sqid: le16(data, 8),
+            cmdid: le16(data, 10),
+            sct: status.SCT(),
+            sc: status.SC(),
+            status: nvme_status_name(status.SCT(), status.SC()),
+            more: status.M() != 0,
+            dnr: status.DNR() != 0,
+            parameter_byte: location.Byte(),
+            parameter_bit: location.Bit(),
+            lba: le64(data, 16),
+            nsid: le32(data, 24),
+            vendor_log_page: data[28],
+            transport_type: data[29],
+            command_specific: le64(data, 32),
+        }
+    }
+
+    /// Entries with a nonzero error count, newest first as the controller keeps them
+    pub fn parse_log(data: &[u8]) -> Vec<Self> {
+        data.chunks_exact(NVME_ERROR_LOG_ENTRY_SIZE)
+            .map(Self::parse)
+            .filter(|e| e.error_count != 0)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeFirmwareSlot {
+    pub slot: u8,
+    pub revision: String,
+}
+
+/// Firmware Slot Information (Log Identifier 03h)
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeFirmwareSlotLog {
+    pub active_slot: u8,
+    /// slot activated at the next reset, 0 when none is pending
+    pub next_slot: u8,
+    /// slots holding a firmware revision
+    pub slots: Vec<NvmeFirmwareSlot>,
+}
+
+impl NvmeFirmwareSlotLog {
+    pub fn parse(data: &[u8]) -> Self {
+        let afi = NVME_FIRMWARE_SLOT_INFO_LOG_AFI::from(data[0]);
+        let slots = (0..7)
+            .map(|i| NvmeFirmwareSlot {
+                slot: i as u8 + 1,
+                revision: text(&data[8 + i * 8..16 + i * 8]),
+            })
+            .filter(|s| !s.revision.is_empty())
+            .collect();
+        Self {
+            active_slot: afi.ActiveSlot(),
+            next_slot: afi.PendingActivateSlot(),
+            slots,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeCommandEffect {
+    pub opcode: u8,
+    pub raw: u32,
+    pub lbcc: bool,
+    pub ncc: bool,
+    pub nic: bool,
+    pub ccc: bool,
+    pub cse: u8,
+}
+
+/// Commands Supported and Effects (Log Identifier 05h), supported opcodes only
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeCommandEffectsLog {
+    pub admin: Vec<NvmeCommandEffect>,
+    pub io: Vec<NvmeCommandEffect>,
+}
+
+impl NvmeCommandEffectsLog {
+    pub fn parse(data: &[u8]) -> Self {
+        let effects = |base: usize| -> Vec<NvmeCommandEffect> {
+            (0..256)
+                .filter_map(|opcode| {
+                    let raw = le32(data, base + opcode * 4);
+                    let effect = NVME_COMMAND_EFFECTS_DATA::from(raw);
+                    (effect.CSUPP() != 0).then_some(NvmeCommandEffect {
+                        opcode: opcode as u8,
+                        raw,
+                        lbcc: effect.LBCC() != 0,
+                        ncc: effect.NCC() != 0,
+                        nic: effect.NIC() != 0,
+                        ccc: effect.CCC() != 0,
+                        cse: effect.CSE(),
+                    })
+                })
+                .collect()
+        };
+        Self {
+            admin: effects(0),
+            io: effects(1024),
+        }
+    }
+}
+
+pub fn nvme_self_test_result_name(result: u8) -> &'static str {
+    match result {
+        0x0 => "Completed without error",
+        0x1 => "Aborted by a Device Self-test command",
+        0x2 => "Aborted by a Controller Level Reset",
+        0x3 => "Aborted due to a removal of a namespace",
+        0x4 => "Aborted due to a Format NVM command",
+        0x5 => "Fatal or unknown test error",
+        0x6 => "Completed with a failed segment, unknown which",
+        0x7 => "Completed with one or more failed segments",
+        0x8 => "Aborted for unknown reason",
+        0x9 => "Aborted due to a sanitize operation",
+        0xf => "Entry not used",
+        _ => "Reserved",
+    }
+}
+
+pub fn nvme_self_test_code_name(code: u8) -> &'static str {
+    match code {
+        0x1 => "Short",
+        0x2 => "Extended",
+        0xe => "Vendor specific",
+        _ => "Reserved",
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeSelfTestResult {
+    pub result: u8,
+    pub result_name: &'static str,
+    pub code: u8,
+    pub segment: u8,
+    pub power_on_hours: u64,
+    pub nsid: Option<u32>,
+    pub failing_lba: Option<u64>,
+    pub sct: Option<u8>,
+    pub sc: Option<u8>,
+    pub vendor_specific: u16,
+}
+
+/// Device Self-test (Log Identifier 06h)
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeSelfTestLog {
+    /// test in progress, 0 when none
+    pub current_operation: u8,
+    pub current_completion: u8,
+    /// newest first, unused entries left out
+    pub results: Vec<NvmeSelfTestResult>,
+}
+
+impl NvmeSelfTestLog {
+    pub fn parse(data: &[u8]) -> Self {
+        let results = data[4..]
+            .chunks_exact(NVME_SELF_TEST_RESULT_SIZE)
+            .take(20)
+            .filter_map(|entry| {
+                let status = NVME_DEVICE_SELF_TEST_RESULT_DATA_Status::from(entry[0]);
+                let valid = NVME_DEVICE_SELF_TEST_RESULT_DATA_ValidDiagnostics::from(entry[2]);
+                (status.Result() != 0xf).then_some(NvmeSelfTestResult {
+                    result: status.Result(),
+                    result_name: nvme_self_test_result_name(status.Result()),
+                    code: status.CodeValue(),
+                    segment: entry[1],
+                    power_on_hours: le64(entry, 4),
+                    nsid: (valid.NSIDValid() != 0).then_some(le32(entry, 12)),
+                    failing_lba: (valid.FLBAValid() != 0).then_some(le64(entry, 16)),
+                    sct: (valid.SCTValid() != 0).then_some(entry[24] & 0x7),
+                    sc: (valid.SCValid() != 0).then_some(entry[25]),
+                    vendor_specific: le16(entry, 26),
+                })
+            })
+            .collect();
+        Self {
+            current_operation: data[0] & 0xf,
+            current_completion: data[1] & 0x7f,
+            results,
+        }
+    }
+}
+
+pub fn nvme_sanitize_status_name(status: u8) -> &'static str {
+    use NVME_SANITIZE_OPERATION_STATUS::*;
+    match status {
+        x if x == NVME_SANITIZE_OPERATION_NONE as u8 => "Never sanitized",
+        x if x == NVME_SANITIZE_OPERATION_SUCCEEDED as u8 => "Completed successfully",
+        x if x == NVME_SANITIZE_OPERATION_IN_PROGRESS as u8 => "In progress",
+        x if x == NVME_SANITIZE_OPERATION_FAILED as u8 => "Failed",
+        x if x == NVME_SANITIZE_OPERATION_SUCCEEDED_WITH_FORCED_DEALLOCATION as u8 => {
+            "Completed successfully with deallocation"
+        }
+        _ => "Reserved",
+    }
+}
+
+/// Sanitize Status (Log Identifier 81h)
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeSanitizeLog {
+    /// progress in 1/65536 units
+    pub progress: u16,
+    pub status: u8,
+    pub status_name: &'static str,
+    pub overwrite_passes: u8,
+    pub global_data_erased: bool,
+    pub cdw10: u32,
+    /// estimated seconds, 0xffffffff when not reported
+    pub time_overwrite: u32,
+    pub time_block_erase: u32,
+    pub time_crypto_erase: u32,
+    pub time_overwrite_no_dealloc: u32,
+    pub time_block_erase_no_dealloc: u32,
+    pub time_crypto_erase_no_dealloc: u32,
+}
+
+impl NvmeSanitizeLog {
+    pub fn parse(data: &[u8]) -> Self {
+        let sstat = le16(data, 2);
+        Self {
+            progress: le16(data, 0),
+            status: (sstat & 0x7) as u8,
+            status_name: nvme_sanitize_status_name((sstat & 0x7) as u8),
+            overwrite_passes: ((sstat >> 3) & 0x1f) as u8,
+            global_data_erased: sstat & 0x100 != 0,
+            cdw10: le32(data, 4),
+            time_overwrite: le32(data, 8),
+            time_block_erase: le32(data, 12),
+            time_crypto_erase: le32(data, 16),
+            time_overwrite_no_dealloc: le32(data, 20),
+            time_block_erase_no_dealloc: le32(data, 24),
+            time_crypto_erase_no_dealloc: le32(data, 28),
+        }
+    }
+}
+
+/// Telemetry Host-Initiated or Controller-Initiated log header (07h/08h)
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeTelemetryHeader {
+    pub log_id: u8,
+    pub ieee: String,
+    /// last 512-byte block of data areas 1 to 4
+    pub area_last_block: [u32; 4],
+    pub host_generation: u8,
+    pub controller_available: bool,
+    pub controller_generation: u8,
+    pub reason_identifier: String,
+}
+
+impl NvmeTelemetryHeader {
+    pub fn parse(data: &[u8]) -> Self {
+        let host_initiated =
+            data[0] == NVME_LOG_PAGES::NVME_LOG_PAGE_TELEMETRY_HOST_INITIATED as u8;
+        Self {
+            log_id: data[0],
+            ieee: hex(&data[5..8]),
+            area_last_block: [
+                le16(data, 8) as u32,
+                le16(data, 10) as u32,
+                le16(data, 12) as u32,
+                le32(data, 16),
+            ],
+            host_generation: if host_initiated { data[381] } else { 0 },
+            controller_available: data[382] != 0,
+            controller_generation: data[383],
+            reason_identifier: hex(&data[384..512]),
+        }
+    }
+}
+
+pub fn nvme_persistent_event_name(event_type: u8) -> &'static str {
+    use NVME_PERSISTENT_EVENT_LOG_EVENT_TYPES::*;
+    const NAMES: [(NVME_PERSISTENT_EVENT_LOG_EVENT_TYPES, &str); 15] = [
+        (
+            NVME_PERSISTENT_EVENT_TYPE_SMART_HEALTH_LOG_SNAPSHOT,
+            "SMART / Health Log Snapshot",
+        ),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_FIRMWARE_COMMIT,
+            "Firmware Commit",
+        ),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_TIMESTAMP_CHANGE,
+            "Timestamp Change",
+        ),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_POWER_ON_OR_RESET,
+            "Power-on or Reset",
+        ),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_NVM_SUBSYSTEM_HARDWARE_ERROR,
+            "NVM Subsystem Hardware Error",
+        ),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_CHANGE_NAMESPACE,
+            "Change Namespace",
+        ),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_FORMAT_NVM_START,
+            "Format NVM Start",
+        ),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_FORMAT_NVM_COMPLETION,
+            "Format NVM Completion",
+        ),
+        (NVME_PERSISTENT_EVENT_TYPE_SANITIZE_START, "Sanitize Start"),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_SANITIZE_COMPLETION,
+            "Sanitize Completion",
+        ),
+        (NVME_PERSISTENT_EVENT_TYPE_SET_FEATURE, "Set Feature"),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_TELEMETRY_LOG_CREATED,
+            "Telemetry Log Created",
+        ),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_THERMAL_EXCURSION,
+            "Thermal Excursion",
+        ),
+        (
+            NVME_PERSISTENT_EVENT_TYPE_VENDOR_SPECIFIC_EVENT,
+            "Vendor Specific Event",
+        ),
+        (NVME_PERSISTENT_EVENT_TYPE_TCG_DEFINED, "TCG Defined"),
+    ];
+    NAMES
+        .iter()
+        .find(|(t, _)| *t as u8 == event_type)
+        .map_or("Reserved", |(_, name)| name)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmePersistentEvent {
+    pub event_type: u8,
+    pub name: &'static str,
+    pub revision: u8,
+    pub controller_id: u16,
+    /// milliseconds since the Unix epoch in bits 47:0
+    pub timestamp: u64,
+    pub vendor_info_len: u16,
+    pub data: String,
+}
+
+/// Persistent Event Log (Log Identifier 0Dh): header and the events the buffer holds
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmePersistentEventLog {
+    pub log_id: u8,
+    pub total_events: u32,
+    pub total_length: u64,
+    pub revision: u8,
+    pub header_length: u16,
+    pub timestamp: u64,
+    #[serde(serialize_with = "serialize_u128")]
+    pub power_on_hours: u128,
+    pub power_cycles: u64,
+    pub vid: u16,
+    pub ssvid: u16,
+    pub sn: String,
+    pub mn: String,
+    pub subnqn: String,
+    pub supported_events: Vec<u8>,
+    pub events: Vec<NvmePersistentEvent>,
+}
+
+impl NvmePersistentEventLog {
+    pub fn parse(data: &[u8]) -> Self {
+        let total_events = le32(data, 4);
+        let supported_events = (0..=255u8)
+            .filter(|&t| data[480 + t as usize / 8] & (1 << (t % 8)) != 0)
+            .collect();
+        let mut events = Vec::new();
+        let mut offset = NVME_PERSISTENT_EVENT_LOG_HEADER_SIZE;
+        while events.len() < total_events as usize && offset + 24 <= data.len() {
+            let event = &data[offset..];
+            // event header length counts the bytes after the first three
+            let start = offset + event[2] as usize + 3;
+            let end = start + le16(event, 22) as usize;
+            if end > data.len() {
+                break;
+            }
+            events.push(NvmePersistentEvent {
+                event_type: event[0],
+                name: nvme_persistent_event_name(event[0]),
+                revision: event[1],
+                controller_id: le16(event, 4),
+                timestamp: le64(event, 6) & 0xffff_ffff_ffff,
+                vendor_info_len: le16(event, 20),
+                data: hex(&data[start..end]),
+            });
+            offset = end;
+        }
+        Self {
+            log_id: data[0],
+            total_events,
+            total_length: le64(data, 8),
+            revision: data[16],
+            header_length: le16(data, 18),
+            timestamp: le64(data, 20) & 0xffff_ffff_ffff,
+            power_on_hours: le128(data, 28),
+            power_cycles: le64(data, 44),
+            vid: le16(data, 52),
+            ssvid: le16(data, 54),
+            sn: text(&data[56..76]),
+            mn: text(&data[76..116]),
+            subnqn: text(&data[116..372]),
+            supported_events,
+            events,
+        }
+    }
+}
+
+/// Changed Namespace List (Log Identifier 04h); a first entry of FFFFFFFFh
+/// means more than 1024 namespaces changed
+pub fn nvme_changed_ns_parse(data: &[u8]) -> Vec<u32> {
+    data.chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .take_while(|&nsid| nsid != 0)
+        .collect()
+}
+
+pub enum NvmeDecoded {
+    IdCtrl(Box<NVME_IDENTIFY_CONTROLLER_DATA>),
+    IdNs(Box<NVME_IDENTIFY_NAMESPACE_DATA>),
+    Smart(NvmeSmartLog),
+    Error(Vec<NvmeErrorLogEntry>),
+    FwSlot(NvmeFirmwareSlotLog),
+    Effects(NvmeCommandEffectsLog),
+    SelfTest(NvmeSelfTestLog),
+    Sanitize(NvmeSanitizeLog),
+    Telemetry(NvmeTelemetryHeader),
+    PersistentEvent(NvmePersistentEventLog),
+    ChangedNs(Vec<u32>),
+}
+
+/// Decode `data` as `kind`; the buffer may be longer than the structure
+pub fn nvme_decode(kind: NvmeDecodeKind, data: &[u8]) -> io::Result<NvmeDecoded> {
+    if data.len() < kind.min_len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} needs at least {} bytes, got {}",
+                kind,
+                kind.min_len(),
+                data.len()
+            ),
+        ));
+    }
+    Ok(match kind {
+        NvmeDecodeKind::IdCtrl => NvmeDecoded::IdCtrl(Box::new(read_struct(data))),
+        NvmeDecodeKind::IdNs => NvmeDecoded::IdNs(Box::new(read_struct(data))),
+        NvmeDecodeKind::Smart => NvmeDecoded::Smart(NvmeSmartLog::parse(data)),
+        NvmeDecodeKind::Error => NvmeDecoded::Error(NvmeErrorLogEntry::parse_log(data)),
+        NvmeDecodeKind::FwSlot => NvmeDecoded::FwSlot(NvmeFirmwareSlotLog::parse(data)),
+        NvmeDecodeKind::Effects => NvmeDecoded::Effects(NvmeCommandEffectsLog::parse(data)),
+        NvmeDecodeKind::SelfTest => NvmeDecoded::SelfTest(NvmeSelfTestLog::parse(data)),
+        NvmeDecodeKind::Sanitize => NvmeDecoded::Sanitize(NvmeSanitizeLog::parse(data)),
+        NvmeDecodeKind::Telemetry => NvmeDecoded::Telemetry(NvmeTelemetryHeader::parse(data)),
+        NvmeDecodeKind::PersistentEvent => {
+            NvmeDecoded::PersistentEvent(NvmePersistentEventLog::parse(data))
+        }
+        NvmeDecodeKind::ChangedNs => NvmeDecoded::ChangedNs(nvme_changed_ns_parse(data)),
+    })
+}
+
+/// Counters too large for a JSON number go out as decimal strings
+fn json_u128(value: u128) -> serde_json::Value {
+    match u64::try_from(value) {
+        Ok(v) => json!(v),
+        Err(_) => json!(value.to_string()),
+    }
+}
+
+fn serialize_u128<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    json_u128(*value).serialize(serializer)
+}
+
+fn json_object<const N: usize>(fields: [(&str, serde_json::Value); N]) -> serde_json::Value {
+    serde_json::Value::Object(
+        fields
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+pub fn nvme_identify_controller_json(data: &NVME_IDENTIFY_CONTROLLER_DATA) -> serde_json::Value {
+    json_object([
+        ("vid", json!(data.VID)),
+        ("ssvid", json!(data.SSVID)),
+        ("sn", json!(text(&data.SN))),
+        ("mn", json!(text(&data.MN))),
+        ("fr", json!(text(&data.FR))),
+        ("rab", json!(data.RAB)),
+        ("ieee", json!(hex(&data.IEEE))),
+        ("cmic", json!(u8::from(data.CMIC))),
+        ("mdts", json!(data.MDTS)),
+        ("cntlid", json!(data.CNTLID)),
+        ("ver", json!(data.VER)),
+        ("rtd3r", json!(data.RTD3R)),
+        ("rtd3e", json!(data.RTD3E)),
+        ("oaes", json!(u32::from(data.OAES))),
+        ("ctratt", json!(u32::from(data.CTRATT))),
+        ("rrls", json!(u16::from(data.RRLS))),
+        ("cntrltype", json!(data.CNTRLTYPE)),
+        ("fguid", json!(hex(&data.FGUID))),
+        ("crdt1", json!(data.CRDT1)),
+        ("crdt2", json!(data.CRDT2)),
+        ("crdt3", json!(data.CRDT3)),
+        ("oacs", json!(u16::from(data.OACS))),
+        ("acl", json!(data.ACL)),
+        ("aerl", json!(data.AERL)),
+        ("frmw", json!(u8::from(data.FRMW))),
+        ("lpa", json!(u8::from(data.LPA))),
+        ("elpe", json!(data.ELPE)),
+        ("npss", json!(data.NPSS)),
+        ("avscc", json!(u8::from(data.AVSCC))),
+        ("apsta", json!(u8::from(data.APSTA))),
+        ("wctemp", json!(data.WCTEMP)),
+        ("cctemp", json!(data.CCTEMP)),
+        ("mtfa", json!(data.MTFA)),
+        ("hmpre", json!(data.HMPRE)),
+        ("hmmin", json!(data.HMMIN)),
+        ("tnvmcap", json_u128(u128::from_le_bytes(data.TNVMCAP))),
+        ("unvmcap", json_u128(u128::from_le_bytes(data.UNVMCAP))),
+        ("rpmbs", json!(u32::from(data.RPMBS))),
+        ("edstt", json!(data.EDSTT)),
+        ("dsto", json!(data.DSTO)),
+        ("fwug", json!(data.FWUG)),
+        ("kas", json!(data.KAS)),
+        ("hctma", json!(u16::from(data.HCTMA))),
+        ("mntmt", json!(data.MNTMT)),
+        ("mxtmt", json!(data.MXTMT)),
+        ("sanicap", json!(u32::from(data.SANICAP))),
+        ("hmminds", json!(data.HMMINDS)),
+        ("hmmaxd", json!(data.HMMAXD)),
+        ("nsetidmax", json!(data.NSETIDMAX)),
+        ("endgidmax", json!(data.ENDGIDMAX)),
+        ("anatt", json!(data.ANATT)),
+        ("anacap", json!(u8::from(data.ANACAP))),
+        ("anagrpmax", json!(data.ANAGRPMAX)),
+        ("nanagrpid", json!(data.NANAGRPID)),
+        ("pels", json!(data.PELS)),
+        ("sqes", json!(u8::from(data.SQES))),
+        ("cqes", json!(u8::from(data.CQES))),
+        ("maxcmd", json!(data.MAXCMD)),
+        ("nn", json!(data.NN)),
+        ("oncs", json!(u16::from(data.ONCS))),
+        ("fuses", json!(u16::from(data.FUSES))),
+        ("fna", json!(u8::from(data.FNA))),
+        ("vwc", json!(u8::from(data.VWC))),
+        ("awun", json!(data.AWUN)),
+        ("awupf", json!(data.AWUPF)),
+        ("nvscc", json!(u8::from(data.NVSCC))),
+        ("nwpc", json!(u8::from(data.NWPC))),
+        ("acwu", json!(data.ACWU)),
+        ("sgls", json!(u32::from(data.SGLS))),
+        ("mnan", json!(data.MNAN)),
+        ("subnqn", json!(text(&data.SUBNQN))),
+    ])
+}
+
+pub fn nvme_identify_namespace_json(data: &NVME_IDENTIFY_NAMESPACE_DATA) -> serde_json::Value {
+    let lbaf: Vec<_> = data.LBAF[..=(data.NLBAF as usize).min(15)]
+        .iter()
+        .map(|f| json!({ "ms": f.MS(), "lbads": f.LBADS(), "rp": f.RP() }))
+        .collect();
+    json_object([
+        ("nsze", json!(data.NSZE)),
+        ("ncap", json!(data.NCAP)),
+        ("nuse", json!(data.NUSE)),
+        ("nsfeat", json!(u8::from(data.NSFEAT))),
+        ("nlbaf", json!(data.NLBAF)),
+        ("flbas", json!(u8::from(data.FLBAS))),
+        ("mc", json!(u8::from(data.MC))),
+        ("dpc", json!(u8::from(data.DPC))),
+        ("dps", json!(u8::from(data.DPS))),
+        ("nmic", json!(u8::from(data.NMIC))),
+        ("rescap", json!(u8::from(data.RESCAP))),
+        ("fpi", json!(u8::from(data.FPI))),
+        ("dlfeat", json!(u8::from(data.DLFEAT))),
+        ("nawun", json!(data.NAWUN)),
+        ("nawupf", json!(data.NAWUPF)),
+        ("nacwu", json!(data.NACWU)),
+        ("nabsn", json!(data.NABSN)),
+        ("nabo", json!(data.NABO)),
+        ("nabspf", json!(data.NABSPF)),
+        ("noiob", json!(data.NOIOB)),
+        ("nvmcap", json_u128(u128::from_le_bytes(data.NVMCAP))),
+        ("npwg", json!(data.NPWG)),
+        ("npwa", json!(data.NPWA)),
+        ("npdg", json!(data.NPDG)),
+        ("npda", json!(data.NPDA)),
+        ("nows", json!(data.NOWS)),
+        ("anagrpid", json!(data.ANAGRPID)),
+        ("nsattr", json!(u8::from(data.NSATTR))),
+        ("nvmsetid", json!(data.NVMSETID)),
+        ("endgid", json!(data.ENDGID)),
+        ("nguid", json!(hex(&data.NGUID))),
+        ("eui64", json!(hex(&data.EUI64))),
+        ("lbaf", json!(lbaf)),
+    ])
+}
+
+impl NvmeDecoded {
+    /// Print with the same printers the live commands use
+    pub fn print(&self) {
+        match self {
+            Self::IdCtrl(data) => print_nvme_identify_controller_data(data),
+            Self::IdNs(data) => print_nvme_identify_namespace_data(data),
+            Self::Smart(log) => print_nvme_smart_log(log),
+            Self::Error(entries) => print_nvme_error_log(entries),
+            Self::FwSlot(log) => print_nvme_fw_slot_log(log),
+            Self::Effects(log) => print_nvme_effects_log(log),
+            Self::SelfTest(log) => print_nvme_self_test_log(log),
+            Self::Sanitize(log) => print_nvme_sanitize_log(log),
+            Self::Telemetry(header) => print_nvme_telemetry_header(header),
+            Self::PersistentEvent(log) => print_nvme_persistent_event_log(log),
+            Self::ChangedNs(list) => print_nvme_ns_list(list),
+        }
+    }
+
+    pub fn to_json(&self) -> io::Result<String> {
+        serde_json::to_string_pretty(self).map_err(io::Error::other)
+    }
+}
+
+impl Serialize for NvmeDecoded {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::IdCtrl(data) => nvme_identify_controller_json(data).serialize(serializer),
+            Self::IdNs(data) => nvme_identify_namespace_json(data).serialize(serializer),
+            Self::Smart(log) => log.serialize(serializer),
+            Self::Error(entries) => entries.serialize(serializer),
+            Self::FwSlot(log) => log.serialize(serializer),
+            Self::Effects(log) => log.serialize(serializer),
+            Self::SelfTest(log) => log.serialize(serializer),
+            Self::Sanitize(log) => log.serialize(serializer),
+            Self::Telemetry(header) => header.serialize(serializer),
+            Self::PersistentEvent(log) => log.serialize(serializer),
+            Self::ChangedNs(list) => list.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID_CTRL: &[u8] = include_bytes!("../../tests/fixtures/id-ctrl.bin");
+    const ID_NS: &[u8] = include_bytes!("../../tests/fixtures/id-ns.bin");
+    const SMART: &[u8] = include_bytes!("../../tests/fixtures/smart.bin");
+
+    fn json(decoded: &NvmeDecoded) -> serde_json::Value {
+        serde_json::from_str(&decoded.to_json().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn identify_controller() {
+        let decoded = nvme_decode(NvmeDecodeKind::IdCtrl, ID_CTRL).unwrap();
+        let NvmeDecoded::IdCtrl(ctrl) = &decoded else {
+            panic!("not identify controller data");
+        };
+        assert_eq!((ctrl.VID, ctrl.SSVID), (0x144d, 0x144d));
+        assert_eq!(text(&ctrl.SN), "S4EWNX0R123456");
+        assert_eq!(text(&ctrl.MN), "Samsung SSD 980 PRO 1TB");
+        assert_eq!(text(&ctrl.FR), "5B2QGXA7");
+        assert_eq!((ctrl.RAB, ctrl.MDTS, ctrl.CNTLID), (2, 9, 6));
+        assert_eq!(ctrl.IEEE, [0x38, 0x25, 0x00]);
+        assert_eq!(ctrl.VER, 0x00010300);
+        assert_eq!(ctrl.NPSS, 4);
+        assert_eq!((ctrl.WCTEMP, ctrl.CCTEMP), (355, 358));
+        assert_eq!(ctrl.NN, 1);
+        assert_eq!(u16::from(ctrl.ONCS), 0x57);
+
+        let json = json(&decoded);
+        assert_eq!(json["sn"], "S4EWNX0R123456");
+        assert_eq!(json["tnvmcap"], 1000204886016u64);
+        assert_eq!(json["unvmcap"], 0);
+        assert_eq!(
+            json["subnqn"],
+            "nqn.1994-11.com.samsung:nvme:980PRO:M.2:S4EWNX0R123456"
+        );
+    }
+
+    #[test]
+    fn identify_namespace() {
+        let decoded = nvme_decode(NvmeDecodeKind::IdNs, ID_NS).unwrap();
+        let NvmeDecoded::IdNs(ns) = &decoded else {
+            panic!("not identify namespace data");
+        };
+        assert_eq!(
+            (ns.NSZE, ns.NCAP, ns.NUSE),
+            (1953525168, 1953525168, 123456789)
+        );
+        assert_eq!(ns.NLBAF, 1);
+        assert_eq!(ns.FLBAS.LbaFormatIndex(), 1);
+        assert_eq!((ns.LBAF[0].LBADS(), ns.LBAF[0].MS()), (9, 0));
+        assert_eq!((ns.LBAF[1].LBADS(), ns.LBAF[1].RP()), (12, 1));
+
+        let json = json(&decoded);
+        assert_eq!(json["nvmcap"], 1000204886016u64);
+        assert_eq!(json["nguid"], "101112131415161718191a1b1c1d1e1f");
+        assert_eq!(json["eui64"], "0025385b21b01234");
+        assert_eq!(json["lbaf"].as_array().unwrap().len(), 2);
+        assert_eq!(json["lbaf"][1]["lbads"], 12);
+    }
+
+    #[test]
+    fn smart_log() {
+        let decoded = nvme_decode(NvmeDecodeKind::Smart, SMART).unwrap();
+        let NvmeDecoded::Smart(smart) = &decoded else {
+            panic!("not a SMART log");
+        };
+        assert_eq!(smart.critical_warning, 0x04);
+        assert_eq!(smart.temperature, 321);
+        assert_eq!(
+            (smart.available_spare, smart.available_spare_threshold),
+            (100, 10)
+        );
+        assert_eq!(smart.percentage_used, 3);
+        assert_eq!(smart.data_units_read, (1u128 << 64) + 5);
+        assert_eq!(smart.data_units_written, 987654);
+        assert_eq!((smart.power_cycles, smart.power_on_hours), (1234, 5678));
+        assert_eq!((smart.media_errors, smart.error_log_entries), (2, 17));
+        assert_eq!(
+            (
+                smart.warning_temperature_time,
+                smart.critical_temperature_time
+            ),
+            (7, 1)
+        );
+        assert_eq!(&smart.temperature_sensors[..3], &[321, 330, 0]);
+
+        // counters past 64 bits become strings rather than lossy numbers
+        let json = json(&decoded);
+        assert_eq!(json["data_units_read"], "18446744073709551621");
+        assert_eq!(json["data_units_written"], 987654);
+        assert_eq!(json["power_on_hours"], 5678);
+    }
+
+    #[test]
+    fn short_buffers() {
+        assert!(nvme_decode(NvmeDecodeKind::IdCtrl, &ID_CTRL[..4095]).is_err());
+        assert!(nvme_decode(NvmeDecodeKind::IdNs, &ID_NS[..100]).is_err());
+        assert!(nvme_decode(NvmeDecodeKind::Smart, &SMART[..511]).is_err());
+        assert!(nvme_decode(NvmeDecodeKind::Smart, &[]).is_err());
+    }
+}
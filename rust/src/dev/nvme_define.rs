@@ -3563,7 +3563,7 @@ pub union NVME_CDW12_DIRECTIVE_RECEIVE {
 #[repr(u32)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct NVME_CDW10_SECURITY_SEND_RECEIVE {
-    Reserved0: B8, // Reserved0
+    pub NSSF: B8,  // NVMe Security Specific Field (NSSF)
     pub SPSP: B16, // SP Specific (SPSP)
     pub SECP: B8,  // Security Protocol (SECP)
 }
@@ -3943,7 +3943,7 @@ const NVME_NAMESPACE_ALL: u32 = 0xFFFFFFFF;
 pub struct NVME_COMMAND {
     pub CDW0: NVME_COMMAND_DWORD0,
     pub NSID: u32,
-    Reserved0: [u32; 2],
+    pub Reserved0: [u32; 2], // CDW2, CDW3: command specific
     pub MPTR: u64,
     pub PRP1: u64,
     pub PRP2: u64,
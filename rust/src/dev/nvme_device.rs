@@ -1,5 +1,8 @@
 use crate::dev::disk::open;
+use crate::dev::nvme_aer::nvme_aer_inbox_error;
 use crate::dev::nvme_define::*;
+use crate::dev::nvme_retry::{NvmeRetryPolicy, NvmeTimeouts, NVME_DEFAULT_TIMEOUT};
+use std::cell::Cell;
 use std::mem::offset_of;
 use std::{ffi::c_void, io, mem::size_of, ptr::null_mut};
 use windows_sys::Win32::Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE};
@@ -9,8 +12,10 @@ use windows_sys::Win32::System::IO::DeviceIoControl;
 trait StorageProtocolCommand {
     fn new(&mut self) -> &mut Self;
     fn nvme_command(&mut self, command: &NVME_COMMAND) -> &mut Self;
+    fn command_specific(&mut self, value: u32) -> &mut Self;
     fn set_data_in(&mut self, direction: u8, data: &[u8]) -> &mut Self;
     fn get_data(&mut self, data: &mut [u8]) -> &mut Self;
+    fn timeout(&mut self, seconds: u32) -> &mut Self;
 }
 
 impl StorageProtocolCommand for STORAGE_PROTOCOL_COMMAND {
@@ -20,7 +25,7 @@ impl StorageProtocolCommand for STORAGE_PROTOCOL_COMMAND {
         self.ProtocolType = ProtocolTypeNvme as i32;
         self.Flags = STORAGE_PROTOCOL_COMMAND_FLAG_ADAPTER_REQUEST;
         self.CommandLength = STORAGE_PROTOCOL_COMMAND_LENGTH_NVME;
-        self.TimeOutValue = 30;
+        self.TimeOutValue = NVME_DEFAULT_TIMEOUT;
         self
     }
     fn nvme_command(&mut self, command: &NVME_COMMAND) -> &mut Self {
@@ -38,6 +43,10 @@ impl StorageProtocolCommand for STORAGE_PROTOCOL_COMMAND {
         self.CommandSpecific = STORAGE_PROTOCOL_SPECIFIC_NVME_ADMIN_COMMAND;
         self
     }
+    fn command_specific(&mut self, value: u32) -> &mut Self {
+        self.CommandSpecific = value;
+        self
+    }
     fn set_data_in(&mut self, direction: u8, data: &[u8]) -> &mut Self {
         match direction {
             1 => self.DataToDeviceTransferLength = data.len() as u32,
@@ -67,6 +76,10 @@ impl StorageProtocolCommand for STORAGE_PROTOCOL_COMMAND {
         }
         self
     }
+    fn timeout(&mut self, seconds: u32) -> &mut Self {
+        self.TimeOutValue = seconds;
+        self
+    }
 }
 
 trait StorageProtocolSpecificData {
@@ -142,9 +155,14 @@ impl StorageProtocolSpecificData for STORAGE_PROTOCOL_SPECIFIC_DATA_EXT {
 
 // To use FIELD_OFFSET macro equivalent in Rust:
 // let offset = field_offset::<SomeType, SomeFieldType>(0 as *const SomeType, |s| &s.some_field);
+
 #[derive(Debug, Clone)]
 pub struct InboxDriver {
     handle: HANDLE,
+    timeouts: Cell<NvmeTimeouts>,
+    // overrides `timeouts` for every command when set
+    timeout: Cell<Option<u32>>,
+    retry: Cell<NvmeRetryPolicy>,
 }
 
 impl InboxDriver {
@@ -153,7 +171,12 @@ impl InboxDriver {
         if handle == INVALID_HANDLE_VALUE {
             Err(io::Error::last_os_error())
         } else {
-            Ok(Self { handle })
+            Ok(Self {
+                handle,
+                timeouts: Cell::new(NvmeTimeouts::default()),
+                timeout: Cell::new(None),
+                retry: Cell::new(NvmeRetryPolicy::default()),
+            })
         }
     }
 
@@ -161,6 +184,38 @@ impl InboxDriver {
         self.handle
     }
 
+    /// Timeout in seconds for all the passthrough commands that follow,
+    /// whatever their opcode
+    pub fn set_timeout(&self, seconds: u32) {
+        self.timeout.set(Some(seconds.max(1)));
+    }
+
+    /// Per-opcode timeouts, used when no `set_timeout` override is in place
+    pub fn set_timeouts(&self, timeouts: NvmeTimeouts) {
+        self.timeouts.set(timeouts);
+        self.timeout.set(None);
+    }
+
+    pub fn timeouts(&self) -> NvmeTimeouts {
+        self.timeouts.get()
+    }
+
+    /// Timeout in seconds the command would be sent with
+    pub fn timeout(&self, admin: bool, opcode: u8) -> u32 {
+        self.timeout
+            .get()
+            .unwrap_or_else(|| self.timeouts.get().for_command(admin, opcode))
+            .max(1)
+    }
+
+    pub fn set_retry_policy(&self, policy: NvmeRetryPolicy) {
+        self.retry.set(policy);
+    }
+
+    pub fn retry_policy(&self) -> NvmeRetryPolicy {
+        self.retry.get()
+    }
+
     pub fn nvme_send_passthrough_command(
         &self,
         direction: u8,
@@ -168,6 +223,64 @@ impl InboxDriver {
         data_buffer: &mut [u8],
         return_dw0: &mut u32,
     ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.nvme_send_protocol_command(
+            STORAGE_PROTOCOL_SPECIFIC_NVME_ADMIN_COMMAND,
+            direction,
+            nvme_command,
+            data_buffer,
+            return_dw0,
+        )
+    }
+
+    pub fn nvme_send_io_passthrough_command(
+        &self,
+        direction: u8,
+        nvme_command: &NVME_COMMAND,
+        data_buffer: &mut [u8],
+        return_dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.nvme_send_protocol_command(
+            STORAGE_PROTOCOL_SPECIFIC_NVME_NVM_COMMAND,
+            direction,
+            nvme_command,
+            data_buffer,
+            return_dw0,
+        )
+    }
+
+    fn nvme_send_protocol_command(
+        &self,
+        command_specific: u32,
+        direction: u8,
+        nvme_command: &NVME_COMMAND,
+        data_buffer: &mut [u8],
+        return_dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        let admin = command_specific == STORAGE_PROTOCOL_SPECIFIC_NVME_ADMIN_COMMAND;
+        let opcode = nvme_command.CDW0.OPC();
+        if admin && opcode == NVME_ADMIN_COMMANDS::NVME_ADMIN_COMMAND_ASYNC_EVENT_REQUEST as u8 {
+            return Err(nvme_aer_inbox_error());
+        }
+        self.retry.get().run(admin, opcode, || {
+            self.nvme_send_protocol_command_once(
+                command_specific,
+                direction,
+                nvme_command,
+                data_buffer,
+                return_dw0,
+            )
+        })
+    }
+
+    fn nvme_send_protocol_command_once(
+        &self,
+        command_specific: u32,
+        direction: u8,
+        nvme_command: &NVME_COMMAND,
+        data_buffer: &mut [u8],
+        return_dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        let admin = command_specific == STORAGE_PROTOCOL_SPECIFIC_NVME_ADMIN_COMMAND;
         let command_offset = offset_of!(STORAGE_PROTOCOL_COMMAND, Command);
         let buffer_size = command_offset + size_of::<NVME_COMMAND>() + data_buffer.len();
         let mut buffer = vec![0; buffer_size];
@@ -176,6 +289,8 @@ impl InboxDriver {
         protocol_command
             .new()
             .nvme_command(nvme_command)
+            .command_specific(command_specific)
+            .timeout(self.timeout(admin, nvme_command.CDW0.OPC()))
             .set_data_in(direction, data_buffer);
 
         let mut returned_length = 0;
@@ -207,7 +322,7 @@ impl InboxDriver {
         &self,
         property_id: i32,
         protocol_data: &mut STORAGE_PROTOCOL_SPECIFIC_DATA,
-    ) -> io::Result<&[u8]> {
+    ) -> io::Result<Vec<u8>> {
         let data_length = protocol_data.ProtocolDataLength as usize;
         let data_offset = offset_of!(STORAGE_PROPERTY_QUERY, AdditionalParameters);
         let query_size = data_offset + size_of::<STORAGE_PROTOCOL_SPECIFIC_DATA>() + data_length;
@@ -253,10 +368,11 @@ impl InboxDriver {
         unsafe {
             std::ptr::copy_nonoverlapping(protocol_specific_data_ptr, protocol_data, 1);
         }
-        Ok(protocol_specific_data.get_data())
+        // the returned data lives in `buffer`, so hand back an owned copy
+        Ok(protocol_specific_data.get_data().to_vec())
     }
 
-    pub fn nvme_identify_query(&self, cns: u32, nsid: u32) -> io::Result<&[u8]> {
+    pub fn nvme_identify_query(&self, cns: u32, nsid: u32) -> io::Result<Vec<u8>> {
         let mut protocol_specific_data = STORAGE_PROTOCOL_SPECIFIC_DATA::new(
             NVMeDataTypeIdentify,
             cns,
@@ -269,7 +385,7 @@ impl InboxDriver {
         )
     }
 
-    pub fn nvme_logpage_query(&self, lid: u32, cdw11: u32) -> io::Result<&[u8]> {
+    pub fn nvme_logpage_query(&self, lid: u32, cdw11: u32) -> io::Result<Vec<u8>> {
         let mut protocol_specific_data =
             STORAGE_PROTOCOL_SPECIFIC_DATA::new(NVMeDataTypeLogPage, lid, cdw11, NVME_MAX_LOG_SIZE);
         self.nvme_send_query_command(
@@ -288,12 +404,29 @@ impl InboxDriver {
         .map(|_| protocol_specific_data.FixedProtocolReturnData)
     }
 
+    /// Get Features for the features that return a data structure (e.g. APST)
+    pub fn nvme_getfeature_data_query(
+        &self,
+        fid: u32,
+        cdw11: u32,
+        length: usize,
+    ) -> io::Result<(u32, Vec<u8>)> {
+        let mut protocol_specific_data =
+            STORAGE_PROTOCOL_SPECIFIC_DATA::new(NVMeDataTypeFeature, fid, cdw11, length);
+        self.nvme_send_query_command(
+            StorageDeviceProtocolSpecificProperty,
+            &mut protocol_specific_data,
+        )
+        .map(|data| (protocol_specific_data.FixedProtocolReturnData, data))
+    }
+
     pub fn nvme_send_set_command(
         &self,
         property_id: i32,
         protocol_data: &STORAGE_PROTOCOL_SPECIFIC_DATA_EXT,
-    ) -> io::Result<&[u8]> {
-        let data_length = protocol_data.ProtocolDataLength as usize;
+        data: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let data_length = (protocol_data.ProtocolDataLength as usize).max(data.len());
         let data_offset = offset_of!(STORAGE_PROPERTY_SET, AdditionalParameters);
         let set_size = data_offset + size_of::<STORAGE_PROTOCOL_SPECIFIC_DATA_EXT>() + data_length;
         let mut buffer = vec![0u8; set_size];
@@ -307,6 +440,10 @@ impl InboxDriver {
         unsafe {
             std::ptr::copy_nonoverlapping(protocol_data, protocol_specific_data_ptr, 1);
         }
+        if !data.is_empty() {
+            let data_start = data_offset + protocol_data.ProtocolDataOffset as usize;
+            buffer[data_start..data_start + data.len()].copy_from_slice(data);
+        }
         let mut returned_length = 0;
         if unsafe {
             DeviceIoControl(
@@ -335,7 +472,7 @@ impl InboxDriver {
             ));
         }
 
-        Ok(data_descriptor.ProtocolSpecificData.get_data())
+        Ok(data_descriptor.ProtocolSpecificData.get_data().to_vec())
     }
 
     pub fn nvme_set_features(&self, fid: u32, cdw11: u32) -> io::Result<u32> {
@@ -348,6 +485,19 @@ impl InboxDriver {
         self.nvme_send_set_command(
             StorageAdapterProtocolSpecificProperty,
             &protocol_specific_data,
+            &[],
+        )
+        .map(|_| protocol_specific_data.FixedProtocolReturnData)
+    }
+
+    /// Set Features with a data structure following the command (e.g. APST table)
+    pub fn nvme_set_features_data(&self, fid: u32, cdw11: u32, data: &[u8]) -> io::Result<u32> {
+        let protocol_specific_data =
+            STORAGE_PROTOCOL_SPECIFIC_DATA_EXT::new(NVMeDataTypeFeature, fid, cdw11, data.len());
+        self.nvme_send_set_command(
+            StorageAdapterProtocolSpecificProperty,
+            &protocol_specific_data,
+            data,
         )
         .map(|_| protocol_specific_data.FixedProtocolReturnData)
     }
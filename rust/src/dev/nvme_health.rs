@@ -0,0 +1,621 @@
+// SMART / Health rules for long-running monitoring. NvmeHealthWatch keeps the
+// previous sample of one controller and raises an alert when a rule's condition
+// starts (or stops) holding, so a hot drive is reported once rather than at
+// every poll. Alerts go to stdout, a JSON-lines file or a local HTTP webhook.
+use crate::dev::nvme_decode::NvmeSmartLog;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const NVME_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which checks run, all of them by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvmeHealthRules {
+    /// composite temperature against WCTEMP and CCTEMP
+    pub temperature: bool,
+    /// available spare against its threshold
+    pub spare: bool,
+    /// percentage-used points between alerts, 0 to disable
+    pub percentage_used_step: u8,
+    pub media_errors: bool,
+    pub error_log_entries: bool,
+}
+
+impl Default for NvmeHealthRules {
+    fn default() -> Self {
+        Self {
+            temperature: true,
+            spare: true,
+            percentage_used_step: 1,
+            media_errors: true,
+            error_log_entries: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NvmeHealthRule {
+    Temperature,
+    AvailableSpare,
+    PercentageUsed,
+    MediaErrors,
+    ErrorLogEntries,
+}
+
+impl NvmeHealthRule {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::AvailableSpare => "available-spare",
+            Self::PercentageUsed => "percentage-used",
+            Self::MediaErrors => "media-errors",
+            Self::ErrorLogEntries => "error-log-entries",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NvmeAlertSeverity {
+    /// a condition cleared
+    Info,
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for NvmeAlertSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Critical => "critical",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NvmeHealthAlert {
+    /// seconds since the Unix epoch
+    pub timestamp: u64,
+    pub device: String,
+    pub rule: NvmeHealthRule,
+    pub severity: NvmeAlertSeverity,
+    pub message: String,
+    pub value: u64,
+    pub threshold: u64,
+}
+
+impl fmt::Display for NvmeHealthAlert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {} {}: {}",
+            self.severity,
+            self.device,
+            self.rule.name(),
+            self.message
+        )
+    }
+}
+
+fn kelvin(k: u64) -> String {
+    format!("{} K ({} C)", k, k as i64 - 273)
+}
+
+/// Rule state for one controller
+pub struct NvmeHealthWatch {
+    pub device: String,
+    pub rules: NvmeHealthRules,
+    /// Warning and Critical Composite Temperature Thresholds from Identify
+    /// Controller, Kelvin; 0 when not reported
+    pub wctemp: u16,
+    pub cctemp: u16,
+    last: Option<NvmeSmartLog>,
+    temperature_level: Option<NvmeAlertSeverity>,
+    spare_low: bool,
+    used_reported: u8,
+}
+
+impl NvmeHealthWatch {
+    pub fn new(device: &str, rules: NvmeHealthRules, wctemp: u16, cctemp: u16) -> Self {
+        Self {
+            device: device.to_string(),
+            rules,
+            wctemp,
+            cctemp,
+            last: None,
+            temperature_level: None,
+            spare_low: false,
+            used_reported: 0,
+        }
+    }
+
+    pub fn last(&self) -> Option<&NvmeSmartLog> {
+        self.last.as_ref()
+    }
+
+    /// Compare a new sample with the previous one. The first sample is the
+    /// baseline for the counters; conditions already present are reported.
+    pub fn evaluate(&mut self, smart: &NvmeSmartLog) -> Vec<NvmeHealthAlert> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut alerts = vec![];
+        let mut alert = |rule, severity, message: String, value: u64, threshold: u64| {
+            alerts.push(NvmeHealthAlert {
+                timestamp,
+                device: self.device.clone(),
+                rule,
+                severity,
+                message,
+                value,
+                threshold,
+            })
+        };
+
+        if self.rules.temperature {
+            let temperature = smart.temperature as u64;
+            let level = if self.cctemp != 0 && temperature >= self.cctemp as u64 {
+                Some((NvmeAlertSeverity::Critical, "CCTEMP", self.cctemp))
+            } else if self.wctemp != 0 && temperature >= self.wctemp as u64 {
+                Some((NvmeAlertSeverity::Warning, "WCTEMP", self.wctemp))
+            } else {
+                None
+            };
+            let severity = level.map(|(severity, _, _)| severity);
+            if severity != self.temperature_level {
+                match level {
+                    Some((severity, name, threshold)) => alert(
+                        NvmeHealthRule::Temperature,
+                        severity,
+                        format!(
+                            "composite temperature {} at or above {} {}",
+                            kelvin(temperature),
+                            name,
+                            kelvin(threshold as u64)
+                        ),
+                        temperature,
+                        threshold as u64,
+                    ),
+                    None => {
+                        // the lowest threshold reported; with neither there
+                        // was no alert to recover from
+                        let (name, threshold) = if self.wctemp != 0 {
+                            ("WCTEMP", self.wctemp)
+                        } else {
+                            ("CCTEMP", self.cctemp)
+                        };
+                        alert(
+                            NvmeHealthRule::Temperature,
+                            NvmeAlertSeverity::Info,
+                            format!(
+                                "composite temperature {} back below {} {}",
+                                kelvin(temperature),
+                                name,
+                                kelvin(threshold as u64)
+                            ),
+                            temperature,
+                            threshold as u64,
+                        )
+                    }
+                }
+                self.temperature_level = severity;
+            }
+        }
+
+        if self.rules.spare {
+            let low = smart.available_spare < smart.available_spare_threshold;
+            if low != self.spare_low {
+                alert(
+                    NvmeHealthRule::AvailableSpare,
+                    if low {
+                        NvmeAlertSeverity::Critical
+                    } else {
+                        NvmeAlertSeverity::Info
+                    },
+                    format!(
+                        "available spare {}% {} threshold {}%",
+                        smart.available_spare,
+                        if low { "below" } else { "back at or above" },
+                        smart.available_spare_threshold
+                    ),
+                    smart.available_spare as u64,
+                    smart.available_spare_threshold as u64,
+                );
+                self.spare_low = low;
+            }
+        }
+
+        if let Some(last) = &self.last {
+            let step = self.rules.percentage_used_step;
+            if step != 0 && smart.percentage_used >= self.used_reported.saturating_add(step) {
+                alert(
+                    NvmeHealthRule::PercentageUsed,
+                    if smart.percentage_used >= 100 {
+                        NvmeAlertSeverity::Critical
+                    } else {
+                        NvmeAlertSeverity::Warning
+                    },
+                    format!(
+                        "percentage used grew from {}% to {}%",
+                        self.used_reported, smart.percentage_used
+                    ),
+                    smart.percentage_used as u64,
+                    self.used_reported as u64,
+                );
+                self.used_reported = smart.percentage_used;
+            }
+            if self.rules.media_errors && smart.media_errors > last.media_errors {
+                alert(
+                    NvmeHealthRule::MediaErrors,
+                    NvmeAlertSeverity::Critical,
+                    format!(
+                        "{} new media and data integrity errors, {} in total",
+                        smart.media_errors - last.media_errors,
+                        smart.media_errors
+                    ),
+                    smart.media_errors.min(u64::MAX as u128) as u64,
+                    last.media_errors.min(u64::MAX as u128) as u64,
+                );
+            }
+            if self.rules.error_log_entries && smart.error_log_entries > last.error_log_entries {
+                alert(
+                    NvmeHealthRule::ErrorLogEntries,
+                    NvmeAlertSeverity::Warning,
+                    format!(
+                        "{} new error information log entries, {} in total",
+                        smart.error_log_entries - last.error_log_entries,
+                        smart.error_log_entries
+                    ),
+                    smart.error_log_entries.min(u64::MAX as u128) as u64,
+                    last.error_log_entries.min(u64::MAX as u128) as u64,
+                );
+            }
+        } else {
+            self.used_reported = smart.percentage_used;
+        }
+
+        self.last = Some(smart.clone());
+        alerts
+    }
+}
+
+/// Where alerts are sent
+pub enum NvmeAlertSink {
+    Stdout,
+    JsonLines(File),
+    /// `http://host[:port]/path`, posted one alert per request
+    Webhook(String),
+}
+
+impl NvmeAlertSink {
+    /// Append to a JSON-lines file, creating it when missing
+    pub fn json_lines<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::JsonLines(file))
+    }
+
+    pub fn webhook(url: &str) -> io::Result<Self> {
+        webhook_parse(url)?;
+        Ok(Self::Webhook(url.to_string()))
+    }
+
+    pub fn emit(&mut self, alert: &NvmeHealthAlert) -> io::Result<()> {
+        match self {
+            Self::Stdout => {
+                println!("{}", alert);
+                Ok(())
+            }
+            Self::JsonLines(file) => {
+                serde_json::to_writer(&mut *file, alert)?;
+                file.write_all(b"\n")?;
+                file.flush()
+            }
+            Self::Webhook(url) => {
+                let body = serde_json::to_string(alert).map_err(io::Error::other)?;
+                webhook_post(url, &body)
+            }
+        }
+    }
+}
+
+/// Split a webhook URL into host, port and path. Only plain HTTP is
+/// supported, which is what a local alert receiver listens on.
+fn webhook_parse(url: &str) -> io::Result<(String, u16, String)> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: webhook must be an http:// URL", url),
+        )
+    })?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse::<u16>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: invalid port", url),
+                )
+            })?,
+        ),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: missing host", url),
+        ));
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+fn webhook_post(url: &str, body: &str) -> io::Result<()> {
+    let (host, port, path) = webhook_parse(url)?;
+    let address = (host.as_str(), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{}: no address", host)))?;
+    let mut stream = TcpStream::connect_timeout(&address, NVME_WEBHOOK_TIMEOUT)?;
+    stream.set_read_timeout(Some(NVME_WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(NVME_WEBHOOK_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        port,
+        body.len(),
+        body
+    )?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "{}: unexpected response \"{}\"",
+            url, status_line
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WCTEMP: u16 = 353;
+    const CCTEMP: u16 = 358;
+
+    /// SMART / Health page with the fields the rules look at
+    #[derive(Clone, Copy)]
+    struct Page {
+        temperature: u16,
+        spare: u8,
+        used: u8,
+        media_errors: u64,
+        error_entries: u64,
+    }
+
+    const HEALTHY: Page = Page {
+        temperature: 313,
+        spare: 100,
+        used: 3,
+        media_errors: 0,
+        error_entries: 0,
+    };
+
+    fn smart(page: Page) -> NvmeSmartLog {
+        let mut data = vec![0u8; 512];
+        data[1..3].copy_from_slice(&page.temperature.to_le_bytes());
+        data[3] = page.spare;
+        data[4] = 10; // available spare threshold
+        data[5] = page.used;
+        data[160..168].copy_from_slice(&page.media_errors.to_le_bytes());
+        data[176..184].copy_from_slice(&page.error_entries.to_le_bytes());
+        NvmeSmartLog::parse(&data)
+    }
+
+    fn health_watch(wctemp: u16, cctemp: u16) -> NvmeHealthWatch {
+        NvmeHealthWatch::new("nvme0", NvmeHealthRules::default(), wctemp, cctemp)
+    }
+
+    /// Rule and severity of each alert for `page`
+    fn evaluate(
+        watch: &mut NvmeHealthWatch,
+        page: Page,
+    ) -> Vec<(NvmeHealthRule, NvmeAlertSeverity)> {
+        watch
+            .evaluate(&smart(page))
+            .iter()
+            .map(|alert| (alert.rule, alert.severity))
+            .collect()
+    }
+
+    #[test]
+    fn healthy_baseline() {
+        let mut watch = health_watch(WCTEMP, CCTEMP);
+        assert!(evaluate(&mut watch, HEALTHY).is_empty());
+        assert!(evaluate(&mut watch, HEALTHY).is_empty());
+        assert_eq!(watch.last().unwrap().temperature, 313);
+    }
+
+    #[test]
+    fn temperature_levels() {
+        use NvmeAlertSeverity::*;
+        let mut watch = health_watch(WCTEMP, CCTEMP);
+        let at = |temperature| Page {
+            temperature,
+            ..HEALTHY
+        };
+        let temperature = |severity| vec![(NvmeHealthRule::Temperature, severity)];
+
+        assert!(evaluate(&mut watch, at(352)).is_empty());
+        assert_eq!(evaluate(&mut watch, at(353)), temperature(Warning));
+        // reported once while the level holds
+        assert!(evaluate(&mut watch, at(355)).is_empty());
+        assert_eq!(evaluate(&mut watch, at(360)), temperature(Critical));
+        assert_eq!(evaluate(&mut watch, at(354)), temperature(Warning));
+
+        let alerts = watch.evaluate(&smart(at(340)));
+        assert_eq!(alerts[0].severity, Info);
+        assert_eq!(
+            alerts[0].message,
+            "composite temperature 340 K (67 C) back below WCTEMP 353 K (80 C)"
+        );
+        assert_eq!((alerts[0].value, alerts[0].threshold), (340, 353));
+    }
+
+    #[test]
+    fn temperature_thresholds_not_reported() {
+        let hot = Page {
+            temperature: 400,
+            ..HEALTHY
+        };
+        // neither threshold: no rule to break
+        let mut watch = health_watch(0, 0);
+        assert!(evaluate(&mut watch, hot).is_empty());
+        assert!(evaluate(&mut watch, HEALTHY).is_empty());
+
+        // only CCTEMP: recovery names it rather than a WCTEMP of 0 K
+        let mut watch = health_watch(0, CCTEMP);
+        let alerts = watch.evaluate(&smart(hot));
+        assert_eq!(alerts[0].severity, NvmeAlertSeverity::Critical);
+        assert_eq!(alerts[0].threshold, CCTEMP as u64);
+        let alerts = watch.evaluate(&smart(HEALTHY));
+        assert_eq!(alerts[0].severity, NvmeAlertSeverity::Info);
+        assert!(alerts[0]
+            .message
+            .ends_with("back below CCTEMP 358 K (85 C)"));
+        assert_eq!(alerts[0].threshold, CCTEMP as u64);
+
+        // only WCTEMP: never critical
+        let mut watch = health_watch(WCTEMP, 0);
+        let alerts = watch.evaluate(&smart(hot));
+        assert_eq!(alerts[0].severity, NvmeAlertSeverity::Warning);
+    }
+
+    #[test]
+    fn available_spare() {
+        let mut watch = health_watch(WCTEMP, CCTEMP);
+        let spare = |spare| Page { spare, ..HEALTHY };
+        assert!(evaluate(&mut watch, spare(10)).is_empty());
+        let alerts = watch.evaluate(&smart(spare(9)));
+        assert_eq!(alerts[0].rule, NvmeHealthRule::AvailableSpare);
+        assert_eq!(alerts[0].severity, NvmeAlertSeverity::Critical);
+        assert_eq!(alerts[0].message, "available spare 9% below threshold 10%");
+        assert!(evaluate(&mut watch, spare(5)).is_empty());
+        assert_eq!(
+            evaluate(&mut watch, spare(10)),
+            [(NvmeHealthRule::AvailableSpare, NvmeAlertSeverity::Info)]
+        );
+    }
+
+    #[test]
+    fn percentage_used_steps() {
+        let mut watch = NvmeHealthWatch::new(
+            "nvme0",
+            NvmeHealthRules {
+                percentage_used_step: 5,
+                ..Default::default()
+            },
+            WCTEMP,
+            CCTEMP,
+        );
+        let used = |used| Page { used, ..HEALTHY };
+        // the first sample is the baseline, even when worn
+        assert!(evaluate(&mut watch, used(40)).is_empty());
+        assert!(evaluate(&mut watch, used(44)).is_empty());
+        let alerts = watch.evaluate(&smart(used(46)));
+        assert_eq!(alerts[0].severity, NvmeAlertSeverity::Warning);
+        assert_eq!(alerts[0].message, "percentage used grew from 40% to 46%");
+        assert!(evaluate(&mut watch, used(50)).is_empty());
+        assert_eq!(
+            evaluate(&mut watch, used(255)),
+            [(NvmeHealthRule::PercentageUsed, NvmeAlertSeverity::Critical)]
+        );
+    }
+
+    #[test]
+    fn error_counters() {
+        let mut watch = health_watch(WCTEMP, CCTEMP);
+        let errors = |media_errors, error_entries| Page {
+            media_errors,
+            error_entries,
+            ..HEALTHY
+        };
+        // counters already present are the baseline
+        assert!(evaluate(&mut watch, errors(2, 17)).is_empty());
+        let alerts = watch.evaluate(&smart(errors(5, 18)));
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].rule, NvmeHealthRule::MediaErrors);
+        assert_eq!(
+            alerts[0].message,
+            "3 new media and data integrity errors, 5 in total"
+        );
+        assert_eq!((alerts[0].value, alerts[0].threshold), (5, 2));
+        assert_eq!(alerts[1].rule, NvmeHealthRule::ErrorLogEntries);
+        assert_eq!(alerts[1].severity, NvmeAlertSeverity::Warning);
+        assert!(evaluate(&mut watch, errors(5, 18)).is_empty());
+
+        let mut watch = NvmeHealthWatch::new(
+            "nvme0",
+            NvmeHealthRules {
+                media_errors: false,
+                error_log_entries: false,
+                ..Default::default()
+            },
+            WCTEMP,
+            CCTEMP,
+        );
+        evaluate(&mut watch, HEALTHY);
+        assert!(evaluate(&mut watch, errors(5, 18)).is_empty());
+    }
+
+    #[test]
+    fn alert_output() {
+        let mut watch = health_watch(WCTEMP, CCTEMP);
+        let alerts = watch.evaluate(&smart(Page {
+            spare: 9,
+            ..HEALTHY
+        }));
+        assert_eq!(
+            alerts[0].to_string(),
+            "[critical] nvme0 available-spare: available spare 9% below threshold 10%"
+        );
+        let json: serde_json::Value = serde_json::to_value(&alerts[0]).unwrap();
+        assert_eq!(json["rule"], "available-spare");
+        assert_eq!(json["severity"], "critical");
+        assert_eq!(
+            (json["value"].as_u64(), json["threshold"].as_u64()),
+            (Some(9), Some(10))
+        );
+    }
+
+    #[test]
+    fn webhook_urls() {
+        assert_eq!(
+            webhook_parse("http://localhost:8080/alerts").unwrap(),
+            ("localhost".to_string(), 8080, "/alerts".to_string())
+        );
+        assert_eq!(
+            webhook_parse("http://10.0.0.1").unwrap(),
+            ("10.0.0.1".to_string(), 80, "/".to_string())
+        );
+        assert!(webhook_parse("https://example.com/").is_err());
+        assert!(webhook_parse("http://host:port/").is_err());
+        assert!(webhook_parse("http://:80/").is_err());
+    }
+}
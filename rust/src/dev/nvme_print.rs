@@ -1,4 +1,7 @@
+use crate::dev::nvme_commands::{NvmeApstTable, NvmeReservationStatus, NvmeZoneReport};
+use crate::dev::nvme_decode::*;
 use crate::dev::nvme_define::*;
+use crate::dev::opal::{self, Token};
 
 pub fn print_nvme_identify_controller_data(data: &NVME_IDENTIFY_CONTROLLER_DATA) {
     println!("{:<12} : 0x{:04X}", "vid", data.VID);
@@ -181,3 +184,507 @@ pub fn print_nvme_get_feature(fid: u32, value: u32) {
 pub fn print_nvme_set_feature(fid: u32, value: u32) {
     print_nvme_get_feature(fid, value);
 }
+
+pub fn print_nvme_apst_table(
+    apste: bool,
+    table: &NvmeApstTable,
+    ctrl: &NVME_IDENTIFY_CONTROLLER_DATA,
+) {
+    println!("Autonomous Power State Transition");
+    println!("  APSTE: {}", apste as u8);
+    println!(
+        "  {:>3}  {:>5}  {:>10}  {:>4}  {:>10}  {:>10}",
+        "ps", "itps", "itpt(ms)", "nops", "enlat(us)", "exlat(us)"
+    );
+    for (ps, entry) in table.iter().enumerate().take(ctrl.NPSS as usize + 1) {
+        let psd = &ctrl.PDS[ps];
+        println!(
+            "  {:>3}  {:>5}  {:>10}  {:>4}  {:>10}  {:>10}",
+            ps,
+            entry.IdleTransitionPowerState(),
+            entry.IdleTimePriorToTransition(),
+            psd.NOPS(),
+            psd.ENLAT(),
+            psd.EXLAT()
+        );
+    }
+}
+
+fn nvme_resv_type_name(rtype: u8) -> &'static str {
+    match rtype {
+        0 => "None",
+        1 => "Write Exclusive",
+        2 => "Exclusive Access",
+        3 => "Write Exclusive - Registrants Only",
+        4 => "Exclusive Access - Registrants Only",
+        5 => "Write Exclusive - All Registrants",
+        6 => "Exclusive Access - All Registrants",
+        _ => "Reserved",
+    }
+}
+
+pub fn print_nvme_resv_report(status: &NvmeReservationStatus) {
+    println!("Reservation Status");
+    println!("  Generation: {}", status.generation);
+    println!(
+        "  Reservation Type: {} ({})",
+        status.rtype,
+        nvme_resv_type_name(status.rtype)
+    );
+    println!(
+        "  Number of Registered Controllers: {}",
+        status.registrants.len()
+    );
+    println!("  Persist Through Power Loss State: {}", status.ptpls);
+    match status.holder() {
+        Some(holder) => println!(
+            "  Holder: cntlid {} rkey 0x{:016X}",
+            holder.cntlid, holder.rkey
+        ),
+        None => println!("  Holder: none"),
+    }
+    for (index, reg) in status.registrants.iter().enumerate() {
+        println!("  Registrant {}", index);
+        println!("    Controller ID: {}", reg.cntlid);
+        println!("    Holds Reservation: {}", reg.holds_reservation as u8);
+        println!("    Reservation Key: 0x{:016X}", reg.rkey);
+        let host_id: String = reg.host_id.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("    Host Identifier: {}", host_id);
+    }
+}
+
+pub fn print_nvme_dir_identify(params: &NVME_DIRECTIVE_IDENTIFY_RETURN_PARAMETERS) {
+    let supported = &params.DirectivesSupported;
+    let enabled = &params.DirectivesEnabled;
+    println!("Directives (Identify Return Parameters)");
+    println!("  {:<10} {:>9} {:>8}", "directive", "supported", "enabled");
+    println!(
+        "  {:<10} {:>9} {:>8}",
+        "identify",
+        supported.Identify(),
+        enabled.Identify()
+    );
+    println!(
+        "  {:<10} {:>9} {:>8}",
+        "streams",
+        supported.Streams(),
+        enabled.Streams()
+    );
+}
+
+pub fn print_nvme_streams_params(params: &NVME_DIRECTIVE_STREAMS_RETURN_PARAMETERS) {
+    println!("Streams Directive (Return Parameters)");
+    println!("  Max Streams Limit (MSL): {}", params.MSL);
+    println!("  NVM Subsystem Streams Available (NSSA): {}", params.NSSA);
+    println!("  NVM Subsystem Streams Open (NSSO): {}", params.NSSO);
+    println!("  Stream Write Size (SWS): {}", params.SWS);
+    println!("  Stream Granularity Size (SGS): {}", params.SGS);
+    println!("  Namespace Streams Allocated (NSA): {}", params.NSA);
+    println!("  Namespace Streams Open (NSO): {}", params.NSO);
+}
+
+pub fn print_nvme_streams_status(stream_ids: &[u16]) {
+    println!("Streams Directive (Get Status)");
+    println!("  Open Stream Count: {}", stream_ids.len());
+    for id in stream_ids {
+        println!("  Stream Identifier: {}", id);
+    }
+}
+
+pub fn print_hex_dump(data: &[u8]) {
+    for (index, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!("{:06x}: {:<47}  {}", index * 16, hex.join(" "), ascii);
+    }
+}
+
+/// Submission queue entry dword by dword, then as bytes
+pub fn print_nvme_sqe(sqe: &[u8; 64]) {
+    const NAMES: [&str; 16] = [
+        "cdw0", "nsid", "cdw2", "cdw3", "mptr", "mptr_hi", "prp1", "prp1_hi", "prp2", "prp2_hi",
+        "cdw10", "cdw11", "cdw12", "cdw13", "cdw14", "cdw15",
+    ];
+    for (name, dword) in NAMES.iter().zip(sqe.chunks_exact(4)) {
+        let value = u32::from_le_bytes(dword.try_into().unwrap());
+        println!("{:<12} : 0x{:08X}", name, value);
+    }
+    print_hex_dump(sqe);
+}
+
+fn nvme_security_protocol_name(secp: u8) -> &'static str {
+    match secp {
+        0x00 => "Security protocol information",
+        0x01..=0x06 => "TCG",
+        0x07 => "CbCS",
+        0x20 => "Tape Data Encryption",
+        0x21 => "Data Encryption Configuration",
+        0x40 => "SA Creation Capabilities",
+        0x41 => "IKEv2-SCSI",
+        0xEA => "NVMe",
+        0xEC => "JEDEC UFS",
+        0xED => "SDcard TrustedFlash",
+        0xEE => "IEEE 1667",
+        0xEF => "ATA Device Server Password Security",
+        _ => "Vendor/Reserved",
+    }
+}
+
+pub fn print_nvme_security_protocols(protocols: &[u8]) {
+    println!("Supported Security Protocols: {}", protocols.len());
+    for &secp in protocols {
+        println!("  0x{:02X}: {}", secp, nvme_security_protocol_name(secp));
+    }
+}
+
+pub fn print_nvme_security_certificates(certificate: &[u8]) {
+    println!("Certificate Data: {} bytes", certificate.len());
+    print_hex_dump(certificate);
+}
+
+pub fn print_opal_locking_range(range: u8, columns: &[(u64, Token)]) {
+    println!("Locking Range {}:", range);
+    for (column, value) in columns {
+        let name = match *column {
+            opal::COL_RANGE_START => "RangeStart",
+            opal::COL_RANGE_LENGTH => "RangeLength",
+            opal::COL_READ_LOCK_ENABLED => "ReadLockEnabled",
+            opal::COL_WRITE_LOCK_ENABLED => "WriteLockEnabled",
+            opal::COL_READ_LOCKED => "ReadLocked",
+            opal::COL_WRITE_LOCKED => "WriteLocked",
+            opal::COL_LOCK_ON_RESET => "LockOnReset",
+            _ => "Unknown",
+        };
+        match value {
+            Token::Uint(v) => println!("  {:<18} : {}", name, v),
+            other => println!("  {:<18} : {:?}", name, other),
+        }
+    }
+}
+
+fn nvme_zns_limit(value: u32) -> String {
+    // 0's based, all ones means no limit
+    match value {
+        u32::MAX => "no limit".to_string(),
+        n => (n as u64 + 1).to_string(),
+    }
+}
+
+pub fn print_nvme_zns_id_ns(
+    zns: &NVME_IDENTIFY_SPECIFIC_NAMESPACE_IO_COMMAND_SET,
+    ns: &NVME_IDENTIFY_NAMESPACE_DATA,
+) {
+    println!("ZNS Identify Namespace");
+    println!(
+        "  Zone Operation Characteristics (ZOC): vzcap {} zone excursions {}",
+        zns.ZOC.VariableZoneCapacity(),
+        zns.ZOC.ZoneExcursions()
+    );
+    println!(
+        "  Read Across Zone Boundaries (OZCS.RAZB): {}",
+        zns.OZCS.ReadAcrossZoneBoundaries()
+    );
+    println!("  Max Active Resources (MAR): {}", nvme_zns_limit(zns.MAR));
+    println!("  Max Open Resources (MOR): {}", nvme_zns_limit(zns.MOR));
+    println!("  Reset Recommended Limit (RRL): {} s", zns.RRL);
+    println!("  Finish Recommended Limit (FRL): {} s", zns.FRL);
+    let current = ns.FLBAS.LbaFormatIndex() as usize;
+    for (index, format) in zns.LBAEF.iter().enumerate().take(ns.NLBAF as usize + 1) {
+        println!(
+            "  LBAEF {:>2}: Zone Size {} blocks ({} MiB) ZDES {} bytes{}",
+            index,
+            format.ZoneSize,
+            (format.ZoneSize << ns.LBAF[index].LBADS()) >> 20,
+            format.ZDES as usize * 64,
+            if index == current { " (in use)" } else { "" }
+        );
+    }
+}
+
+pub fn print_nvme_zns_id_ctrl(zns: &NVME_IDENTIFY_ZNS_SPECIFIC_CONTROLLER_IO_COMMAND_SET) {
+    println!("ZNS Identify Controller");
+    match zns.ZASL {
+        0 => println!("  Zone Append Size Limit (ZASL): MDTS"),
+        n => println!(
+            "  Zone Append Size Limit (ZASL): {} (2^{} x MPSMIN)",
+            1u64 << n,
+            n
+        ),
+    }
+}
+
+fn nvme_zone_state_name(state: u8) -> &'static str {
+    match state {
+        0x1 => "EMPTY",
+        0x2 => "IMP_OPENED",
+        0x3 => "EXP_OPENED",
+        0x4 => "CLOSED",
+        0xD => "READ_ONLY",
+        0xE => "FULL",
+        0xF => "OFFLINE",
+        _ => "RESERVED",
+    }
+}
+
+pub fn print_nvme_zone_report(report: &NvmeZoneReport) {
+    println!(
+        "Zones: {} (reported {})",
+        report.zone_count,
+        report.zones.len()
+    );
+    for zone in &report.zones {
+        let desc = &zone.desc;
+        let za = &desc.ZA;
+        println!(
+            "  SLBA: 0x{:016X} WP: 0x{:016X} Cap: 0x{:X} State: {:<10} Type: {} Attrs: {}{}{}{}",
+            desc.ZSLBA,
+            desc.WritePointer,
+            desc.ZCAP,
+            nvme_zone_state_name(desc.ZS.ZS()),
+            if desc.ZT.ZT() == 0x2 {
+                "SEQWRITE_REQ"
+            } else {
+                "RESERVED"
+            },
+            if za.ZFC() != 0 { "ZFC " } else { "" },
+            if za.FZR() != 0 { "FZR " } else { "" },
+            if za.RZR() != 0 { "RZR " } else { "" },
+            if za.ZDEV() != 0 { "ZDEV" } else { "" },
+        );
+        if !zone.extension.is_empty() {
+            print_hex_dump(&zone.extension);
+        }
+    }
+}
+
+fn kelvin(value: u16) -> String {
+    format!("{} K ({} C)", value, value as i32 - 273)
+}
+
+pub fn print_nvme_smart_log(log: &NvmeSmartLog) {
+    println!("SMART / Health Information");
+    println!("  Critical Warning: 0x{:02X}", log.critical_warning);
+    println!("  Composite Temperature: {}", kelvin(log.temperature));
+    println!("  Available Spare: {}%", log.available_spare);
+    println!(
+        "  Available Spare Threshold: {}%",
+        log.available_spare_threshold
+    );
+    println!("  Percentage Used: {}%", log.percentage_used);
+    println!(
+        "  Endurance Group Critical Warning Summary: 0x{:02X}",
+        log.endurance_group_critical_warning
+    );
+    println!("  Data Units Read: {}", log.data_units_read);
+    println!("  Data Units Written: {}", log.data_units_written);
+    println!("  Host Read Commands: {}", log.host_read_commands);
+    println!("  Host Write Commands: {}", log.host_write_commands);
+    println!("  Controller Busy Time: {}", log.controller_busy_time);
+    println!("  Power Cycles: {}", log.power_cycles);
+    println!("  Power On Hours: {}", log.power_on_hours);
+    println!("  Unsafe Shutdowns: {}", log.unsafe_shutdowns);
+    println!("  Media and Data Integrity Errors: {}", log.media_errors);
+    println!("  Error Information Log Entries: {}", log.error_log_entries);
+    println!(
+        "  Warning Composite Temperature Time: {}",
+        log.warning_temperature_time
+    );
+    println!(
+        "  Critical Composite Temperature Time: {}",
+        log.critical_temperature_time
+    );
+    for (index, &sensor) in log.temperature_sensors.iter().enumerate() {
+        if sensor != 0 {
+            println!("  Temperature Sensor {}: {}", index + 1, kelvin(sensor));
+        }
+    }
+    for index in 0..2 {
+        println!(
+            "  Thermal Management T{} Transitions: {}, Total Time: {}",
+            index + 1,
+            log.thermal_transitions[index],
+            log.thermal_time[index]
+        );
+    }
+}
+
+pub fn print_nvme_error_log(entries: &[NvmeErrorLogEntry]) {
+    println!("Error Information Log: {} entries", entries.len());
+    for entry in entries {
+        println!("  Error Count: {}", entry.error_count);
+        println!("    SQID: {} CMDID: 0x{:04X}", entry.sqid, entry.cmdid);
+        println!(
+            "    Status: {} (SCT 0x{:X} SC 0x{:02X}){}{}",
+            entry.status,
+            entry.sct,
+            entry.sc,
+            if entry.more { ", More" } else { "" },
+            if entry.dnr { ", Do Not Retry" } else { "" }
+        );
+        println!(
+            "    Parameter Error Location: byte {} bit {}",
+            entry.parameter_byte, entry.parameter_bit
+        );
+        println!("    LBA: 0x{:016X}", entry.lba);
+        println!("    NSID: 0x{:X}", entry.nsid);
+        println!(
+            "    Vendor Specific Log Page: 0x{:02X}",
+            entry.vendor_log_page
+        );
+        println!("    Transport Type: {}", entry.transport_type);
+        println!("    Command Specific: 0x{:016X}", entry.command_specific);
+    }
+}
+
+pub fn print_nvme_fw_slot_log(log: &NvmeFirmwareSlotLog) {
+    println!("Firmware Slot Information");
+    println!("  Active Slot: {}", log.active_slot);
+    if log.next_slot != 0 {
+        println!("  Next Reset Activates Slot: {}", log.next_slot);
+    }
+    for slot in &log.slots {
+        println!("  Slot {}: {}", slot.slot, slot.revision);
+    }
+}
+
+pub fn print_nvme_effects_log(log: &NvmeCommandEffectsLog) {
+    println!("Commands Supported and Effects");
+    for (set, effects) in [("Admin", &log.admin), ("I/O", &log.io)] {
+        println!("  {} Commands", set);
+        for effect in effects {
+            println!(
+                "    0x{:02X}: 0x{:08X} {}{}{}{}CSE {}",
+                effect.opcode,
+                effect.raw,
+                if effect.lbcc { "LBCC " } else { "" },
+                if effect.ncc { "NCC " } else { "" },
+                if effect.nic { "NIC " } else { "" },
+                if effect.ccc { "CCC " } else { "" },
+                effect.cse
+            );
+        }
+    }
+}
+
+pub fn print_nvme_self_test_log(log: &NvmeSelfTestLog) {
+    println!("Device Self-test Log");
+    if log.current_operation != 0 {
+        println!(
+            "  Current Operation: {} ({}% complete)",
+            nvme_self_test_code_name(log.current_operation),
+            log.current_completion
+        );
+    } else {
+        println!("  Current Operation: none");
+    }
+    for (index, result) in log.results.iter().enumerate() {
+        println!(
+            "  Result {}: {} ({})",
+            index,
+            nvme_self_test_code_name(result.code),
+            result.result_name
+        );
+        println!("    Power On Hours: {}", result.power_on_hours);
+        if result.segment != 0 {
+            println!("    Failed Segment: {}", result.segment);
+        }
+        if let Some(nsid) = result.nsid {
+            println!("    NSID: 0x{:X}", nsid);
+        }
+        if let Some(lba) = result.failing_lba {
+            println!("    Failing LBA: 0x{:016X}", lba);
+        }
+        if let (Some(sct), Some(sc)) = (result.sct, result.sc) {
+            println!("    Status: SCT 0x{:X} SC 0x{:02X}", sct, sc);
+        }
+    }
+}
+
+pub fn print_nvme_sanitize_log(log: &NvmeSanitizeLog) {
+    println!("Sanitize Status");
+    println!("  Progress: {:.1}%", log.progress as f64 * 100.0 / 65536.0);
+    println!("  Status: {} ({})", log.status, log.status_name);
+    println!("  Completed Overwrite Passes: {}", log.overwrite_passes);
+    println!("  Global Data Erased: {}", log.global_data_erased as u8);
+    println!("  Last Sanitize CDW10: 0x{:08X}", log.cdw10);
+    for (name, time) in [
+        ("Overwrite", log.time_overwrite),
+        ("Block Erase", log.time_block_erase),
+        ("Crypto Erase", log.time_crypto_erase),
+        ("Overwrite (No-Deallocate)", log.time_overwrite_no_dealloc),
+        (
+            "Block Erase (No-Deallocate)",
+            log.time_block_erase_no_dealloc,
+        ),
+        (
+            "Crypto Erase (No-Deallocate)",
+            log.time_crypto_erase_no_dealloc,
+        ),
+    ] {
+        match time {
+            u32::MAX => println!("  Estimated Time {}: not reported", name),
+            _ => println!("  Estimated Time {}: {} s", name, time),
+        }
+    }
+}
+
+pub fn print_nvme_telemetry_header(header: &NvmeTelemetryHeader) {
+    println!("Telemetry Log Header (LID 0x{:02X})", header.log_id);
+    println!("  IEEE OUI: {}", header.ieee);
+    for (index, block) in header.area_last_block.iter().enumerate() {
+        println!("  Data Area {} Last Block: {}", index + 1, block);
+    }
+    println!("  Host Generation Number: {}", header.host_generation);
+    println!(
+        "  Controller Data Available: {}",
+        header.controller_available as u8
+    );
+    println!(
+        "  Controller Generation Number: {}",
+        header.controller_generation
+    );
+    println!("  Reason Identifier: {}", header.reason_identifier);
+}
+
+pub fn print_nvme_persistent_event_log(log: &NvmePersistentEventLog) {
+    println!("Persistent Event Log");
+    println!("  Total Number of Events: {}", log.total_events);
+    println!("  Total Log Length: {}", log.total_length);
+    println!("  Log Revision: {}", log.revision);
+    println!("  Log Header Length: {}", log.header_length);
+    println!("  Timestamp: {}", log.timestamp);
+    println!("  Power On Hours: {}", log.power_on_hours);
+    println!("  Power Cycle Count: {}", log.power_cycles);
+    println!("  VID: 0x{:04X} SSVID: 0x{:04X}", log.vid, log.ssvid);
+    println!("  SN: {}", log.sn);
+    println!("  MN: {}", log.mn);
+    println!("  SUBNQN: {}", log.subnqn);
+    let supported: Vec<String> = log
+        .supported_events
+        .iter()
+        .map(|t| format!("0x{:02X}", t))
+        .collect();
+    println!("  Supported Events: {}", supported.join(" "));
+    for (index, event) in log.events.iter().enumerate() {
+        println!(
+            "  Event {}: 0x{:02X} {} (revision {})",
+            index, event.event_type, event.name, event.revision
+        );
+        println!("    Controller ID: {}", event.controller_id);
+        println!("    Timestamp: {}", event.timestamp);
+        println!(
+            "    Vendor Specific Information Length: {}",
+            event.vendor_info_len
+        );
+        println!("    Data: {}", event.data);
+    }
+}
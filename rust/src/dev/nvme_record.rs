@@ -0,0 +1,525 @@
+// Record and replay for NvmeTransport. NvmeRecorder passes commands through to
+// a real transport and appends one JSON line per command to a trace file;
+// NvmeReplay answers identical commands from such a file, so issues seen on a
+// drive can be reproduced, and tests run, on machines without it.
+//
+// Only commands submitted through an NvmeTransport are traced. The InboxDriver
+// library commands (identify, log pages, features, format, security and the
+// rest) issue their IOCTLs directly, so a recording holds the passthru and
+// vendor commands sent through the recorder and nothing else; the CLI refuses
+// --record and --replay for the commands it would not trace.
+use crate::dev::nvme_commands::{NvmeOpcodeType, NvmeTransport};
+use crate::dev::nvme_define::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// One command and its completion, a line of the trace file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NvmeTraceRecord {
+    pub admin: bool,
+    pub opcode: u8,
+    pub direction: u8,
+    /// the 64-byte submission queue entry, hex
+    pub command: String,
+    pub data_len: usize,
+    /// data sent to the controller, hex
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub data_out: String,
+    /// data returned by the controller, hex
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub data_in: String,
+    pub dw0: u32,
+    pub status: u16,
+    pub latency_us: u64,
+    /// transport error instead of a completion, as "Kind: message"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> io::Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "odd number of hex digits",
+        ));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&text[i..i + 2], 16)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+fn error_to_string(error: &io::Error) -> String {
+    format!("{:?}: {}", error.kind(), error)
+}
+
+fn error_from_string(text: &str) -> io::Error {
+    let (kind, message) = text.split_once(": ").unwrap_or(("Other", text));
+    let kind = match kind {
+        "NotFound" => io::ErrorKind::NotFound,
+        "PermissionDenied" => io::ErrorKind::PermissionDenied,
+        "InvalidInput" => io::ErrorKind::InvalidInput,
+        "InvalidData" => io::ErrorKind::InvalidData,
+        "TimedOut" => io::ErrorKind::TimedOut,
+        "Unsupported" => io::ErrorKind::Unsupported,
+        "ResourceBusy" => io::ErrorKind::ResourceBusy,
+        "NotConnected" => io::ErrorKind::NotConnected,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, message.to_string())
+}
+
+fn data_sent(direction: u8) -> bool {
+    direction & NvmeOpcodeType::WRITE as u8 != 0
+}
+
+fn data_returned(direction: u8) -> bool {
+    direction & NvmeOpcodeType::READ as u8 != 0
+}
+
+/// Passes commands to `transport` and writes each one to the trace
+pub struct NvmeRecorder<T: NvmeTransport, W: Write> {
+    transport: T,
+    trace: RefCell<W>,
+}
+
+impl<T: NvmeTransport> NvmeRecorder<T, File> {
+    /// Record into a new trace file at `path`
+    pub fn create<P: AsRef<Path>>(transport: T, path: P) -> io::Result<Self> {
+        Ok(Self::new(transport, File::create(path)?))
+    }
+}
+
+impl<T: NvmeTransport, W: Write> NvmeRecorder<T, W> {
+    pub fn new(transport: T, trace: W) -> Self {
+        Self {
+            transport,
+            trace: RefCell::new(trace),
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn into_inner(self) -> (T, W) {
+        (self.transport, self.trace.into_inner())
+    }
+
+    fn record(
+        &self,
+        admin: bool,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        let data_out = if data_sent(direction) {
+            to_hex(data)
+        } else {
+            String::new()
+        };
+        let start = Instant::now();
+        let result = if admin {
+            self.transport.admin_passthru(direction, command, data, dw0)
+        } else {
+            self.transport.io_passthru(direction, command, data, dw0)
+        };
+        let latency = start.elapsed();
+
+        let mut record = NvmeTraceRecord {
+            admin,
+            opcode: command.CDW0.OPC(),
+            direction,
+            command: to_hex(&command.to_bytes()),
+            data_len: data.len(),
+            data_out,
+            data_in: String::new(),
+            dw0: *dw0,
+            status: 0,
+            latency_us: latency.as_micros() as u64,
+            error: None,
+        };
+        match &result {
+            Ok(status) => {
+                record.status = u16::from(*status);
+                if data_returned(direction) {
+                    record.data_in = to_hex(data);
+                }
+            }
+            Err(e) => record.error = Some(error_to_string(e)),
+        }
+        let mut trace = self.trace.borrow_mut();
+        serde_json::to_writer(&mut *trace, &record)?;
+        trace.write_all(b"\n")?;
+        trace.flush()?;
+        result
+    }
+}
+
+impl<T: NvmeTransport, W: Write> NvmeTransport for NvmeRecorder<T, W> {
+    fn admin_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.record(true, direction, command, data, dw0)
+    }
+
+    fn io_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.record(false, direction, command, data, dw0)
+    }
+}
+
+/// What makes two submissions identical for replay
+#[derive(PartialEq, Eq, Hash)]
+struct NvmeReplayKey {
+    admin: bool,
+    command: String,
+    data_len: usize,
+    data_out: String,
+}
+
+/// Answers commands from a trace. Identical commands get their recorded
+/// completions in order, the last one repeating once they run out.
+pub struct NvmeReplay {
+    records: Vec<NvmeTraceRecord>,
+    index: HashMap<NvmeReplayKey, Vec<usize>>,
+    next: RefCell<HashMap<usize, usize>>,
+}
+
+impl NvmeReplay {
+    pub fn new(records: Vec<NvmeTraceRecord>) -> Self {
+        let mut index: HashMap<NvmeReplayKey, Vec<usize>> = HashMap::new();
+        for (i, record) in records.iter().enumerate() {
+            let key = NvmeReplayKey {
+                admin: record.admin,
+                command: record.command.clone(),
+                data_len: record.data_len,
+                data_out: record.data_out.clone(),
+            };
+            index.entry(key).or_default().push(i);
+        }
+        Self {
+            records,
+            index,
+            next: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Read a trace written by NvmeRecorder
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut records = Vec::new();
+        for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(&line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), number + 1, e),
+                )
+            })?;
+            records.push(record);
+        }
+        Ok(Self::new(records))
+    }
+
+    pub fn records(&self) -> &[NvmeTraceRecord] {
+        &self.records
+    }
+
+    fn replay(
+        &self,
+        admin: bool,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        let key = NvmeReplayKey {
+            admin,
+            command: to_hex(&command.to_bytes()),
+            data_len: data.len(),
+            data_out: if data_sent(direction) {
+                to_hex(data)
+            } else {
+                String::new()
+            },
+        };
+        let matches = self.index.get(&key).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no recorded {} command {:#04x} matches (nsid {:#x}, {} bytes)",
+                    if admin { "admin" } else { "I/O" },
+                    command.CDW0.OPC(),
+                    command.NSID,
+                    data.len()
+                ),
+            )
+        })?;
+        let record = {
+            let mut next = self.next.borrow_mut();
+            let position = next.entry(matches[0]).or_insert(0);
+            let record = &self.records[matches[(*position).min(matches.len() - 1)]];
+            *position += 1;
+            record
+        };
+
+        if let Some(error) = &record.error {
+            return Err(error_from_string(error));
+        }
+        if data_returned(direction) {
+            let data_in = from_hex(&record.data_in)?;
+            let n = data.len().min(data_in.len());
+            data[..n].copy_from_slice(&data_in[..n]);
+        }
+        *dw0 = record.dw0;
+        Ok(NVME_COMMAND_STATUS::from(record.status))
+    }
+}
+
+impl NvmeTransport for NvmeReplay {
+    fn admin_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.replay(true, direction, command, data, dw0)
+    }
+
+    fn io_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.replay(false, direction, command, data, dw0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev::nvme_define::NVME_ADMIN_COMMANDS::*;
+    use crate::dev::nvme_define::NVME_NVM_COMMANDS::*;
+    use crate::dev::nvme_sim::{NvmeSimulator, NVME_SIM_NSID};
+
+    /// Status, DW0 and data of a command, or the transport error
+    type Completion = io::Result<(u16, u32, Vec<u8>)>;
+
+    fn identify(cns: u32) -> NVME_COMMAND {
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_ADMIN_COMMAND_IDENTIFY as u32).cdw10(cns);
+        nc
+    }
+
+    fn io(opcode: NVME_NVM_COMMANDS, slba: u64) -> NVME_COMMAND {
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(opcode as u32)
+            .nsid(NVME_SIM_NSID)
+            .cdw10(slba as u32)
+            .cdw11((slba >> 32) as u32);
+        nc
+    }
+
+    /// The commands every test sends, with what came back
+    fn session<T: NvmeTransport>(t: &T) -> Vec<Completion> {
+        let read = NvmeOpcodeType::READ as u8;
+        let write = NvmeOpcodeType::WRITE as u8;
+        let mut aer = NVME_COMMAND::default();
+        aer.opcode(NVME_ADMIN_COMMAND_ASYNC_EVENT_REQUEST as u32);
+        let commands = [
+            (true, read, identify(0x01), vec![0u8; 4096]),
+            (false, write, io(NVME_NVM_COMMAND_WRITE, 7), vec![0x5a; 512]),
+            (false, read, io(NVME_NVM_COMMAND_READ, 7), vec![0u8; 512]),
+            (
+                false,
+                read,
+                io(NVME_NVM_COMMAND_READ, 1 << 40),
+                vec![0u8; 512],
+            ),
+            (true, NvmeOpcodeType::NOBUFFER as u8, aer, vec![]),
+        ];
+        commands
+            .into_iter()
+            .map(|(admin, direction, nc, mut data)| {
+                let mut dw0 = 0;
+                let status = if admin {
+                    t.admin_passthru(direction, &nc, &mut data, &mut dw0)?
+                } else {
+                    t.io_passthru(direction, &nc, &mut data, &mut dw0)?
+                };
+                Ok((u16::from(status), dw0, data))
+            })
+            .collect()
+    }
+
+    fn record(sim: &NvmeSimulator) -> (Vec<Completion>, Vec<u8>) {
+        let recorder = NvmeRecorder::new(sim, Vec::new());
+        let results = session(&recorder);
+        (results, recorder.into_inner().1)
+    }
+
+    fn parse(trace: &[u8]) -> Vec<NvmeTraceRecord> {
+        trace
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    fn same(recorded: &[Completion], replayed: &[Completion]) {
+        assert_eq!(recorded.len(), replayed.len());
+        for (a, b) in recorded.iter().zip(replayed) {
+            match (a, b) {
+                (Ok(a), Ok(b)) => assert_eq!(a, b),
+                (Err(a), Err(b)) => {
+                    assert_eq!(a.kind(), b.kind());
+                    assert_eq!(a.to_string(), b.to_string());
+                }
+                _ => panic!("recorded {:?}, replayed {:?}", a, b),
+            }
+        }
+    }
+
+    #[test]
+    fn record_simulator() {
+        let sim = NvmeSimulator::new(1 << 20, 9);
+        let (results, trace) = record(&sim);
+        let records = parse(&trace);
+        assert_eq!(records.len(), 5);
+        assert_eq!(sim.history().len(), 5);
+
+        assert!(records[0].admin);
+        assert_eq!(records[0].opcode, NVME_ADMIN_COMMAND_IDENTIFY as u8);
+        assert_eq!(records[0].data_len, 4096);
+        assert!(records[0].data_out.is_empty());
+        assert_eq!(
+            from_hex(&records[0].data_in).unwrap(),
+            sim.identify_controller()
+        );
+
+        assert!(!records[1].admin);
+        assert_eq!(records[1].data_out, "5a".repeat(512));
+        assert!(records[1].data_in.is_empty());
+        assert_eq!(records[2].data_in, "5a".repeat(512));
+
+        // LBA Out of Range comes back as a completion, the idle AER as an error
+        assert_eq!(NVME_COMMAND_STATUS::from(records[3].status).SC(), 0x80);
+        assert_eq!(results[3].as_ref().unwrap().0, records[3].status);
+        assert!(records[4].error.as_ref().unwrap().starts_with("TimedOut: "));
+        assert!(results[4].is_err());
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let sim = NvmeSimulator::new(1 << 20, 9);
+        let (recorded, trace) = record(&sim);
+        let replay = NvmeReplay::new(parse(&trace));
+        same(&recorded, &session(&replay));
+        // answered from the trace alone
+        assert_eq!(sim.history().len(), 5);
+    }
+
+    #[test]
+    fn replay_from_file() {
+        let path = std::env::temp_dir().join(format!("nvme-trace-{}.jsonl", std::process::id()));
+        let sim = NvmeSimulator::new(1 << 20, 9);
+        let recorded = session(&NvmeRecorder::create(&sim, &path).unwrap());
+        let replay = NvmeReplay::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let replay = replay.unwrap();
+        assert_eq!(replay.records().len(), 5);
+        same(&recorded, &session(&replay));
+    }
+
+    #[test]
+    fn replay_in_order() {
+        let sim = NvmeSimulator::new(1 << 20, 9);
+        // Namespace Not Ready, then success, then the replay keeps the last one
+        sim.inject_status(true, NVME_ADMIN_COMMAND_IDENTIFY as u8, 0, 0x82);
+        let recorder = NvmeRecorder::new(&sim, Vec::new());
+        let nc = identify(0x01);
+        let mut data = vec![0u8; 4096];
+        for _ in 0..2 {
+            recorder
+                .admin_passthru(NvmeOpcodeType::READ as u8, &nc, &mut data, &mut 0)
+                .unwrap();
+        }
+        let replay = NvmeReplay::new(parse(&recorder.into_inner().1));
+        let sc = |replay: &NvmeReplay| {
+            let mut data = vec![0u8; 4096];
+            replay
+                .admin_passthru(NvmeOpcodeType::READ as u8, &nc, &mut data, &mut 0)
+                .unwrap()
+                .SC()
+        };
+        assert_eq!(sc(&replay), 0x82);
+        assert_eq!(sc(&replay), 0);
+        assert_eq!(sc(&replay), 0);
+    }
+
+    #[test]
+    fn replay_unmatched() {
+        let sim = NvmeSimulator::new(1 << 20, 9);
+        let replay = NvmeReplay::new(parse(&record(&sim).1));
+        let write = NvmeOpcodeType::WRITE as u8;
+
+        // other data than was recorded
+        let mut data = vec![0xa5; 512];
+        let error = replay
+            .io_passthru(write, &io(NVME_NVM_COMMAND_WRITE, 7), &mut data, &mut 0)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        // a command that was never sent
+        let mut data = vec![0u8; 4096];
+        let error = replay
+            .admin_passthru(
+                NvmeOpcodeType::READ as u8,
+                &identify(0x02),
+                &mut data,
+                &mut 0,
+            )
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("admin command 0x06"));
+    }
+
+    #[test]
+    fn bad_trace_lines() {
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+        let error = error_from_string("TimedOut: no asynchronous event pending");
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), "no asynchronous event pending");
+        assert_eq!(error_from_string("garbled").kind(), io::ErrorKind::Other);
+    }
+}
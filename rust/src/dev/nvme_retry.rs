@@ -0,0 +1,399 @@
+// Command timeouts and retries. NvmeTimeouts picks a timeout by opcode, since
+// format, sanitize and firmware commit can run far longer than an identify;
+// NvmeRetryPolicy resubmits commands that failed with a transient status and
+// the Do Not Retry bit clear, backing off between attempts. Only commands that
+// can run twice without harm are resubmitted: a Format NVM, Sanitize or
+// Firmware Commit that failed part way is reported, never sent again.
+use crate::dev::nvme_commands::NvmeTransport;
+use crate::dev::nvme_define::NVME_ADMIN_COMMANDS::*;
+use crate::dev::nvme_define::NVME_NVM_COMMANDS::*;
+use crate::dev::nvme_define::NVME_STATUS_GENERIC_COMMAND_CODES::*;
+use crate::dev::nvme_define::NVME_STATUS_TYPES::*;
+use crate::dev::nvme_define::*;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+pub const NVME_DEFAULT_TIMEOUT: u32 = 30; // seconds
+pub const NVME_FORMAT_TIMEOUT: u32 = 3600;
+pub const NVME_SANITIZE_TIMEOUT: u32 = 600;
+pub const NVME_FW_COMMIT_TIMEOUT: u32 = 300;
+pub const NVME_SELF_TEST_TIMEOUT: u32 = 120;
+
+/// Timeout in seconds for each kind of command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvmeTimeouts {
+    pub default: u32,
+    pub format: u32,
+    pub sanitize: u32,
+    pub fw_commit: u32,
+    pub self_test: u32,
+}
+
+impl Default for NvmeTimeouts {
+    fn default() -> Self {
+        Self {
+            default: NVME_DEFAULT_TIMEOUT,
+            format: NVME_FORMAT_TIMEOUT,
+            sanitize: NVME_SANITIZE_TIMEOUT,
+            fw_commit: NVME_FW_COMMIT_TIMEOUT,
+            self_test: NVME_SELF_TEST_TIMEOUT,
+        }
+    }
+}
+
+impl NvmeTimeouts {
+    /// The same timeout for every command
+    pub fn uniform(seconds: u32) -> Self {
+        Self {
+            default: seconds,
+            format: seconds,
+            sanitize: seconds,
+            fw_commit: seconds,
+            self_test: seconds,
+        }
+    }
+
+    pub fn for_command(&self, admin: bool, opcode: u8) -> u32 {
+        if !admin {
+            return self.default;
+        }
+        match opcode {
+            x if x == NVME_ADMIN_COMMAND_FORMAT_NVM as u8 => self.format,
+            x if x == NVME_ADMIN_COMMAND_SANITIZE as u8 => self.sanitize,
+            x if x == NVME_ADMIN_COMMAND_FIRMWARE_COMMIT as u8 => self.fw_commit,
+            x if x == NVME_ADMIN_COMMAND_DEVICE_SELF_TEST as u8 => self.self_test,
+            _ => self.default,
+        }
+    }
+}
+
+impl NVME_COMMAND_STATUS {
+    /// Statuses that describe a passing condition on the controller or the
+    /// path to it, rather than a problem with the command itself
+    pub fn is_transient(&self) -> bool {
+        let sc = self.SC();
+        match self.SCT() {
+            x if x == NVME_STATUS_TYPE_GENERIC_COMMAND as u8 => [
+                NVME_STATUS_COMMAND_ABORTED_DUE_TO_POWER_LOSS_NOTIFICATION as u8,
+                NVME_STATUS_COMMAND_ABORTED_DUE_TO_SQ_DELETION as u8,
+                0x21, // Command Interrupted
+                NVME_STATUS_NVM_NAMESPACE_NOT_READY as u8,
+                NVME_STATUS_FORMAT_IN_PROGRESS as u8,
+            ]
+            .contains(&sc),
+            // path related: internal path error, ANA transition, controller
+            // and host pathing errors, aborted by host
+            3 => matches!(sc, 0x00 | 0x03 | 0x60 | 0x70 | 0x71),
+            _ => false,
+        }
+    }
+
+    /// Failed with a transient status the controller allows to be retried
+    pub fn is_retryable(&self) -> bool {
+        !self.is_success() && self.DNR() == 0 && self.is_transient()
+    }
+}
+
+/// Commands that leave the controller in the same state when sent twice. The
+/// rest, and any vendor specific opcode, are never resubmitted.
+pub fn nvme_idempotent(admin: bool, opcode: u8) -> bool {
+    if admin {
+        [
+            NVME_ADMIN_COMMAND_GET_LOG_PAGE as u8,
+            NVME_ADMIN_COMMAND_IDENTIFY as u8,
+            NVME_ADMIN_COMMAND_SET_FEATURES as u8,
+            NVME_ADMIN_COMMAND_GET_FEATURES as u8,
+            NVME_ADMIN_COMMAND_DIRECTIVE_RECEIVE as u8,
+            NVME_ADMIN_COMMAND_GET_LBA_STATUS as u8,
+        ]
+        .contains(&opcode)
+    } else {
+        [
+            NVME_NVM_COMMAND_FLUSH as u8,
+            NVME_NVM_COMMAND_WRITE as u8,
+            NVME_NVM_COMMAND_READ as u8,
+            NVME_NVM_COMMAND_WRITE_UNCORRECTABLE as u8,
+            NVME_NVM_COMMAND_COMPARE as u8,
+            NVME_NVM_COMMAND_WRITE_ZEROES as u8,
+            NVME_NVM_COMMAND_DATASET_MANAGEMENT as u8,
+            NVME_NVM_COMMAND_VERIFY as u8,
+            NVME_NVM_COMMAND_RESERVATION_REPORT as u8,
+            NVME_NVM_COMMAND_ZONE_MANAGEMENT_RECEIVE as u8,
+        ]
+        .contains(&opcode)
+    }
+}
+
+/// How often and how soon to resubmit a command that failed transiently. The
+/// delay doubles after each attempt, from `backoff` up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvmeRetryPolicy {
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for NvmeRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl NvmeRetryPolicy {
+    /// Submit once and never retry
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before retry number `retry`, counted from 0
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1u32.checked_shl(retry).unwrap_or(u32::MAX))
+            .min(self.max_backoff)
+    }
+
+    /// Call `submit` until it succeeds, fails for good or the retries run out.
+    /// Commands that are not idempotent are submitted once.
+    pub fn run(
+        &self,
+        admin: bool,
+        opcode: u8,
+        mut submit: impl FnMut() -> io::Result<NVME_COMMAND_STATUS>,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        let max_retries = if nvme_idempotent(admin, opcode) {
+            self.max_retries
+        } else {
+            0
+        };
+        let mut retry = 0;
+        loop {
+            let status = submit()?;
+            if retry >= max_retries || !status.is_retryable() {
+                return Ok(status);
+            }
+            thread::sleep(self.delay(retry));
+            retry += 1;
+        }
+    }
+}
+
+/// Applies a retry policy to every command sent through `transport`
+pub struct NvmeRetry<T: NvmeTransport> {
+    transport: T,
+    policy: NvmeRetryPolicy,
+}
+
+impl<T: NvmeTransport> NvmeRetry<T> {
+    pub fn new(transport: T, policy: NvmeRetryPolicy) -> Self {
+        Self { transport, policy }
+    }
+
+    pub fn policy(&self) -> &NvmeRetryPolicy {
+        &self.policy
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: NvmeTransport> NvmeTransport for NvmeRetry<T> {
+    fn admin_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.policy.run(true, command.CDW0.OPC(), || {
+            self.transport.admin_passthru(direction, command, data, dw0)
+        })
+    }
+
+    fn io_passthru(
+        &self,
+        direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.policy.run(false, command.CDW0.OPC(), || {
+            self.transport.io_passthru(direction, command, data, dw0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev::nvme_commands::NvmeOpcodeType;
+    use crate::dev::nvme_sim::{nvme_status, NvmeSimulator};
+    use std::cell::Cell;
+
+    fn no_wait(max_retries: u32) -> NvmeRetryPolicy {
+        NvmeRetryPolicy {
+            max_retries,
+            backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn retryable_statuses() {
+        // generic: interrupted, aborted and not-ready completions
+        for sc in [0x05, 0x08, 0x21, 0x82, 0x84] {
+            assert!(nvme_status(0, sc).is_retryable(), "generic {:#x}", sc);
+        }
+        // generic: problems with the command, or a retry would be wrong
+        for sc in [0x01, 0x02, 0x04, 0x06, 0x07, 0x0b, 0x1d, 0x80, 0x81] {
+            assert!(!nvme_status(0, sc).is_retryable(), "generic {:#x}", sc);
+        }
+        // path related
+        for sc in [0x00, 0x03, 0x60, 0x70, 0x71] {
+            assert!(nvme_status(3, sc).is_retryable(), "path {:#x}", sc);
+        }
+        assert!(!nvme_status(3, 0x01).is_retryable());
+        // command specific, media and vendor specific never are
+        for sct in [1, 2, 7] {
+            for sc in [0x00, 0x05, 0x21, 0x82] {
+                assert!(!nvme_status(sct, sc).is_retryable(), "{} {:#x}", sct, sc);
+            }
+        }
+        assert!(!nvme_status(0, 0).is_retryable());
+    }
+
+    #[test]
+    fn do_not_retry() {
+        for (sct, sc) in [(0, 0x21), (0, 0x82), (3, 0x03)] {
+            let status = nvme_status(sct, sc);
+            assert!(status.is_transient());
+            assert!(status.is_retryable());
+            let status = status.with_DNR(1);
+            assert!(status.is_transient());
+            assert!(!status.is_retryable());
+        }
+    }
+
+    #[test]
+    fn backoff_delay() {
+        let policy = NvmeRetryPolicy::default();
+        let ms = |retry| policy.delay(retry).as_millis();
+        assert_eq!((ms(0), ms(1), ms(2), ms(3)), (100, 200, 400, 800));
+        assert_eq!((ms(4), ms(5)), (1600, 2000));
+        // no overflow however far the count runs
+        assert_eq!((ms(31), ms(32), ms(u32::MAX)), (2000, 2000, 2000));
+        assert_eq!(no_wait(3).delay(10), Duration::ZERO);
+    }
+
+    #[test]
+    fn run_counts_retries() {
+        let read = NVME_NVM_COMMAND_READ as u8;
+        let attempts = Cell::new(0);
+        let busy = || {
+            attempts.set(attempts.get() + 1);
+            Ok(nvme_status(0, 0x82))
+        };
+        assert_eq!(no_wait(3).run(false, read, busy).unwrap().SC(), 0x82);
+        assert_eq!(attempts.replace(0), 4);
+        no_wait(0).run(false, read, busy).unwrap();
+        assert_eq!(attempts.replace(0), 1);
+        // DNR set, or a failure that is not transient
+        no_wait(3)
+            .run(false, read, || {
+                attempts.set(attempts.get() + 1);
+                Ok(nvme_status(0, 0x82).with_DNR(1))
+            })
+            .unwrap();
+        assert_eq!(attempts.replace(0), 1);
+        no_wait(3)
+            .run(false, read, || {
+                attempts.set(attempts.get() + 1);
+                Ok(nvme_status(0, 0x02))
+            })
+            .unwrap();
+        assert_eq!(attempts.replace(0), 1);
+        // transport errors are not retried
+        let error = no_wait(3).run(false, read, || {
+            attempts.set(attempts.get() + 1);
+            Err(io::Error::new(io::ErrorKind::TimedOut, "timeout"))
+        });
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn idempotent_commands() {
+        assert!(nvme_idempotent(true, NVME_ADMIN_COMMAND_IDENTIFY as u8));
+        assert!(nvme_idempotent(true, NVME_ADMIN_COMMAND_GET_LOG_PAGE as u8));
+        assert!(nvme_idempotent(false, NVME_NVM_COMMAND_READ as u8));
+        assert!(nvme_idempotent(false, NVME_NVM_COMMAND_WRITE as u8));
+        for opcode in [
+            NVME_ADMIN_COMMAND_FORMAT_NVM,
+            NVME_ADMIN_COMMAND_SANITIZE,
+            NVME_ADMIN_COMMAND_FIRMWARE_COMMIT,
+            NVME_ADMIN_COMMAND_FIRMWARE_IMAGE_DOWNLOAD,
+            NVME_ADMIN_COMMAND_SECURITY_SEND,
+            NVME_ADMIN_COMMAND_SECURITY_RECEIVE,
+            NVME_ADMIN_COMMAND_NAMESPACE_MANAGEMENT,
+            NVME_ADMIN_COMMAND_NAMESPACE_ATTACHMENT,
+            NVME_ADMIN_COMMAND_DEVICE_SELF_TEST,
+        ] {
+            let opcode = opcode as u8;
+            assert!(!nvme_idempotent(true, opcode), "{:#x}", opcode);
+        }
+        for opcode in [
+            NVME_NVM_COMMAND_RESERVATION_REGISTER,
+            NVME_NVM_COMMAND_RESERVATION_ACQUIRE,
+            NVME_NVM_COMMAND_RESERVATION_RELEASE,
+            NVME_NVM_COMMAND_ZONE_MANAGEMENT_SEND,
+            NVME_NVM_COMMAND_ZONE_APPEND,
+        ] {
+            let opcode = opcode as u8;
+            assert!(!nvme_idempotent(false, opcode), "{:#x}", opcode);
+        }
+        // vendor specific
+        assert!(!nvme_idempotent(true, 0xc0));
+        assert!(!nvme_idempotent(false, 0x80));
+    }
+
+    #[test]
+    fn retry_transport() {
+        let sim = NvmeSimulator::new(1 << 20, 9);
+        let retry = NvmeRetry::new(&sim, no_wait(3));
+        let read = NvmeOpcodeType::READ as u8;
+        let mut identify = NVME_COMMAND::default();
+        identify
+            .opcode(NVME_ADMIN_COMMAND_IDENTIFY as u32)
+            .cdw10(0x01);
+        let mut format = NVME_COMMAND::default();
+        format.opcode(NVME_ADMIN_COMMAND_FORMAT_NVM as u32).nsid(1);
+
+        // Namespace Not Ready twice, then the identify goes through
+        sim.inject_status(true, NVME_ADMIN_COMMAND_IDENTIFY as u8, 0, 0x82);
+        sim.inject_status(true, NVME_ADMIN_COMMAND_IDENTIFY as u8, 0, 0x82);
+        let mut data = vec![0u8; 4096];
+        let status = retry
+            .admin_passthru(read, &identify, &mut data, &mut 0)
+            .unwrap();
+        assert!(status.is_success());
+        assert_eq!(sim.history().len(), 3);
+        assert_eq!(data, sim.identify_controller());
+
+        // the same status on a format is handed back after one attempt
+        sim.clear_history();
+        sim.inject_status(true, NVME_ADMIN_COMMAND_FORMAT_NVM as u8, 0, 0x82);
+        let status = retry
+            .admin_passthru(NvmeOpcodeType::NOBUFFER as u8, &format, &mut [], &mut 0)
+            .unwrap();
+        assert_eq!(status.SC(), 0x82);
+        assert_eq!(sim.history().len(), 1);
+    }
+}
@@ -0,0 +1,388 @@
+// In-memory NVMe controller with one namespace, enough of the admin and NVM
+// command sets to exercise the translation layer and tools without hardware
+use crate::dev::nvme_commands::NvmeTransport;
+use crate::dev::nvme_define::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+
+pub const NVME_SIM_NSID: u32 = 1;
+const NVME_SIM_MDTS: u8 = 5; // 2^5 * 4KiB = 128KiB per command
+
+/// Command seen by the simulator, in submission order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NvmeSimCommand {
+    pub admin: bool,
+    pub opcode: u8,
+    pub nsid: u32,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub data_len: usize,
+}
+
+struct NvmeSimState {
+    blocks: HashMap<u64, Vec<u8>>, // written LBAs only, the rest reads as zeros
+    security: BTreeMap<(u8, u16), Vec<u8>>,
+    injected: Vec<(bool, u8, NVME_COMMAND_STATUS)>,
+    history: Vec<NvmeSimCommand>,
+    features: HashMap<u8, u32>,
+    logs: HashMap<u8, Vec<u8>>,
+    events: VecDeque<u32>, // completion DW0 of pending asynchronous events
+}
+
+pub struct NvmeSimulator {
+    pub lba_shift: u8,
+    pub nsze: u64,
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    pub nguid: [u8; 16],
+    pub eui64: [u8; 8],
+    pub volatile_write_cache: bool,
+    state: RefCell<NvmeSimState>,
+}
+
+fn put(buf: &mut [u8], at: usize, bytes: &[u8]) {
+    buf[at..at + bytes.len()].copy_from_slice(bytes);
+}
+
+/// ASCII field padded with spaces, as identify strings are
+fn put_str(buf: &mut [u8], at: usize, len: usize, text: &str) {
+    let field = &mut buf[at..at + len];
+    field.fill(b' ');
+    let bytes = text.as_bytes();
+    let n = bytes.len().min(len);
+    field[..n].copy_from_slice(&bytes[..n]);
+}
+
+pub fn nvme_status(sct: u8, sc: u8) -> NVME_COMMAND_STATUS {
+    NVME_COMMAND_STATUS::new().with_SCT(sct).with_SC(sc)
+}
+
+impl NvmeSimulator {
+    pub fn new(nsze: u64, lba_shift: u8) -> Self {
+        Self {
+            lba_shift,
+            nsze,
+            model: "NVMe Simulator".to_string(),
+            serial: "SIM0000001".to_string(),
+            firmware: "1.0".to_string(),
+            nguid: [
+                0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff,
+            ],
+            eui64: [0x00, 0x25, 0x38, 0x00, 0x00, 0x00, 0x00, 0x01],
+            volatile_write_cache: true,
+            state: RefCell::new(NvmeSimState {
+                blocks: HashMap::new(),
+                security: BTreeMap::new(),
+                injected: vec![],
+                history: vec![],
+                features: HashMap::new(),
+                logs: HashMap::from([(0x02, Self::default_smart_log())]),
+                events: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        1 << self.lba_shift
+    }
+
+    /// Complete the next admin (or I/O) command with `opcode` with this status
+    pub fn inject_status(&self, admin: bool, opcode: u8, sct: u8, sc: u8) {
+        self.state
+            .borrow_mut()
+            .injected
+            .push((admin, opcode, nvme_status(sct, sc)));
+    }
+
+    /// Complete the next Asynchronous Event Request with this event
+    pub fn post_async_event(&self, event_type: u8, info: u8, log_page: u8) {
+        let dw0 = NVME_COMPLETION_DW0_ASYNC_EVENT_REQUEST::new()
+            .with_AsyncEventType(event_type)
+            .with_AsyncEventInfo(info)
+            .with_LogPage(log_page);
+        self.state.borrow_mut().events.push_back(dw0.into());
+    }
+
+    /// Contents Get Log Page returns for `lid`; reading the Changed Namespace
+    /// List with RAE clear empties it again
+    pub fn set_log_page(&self, lid: u8, data: Vec<u8>) {
+        self.state.borrow_mut().logs.insert(lid, data);
+    }
+
+    /// Current value of a feature set through Set Features
+    pub fn feature(&self, fid: u8) -> Option<u32> {
+        self.state.borrow().features.get(&fid).copied()
+    }
+
+    fn default_smart_log() -> Vec<u8> {
+        let mut data = vec![0u8; 512];
+        put(&mut data, 1, &313u16.to_le_bytes()); // composite temperature, 40 C
+        data[3] = 100; // available spare
+        data[4] = 10; // available spare threshold
+        data
+    }
+
+    pub fn history(&self) -> Vec<NvmeSimCommand> {
+        self.state.borrow().history.clone()
+    }
+
+    pub fn clear_history(&self) {
+        self.state.borrow_mut().history.clear();
+    }
+
+    /// Contents of one LBA, zeros when never written or deallocated
+    pub fn block(&self, lba: u64) -> Vec<u8> {
+        self.state
+            .borrow()
+            .blocks
+            .get(&lba)
+            .cloned()
+            .unwrap_or_else(|| vec![0; self.block_size()])
+    }
+
+    pub fn identify_controller(&self) -> Vec<u8> {
+        let mut data = vec![0u8; 4096];
+        put(&mut data, 0, &0x1b36u16.to_le_bytes()); // VID
+        put(&mut data, 2, &0x1af4u16.to_le_bytes()); // SSVID
+        put_str(&mut data, 4, 20, &self.serial);
+        put_str(&mut data, 24, 40, &self.model);
+        put_str(&mut data, 64, 8, &self.firmware);
+        put(&mut data, 73, &[0x38, 0x25, 0x00]); // IEEE OUI
+        data[77] = NVME_SIM_MDTS;
+        put(&mut data, 80, &0x0001_0400u32.to_le_bytes()); // VER 1.4
+        put(&mut data, 92, &0x0000_0300u32.to_le_bytes()); // OAES: ns attribute, fw activation
+        put(&mut data, 256, &0x0001u16.to_le_bytes()); // OACS: security send/receive
+        data[512] = 0x66; // SQES
+        data[513] = 0x44; // CQES
+        put(&mut data, 516, &1u32.to_le_bytes()); // NN
+        put(&mut data, 520, &0x000Cu16.to_le_bytes()); // ONCS: DSM, write zeroes
+        data[525] = self.volatile_write_cache as u8; // VWC
+        data
+    }
+
+    pub fn identify_namespace(&self) -> Vec<u8> {
+        let mut data = vec![0u8; 4096];
+        put(&mut data, 0, &self.nsze.to_le_bytes()); // NSZE
+        put(&mut data, 8, &self.nsze.to_le_bytes()); // NCAP
+        let used = self.state.borrow().blocks.len() as u64;
+        put(&mut data, 16, &used.to_le_bytes()); // NUSE
+        data[24] = 0x10; // NSFEAT: NPWG/NPWA/NPDG/NPDA/NOWS valid
+        data[33] = 0x01; // DLFEAT: deallocated blocks read as zeros
+        put(&mut data, 64, &7u16.to_le_bytes()); // NPWG, 0's based
+        put(&mut data, 68, &7u16.to_le_bytes()); // NPDG, 0's based
+        put(&mut data, 104, &self.nguid);
+        put(&mut data, 120, &self.eui64);
+        data[130] = self.lba_shift; // LBAF0.LBADS
+        data
+    }
+
+    fn take_injected(&self, admin: bool, opcode: u8) -> Option<NVME_COMMAND_STATUS> {
+        let mut state = self.state.borrow_mut();
+        let index = state
+            .injected
+            .iter()
+            .position(|&(a, o, _)| a == admin && o == opcode)?;
+        Some(state.injected.remove(index).2)
+    }
+
+    fn record(&self, admin: bool, command: &NVME_COMMAND, data_len: usize) {
+        let general = unsafe { command.u.GENERAL };
+        self.state.borrow_mut().history.push(NvmeSimCommand {
+            admin,
+            opcode: command.CDW0.OPC(),
+            nsid: command.NSID,
+            cdw10: general.CDW10,
+            cdw11: general.CDW11,
+            cdw12: general.CDW12,
+            data_len,
+        });
+    }
+
+    fn admin(&self, command: &NVME_COMMAND, data: &mut [u8], dw0: &mut u32) -> NVME_COMMAND_STATUS {
+        use NVME_ADMIN_COMMANDS::*;
+        let general = unsafe { command.u.GENERAL };
+        let opcode = command.CDW0.OPC();
+        let success = nvme_status(0, 0);
+        if opcode == NVME_ADMIN_COMMAND_IDENTIFY as u8 {
+            let identify = match general.CDW10 & 0xff {
+                0x00 if command.NSID == NVME_SIM_NSID => self.identify_namespace(),
+                0x00 => return nvme_status(0, 0x0b), // Invalid Namespace or Format
+                0x01 => self.identify_controller(),
+                0x02 => {
+                    let mut list = vec![0u8; 4096];
+                    put(&mut list, 0, &NVME_SIM_NSID.to_le_bytes());
+                    list
+                }
+                _ => return nvme_status(0, 0x02),
+            };
+            let n = data.len().min(identify.len());
+            data[..n].copy_from_slice(&identify[..n]);
+            success
+        } else if opcode == NVME_ADMIN_COMMAND_SECURITY_SEND as u8 {
+            let secp = (general.CDW10 >> 24) as u8;
+            let spsp = (general.CDW10 >> 8) as u16;
+            self.state
+                .borrow_mut()
+                .security
+                .insert((secp, spsp), data.to_vec());
+            success
+        } else if opcode == NVME_ADMIN_COMMAND_SECURITY_RECEIVE as u8 {
+            let secp = (general.CDW10 >> 24) as u8;
+            let spsp = (general.CDW10 >> 8) as u16;
+            data.fill(0);
+            if secp == 0 && spsp == 0 {
+                // supported security protocol list: just protocol 00h and 01h
+                let list = [0, 0, 0, 0, 0, 0, 0, 2, 0x00, 0x01];
+                let n = data.len().min(list.len());
+                data[..n].copy_from_slice(&list[..n]);
+            } else if let Some(stored) = self.state.borrow().security.get(&(secp, spsp)) {
+                let n = data.len().min(stored.len());
+                data[..n].copy_from_slice(&stored[..n]);
+            }
+            success
+        } else if opcode == NVME_ADMIN_COMMAND_SET_FEATURES as u8 {
+            let fid = general.CDW10 as u8;
+            self.state.borrow_mut().features.insert(fid, general.CDW11);
+            success
+        } else if opcode == NVME_ADMIN_COMMAND_GET_FEATURES as u8 {
+            let fid = general.CDW10 as u8;
+            *dw0 = self.feature(fid).unwrap_or(0);
+            success
+        } else if opcode == NVME_ADMIN_COMMAND_GET_LOG_PAGE as u8 {
+            let lid = general.CDW10 as u8;
+            let rae = general.CDW10 & (1 << 15) != 0;
+            let offset = (general.CDW12 as u64 | (general.CDW13 as u64) << 32) as usize;
+            let mut state = self.state.borrow_mut();
+            let Some(log) = state.logs.get(&lid) else {
+                return nvme_status(1, 0x09); // Invalid Log Page
+            };
+            data.fill(0);
+            if offset < log.len() {
+                let n = data.len().min(log.len() - offset);
+                data[..n].copy_from_slice(&log[offset..offset + n]);
+            }
+            if lid == NVME_LOG_PAGES::NVME_LOG_PAGE_CHANGED_NAMESPACE_LIST as u8 && !rae {
+                state.logs.insert(lid, vec![0; 4096]);
+            }
+            success
+        } else if opcode == NVME_ADMIN_COMMAND_ASYNC_EVENT_REQUEST as u8 {
+            // only completed when an event is pending, see admin_passthru
+            *dw0 = self.state.borrow_mut().events.pop_front().unwrap_or(0);
+            success
+        } else {
+            nvme_status(0, 0x01) // Invalid Command Opcode
+        }
+    }
+
+    fn io(&self, command: &NVME_COMMAND, data: &mut [u8]) -> NVME_COMMAND_STATUS {
+        use NVME_NVM_COMMANDS::*;
+        if command.NSID != NVME_SIM_NSID {
+            return nvme_status(0, 0x0b);
+        }
+        let general = unsafe { command.u.GENERAL };
+        let opcode = command.CDW0.OPC();
+        let slba = general.CDW10 as u64 | (general.CDW11 as u64) << 32;
+        let nlb = (general.CDW12 & 0xffff) as u64 + 1;
+        let bs = self.block_size();
+        let in_range =
+            |slba: u64, nlb: u64| slba.checked_add(nlb).is_some_and(|end| end <= self.nsze);
+        let mut state = self.state.borrow_mut();
+
+        if opcode == NVME_NVM_COMMAND_READ as u8 || opcode == NVME_NVM_COMMAND_WRITE as u8 {
+            if !in_range(slba, nlb) {
+                return nvme_status(0, 0x80); // LBA Out of Range
+            }
+            if data.len() < nlb as usize * bs {
+                return nvme_status(0, 0x02);
+            }
+            for i in 0..nlb {
+                let chunk = &mut data[i as usize * bs..(i as usize + 1) * bs];
+                if opcode == NVME_NVM_COMMAND_READ as u8 {
+                    match state.blocks.get(&(slba + i)) {
+                        Some(block) => chunk.copy_from_slice(block),
+                        None => chunk.fill(0),
+                    }
+                } else {
+                    state.blocks.insert(slba + i, chunk.to_vec());
+                }
+            }
+            nvme_status(0, 0)
+        } else if opcode == NVME_NVM_COMMAND_FLUSH as u8 {
+            nvme_status(0, 0)
+        } else if opcode == NVME_NVM_COMMAND_WRITE_ZEROES as u8 {
+            if !in_range(slba, nlb) {
+                return nvme_status(0, 0x80);
+            }
+            for lba in slba..slba + nlb {
+                state.blocks.remove(&lba);
+            }
+            nvme_status(0, 0)
+        } else if opcode == NVME_NVM_COMMAND_DATASET_MANAGEMENT as u8 {
+            let ranges = (general.CDW10 & 0xff) as usize + 1;
+            let deallocate = general.CDW11 & 0x4 != 0;
+            if data.len() < ranges * 16 {
+                return nvme_status(0, 0x02);
+            }
+            for range in data[..ranges * 16].chunks_exact(16) {
+                let nlb = u32::from_le_bytes(range[4..8].try_into().unwrap()) as u64;
+                let slba = u64::from_le_bytes(range[8..16].try_into().unwrap());
+                if !in_range(slba, nlb) {
+                    return nvme_status(0, 0x80);
+                }
+                if deallocate {
+                    for lba in slba..slba + nlb {
+                        state.blocks.remove(&lba);
+                    }
+                }
+            }
+            nvme_status(0, 0)
+        } else {
+            nvme_status(0, 0x01)
+        }
+    }
+}
+
+impl NvmeTransport for NvmeSimulator {
+    fn admin_passthru(
+        &self,
+        _direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.record(true, command, data.len());
+        *dw0 = 0;
+        if let Some(status) = self.take_injected(true, command.CDW0.OPC()) {
+            return Ok(status);
+        }
+        if command.CDW0.OPC() == NVME_ADMIN_COMMANDS::NVME_ADMIN_COMMAND_ASYNC_EVENT_REQUEST as u8
+            && self.state.borrow().events.is_empty()
+        {
+            // a controller would hold the command; nothing will ever arrive here
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no asynchronous event pending",
+            ));
+        }
+        Ok(self.admin(command, data, dw0))
+    }
+
+    fn io_passthru(
+        &self,
+        _direction: u8,
+        command: &NVME_COMMAND,
+        data: &mut [u8],
+        dw0: &mut u32,
+    ) -> io::Result<NVME_COMMAND_STATUS> {
+        self.record(false, command, data.len());
+        *dw0 = 0;
+        if let Some(status) = self.take_injected(false, command.CDW0.OPC()) {
+            return Ok(status);
+        }
+        Ok(self.io(command, data))
+    }
+}
@@ -0,0 +1,1062 @@
+// Vendor-specific command registry. Commands are described in TOML or JSON
+// files instead of being hard-coded, keyed by PCI vendor ID and model number
+// prefix, and run over any NvmeTransport.
+//
+// A description file holds a list of vendors, each with its commands:
+//
+//   [[vendor]]
+//   name = "Example"
+//   vid = 0x1b36
+//   models = ["QEMU"]
+//
+//   [[vendor.command]]
+//   name = "temperature"
+//   param = [{ name = "sub_opcode", default = 0x10 }]
+//   phase = [
+//     { opcode = 0xf1, data_len = 4096, cdw10 = "data_dwords", cdw12 = "sub_opcode" },
+//     { opcode = 0xf2, data_len = 512, cdw10 = "data_dwords", cdw12 = "sub_opcode", cdw14 = 1 },
+//   ]
+//   field = [{ name = "composite", offset = 0, size = 2, unit = "K" }]
+//
+// A CDW value is a number, the name of a parameter, one of the built-in
+// values (nsid, data_len, data_dwords), or a list of bit fields built from
+// those: [{ bits = "31:16", value = "length" }, { bits = "7:0", value = 1 }].
+// Numbers may be written as strings ("0xf1") so JSON files can use hex.
+use crate::dev::nvme_commands::{nvme_opcode_direction, NvmeOpcodeType, NvmeTransport};
+use crate::dev::nvme_define::*;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::path::Path;
+use std::{fmt, fs, io};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VendorFormat {
+    Toml,
+    Json,
+}
+
+impl VendorFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText {
+    Number(u64),
+    Text(String),
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    let text = text.trim().replace('_', "");
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn de_number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    let value = match NumberOrText::deserialize(deserializer)? {
+        NumberOrText::Number(n) => n,
+        NumberOrText::Text(text) => parse_number(&text)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid number {:?}", text)))?,
+    };
+    T::try_from(value).map_err(|_| serde::de::Error::custom(format!("{:#x} out of range", value)))
+}
+
+fn de_opt_number<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    de_number(deserializer).map(Some)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VendorFile {
+    #[serde(default)]
+    vendor: Vec<VendorDefinition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VendorDefinition {
+    pub name: String,
+    /// PCI vendor ID from Identify Controller; any controller when absent
+    #[serde(default, deserialize_with = "de_opt_number")]
+    pub vid: Option<u16>,
+    /// Model number prefixes; any model when empty
+    #[serde(default)]
+    pub models: Vec<String>,
+    #[serde(default, rename = "command")]
+    pub commands: Vec<VendorCommand>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VendorQueue {
+    #[default]
+    Admin,
+    Io,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VendorCommand {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub queue: VendorQueue,
+    #[serde(default, rename = "param")]
+    pub params: Vec<VendorParam>,
+    #[serde(rename = "phase")]
+    pub phases: Vec<VendorPhase>,
+    /// Layout of the data returned by the last phase that reads from the controller
+    #[serde(default, rename = "field")]
+    pub fields: Vec<VendorField>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VendorParam {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Required on the command line when absent
+    #[serde(default, deserialize_with = "de_opt_number")]
+    pub default: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VendorDirection {
+    None,
+    Write,
+    Read,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VendorDataSource {
+    /// Zero-filled buffer
+    #[default]
+    Zero,
+    /// The caller's input data, also the default length of the phase
+    Input,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VendorPhase {
+    #[serde(deserialize_with = "de_number")]
+    pub opcode: u8,
+    /// Defaults to the data transfer bits of the opcode
+    #[serde(default)]
+    pub direction: Option<VendorDirection>,
+    #[serde(default)]
+    pub nsid: Option<VendorValue>,
+    #[serde(default)]
+    pub cdw2: Option<VendorValue>,
+    #[serde(default)]
+    pub cdw3: Option<VendorValue>,
+    #[serde(default)]
+    pub cdw10: Option<VendorValue>,
+    #[serde(default)]
+    pub cdw11: Option<VendorValue>,
+    #[serde(default)]
+    pub cdw12: Option<VendorValue>,
+    #[serde(default)]
+    pub cdw13: Option<VendorValue>,
+    #[serde(default)]
+    pub cdw14: Option<VendorValue>,
+    #[serde(default)]
+    pub cdw15: Option<VendorValue>,
+    /// Data length in bytes
+    #[serde(default)]
+    pub data_len: Option<VendorValue>,
+    #[serde(default)]
+    pub data: VendorDataSource,
+    /// Values stored little endian into the data buffer, e.g. a parameter block
+    #[serde(default)]
+    pub put: Vec<VendorPut>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VendorPut {
+    #[serde(deserialize_with = "de_number")]
+    pub offset: usize,
+    #[serde(default = "default_put_size", deserialize_with = "de_number")]
+    pub size: usize,
+    pub value: VendorValue,
+}
+
+fn default_put_size() -> usize {
+    4
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum VendorValue {
+    Number(u64),
+    Name(String),
+    Bits(Vec<VendorBits>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VendorBits {
+    /// "hi:lo" or a single bit
+    pub bits: String,
+    pub value: VendorValue,
+}
+
+fn parse_bits(bits: &str) -> Option<(u32, u32)> {
+    let (hi, lo) = match bits.split_once(':') {
+        Some((hi, lo)) => (hi.trim().parse().ok()?, lo.trim().parse().ok()?),
+        None => {
+            let bit = bits.trim().parse().ok()?;
+            (bit, bit)
+        }
+    };
+    (hi >= lo && hi < 64).then_some((hi, lo))
+}
+
+fn bit_mask(hi: u32, lo: u32) -> u64 {
+    (u64::MAX >> (63 - hi)) & !((1u64 << lo) - 1)
+}
+
+/// Values a CDW expression can refer to besides numbers
+pub struct VendorContext<'a> {
+    pub args: &'a HashMap<String, u32>,
+    pub nsid: u32,
+    pub data_len: u32,
+}
+
+impl VendorContext<'_> {
+    fn lookup(&self, name: &str) -> Option<u64> {
+        match name {
+            "nsid" => Some(self.nsid as u64),
+            "data_len" => Some(self.data_len as u64),
+            "data_dwords" => Some(self.data_len as u64 / 4),
+            _ => self.args.get(name).map(|&v| v as u64),
+        }
+    }
+}
+
+impl VendorValue {
+    pub fn resolve(&self, context: &VendorContext) -> io::Result<u64> {
+        match self {
+            Self::Number(n) => Ok(*n),
+            Self::Name(name) => parse_number(name)
+                .or_else(|| context.lookup(name))
+                .ok_or_else(|| invalid(format!("unknown value {:?}", name))),
+            Self::Bits(fields) => {
+                let mut value = 0;
+                for field in fields {
+                    let (hi, lo) = parse_bits(&field.bits)
+                        .ok_or_else(|| invalid(format!("invalid bit range {:?}", field.bits)))?;
+                    value |= (field.value.resolve(context)? << lo) & bit_mask(hi, lo);
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    fn resolve_u32(&self, what: &str, context: &VendorContext) -> io::Result<u32> {
+        let value = self.resolve(context)?;
+        u32::try_from(value)
+            .map_err(|_| invalid(format!("{} {:#x} does not fit in 32 bits", what, value)))
+    }
+
+    /// Names referred to, for checking them against the declared parameters
+    fn names<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Self::Number(_) => {}
+            Self::Name(name) => {
+                if parse_number(name).is_none() {
+                    names.push(name)
+                }
+            }
+            Self::Bits(fields) => fields.iter().for_each(|f| f.value.names(names)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VendorFieldType {
+    /// Little endian unsigned integer, 1 to 8 bytes
+    #[default]
+    Uint,
+    /// Little endian signed integer, 1 to 8 bytes
+    Int,
+    /// ASCII text, trailing spaces and NULs trimmed
+    String,
+    Hex,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VendorField {
+    pub name: String,
+    #[serde(deserialize_with = "de_number")]
+    pub offset: usize,
+    #[serde(default = "default_field_size", deserialize_with = "de_number")]
+    pub size: usize,
+    #[serde(default, rename = "type")]
+    pub kind: VendorFieldType,
+    /// Bit range of an integer field, "hi:lo" or a single bit
+    #[serde(default)]
+    pub bits: Option<String>,
+    #[serde(default)]
+    pub unit: Option<String>,
+}
+
+fn default_field_size() -> usize {
+    4
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VendorFieldData {
+    Uint(u64),
+    Int(i64),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VendorFieldValue {
+    pub name: String,
+    pub data: VendorFieldData,
+    pub unit: Option<String>,
+}
+
+impl fmt::Display for VendorFieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.data {
+            VendorFieldData::Uint(v) => write!(f, "{} ({:#x})", v, v)?,
+            VendorFieldData::Int(v) => write!(f, "{}", v)?,
+            VendorFieldData::Text(s) => write!(f, "{}", s)?,
+            VendorFieldData::Bytes(b) => {
+                for byte in b {
+                    write!(f, "{:02x}", byte)?;
+                }
+            }
+        }
+        if let Some(unit) = &self.unit {
+            write!(f, " {}", unit)?;
+        }
+        Ok(())
+    }
+}
+
+impl VendorField {
+    fn validate(&self) -> Result<(), String> {
+        if matches!(self.kind, VendorFieldType::Uint | VendorFieldType::Int)
+            && !(1..=8).contains(&self.size)
+        {
+            return Err(format!(
+                "field {}: integer size must be 1 to 8 bytes",
+                self.name
+            ));
+        }
+        match &self.bits {
+            Some(bits) if parse_bits(bits).is_none_or(|(hi, _)| hi >= self.size as u32 * 8) => {
+                Err(format!("field {}: invalid bit range {:?}", self.name, bits))
+            }
+            Some(_) if !matches!(self.kind, VendorFieldType::Uint | VendorFieldType::Int) => {
+                Err(format!("field {}: bits need an integer field", self.name))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> io::Result<VendorFieldValue> {
+        let bytes = self
+            .offset
+            .checked_add(self.size)
+            .and_then(|end| data.get(self.offset..end))
+            .ok_or_else(|| {
+                invalid(format!(
+                    "field {} at {}+{} is past the {} byte response",
+                    self.name,
+                    self.offset,
+                    self.size,
+                    data.len()
+                ))
+            })?;
+        let mut raw = [0u8; 8];
+        let (hi, lo) = match &self.bits {
+            Some(bits) => parse_bits(bits).unwrap_or((63, 0)),
+            None => (self.size as u32 * 8 - 1, 0),
+        };
+        let data = match self.kind {
+            VendorFieldType::Uint => {
+                raw[..bytes.len()].copy_from_slice(bytes);
+                VendorFieldData::Uint((u64::from_le_bytes(raw) & bit_mask(hi, lo)) >> lo)
+            }
+            VendorFieldType::Int => {
+                raw[..bytes.len()].copy_from_slice(bytes);
+                let value = (u64::from_le_bytes(raw) & bit_mask(hi, lo)) >> lo;
+                let width = hi - lo + 1;
+                VendorFieldData::Int(((value << (64 - width)) as i64) >> (64 - width))
+            }
+            VendorFieldType::String => VendorFieldData::Text(
+                String::from_utf8_lossy(bytes)
+                    .trim_end_matches([' ', '\0'])
+                    .to_string(),
+            ),
+            VendorFieldType::Hex => VendorFieldData::Bytes(bytes.to_vec()),
+        };
+        Ok(VendorFieldValue {
+            name: self.name.clone(),
+            data,
+            unit: self.unit.clone(),
+        })
+    }
+}
+
+/// One command of a sequence, ready to submit
+#[derive(Clone)]
+pub struct VendorPhaseCommand {
+    pub direction: u8,
+    pub command: NVME_COMMAND,
+    pub data: Vec<u8>,
+}
+
+/// Completion of the last phase submitted and the response data
+#[derive(Clone)]
+pub struct VendorResponse {
+    pub phases: usize,
+    pub dw0: u32,
+    pub status: NVME_COMMAND_STATUS,
+    pub data: Vec<u8>,
+}
+
+impl VendorPhase {
+    fn direction(&self) -> u8 {
+        match self.direction {
+            Some(VendorDirection::None) => NvmeOpcodeType::NOBUFFER as u8,
+            Some(VendorDirection::Write) => NvmeOpcodeType::WRITE as u8,
+            Some(VendorDirection::Read) => NvmeOpcodeType::READ as u8,
+            None => nvme_opcode_direction(self.opcode) as u8,
+        }
+    }
+
+    fn values(&self) -> impl Iterator<Item = &VendorValue> {
+        [
+            &self.nsid,
+            &self.cdw2,
+            &self.cdw3,
+            &self.cdw10,
+            &self.cdw11,
+            &self.cdw12,
+            &self.cdw13,
+            &self.cdw14,
+            &self.cdw15,
+            &self.data_len,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.put.iter().map(|p| &p.value))
+    }
+}
+
+impl VendorCommand {
+    fn validate(&self) -> Result<(), String> {
+        if self.phases.is_empty() {
+            return Err(format!("command {} has no phases", self.name));
+        }
+        let mut names = Vec::new();
+        for phase in &self.phases {
+            phase.values().for_each(|v| v.names(&mut names));
+            if phase.direction() == NvmeOpcodeType::READWRITE as u8 {
+                return Err(format!(
+                    "command {}: opcode {:#04x} needs an explicit direction",
+                    self.name, phase.opcode
+                ));
+            }
+            for put in &phase.put {
+                if !(1..=8).contains(&put.size) {
+                    return Err(format!(
+                        "command {}: put size must be 1 to 8 bytes",
+                        self.name
+                    ));
+                }
+            }
+        }
+        let builtin = ["nsid", "data_len", "data_dwords"];
+        for name in names {
+            if !builtin.contains(&name) && !self.params.iter().any(|p| p.name == name) {
+                return Err(format!("command {}: unknown value {:?}", self.name, name));
+            }
+        }
+        self.fields.iter().try_for_each(|f| f.validate())
+    }
+
+    /// Parameter values from `name=value` arguments and the declared defaults
+    pub fn arguments(&self, args: &[String]) -> io::Result<HashMap<String, u32>> {
+        let mut values = HashMap::new();
+        for arg in args {
+            let (name, value) = arg
+                .split_once('=')
+                .ok_or_else(|| invalid_input(format!("expected name=value, got {:?}", arg)))?;
+            if !self.params.iter().any(|p| p.name == name) {
+                return Err(invalid_input(format!(
+                    "{} has no parameter {:?}",
+                    self.name, name
+                )));
+            }
+            let value = parse_number(value)
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| invalid_input(format!("invalid value for {}: {:?}", name, value)))?;
+            values.insert(name.to_string(), value);
+        }
+        for param in &self.params {
+            if !values.contains_key(&param.name) {
+                let value = param.default.ok_or_else(|| {
+                    invalid_input(format!("{} needs parameter {}", self.name, param.name))
+                })?;
+                values.insert(param.name.clone(), value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// The phase commands with their data buffers; `input` feeds the phases
+    /// that take the caller's data
+    pub fn build(
+        &self,
+        nsid: u32,
+        args: &HashMap<String, u32>,
+        input: &[u8],
+    ) -> io::Result<Vec<VendorPhaseCommand>> {
+        let mut commands = Vec::with_capacity(self.phases.len());
+        for phase in &self.phases {
+            let direction = phase.direction();
+            let mut context = VendorContext {
+                args,
+                nsid,
+                data_len: 0,
+            };
+            if phase.data == VendorDataSource::Input && input.is_empty() {
+                return Err(invalid_input(format!(
+                    "{}: opcode {:#04x} sends the input data, but there is none",
+                    self.name, phase.opcode
+                )));
+            }
+            let data_len = match (&phase.data_len, phase.data) {
+                (Some(len), _) => len.resolve_u32("data_len", &context)? as usize,
+                (None, VendorDataSource::Input) => input.len(),
+                (None, VendorDataSource::Zero) => 0,
+            };
+            if direction == NvmeOpcodeType::NOBUFFER as u8 && data_len != 0 {
+                return Err(invalid(format!(
+                    "{}: opcode {:#04x} transfers no data but has a {} byte buffer",
+                    self.name, phase.opcode, data_len
+                )));
+            }
+            context.data_len = data_len as u32;
+
+            let mut data = vec![0u8; data_len];
+            if phase.data == VendorDataSource::Input {
+                let n = input.len().min(data_len);
+                data[..n].copy_from_slice(&input[..n]);
+            }
+            for put in &phase.put {
+                let value = put.value.resolve(&context)?.to_le_bytes();
+                let dest = put
+                    .offset
+                    .checked_add(put.size)
+                    .and_then(|end| data.get_mut(put.offset..end))
+                    .ok_or_else(|| {
+                        invalid(format!(
+                            "{}: put at {}+{} is past the {} byte buffer",
+                            self.name, put.offset, put.size, data_len
+                        ))
+                    })?;
+                dest.copy_from_slice(&value[..put.size]);
+            }
+
+            let value = |cdw: &Option<VendorValue>, what: &str| match cdw {
+                Some(v) => v.resolve_u32(what, &context),
+                None => Ok(0),
+            };
+            let mut command = NVME_COMMAND::default();
+            command
+                .opcode(phase.opcode as u32)
+                .nsid(match &phase.nsid {
+                    Some(v) => v.resolve_u32("nsid", &context)?,
+                    None => nsid,
+                })
+                .cdw2(value(&phase.cdw2, "cdw2")?)
+                .cdw3(value(&phase.cdw3, "cdw3")?)
+                .cdw10(value(&phase.cdw10, "cdw10")?)
+                .cdw11(value(&phase.cdw11, "cdw11")?)
+                .cdw12(value(&phase.cdw12, "cdw12")?)
+                .cdw13(value(&phase.cdw13, "cdw13")?)
+                .cdw14(value(&phase.cdw14, "cdw14")?)
+                .cdw15(value(&phase.cdw15, "cdw15")?);
+            commands.push(VendorPhaseCommand {
+                direction,
+                command,
+                data,
+            });
+        }
+        Ok(commands)
+    }
+
+    /// Submit the phases in order, stopping at the first one that does not
+    /// complete successfully
+    pub fn execute<T: NvmeTransport + ?Sized>(
+        &self,
+        transport: &T,
+        phases: Vec<VendorPhaseCommand>,
+    ) -> io::Result<VendorResponse> {
+        let mut response = VendorResponse {
+            phases: 0,
+            dw0: 0,
+            status: NVME_COMMAND_STATUS::default(),
+            data: Vec::new(),
+        };
+        for mut phase in phases {
+            let mut dw0 = 0;
+            response.status = match self.queue {
+                VendorQueue::Admin => transport.admin_passthru(
+                    phase.direction,
+                    &phase.command,
+                    &mut phase.data,
+                    &mut dw0,
+                )?,
+                VendorQueue::Io => transport.io_passthru(
+                    phase.direction,
+                    &phase.command,
+                    &mut phase.data,
+                    &mut dw0,
+                )?,
+            };
+            response.phases += 1;
+            response.dw0 = dw0;
+            if phase.direction & NvmeOpcodeType::READ as u8 != 0 {
+                response.data = phase.data;
+            }
+            if !response.status.is_success() {
+                break;
+            }
+        }
+        Ok(response)
+    }
+
+    pub fn decode(&self, data: &[u8]) -> io::Result<Vec<VendorFieldValue>> {
+        self.fields.iter().map(|f| f.decode(data)).collect()
+    }
+}
+
+impl VendorDefinition {
+    pub fn matches(&self, vid: u16, model: &str) -> bool {
+        let model = model.trim();
+        self.vid.is_none_or(|v| v == vid)
+            && (self.models.is_empty() || self.models.iter().any(|m| model.starts_with(m.trim())))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (i, command) in self.commands.iter().enumerate() {
+            if self.commands[..i].iter().any(|c| c.name == command.name) {
+                return Err(format!("duplicate command {}", command.name));
+            }
+            command.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VendorRegistry {
+    pub vendors: Vec<VendorDefinition>,
+}
+
+impl VendorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the vendors described in `text`
+    pub fn load_str(&mut self, text: &str, format: VendorFormat) -> io::Result<()> {
+        let file: VendorFile = match format {
+            VendorFormat::Toml => toml::from_str(text).map_err(|e| invalid(e.to_string()))?,
+            VendorFormat::Json => serde_json::from_str(text).map_err(|e| invalid(e.to_string()))?,
+        };
+        for vendor in &file.vendor {
+            vendor
+                .validate()
+                .map_err(|e| invalid(format!("vendor {}: {}", vendor.name, e)))?;
+        }
+        self.vendors.extend(file.vendor);
+        Ok(())
+    }
+
+    /// Load a .toml or .json file, or every such file in a directory
+    pub fn load_path(&mut self, path: &Path) -> io::Result<()> {
+        if path.is_dir() {
+            let mut files: Vec<_> = fs::read_dir(path)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && VendorFormat::from_path(p).is_some())
+                .collect();
+            files.sort();
+            return files.iter().try_for_each(|f| self.load_path(f));
+        }
+        let format = VendorFormat::from_path(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: expected a .toml or .json file", path.display()),
+            )
+        })?;
+        let text = fs::read_to_string(path)?;
+        self.load_str(&text, format)
+            .map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Commands registered for a controller, in load order
+    pub fn commands(&self, vid: u16, model: &str) -> Vec<(&VendorDefinition, &VendorCommand)> {
+        self.vendors
+            .iter()
+            .filter(|v| v.matches(vid, model))
+            .flat_map(|v| v.commands.iter().map(move |c| (v, c)))
+            .collect()
+    }
+
+    /// The first command called `name` registered for a controller
+    pub fn find(
+        &self,
+        vid: u16,
+        model: &str,
+        name: &str,
+    ) -> Option<(&VendorDefinition, &VendorCommand)> {
+        self.vendors
+            .iter()
+            .filter(|v| v.matches(vid, model))
+            .find_map(|v| v.commands.iter().find(|c| c.name == name).map(|c| (v, c)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev::nvme_commands::nvme_vsc2_commands;
+    use crate::dev::nvme_sim::NvmeSimulator;
+
+    const VSC2: &str = include_str!("../../vendor/vsc2.toml");
+
+    fn registry(text: &str) -> io::Result<VendorRegistry> {
+        let mut registry = VendorRegistry::new();
+        registry.load_str(text, VendorFormat::Toml)?;
+        Ok(registry)
+    }
+
+    fn command(text: &str) -> io::Result<VendorCommand> {
+        let text = format!("[[vendor]]\nname = \"Test\"\n[[vendor.command]]\n{}", text);
+        Ok(registry(&text)?.vendors.remove(0).commands.remove(0))
+    }
+
+    fn field(offset: usize, size: usize, kind: VendorFieldType, bits: Option<&str>) -> VendorField {
+        VendorField {
+            name: "f".to_string(),
+            offset,
+            size,
+            kind,
+            bits: bits.map(str::to_string),
+            unit: None,
+        }
+    }
+
+    fn bits(bits: &str, value: VendorValue) -> VendorBits {
+        VendorBits {
+            bits: bits.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number(" 0x1F "), Some(0x1f));
+        assert_eq!(parse_number("0XfF"), Some(0xff));
+        assert_eq!(parse_number("1_000"), Some(1000));
+        assert_eq!(parse_number("0xffff_ffff_ffff_ffff"), Some(u64::MAX));
+        for text in ["", "0x", "-1", "length", "0x1g", "18446744073709551616"] {
+            assert_eq!(parse_number(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn bit_ranges() {
+        assert_eq!(parse_bits("63:0"), Some((63, 0)));
+        assert_eq!(bit_mask(63, 0), u64::MAX);
+        assert_eq!(parse_bits("0"), Some((0, 0)));
+        assert_eq!(bit_mask(0, 0), 1);
+        assert_eq!(parse_bits(" 63 "), Some((63, 63)));
+        assert_eq!(bit_mask(63, 63), 1 << 63);
+        assert_eq!(parse_bits("31 : 16"), Some((31, 16)));
+        assert_eq!(bit_mask(31, 16), 0xffff_0000);
+        for text in ["3:4", "64", "64:0", "7:", ":0", "a:b", ""] {
+            assert_eq!(parse_bits(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let args = HashMap::from([("length".to_string(), 0x12345)]);
+        let context = VendorContext {
+            args: &args,
+            nsid: 7,
+            data_len: 512,
+        };
+        let name = |name: &str| VendorValue::Name(name.to_string());
+        assert_eq!(name("0x10").resolve(&context).unwrap(), 0x10);
+        assert_eq!(name("nsid").resolve(&context).unwrap(), 7);
+        assert_eq!(name("data_len").resolve(&context).unwrap(), 512);
+        assert_eq!(name("data_dwords").resolve(&context).unwrap(), 128);
+        assert_eq!(name("length").resolve(&context).unwrap(), 0x12345);
+        assert!(name("missing").resolve(&context).is_err());
+
+        // values are shifted into place and cut to their range
+        let value = VendorValue::Bits(vec![
+            bits("31:16", name("length")),
+            bits("7:0", VendorValue::Number(0x1ff)),
+            bits("8", VendorValue::Number(1)),
+        ]);
+        assert_eq!(value.resolve(&context).unwrap(), 0x2345_01ff);
+        let value = VendorValue::Bits(vec![bits("63:0", VendorValue::Number(u64::MAX))]);
+        assert_eq!(value.resolve(&context).unwrap(), u64::MAX);
+        assert!(value.resolve_u32("cdw10", &context).is_err());
+        let value = VendorValue::Bits(vec![bits("0:1", VendorValue::Number(1))]);
+        assert!(value.resolve(&context).is_err());
+    }
+
+    #[test]
+    fn decode() {
+        let data = [0xf0, 0x0f, 0x70, 0x80, b'A', b'B', b' ', 0, 0, 0];
+        let decode = |f: VendorField| f.decode(&data).unwrap().data;
+        assert_eq!(
+            decode(field(0, 2, VendorFieldType::Uint, None)),
+            VendorFieldData::Uint(0x0ff0)
+        );
+        assert_eq!(
+            decode(field(0, 2, VendorFieldType::Uint, Some("11:4"))),
+            VendorFieldData::Uint(0xff)
+        );
+        // the sign bit is the top of the range, not of the field
+        assert_eq!(
+            decode(field(0, 2, VendorFieldType::Int, Some("11:4"))),
+            VendorFieldData::Int(-1)
+        );
+        assert_eq!(
+            decode(field(2, 1, VendorFieldType::Int, Some("7:4"))),
+            VendorFieldData::Int(7)
+        );
+        assert_eq!(
+            decode(field(2, 2, VendorFieldType::Int, None)),
+            VendorFieldData::Int(0x8070u16 as i16 as i64)
+        );
+        assert_eq!(
+            decode(field(3, 1, VendorFieldType::Uint, Some("7"))),
+            VendorFieldData::Uint(1)
+        );
+        assert_eq!(
+            decode(field(4, 6, VendorFieldType::String, None)),
+            VendorFieldData::Text("AB".to_string())
+        );
+        assert_eq!(
+            decode(field(4, 2, VendorFieldType::Hex, None)),
+            VendorFieldData::Bytes(vec![b'A', b'B'])
+        );
+
+        for f in [
+            field(8, 4, VendorFieldType::Uint, None),
+            field(10, 1, VendorFieldType::Hex, None),
+            field(usize::MAX, 2, VendorFieldType::Uint, None),
+        ] {
+            let e = f.decode(&data).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let phase = "phase = [{ opcode = 0xc0 }]";
+        command(&format!("name = \"ok\"\n{}", phase)).unwrap();
+        for text in [
+            "name = \"no-phase\"\nphase = []".to_string(),
+            "name = \"unknown\"\nphase = [{ opcode = 0xc0, cdw12 = \"sub_opcode\" }]".to_string(),
+            "name = \"unknown-bits\"\nphase = [{ opcode = 0xc0, cdw12 = [{ bits = \"7:0\", value = \"x\" }] }]"
+                .to_string(),
+            "name = \"rw\"\nphase = [{ opcode = 0xc3, data_len = 512 }]".to_string(),
+            "name = \"put0\"\nphase = [{ opcode = 0xc1, data_len = 512, put = [{ offset = 0, size = 0, value = 1 }] }]"
+                .to_string(),
+            "name = \"put9\"\nphase = [{ opcode = 0xc1, data_len = 512, put = [{ offset = 0, size = 9, value = 1 }] }]"
+                .to_string(),
+            format!("name = \"int9\"\n{}\nfield = [{{ name = \"f\", offset = 0, size = 9 }}]", phase),
+            format!(
+                "name = \"bits\"\n{}\nfield = [{{ name = \"f\", offset = 0, size = 1, bits = \"8\" }}]",
+                phase
+            ),
+            format!(
+                "name = \"text\"\n{}\nfield = [{{ name = \"f\", offset = 0, type = \"string\", bits = \"0\" }}]",
+                phase
+            ),
+            format!("name = \"field\"\n{}\nbogus = 1", phase),
+        ] {
+            let e = command(&text).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", text);
+        }
+        // READWRITE opcodes are fine once the direction is given
+        command("name = \"rw\"\nphase = [{ opcode = 0xc3, direction = \"read\", data_len = 512 }]")
+            .unwrap();
+        let text = format!(
+            "[[vendor]]\nname = \"Test\"\n[[vendor.command]]\nname = \"a\"\n{}\n[[vendor.command]]\nname = \"a\"\n{}",
+            phase, phase
+        );
+        assert!(registry(&text).is_err());
+    }
+
+    #[test]
+    fn build() {
+        let registry = registry(VSC2).unwrap();
+        let (_, write) = registry.find(0x1b36, "QEMU", "vsc2-write").unwrap();
+        let args = write.arguments(&["sub_opcode=0x10".to_string()]).unwrap();
+        let e = write.build(1, &args, &[]).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let phases = write.build(1, &args, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(phases.len(), 2);
+        assert_eq!(phases[0].data.len(), 4096);
+        assert_eq!(phases[1].direction, NvmeOpcodeType::WRITE as u8);
+        assert_eq!(phases[1].data, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(phases[1].command.to_bytes()[40..44], 2u32.to_le_bytes()); // CDW10
+
+        assert!(write.arguments(&[]).is_err());
+        assert!(write.arguments(&["sub_opcode".to_string()]).is_err());
+        assert!(write.arguments(&["length=1".to_string()]).is_err());
+        assert!(write
+            .arguments(&["sub_opcode=0x100000000".to_string()])
+            .is_err());
+
+        let put = command(
+            "name = \"put\"\nparam = [{ name = \"v\", default = 0x11223344 }]\n\
+             phase = [{ opcode = 0xc1, data_len = 8, put = [{ offset = 2, size = 3, value = \"v\" }, { offset = 6, size = 2, value = \"data_len\" }] }]",
+        )
+        .unwrap();
+        let phases = put.build(1, &put.arguments(&[]).unwrap(), &[]).unwrap();
+        assert_eq!(phases[0].data, [0, 0, 0x44, 0x33, 0x22, 0, 8, 0]);
+        let past = command(
+            "name = \"past\"\nphase = [{ opcode = 0xc1, data_len = 4, put = [{ offset = 2, value = 1 }] }]",
+        )
+        .unwrap();
+        assert!(past.build(1, &HashMap::new(), &[]).is_err());
+        let nodata =
+            command("name = \"nodata\"\nphase = [{ opcode = 0xc0, data_len = 4 }]").unwrap();
+        assert!(nodata.build(1, &HashMap::new(), &[]).is_err());
+    }
+
+    #[test]
+    fn execute() {
+        let sim = NvmeSimulator::new(1024, 9);
+        let registry = registry(VSC2).unwrap();
+        let (_, id) = registry.find(0x1b36, "QEMU", "id-summary").unwrap();
+        let phases = id.build(1, &HashMap::new(), &[]).unwrap();
+        let response = id.execute(&sim, phases).unwrap();
+        assert!(response.status.is_success());
+        assert_eq!(response.phases, 1);
+        assert_eq!(response.data, sim.identify_controller());
+        let fields = id.decode(&response.data).unwrap();
+        assert_eq!(fields[0].data, VendorFieldData::Uint(0x1b36));
+        assert_eq!(
+            fields[5].data,
+            VendorFieldData::Bytes(vec![0x38, 0x25, 0x00])
+        );
+
+        // the unknown opcode fails, the phase after it is not sent
+        let chain = command(
+            "name = \"chain\"\nphase = [{ opcode = 0x06, data_len = 4096, cdw10 = 1 }, { opcode = 0xc0 }, { opcode = 0x06, data_len = 4096, cdw10 = 1 }]",
+        )
+        .unwrap();
+        sim.clear_history();
+        let phases = chain.build(1, &HashMap::new(), &[]).unwrap();
+        let response = chain.execute(&sim, phases).unwrap();
+        assert_eq!(response.phases, 2);
+        assert!(!response.status.is_success());
+        assert_eq!(response.status.SC(), 0x01);
+        // data of the last read phase that ran
+        assert_eq!(response.data, sim.identify_controller());
+        let opcodes: Vec<u8> = sim.history().iter().map(|c| c.opcode).collect();
+        assert_eq!(opcodes, [0x06, 0xc0]);
+    }
+
+    /// vsc2.toml, and the same definitions as JSON, describe the commands
+    /// nvme_send_vsc2_passthrough_command sends
+    #[test]
+    fn vsc2_parity() {
+        let value: toml::Value = toml::from_str(VSC2).unwrap();
+        let json = serde_json::to_string(&value).unwrap();
+        let toml_registry = registry(VSC2).unwrap();
+        let mut json_registry = VendorRegistry::new();
+        json_registry.load_str(&json, VendorFormat::Json).unwrap();
+
+        let input = vec![0x5a; 1024];
+        for (name, direction, data_len) in [
+            ("vsc2-read", NvmeOpcodeType::READ as u8, 4096),
+            ("vsc2-read", NvmeOpcodeType::READ as u8, 512),
+            ("vsc2-write", NvmeOpcodeType::WRITE as u8, input.len()),
+            ("vsc2-none", 0, 0),
+        ] {
+            let args = vec![
+                "sub_opcode=0x10".to_string(),
+                format!("length={}", data_len),
+            ];
+            let mut built = vec![];
+            for registry in [&toml_registry, &json_registry] {
+                let (_, command) = registry.find(0x1b36, "QEMU", name).unwrap();
+                let args = if name == "vsc2-read" {
+                    &args
+                } else {
+                    &args[..1]
+                };
+                let phases = command
+                    .build(3, &command.arguments(args).unwrap(), &input)
+                    .unwrap();
+                built.push(phases);
+            }
+            let (param, data) = nvme_vsc2_commands(0x10, direction, 4096, data_len, 3);
+            let mut expected = vec![(NvmeOpcodeType::WRITE as u8, param.to_bytes(), 4096)];
+            if direction != 0 {
+                expected.push((direction, data.to_bytes(), data_len));
+            }
+            for phases in built {
+                let phases: Vec<_> = phases
+                    .iter()
+                    .map(|p| (p.direction, p.command.to_bytes(), p.data.len()))
+                    .collect();
+                assert_eq!(phases, expected, "{}", name);
+            }
+        }
+    }
+}
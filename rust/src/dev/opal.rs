@@ -0,0 +1,923 @@
+// TCG Opal host side: ComPacket/Packet/SubPacket framing, token streams, sessions and
+// the locking SP methods (TCG Storage Architecture Core Spec, Opal SSC v2)
+use super::disk::Disk;
+#[cfg(windows)]
+use super::nvme_device::InboxDriver;
+use super::tcg::TcgLevel0Discovery;
+use std::io;
+
+pub const TCG_OPAL_PROTOCOL: u8 = 0x01;
+pub const TCG_IO_BUFFER_LENGTH: usize = 2048;
+const TCG_IO_BUFFER_ALIGNMENT: usize = 512;
+const TCG_COMPACKET_HEADER_SIZE: usize = 20;
+const TCG_PACKET_HEADER_SIZE: usize = 24;
+const TCG_SUBPACKET_HEADER_SIZE: usize = 12;
+const TCG_RECV_RETRIES: usize = 20;
+
+pub type Uid = [u8; 8];
+
+pub const UID_SMUID: Uid = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF];
+pub const UID_THISSP: Uid = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+pub const UID_ADMINSP: Uid = [0x00, 0x00, 0x02, 0x05, 0x00, 0x00, 0x00, 0x01];
+pub const UID_LOCKINGSP: Uid = [0x00, 0x00, 0x02, 0x05, 0x00, 0x00, 0x00, 0x02];
+pub const UID_ANYBODY: Uid = [0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x01];
+pub const UID_SID: Uid = [0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x06];
+pub const UID_ADMIN1: Uid = [0x00, 0x00, 0x00, 0x09, 0x00, 0x01, 0x00, 0x01];
+pub const UID_PSID: Uid = [0x00, 0x00, 0x00, 0x09, 0x00, 0x01, 0xFF, 0x01];
+pub const UID_LOCKING_GLOBAL_RANGE: Uid = [0x00, 0x00, 0x08, 0x02, 0x00, 0x00, 0x00, 0x01];
+pub const UID_MBRCONTROL: Uid = [0x00, 0x00, 0x08, 0x03, 0x00, 0x00, 0x00, 0x01];
+pub const UID_C_PIN_SID: Uid = [0x00, 0x00, 0x00, 0x0B, 0x00, 0x00, 0x00, 0x01];
+pub const UID_C_PIN_MSID: Uid = [0x00, 0x00, 0x00, 0x0B, 0x00, 0x00, 0x84, 0x02];
+pub const UID_C_PIN_ADMIN1: Uid = [0x00, 0x00, 0x00, 0x0B, 0x00, 0x01, 0x00, 0x01];
+
+pub const METHOD_PROPERTIES: Uid = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x01];
+pub const METHOD_STARTSESSION: Uid = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x02];
+pub const METHOD_SYNCSESSION: Uid = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x03];
+pub const METHOD_GET: Uid = [0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x16];
+pub const METHOD_SET: Uid = [0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x17];
+pub const METHOD_REVERT: Uid = [0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x02, 0x02];
+pub const METHOD_ACTIVATE: Uid = [0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x02, 0x03];
+
+// column numbers
+pub const COL_PIN: u64 = 3;
+pub const COL_RANGE_START: u64 = 3;
+pub const COL_RANGE_LENGTH: u64 = 4;
+pub const COL_READ_LOCK_ENABLED: u64 = 5;
+pub const COL_WRITE_LOCK_ENABLED: u64 = 6;
+pub const COL_READ_LOCKED: u64 = 7;
+pub const COL_WRITE_LOCKED: u64 = 8;
+pub const COL_LOCK_ON_RESET: u64 = 9;
+
+// method parameter names
+const NAME_START_COLUMN: u64 = 3;
+const NAME_END_COLUMN: u64 = 4;
+const NAME_VALUES: u64 = 1;
+const NAME_HOST_CHALLENGE: u64 = 0;
+const NAME_HOST_SIGNING_AUTHORITY: u64 = 3;
+
+/// Locking range UID: 0 is the global range
+pub fn uid_locking_range(range: u8) -> Uid {
+    match range {
+        0 => UID_LOCKING_GLOBAL_RANGE,
+        n => [0x00, 0x00, 0x08, 0x02, 0x00, 0x03, 0x00, n],
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    StartList,
+    EndList,
+    StartName,
+    EndName,
+    Call,
+    EndOfData,
+    EndOfSession,
+    StartTransaction,
+    EndTransaction,
+    Empty,
+    Uint(u64),
+    Bytes(Vec<u8>),
+}
+
+impl Token {
+    pub fn as_uint(&self) -> Option<u64> {
+        match self {
+            Token::Uint(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Token::Bytes(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Encode tokens into the atom stream of a SubPacket payload
+pub fn encode_tokens(tokens: &[Token]) -> Vec<u8> {
+    let mut out = vec![];
+    for token in tokens {
+        match token {
+            Token::StartList => out.push(0xF0),
+            Token::EndList => out.push(0xF1),
+            Token::StartName => out.push(0xF2),
+            Token::EndName => out.push(0xF3),
+            Token::Call => out.push(0xF8),
+            Token::EndOfData => out.push(0xF9),
+            Token::EndOfSession => out.push(0xFA),
+            Token::StartTransaction => out.push(0xFB),
+            Token::EndTransaction => out.push(0xFC),
+            Token::Empty => out.push(0xFF),
+            Token::Uint(value) if *value < 0x40 => out.push(*value as u8), // tiny atom
+            Token::Uint(value) => {
+                let bytes = value.to_be_bytes();
+                let skip = bytes.iter().take_while(|&&b| b == 0).count();
+                out.push(0x80 | (8 - skip) as u8); // short atom
+                out.extend_from_slice(&bytes[skip..]);
+            }
+            Token::Bytes(data) => {
+                let len = data.len();
+                if len < 0x10 {
+                    out.push(0xA0 | len as u8); // short atom
+                } else if len < 0x800 {
+                    out.push(0xD0 | (len >> 8) as u8); // medium atom
+                    out.push(len as u8);
+                } else {
+                    out.push(0xE2); // long atom
+                    out.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+                }
+                out.extend_from_slice(data);
+            }
+        }
+    }
+    out
+}
+
+/// Decode the atom stream of a SubPacket payload
+pub fn decode_tokens(data: &[u8]) -> io::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+        let head = data[pos];
+        let (header_len, len, is_bytes) = match head {
+            0x00..=0x7F => {
+                // tiny atom, the signed flag is ignored
+                tokens.push(Token::Uint((head & 0x3F) as u64));
+                pos += 1;
+                continue;
+            }
+            0x80..=0xBF => (1, (head & 0x0F) as usize, head & 0x20 != 0),
+            0xC0..=0xDF => {
+                let len = ((head as usize & 0x07) << 8) | *data.get(pos + 1).unwrap_or(&0) as usize;
+                (2, len, head & 0x10 != 0)
+            }
+            0xE0..=0xE3 => {
+                let b = data.get(pos + 1..pos + 4).unwrap_or(&[0, 0, 0]);
+                let len = ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize;
+                (4, len, head & 0x02 != 0)
+            }
+            _ => {
+                tokens.push(match head {
+                    0xF0 => Token::StartList,
+                    0xF1 => Token::EndList,
+                    0xF2 => Token::StartName,
+                    0xF3 => Token::EndName,
+                    0xF8 => Token::Call,
+                    0xF9 => Token::EndOfData,
+                    0xFA => Token::EndOfSession,
+                    0xFB => Token::StartTransaction,
+                    0xFC => Token::EndTransaction,
+                    0xFF => Token::Empty,
+                    _ => return Err(invalid_data(format!("unknown token {:#04x}", head))),
+                });
+                pos += 1;
+                continue;
+            }
+        };
+        let start = pos + header_len;
+        let value = data
+            .get(start..start + len)
+            .ok_or_else(|| invalid_data(format!("atom at {} truncated", pos)))?;
+        if is_bytes || len > 8 {
+            tokens.push(Token::Bytes(value.to_vec()));
+        } else {
+            tokens.push(Token::Uint(
+                value.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64),
+            ));
+        }
+        pos = start + len;
+    }
+    Ok(tokens)
+}
+
+/// Wrap a token payload in SubPacket, Packet and ComPacket headers, padded for IF-SEND
+pub fn encode_compacket(comid: u16, tsn: u32, hsn: u32, payload: &[u8]) -> Vec<u8> {
+    let padded = payload.len().div_ceil(4) * 4;
+    let subpacket_len = TCG_SUBPACKET_HEADER_SIZE + padded;
+    let packet_len = TCG_PACKET_HEADER_SIZE + subpacket_len;
+
+    let mut out = Vec::with_capacity(TCG_IO_BUFFER_LENGTH);
+    // ComPacket
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&comid.to_be_bytes());
+    out.extend_from_slice(&[0; 2]); // ComID extension
+    out.extend_from_slice(&[0; 4]); // outstanding data
+    out.extend_from_slice(&[0; 4]); // min transfer
+    out.extend_from_slice(&(packet_len as u32).to_be_bytes());
+    // Packet
+    out.extend_from_slice(&tsn.to_be_bytes());
+    out.extend_from_slice(&hsn.to_be_bytes());
+    out.extend_from_slice(&[0; 4]); // sequence number
+    out.extend_from_slice(&[0; 2]);
+    out.extend_from_slice(&[0; 2]); // ack type
+    out.extend_from_slice(&[0; 4]); // acknowledgement
+    out.extend_from_slice(&(subpacket_len as u32).to_be_bytes());
+    // SubPacket
+    out.extend_from_slice(&[0; 6]);
+    out.extend_from_slice(&[0; 2]); // kind: data
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+
+    let total = out.len().div_ceil(TCG_IO_BUFFER_ALIGNMENT) * TCG_IO_BUFFER_ALIGNMENT;
+    out.resize(total.max(TCG_IO_BUFFER_ALIGNMENT), 0);
+    out
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TcgComPacket {
+    pub comid: u16,
+    pub outstanding_data: u32,
+    pub min_transfer: u32,
+    pub tsn: u32,
+    pub hsn: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Split an IF-RECV response into its headers and the SubPacket payload
+pub fn decode_compacket(data: &[u8]) -> io::Result<TcgComPacket> {
+    let be32 = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+            .ok_or_else(|| invalid_data(format!("ComPacket truncated at {}", offset)))
+    };
+    let comid = data
+        .get(4..6)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid_data("ComPacket header truncated".to_string()))?;
+    let mut packet = TcgComPacket {
+        comid,
+        outstanding_data: be32(8)?,
+        min_transfer: be32(12)?,
+        ..Default::default()
+    };
+    if be32(16)? == 0 {
+        // nothing queued yet, the caller polls with IF-RECV again
+        return Ok(packet);
+    }
+    let p = TCG_COMPACKET_HEADER_SIZE;
+    packet.tsn = be32(p)?;
+    packet.hsn = be32(p + 4)?;
+    let sp = p + TCG_PACKET_HEADER_SIZE;
+    let len = be32(sp + 8)? as usize;
+    let start = sp + TCG_SUBPACKET_HEADER_SIZE;
+    packet.payload = start
+        .checked_add(len)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(|| invalid_data("SubPacket payload truncated".to_string()))?
+        .to_vec();
+    Ok(packet)
+}
+
+/// Build a method invocation: CALL invoking method [ args ] EOD [ 0 0 0 ]
+pub fn encode_method(invoking: &Uid, method: &Uid, args: &[Token]) -> Vec<u8> {
+    let mut tokens = vec![
+        Token::Call,
+        Token::Bytes(invoking.to_vec()),
+        Token::Bytes(method.to_vec()),
+        Token::StartList,
+    ];
+    tokens.extend_from_slice(args);
+    tokens.extend_from_slice(&[
+        Token::EndList,
+        Token::EndOfData,
+        Token::StartList,
+        Token::Uint(0),
+        Token::Uint(0),
+        Token::Uint(0),
+        Token::EndList,
+    ]);
+    encode_tokens(&tokens)
+}
+
+pub fn opal_status_name(status: u64) -> &'static str {
+    match status {
+        0x00 => "SUCCESS",
+        0x01 => "NOT_AUTHORIZED",
+        0x03 => "SP_BUSY",
+        0x04 => "SP_FAILED",
+        0x05 => "SP_DISABLED",
+        0x06 => "SP_FROZEN",
+        0x07 => "NO_SESSIONS_AVAILABLE",
+        0x08 => "UNIQUENESS_CONFLICT",
+        0x09 => "INSUFFICIENT_SPACE",
+        0x0A => "INSUFFICIENT_ROWS",
+        0x0C => "INVALID_PARAMETER",
+        0x0F => "TPER_MALFUNCTION",
+        0x10 => "TRANSACTION_FAILURE",
+        0x11 => "RESPONSE_OVERFLOW",
+        0x12 => "AUTHORITY_LOCKED_OUT",
+        0x3F => "FAIL",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Split a method response into its result list and check the trailing status list
+pub fn decode_method_response(tokens: &[Token]) -> io::Result<Vec<Token>> {
+    let eod = tokens
+        .iter()
+        .rposition(|t| *t == Token::EndOfData)
+        .ok_or_else(|| invalid_data("method response without EndOfData".to_string()))?;
+    let status = tokens
+        .get(eod + 2)
+        .and_then(Token::as_uint)
+        .ok_or_else(|| invalid_data("method response without status list".to_string()))?;
+    if status != 0 {
+        return Err(io::Error::other(format!(
+            "method failed: {} ({:#04x})",
+            opal_status_name(status),
+            status
+        )));
+    }
+    // strip the CALL header of session manager responses and the outer list
+    let body = match tokens.first() {
+        Some(Token::Call) => &tokens[3..eod],
+        _ => &tokens[..eod],
+    };
+    match (body.first(), body.last()) {
+        (Some(Token::StartList), Some(Token::EndList)) => Ok(body[1..body.len() - 1].to_vec()),
+        _ => Ok(body.to_vec()),
+    }
+}
+
+/// Collect `STARTNAME column value ENDNAME` pairs of a Get result
+pub fn decode_columns(tokens: &[Token]) -> Vec<(u64, Token)> {
+    tokens
+        .windows(4)
+        .filter_map(|w| match (&w[0], &w[1], &w[3]) {
+            (Token::StartName, Token::Uint(col), Token::EndName) => Some((*col, w[2].clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// IF-SEND / IF-RECV access to a TPer, implemented by the SCSI and NVMe paths
+pub trait TcgTransport {
+    fn if_send(&mut self, protocol: u8, comid: u16, data: &[u8]) -> io::Result<()>;
+    fn if_recv(&mut self, protocol: u8, comid: u16, data: &mut [u8]) -> io::Result<()>;
+}
+
+impl TcgTransport for Disk {
+    fn if_send(&mut self, protocol: u8, comid: u16, data: &[u8]) -> io::Result<()> {
+        self.security_send(protocol, comid, data).map(|_| ())
+    }
+    fn if_recv(&mut self, protocol: u8, comid: u16, data: &mut [u8]) -> io::Result<()> {
+        self.security_recv(protocol, comid, data).map(|_| ())
+    }
+}
+
+#[cfg(windows)]
+impl TcgTransport for &InboxDriver {
+    fn if_send(&mut self, protocol: u8, comid: u16, data: &[u8]) -> io::Result<()> {
+        self.nvme_security_send(0, protocol, comid, 0, &mut data.to_vec())
+    }
+    fn if_recv(&mut self, protocol: u8, comid: u16, data: &mut [u8]) -> io::Result<()> {
+        self.nvme_security_recv(0, protocol, comid, 0, data)
+    }
+}
+
+pub struct OpalDevice<T: TcgTransport> {
+    transport: T,
+    comid: u16,
+    hsn: u32,
+    tsn: u32,
+}
+
+impl<T: TcgTransport> OpalDevice<T> {
+    pub fn new(transport: T, comid: u16) -> Self {
+        Self {
+            transport,
+            comid,
+            hsn: 0,
+            tsn: 0,
+        }
+    }
+
+    /// Use the base ComID reported by Level 0 Discovery
+    pub fn from_discovery(transport: T, discovery: &TcgLevel0Discovery) -> io::Result<Self> {
+        let comid = discovery
+            .base_comid()
+            .ok_or_else(|| io::Error::other("no SSC feature in Level 0 discovery"))?;
+        Ok(Self::new(transport, comid))
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Send one token payload and return the payload of the response
+    pub fn exchange(&mut self, payload: &[u8]) -> io::Result<Vec<Token>> {
+        let packet = encode_compacket(self.comid, self.tsn, self.hsn, payload);
+        self.transport
+            .if_send(TCG_OPAL_PROTOCOL, self.comid, &packet)?;
+
+        let mut buffer = vec![0u8; TCG_IO_BUFFER_LENGTH];
+        for _ in 0..TCG_RECV_RETRIES {
+            buffer.fill(0);
+            self.transport
+                .if_recv(TCG_OPAL_PROTOCOL, self.comid, &mut buffer)?;
+            let response = decode_compacket(&buffer)?;
+            if !response.payload.is_empty() {
+                return decode_tokens(&response.payload);
+            }
+            if response.outstanding_data == 0 {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no response from the TPer",
+        ))
+    }
+
+    pub fn call(&mut self, invoking: &Uid, method: &Uid, args: &[Token]) -> io::Result<Vec<Token>> {
+        let tokens = self.exchange(&encode_method(invoking, method, args))?;
+        decode_method_response(&tokens)
+    }
+
+    /// StartSession / SyncSession with `sp`, authenticating as `authority` when given
+    pub fn start_session(&mut self, sp: &Uid, authority: Option<(&Uid, &[u8])>) -> io::Result<()> {
+        self.hsn = 0x1000 + (self.hsn + 1) % 0x1000;
+        self.tsn = 0;
+        let mut args = vec![
+            Token::Uint(self.hsn as u64),
+            Token::Bytes(sp.to_vec()),
+            Token::Uint(1), // write session
+        ];
+        if let Some((uid, challenge)) = authority {
+            args.extend_from_slice(&[
+                Token::StartName,
+                Token::Uint(NAME_HOST_CHALLENGE),
+                Token::Bytes(challenge.to_vec()),
+                Token::EndName,
+                Token::StartName,
+                Token::Uint(NAME_HOST_SIGNING_AUTHORITY),
+                Token::Bytes(uid.to_vec()),
+                Token::EndName,
+            ]);
+        }
+        let result = self.call(&UID_SMUID, &METHOD_STARTSESSION, &args)?;
+        // SyncSession [ HostSessionID SPSessionID ... ]
+        let ids: Vec<u64> = result.iter().filter_map(Token::as_uint).collect();
+        match ids.as_slice() {
+            [hsn, tsn, ..] if *hsn == self.hsn as u64 => {
+                self.tsn = *tsn as u32;
+                Ok(())
+            }
+            _ => Err(invalid_data(format!("unexpected SyncSession {:?}", result))),
+        }
+    }
+
+    pub fn end_session(&mut self) -> io::Result<()> {
+        let result = self.exchange(&encode_tokens(&[Token::EndOfSession]));
+        self.tsn = 0;
+        result.map(|_| ())
+    }
+
+    /// Run `f` inside a session and always close it
+    pub fn with_session<R>(
+        &mut self,
+        sp: &Uid,
+        authority: Option<(&Uid, &[u8])>,
+        f: impl FnOnce(&mut Self) -> io::Result<R>,
+    ) -> io::Result<R> {
+        self.start_session(sp, authority)?;
+        let result = f(self);
+        let end = self.end_session();
+        let value = result?;
+        end.map(|_| value)
+    }
+
+    pub fn get(&mut self, uid: &Uid, start: u64, end: u64) -> io::Result<Vec<(u64, Token)>> {
+        let args = [
+            Token::StartList,
+            Token::StartName,
+            Token::Uint(NAME_START_COLUMN),
+            Token::Uint(start),
+            Token::EndName,
+            Token::StartName,
+            Token::Uint(NAME_END_COLUMN),
+            Token::Uint(end),
+            Token::EndName,
+            Token::EndList,
+        ];
+        let result = self.call(uid, &METHOD_GET, &args)?;
+        Ok(decode_columns(&result))
+    }
+
+    pub fn set(&mut self, uid: &Uid, values: &[(u64, Token)]) -> io::Result<()> {
+        let mut args = vec![Token::StartName, Token::Uint(NAME_VALUES), Token::StartList];
+        for (column, value) in values {
+            args.extend_from_slice(&[
+                Token::StartName,
+                Token::Uint(*column),
+                value.clone(),
+                Token::EndName,
+            ]);
+        }
+        args.extend_from_slice(&[Token::EndList, Token::EndName]);
+        self.call(uid, &METHOD_SET, &args).map(|_| ())
+    }
+
+    pub fn get_msid(&mut self) -> io::Result<Vec<u8>> {
+        let columns = self.with_session(&UID_ADMINSP, None, |dev| {
+            dev.get(&UID_C_PIN_MSID, COL_PIN, COL_PIN)
+        })?;
+        columns
+            .into_iter()
+            .find(|(col, _)| *col == COL_PIN)
+            .and_then(|(_, value)| value.as_bytes().map(|b| b.to_vec()))
+            .ok_or_else(|| invalid_data("MSID PIN not returned".to_string()))
+    }
+
+    /// Step 1: set the SID PIN, authenticating with the MSID
+    pub fn take_ownership(&mut self, new_pin: &[u8]) -> io::Result<()> {
+        let msid = self.get_msid()?;
+        self.with_session(&UID_ADMINSP, Some((&UID_SID, &msid)), |dev| {
+            dev.set(&UID_C_PIN_SID, &[(COL_PIN, Token::Bytes(new_pin.to_vec()))])
+        })
+    }
+
+    /// Step 2: activate the Locking SP (Admin1 inherits the SID PIN)
+    pub fn activate(&mut self, sid_pin: &[u8]) -> io::Result<()> {
+        self.with_session(&UID_ADMINSP, Some((&UID_SID, sid_pin)), |dev| {
+            dev.call(&UID_LOCKINGSP, &METHOD_ACTIVATE, &[]).map(|_| ())
+        })
+    }
+
+    /// Step 3: configure a locking range and enable read/write locking on it
+    pub fn setup_range(
+        &mut self,
+        admin_pin: &[u8],
+        range: u8,
+        start: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let mut values = vec![];
+        if range != 0 {
+            values.push((COL_RANGE_START, Token::Uint(start)));
+            values.push((COL_RANGE_LENGTH, Token::Uint(length)));
+        }
+        values.push((COL_READ_LOCK_ENABLED, Token::Uint(1)));
+        values.push((COL_WRITE_LOCK_ENABLED, Token::Uint(1)));
+        self.with_session(&UID_LOCKINGSP, Some((&UID_ADMIN1, admin_pin)), |dev| {
+            dev.set(&uid_locking_range(range), &values)
+        })
+    }
+
+    /// Steps 4/5: lock or unlock a range for both reads and writes
+    pub fn lock_range(&mut self, admin_pin: &[u8], range: u8, lock: bool) -> io::Result<()> {
+        let values = [
+            (COL_READ_LOCKED, Token::Uint(lock as u64)),
+            (COL_WRITE_LOCKED, Token::Uint(lock as u64)),
+        ];
+        self.with_session(&UID_LOCKINGSP, Some((&UID_ADMIN1, admin_pin)), |dev| {
+            dev.set(&uid_locking_range(range), &values)
+        })
+    }
+
+    pub fn get_range(&mut self, admin_pin: &[u8], range: u8) -> io::Result<Vec<(u64, Token)>> {
+        self.with_session(&UID_LOCKINGSP, Some((&UID_ADMIN1, admin_pin)), |dev| {
+            dev.get(
+                &uid_locking_range(range),
+                COL_RANGE_START,
+                COL_LOCK_ON_RESET,
+            )
+        })
+    }
+
+    /// Revert the TPer to factory state with the PSID printed on the label.
+    /// The session is closed by the TPer on success.
+    pub fn psid_revert(&mut self, psid: &[u8]) -> io::Result<()> {
+        self.start_session(&UID_ADMINSP, Some((&UID_PSID, psid)))?;
+        match self.call(&UID_ADMINSP, &METHOD_REVERT, &[]) {
+            Ok(_) => {
+                self.tsn = 0;
+                Ok(())
+            }
+            Err(err) => {
+                let _ = self.end_session();
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const SP_TSN: u32 = 0x2001;
+    const STATUS_NOT_AUTHORIZED: u64 = 0x01;
+
+    /// A TPer holding C_PIN and locking range columns, answering one method per IF-SEND
+    #[derive(Default)]
+    struct MockTper {
+        columns: HashMap<(Uid, u64), Token>,
+        session: Option<(u32, Uid)>,
+        response: Option<Vec<u8>>,
+        methods: Vec<Uid>,
+    }
+
+    impl MockTper {
+        fn new() -> Self {
+            let mut tper = Self::default();
+            tper.columns
+                .insert((UID_C_PIN_MSID, COL_PIN), Token::Bytes(b"MSID".to_vec()));
+            tper.columns
+                .insert((UID_C_PIN_SID, COL_PIN), Token::Bytes(b"MSID".to_vec()));
+            tper
+        }
+
+        fn pin(&self, authority: &Uid) -> Option<Token> {
+            let c_pin = match *authority {
+                UID_SID => UID_C_PIN_SID,
+                UID_ADMIN1 => UID_C_PIN_ADMIN1,
+                _ => return None,
+            };
+            self.columns.get(&(c_pin, COL_PIN)).cloned()
+        }
+
+        fn reply(&mut self, hsn: u32, tsn: u32, tokens: &[Token]) {
+            self.response = Some(encode_compacket(0x07FE, tsn, hsn, &encode_tokens(tokens)));
+        }
+
+        fn method_reply(result: &[Token], status: u64) -> Vec<Token> {
+            let mut tokens = vec![Token::StartList];
+            tokens.extend_from_slice(result);
+            tokens.extend_from_slice(&[
+                Token::EndList,
+                Token::EndOfData,
+                Token::StartList,
+                Token::Uint(status),
+                Token::Uint(0),
+                Token::Uint(0),
+                Token::EndList,
+            ]);
+            tokens
+        }
+
+        fn start_session(&mut self, args: &[Token]) -> Vec<Token> {
+            let hsn = args[0].as_uint().unwrap() as u32;
+            let sp: Uid = args[1].as_bytes().unwrap().try_into().unwrap();
+            let named = decode_columns(args);
+            let challenge = named.iter().find(|(name, _)| *name == NAME_HOST_CHALLENGE);
+            let authority = named
+                .iter()
+                .find(|(name, _)| *name == NAME_HOST_SIGNING_AUTHORITY);
+            let status = match (challenge, authority) {
+                (Some((_, challenge)), Some((_, authority))) => {
+                    let uid: Uid = authority.as_bytes().unwrap().try_into().unwrap();
+                    if self.pin(&uid).as_ref() == Some(challenge) {
+                        0
+                    } else {
+                        STATUS_NOT_AUTHORIZED
+                    }
+                }
+                _ => 0,
+            };
+            let mut tokens = vec![
+                Token::Call,
+                Token::Bytes(UID_SMUID.to_vec()),
+                Token::Bytes(METHOD_SYNCSESSION.to_vec()),
+            ];
+            if status == 0 {
+                self.session = Some((hsn, sp));
+                tokens.extend(Self::method_reply(
+                    &[Token::Uint(hsn as u64), Token::Uint(SP_TSN as u64)],
+                    0,
+                ));
+            } else {
+                tokens.extend(Self::method_reply(&[], status));
+            }
+            tokens
+        }
+
+        fn get(&self, invoking: &Uid, args: &[Token]) -> Vec<Token> {
+            let named = decode_columns(args);
+            let column = |name| {
+                named
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .and_then(|(_, v)| v.as_uint())
+                    .unwrap()
+            };
+            let mut result = vec![Token::StartList];
+            for col in column(NAME_START_COLUMN)..=column(NAME_END_COLUMN) {
+                if let Some(value) = self.columns.get(&(*invoking, col)) {
+                    result.extend_from_slice(&[
+                        Token::StartName,
+                        Token::Uint(col),
+                        value.clone(),
+                        Token::EndName,
+                    ]);
+                }
+            }
+            result.push(Token::EndList);
+            Self::method_reply(&result, 0)
+        }
+
+        fn set(&mut self, invoking: &Uid, args: &[Token]) -> Vec<Token> {
+            for (col, value) in decode_columns(args) {
+                self.columns.insert((*invoking, col), value);
+            }
+            Self::method_reply(&[], 0)
+        }
+    }
+
+    impl TcgTransport for MockTper {
+        fn if_send(&mut self, protocol: u8, comid: u16, data: &[u8]) -> io::Result<()> {
+            assert_eq!(protocol, TCG_OPAL_PROTOCOL);
+            let packet = decode_compacket(data)?;
+            assert_eq!(packet.comid, comid);
+            let tokens = decode_tokens(&packet.payload)?;
+            if tokens == [Token::EndOfSession] {
+                let (hsn, _) = self.session.take().expect("no open session");
+                assert_eq!(packet.tsn, SP_TSN);
+                self.reply(hsn, SP_TSN, &[Token::EndOfSession]);
+                return Ok(());
+            }
+
+            let invoking: Uid = tokens[1].as_bytes().unwrap().try_into().unwrap();
+            let method: Uid = tokens[2].as_bytes().unwrap().try_into().unwrap();
+            let eod = tokens.iter().position(|t| *t == Token::EndOfData).unwrap();
+            let args = &tokens[4..eod - 1];
+            self.methods.push(method);
+            if method == METHOD_STARTSESSION {
+                assert_eq!((packet.tsn, invoking), (0, UID_SMUID));
+                let reply = self.start_session(args);
+                self.reply(packet.hsn, 0, &reply);
+                return Ok(());
+            }
+
+            let (hsn, _) = self.session.expect("method outside a session");
+            assert_eq!((packet.hsn, packet.tsn), (hsn, SP_TSN));
+            let reply = match method {
+                METHOD_GET => self.get(&invoking, args),
+                METHOD_SET => self.set(&invoking, args),
+                _ => Self::method_reply(&[], 0),
+            };
+            self.reply(hsn, SP_TSN, &reply);
+            Ok(())
+        }
+
+        fn if_recv(&mut self, protocol: u8, _comid: u16, data: &mut [u8]) -> io::Result<()> {
+            assert_eq!(protocol, TCG_OPAL_PROTOCOL);
+            if let Some(response) = self.response.take() {
+                let len = response.len().min(data.len());
+                data[..len].copy_from_slice(&response[..len]);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn token_encoding() {
+        let cases: &[(Token, &[u8])] = &[
+            (Token::StartList, &[0xF0]),
+            (Token::EndName, &[0xF3]),
+            (Token::EndOfData, &[0xF9]),
+            (Token::Empty, &[0xFF]),
+            (Token::Uint(0x3F), &[0x3F]),
+            (Token::Uint(0x40), &[0x81, 0x40]),
+            (Token::Uint(0x1234), &[0x82, 0x12, 0x34]),
+            (
+                Token::Uint(u64::MAX),
+                &[0x88, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+            ),
+            (Token::Bytes(vec![1, 2, 3]), &[0xA3, 1, 2, 3]),
+        ];
+        for (token, encoded) in cases {
+            assert_eq!(encode_tokens(std::slice::from_ref(token)), *encoded);
+            assert_eq!(decode_tokens(encoded).unwrap(), std::slice::from_ref(token));
+        }
+
+        let medium = Token::Bytes(vec![0xAB; 0x14]);
+        let encoded = encode_tokens(std::slice::from_ref(&medium));
+        assert_eq!(&encoded[..2], &[0xD0, 0x14]);
+        assert_eq!(decode_tokens(&encoded).unwrap(), [medium]);
+
+        let long = Token::Bytes(vec![0xCD; 0x900]);
+        let encoded = encode_tokens(std::slice::from_ref(&long));
+        assert_eq!(&encoded[..4], &[0xE2, 0x00, 0x09, 0x00]);
+        assert_eq!(decode_tokens(&encoded).unwrap(), [long]);
+
+        let method = encode_method(&UID_SMUID, &METHOD_STARTSESSION, &[Token::Uint(1)]);
+        let tokens = decode_tokens(&method).unwrap();
+        assert_eq!(tokens[0], Token::Call);
+        assert_eq!(tokens[2], Token::Bytes(METHOD_STARTSESSION.to_vec()));
+        assert_eq!(decode_method_response(&tokens).unwrap(), [Token::Uint(1)]);
+    }
+
+    #[test]
+    fn token_decoding_errors() {
+        // unassigned token, short atom and medium atom cut short
+        assert!(decode_tokens(&[0xF5]).is_err());
+        assert!(decode_tokens(&[0xA4, 1, 2]).is_err());
+        assert!(decode_tokens(&[0xD0]).is_err());
+        assert!(decode_tokens(&[0xE2, 0x00]).is_err());
+
+        let failed = [
+            Token::StartList,
+            Token::EndList,
+            Token::EndOfData,
+            Token::StartList,
+            Token::Uint(STATUS_NOT_AUTHORIZED),
+            Token::Uint(0),
+            Token::Uint(0),
+            Token::EndList,
+        ];
+        let err = decode_method_response(&failed).unwrap_err();
+        assert!(err.to_string().contains("NOT_AUTHORIZED"));
+        assert!(decode_method_response(&[Token::StartList, Token::EndList]).is_err());
+    }
+
+    #[test]
+    fn compacket_framing() {
+        let payload = [0xF0, 0x01, 0x02, 0xF1, 0xF9];
+        let packet = encode_compacket(0x07FE, 0x2001, 0x1001, &payload);
+        assert_eq!(packet.len(), TCG_IO_BUFFER_ALIGNMENT);
+        assert_eq!(&packet[4..6], &[0x07, 0xFE]);
+        // Packet length covers the Packet header, SubPacket header and padded payload
+        assert_eq!(&packet[16..20], &44u32.to_be_bytes());
+        assert_eq!(&packet[20..24], &0x2001u32.to_be_bytes());
+        assert_eq!(&packet[24..28], &0x1001u32.to_be_bytes());
+        assert_eq!(&packet[40..44], &20u32.to_be_bytes());
+        // SubPacket length is the unpadded payload
+        assert_eq!(&packet[52..56], &5u32.to_be_bytes());
+        assert_eq!(&packet[56..61], &payload);
+
+        let decoded = decode_compacket(&packet).unwrap();
+        assert_eq!(decoded.comid, 0x07FE);
+        assert_eq!((decoded.tsn, decoded.hsn), (0x2001, 0x1001));
+        assert_eq!(decoded.payload, payload);
+    }
+
+    #[test]
+    fn compacket_truncated() {
+        let packet = encode_compacket(0x07FE, 1, 2, &[0xF9; 8]);
+        for len in [0, 5, 19, 30, 47, 55, 60] {
+            assert!(decode_compacket(&packet[..len]).is_err(), "length {}", len);
+        }
+        // a SubPacket length past the end of the buffer
+        let mut bad = packet.clone();
+        bad[52..56].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(decode_compacket(&bad).is_err());
+
+        // an empty ComPacket means the response is not ready yet
+        let empty = decode_compacket(&[0; TCG_COMPACKET_HEADER_SIZE]).unwrap();
+        assert!(empty.payload.is_empty());
+    }
+
+    #[test]
+    fn session_flow() {
+        let mut dev = OpalDevice::new(MockTper::new(), 0x07FE);
+        assert_eq!(dev.get_msid().unwrap(), b"MSID");
+
+        dev.take_ownership(b"owner").unwrap();
+        assert_eq!(
+            dev.transport().columns[&(UID_C_PIN_SID, COL_PIN)],
+            Token::Bytes(b"owner".to_vec())
+        );
+        assert!(dev.transport().session.is_none());
+
+        // the MSID no longer authenticates as SID
+        let err = dev
+            .start_session(&UID_ADMINSP, Some((&UID_SID, b"MSID")))
+            .unwrap_err();
+        assert!(err.to_string().contains("NOT_AUTHORIZED"));
+
+        dev.transport()
+            .columns
+            .insert((UID_C_PIN_ADMIN1, COL_PIN), Token::Bytes(b"admin".to_vec()));
+        dev.setup_range(b"admin", 1, 0x1000, 0x800).unwrap();
+        dev.lock_range(b"admin", 1, true).unwrap();
+        let columns = dev.get_range(b"admin", 1).unwrap();
+        assert_eq!(
+            columns,
+            [
+                (COL_RANGE_START, Token::Uint(0x1000)),
+                (COL_RANGE_LENGTH, Token::Uint(0x800)),
+                (COL_READ_LOCK_ENABLED, Token::Uint(1)),
+                (COL_WRITE_LOCK_ENABLED, Token::Uint(1)),
+                (COL_READ_LOCKED, Token::Uint(1)),
+                (COL_WRITE_LOCKED, Token::Uint(1)),
+            ]
+        );
+        assert!(dev.transport().session.is_none());
+        assert_eq!(
+            dev.transport()
+                .methods
+                .iter()
+                .filter(|&&m| m == METHOD_STARTSESSION)
+                .count(),
+            7
+        );
+    }
+}
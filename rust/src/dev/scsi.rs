@@ -1,13 +1,17 @@
 use endian_codec::{DecodeBE, EncodeBE, PackedSize};
+#[cfg(windows)]
 use memoffset::offset_of;
+#[cfg(windows)]
+use std::{ffi::c_void, mem::size_of, ptr::null_mut};
 use std::{
-    ffi::c_void,
-    fmt,
-    mem::size_of,
+    fmt, io,
     ops::{Index, IndexMut},
-    ptr::null_mut,
+    time::Duration,
+};
+#[cfg(windows)]
+use windows_sys::Win32::Storage::IscsiDisc::{
+    SCSI_IOCTL_DATA_IN, SCSI_IOCTL_DATA_OUT, SCSI_IOCTL_DATA_UNSPECIFIED, SCSI_PASS_THROUGH_DIRECT,
 };
-use windows_sys::Win32::Storage::IscsiDisc::SCSI_PASS_THROUGH_DIRECT;
 
 // Shouldn't have more than 255 bytes
 pub type SenseBuffer = [u8; 32];
@@ -17,6 +21,7 @@ pub type SenseBuffer = [u8; 32];
 #[repr(C, align(64))]
 pub struct ScsiDataBuffer(Vec<u8>);
 
+#[cfg(windows)]
 #[repr(C)]
 #[allow(non_camel_case_types, non_snake_case, unused_assignments)]
 pub struct SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER {
@@ -25,12 +30,14 @@ pub struct SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER {
     pub ucSenseBuf: SenseBuffer,
 }
 
+#[cfg(windows)]
 impl fmt::Debug for SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(self, fmt)
     }
 }
 
+#[cfg(windows)]
 impl fmt::Display for SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -41,6 +48,7 @@ impl fmt::Display for SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER {
     }
 }
 
+#[cfg(windows)]
 impl SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER {
     pub fn new(dir: u32) -> Self {
         Self {
@@ -57,7 +65,7 @@ impl SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER {
                 DataTransferLength: 0,
                 SenseInfoOffset: offset_of!(Self, ucSenseBuf) as u32,
                 SenseInfoLength: size_of::<SenseBuffer>() as u8,
-                TimeOutValue: 10,
+                TimeOutValue: SCSI_DEFAULT_TIMEOUT.as_secs() as u32,
             },
             Filler: 0,
             ucSenseBuf: Default::default(),
@@ -69,6 +77,26 @@ impl SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER {
         self.sptd.DataBuffer = data_src.as_ptr() as *mut c_void;
         self.sptd.DataTransferLength = data_src.len() as u32;
     }
+
+    /// Marshal `command` and its data buffer, clearing the previous status
+    pub fn set_command(&mut self, command: &ScsiCommand, data: &mut [u8]) {
+        let dir = match command.direction {
+            ScsiDataDirection::None => SCSI_IOCTL_DATA_UNSPECIFIED,
+            ScsiDataDirection::In => SCSI_IOCTL_DATA_IN,
+            ScsiDataDirection::Out => SCSI_IOCTL_DATA_OUT,
+        };
+        let data = match command.direction {
+            ScsiDataDirection::None => &mut data[..0],
+            _ => data,
+        };
+        self.set_buffer(dir, data);
+        self.sptd.Cdb = command.cdb;
+        self.sptd.CdbLength = command.cdb_len;
+        // whole seconds, rounded up so a short timeout does not become none
+        self.sptd.TimeOutValue = command.timeout.as_millis().div_ceil(1000).max(1) as u32;
+        self.sptd.ScsiStatus = SCSI_STATUS_GOOD;
+        self.ucSenseBuf = Default::default();
+    }
 }
 
 impl AsRef<[u8]> for ScsiDataBuffer {
@@ -104,6 +132,8 @@ pub enum ScsiOpcode {
     SCSI_OPCODE_TEST_UNIT_READY = 0x00,
     SCSI_OPCODE_SECURITY_RECV = 0xa2,
     SCSI_OPCODE_SECURITY_SEND = 0xb5,
+    SCSI_OPCODE_FORMAT_UNIT = 0x04,
+    SCSI_OPCODE_SANITIZE = 0x48,
 }
 
 #[repr(C)]
@@ -160,3 +190,1599 @@ impl ScsiSecCdb12 {
         }
     }
 }
+
+pub const SCSI_OPCODE_INQUIRY: u8 = 0x12;
+pub const SCSI_INQUIRY_LENGTH: usize = 96;
+pub const SCSI_READ_CAPACITY_16_LENGTH: usize = 32;
+
+pub const SCSI_VPD_SUPPORTED_PAGES: u8 = 0x00;
+pub const SCSI_VPD_UNIT_SERIAL_NUMBER: u8 = 0x80;
+pub const SCSI_VPD_DEVICE_IDENTIFICATION: u8 = 0x83;
+pub const SCSI_VPD_BLOCK_LIMITS: u8 = 0xb0;
+pub const SCSI_VPD_BLOCK_DEVICE_CHARACTERISTICS: u8 = 0xb1;
+pub const SCSI_VPD_LOGICAL_BLOCK_PROVISIONING: u8 = 0xb2;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PackedSize, EncodeBE, DecodeBE)]
+pub struct ScsiInquiryCdb6 {
+    pub opcode: u8,
+    pub evpd: u8,
+    pub page: u8,
+    pub len: u16,
+    pub control: u8,
+}
+
+impl ScsiInquiryCdb6 {
+    /// Standard INQUIRY data when `page` is None, else that VPD page
+    pub fn new(page: Option<u8>, len: u16) -> Self {
+        Self {
+            opcode: SCSI_OPCODE_INQUIRY,
+            evpd: page.is_some() as u8,
+            page: page.unwrap_or(0),
+            len,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PackedSize, EncodeBE, DecodeBE)]
+pub struct ScsiReadCapacityCdb16 {
+    pub opcode: u8,
+    pub service_action: u8,
+    pub lba: u64,
+    pub len: u32,
+    pub reserved: u8,
+    pub control: u8,
+}
+
+impl ScsiReadCapacityCdb16 {
+    pub fn new(len: u32) -> Self {
+        Self {
+            opcode: ScsiOpcode::SCSI_OPCODE_SERVICE_ACTION_IN as u8,
+            service_action: ScsiOpcode::SCSI_SERVICE_ACTION_READ_CAPACITY_16 as u8,
+            len,
+            ..Default::default()
+        }
+    }
+}
+
+pub const SCSI_DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const SCSI_FORMAT_TIMEOUT: Duration = Duration::from_secs(3600);
+pub const SCSI_SANITIZE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Default timeout for a CDB, longer for the commands that rewrite the medium
+pub fn scsi_default_timeout(opcode: u8) -> Duration {
+    match opcode {
+        x if x == ScsiOpcode::SCSI_OPCODE_FORMAT_UNIT as u8 => SCSI_FORMAT_TIMEOUT,
+        x if x == ScsiOpcode::SCSI_OPCODE_SANITIZE as u8 => SCSI_SANITIZE_TIMEOUT,
+        _ => SCSI_DEFAULT_TIMEOUT,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScsiDataDirection {
+    None,
+    In,  // device to host
+    Out, // host to device
+}
+
+/// A CDB with its data direction and timeout, independent of the OS
+/// passthrough interface that carries it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScsiCommand {
+    pub cdb: [u8; 16],
+    pub cdb_len: u8,
+    pub direction: ScsiDataDirection,
+    pub timeout: Duration,
+}
+
+impl ScsiCommand {
+    /// Encode one of the CDB builders, e.g. `ScsiRwCdb16`
+    pub fn new<C: EncodeBE>(cdb: &C, direction: ScsiDataDirection) -> Self {
+        let mut bytes = [0u8; 16];
+        cdb.encode_as_be_bytes(&mut bytes[..C::PACKED_LEN]);
+        Self {
+            cdb: bytes,
+            cdb_len: C::PACKED_LEN as u8,
+            direction,
+            timeout: scsi_default_timeout(bytes[0]),
+        }
+    }
+
+    /// CDB given as bytes, at most 16
+    pub fn from_bytes(cdb: &[u8], direction: ScsiDataDirection) -> Self {
+        let len = cdb.len().min(16);
+        let mut bytes = [0u8; 16];
+        bytes[..len].copy_from_slice(&cdb[..len]);
+        Self {
+            cdb: bytes,
+            cdb_len: len as u8,
+            direction,
+            timeout: scsi_default_timeout(bytes[0]),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.cdb[0]
+    }
+
+    pub fn cdb(&self) -> &[u8] {
+        &self.cdb[..self.cdb_len as usize]
+    }
+}
+
+/// Executes SCSI commands, implemented by `Disk` over the OS passthrough
+/// interface (SPTD on Windows, SG_IO on Linux)
+pub trait ScsiTransport {
+    /// Send `command` with `data` as the data-in or data-out buffer. Returns
+    /// the bytes transferred; a status other than GOOD comes back as an
+    /// `io::Error` wrapping a `ScsiError`.
+    fn scsi_command(&mut self, command: &ScsiCommand, data: &mut [u8]) -> io::Result<usize>;
+}
+
+pub const SCSI_STATUS_GOOD: u8 = 0x00;
+pub const SCSI_STATUS_CHECK_CONDITION: u8 = 0x02;
+pub const SCSI_STATUS_CONDITION_MET: u8 = 0x04;
+pub const SCSI_STATUS_BUSY: u8 = 0x08;
+pub const SCSI_STATUS_RESERVATION_CONFLICT: u8 = 0x18;
+pub const SCSI_STATUS_TASK_SET_FULL: u8 = 0x28;
+pub const SCSI_STATUS_ACA_ACTIVE: u8 = 0x30;
+pub const SCSI_STATUS_TASK_ABORTED: u8 = 0x40;
+
+pub fn scsi_status_name(status: u8) -> &'static str {
+    match status {
+        SCSI_STATUS_GOOD => "GOOD",
+        SCSI_STATUS_CHECK_CONDITION => "CHECK CONDITION",
+        SCSI_STATUS_CONDITION_MET => "CONDITION MET",
+        SCSI_STATUS_BUSY => "BUSY",
+        SCSI_STATUS_RESERVATION_CONFLICT => "RESERVATION CONFLICT",
+        SCSI_STATUS_TASK_SET_FULL => "TASK SET FULL",
+        SCSI_STATUS_ACA_ACTIVE => "ACA ACTIVE",
+        SCSI_STATUS_TASK_ABORTED => "TASK ABORTED",
+        _ => "Unknown",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum ScsiSenseKey {
+    NO_SENSE = 0x0,
+    RECOVERED_ERROR = 0x1,
+    NOT_READY = 0x2,
+    MEDIUM_ERROR = 0x3,
+    HARDWARE_ERROR = 0x4,
+    ILLEGAL_REQUEST = 0x5,
+    UNIT_ATTENTION = 0x6,
+    DATA_PROTECT = 0x7,
+    BLANK_CHECK = 0x8,
+    VENDOR_SPECIFIC = 0x9,
+    COPY_ABORTED = 0xa,
+    ABORTED_COMMAND = 0xb,
+    RESERVED_C = 0xc,
+    VOLUME_OVERFLOW = 0xd,
+    MISCOMPARE = 0xe,
+    COMPLETED = 0xf,
+}
+
+impl From<u8> for ScsiSenseKey {
+    fn from(value: u8) -> Self {
+        use ScsiSenseKey::*;
+        match value & 0xf {
+            0x0 => NO_SENSE,
+            0x1 => RECOVERED_ERROR,
+            0x2 => NOT_READY,
+            0x3 => MEDIUM_ERROR,
+            0x4 => HARDWARE_ERROR,
+            0x5 => ILLEGAL_REQUEST,
+            0x6 => UNIT_ATTENTION,
+            0x7 => DATA_PROTECT,
+            0x8 => BLANK_CHECK,
+            0x9 => VENDOR_SPECIFIC,
+            0xa => COPY_ABORTED,
+            0xb => ABORTED_COMMAND,
+            0xc => RESERVED_C,
+            0xd => VOLUME_OVERFLOW,
+            0xe => MISCOMPARE,
+            _ => COMPLETED,
+        }
+    }
+}
+
+impl fmt::Display for ScsiSenseKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).replace('_', " "))
+    }
+}
+
+// SPC-6 Annex F, the codes a block device or a translation layer reports
+const SCSI_ASC_NAMES: &[(u8, u8, &str)] = &[
+    (0x00, 0x00, "No additional sense information"),
+    (0x00, 0x06, "I/O process terminated"),
+    (0x00, 0x16, "Operation in progress"),
+    (0x00, 0x17, "Cleaning requested"),
+    (0x00, 0x1d, "ATA pass through information available"),
+    (0x01, 0x00, "No index/sector signal"),
+    (0x02, 0x00, "No seek complete"),
+    (0x03, 0x00, "Peripheral device write fault"),
+    (0x04, 0x00, "Logical unit not ready, cause not reportable"),
+    (0x04, 0x01, "Logical unit is in process of becoming ready"),
+    (
+        0x04,
+        0x02,
+        "Logical unit not ready, initializing command required",
+    ),
+    (
+        0x04,
+        0x03,
+        "Logical unit not ready, manual intervention required",
+    ),
+    (0x04, 0x04, "Logical unit not ready, format in progress"),
+    (0x04, 0x07, "Logical unit not ready, operation in progress"),
+    (0x04, 0x09, "Logical unit not ready, self-test in progress"),
+    (
+        0x04,
+        0x0a,
+        "Logical unit not accessible, asymmetric access state transition",
+    ),
+    (
+        0x04,
+        0x0b,
+        "Logical unit not accessible, target port in standby state",
+    ),
+    (
+        0x04,
+        0x0c,
+        "Logical unit not accessible, target port in unavailable state",
+    ),
+    (
+        0x04,
+        0x11,
+        "Logical unit not ready, notify (enable spinup) required",
+    ),
+    (0x04, 0x1b, "Logical unit not ready, sanitize in progress"),
+    (0x05, 0x00, "Logical unit does not respond to selection"),
+    (0x08, 0x00, "Logical unit communication failure"),
+    (0x08, 0x01, "Logical unit communication time-out"),
+    (0x09, 0x00, "Track following error"),
+    (0x0b, 0x01, "Warning - specified temperature exceeded"),
+    (0x0b, 0x02, "Warning - enclosure degraded"),
+    (0x0c, 0x00, "Write error"),
+    (0x0c, 0x02, "Write error - auto reallocation failed"),
+    (0x0c, 0x03, "Write error - recommend reassignment"),
+    (0x10, 0x00, "ID CRC or ECC error"),
+    (0x10, 0x01, "Logical block guard check failed"),
+    (0x10, 0x02, "Logical block application tag check failed"),
+    (0x10, 0x03, "Logical block reference tag check failed"),
+    (0x11, 0x00, "Unrecovered read error"),
+    (0x11, 0x01, "Read retries exhausted"),
+    (0x11, 0x02, "Error too long to correct"),
+    (
+        0x11,
+        0x04,
+        "Unrecovered read error - auto reallocate failed",
+    ),
+    (
+        0x11,
+        0x0b,
+        "Unrecovered read error - recommend reassignment",
+    ),
+    (
+        0x11,
+        0x0c,
+        "Unrecovered read error - recommend rewrite the data",
+    ),
+    (0x12, 0x00, "Address mark not found for ID field"),
+    (0x14, 0x00, "Recorded entity not found"),
+    (0x14, 0x01, "Record not found"),
+    (0x15, 0x00, "Random positioning error"),
+    (0x16, 0x00, "Data synchronization mark error"),
+    (
+        0x17,
+        0x00,
+        "Recovered data with no error correction applied",
+    ),
+    (0x17, 0x01, "Recovered data with retries"),
+    (0x18, 0x00, "Recovered data with error correction applied"),
+    (0x18, 0x02, "Recovered data - data auto-reallocated"),
+    (0x1a, 0x00, "Parameter list length error"),
+    (0x1d, 0x00, "Miscompare during verify operation"),
+    (0x20, 0x00, "Invalid command operation code"),
+    (0x20, 0x02, "Access denied - no access rights"),
+    (0x21, 0x00, "Logical block address out of range"),
+    (0x21, 0x01, "Invalid element address"),
+    (0x21, 0x04, "Unaligned write command"),
+    (0x21, 0x05, "Write boundary violation"),
+    (0x21, 0x06, "Attempt to read invalid data"),
+    (0x24, 0x00, "Invalid field in CDB"),
+    (0x25, 0x00, "Logical unit not supported"),
+    (0x26, 0x00, "Invalid field in parameter list"),
+    (0x26, 0x01, "Parameter not supported"),
+    (0x26, 0x02, "Parameter value invalid"),
+    (0x27, 0x00, "Write protected"),
+    (0x27, 0x01, "Hardware write protected"),
+    (0x27, 0x02, "Logical unit software write protected"),
+    (0x27, 0x07, "Space allocation failed write protect"),
+    (
+        0x28,
+        0x00,
+        "Not ready to ready change, medium may have changed",
+    ),
+    (0x29, 0x00, "Power on, reset, or bus device reset occurred"),
+    (0x29, 0x01, "Power on occurred"),
+    (0x29, 0x02, "SCSI bus reset occurred"),
+    (0x29, 0x03, "Bus device reset function occurred"),
+    (0x29, 0x04, "Device internal reset"),
+    (0x29, 0x07, "I_T nexus loss occurred"),
+    (0x2a, 0x01, "Mode parameters changed"),
+    (0x2a, 0x02, "Log parameters changed"),
+    (0x2a, 0x03, "Reservations preempted"),
+    (0x2a, 0x09, "Capacity data has changed"),
+    (0x2a, 0x10, "Timestamp changed"),
+    (0x2c, 0x00, "Command sequence error"),
+    (0x2e, 0x00, "Insufficient time for operation"),
+    (0x2f, 0x00, "Commands cleared by another initiator"),
+    (0x2f, 0x01, "Commands cleared by power loss notification"),
+    (0x31, 0x00, "Medium format corrupted"),
+    (0x31, 0x01, "Format command failed"),
+    (0x31, 0x03, "Sanitize command failed"),
+    (0x32, 0x00, "No defect spare location available"),
+    (0x35, 0x00, "Enclosure services failure"),
+    (0x37, 0x00, "Rounded parameter"),
+    (0x38, 0x07, "Thin provisioning soft threshold reached"),
+    (0x39, 0x00, "Saving parameters not supported"),
+    (0x3a, 0x00, "Medium not present"),
+    (0x3e, 0x00, "Logical unit has not self-configured yet"),
+    (0x3e, 0x01, "Logical unit failure"),
+    (0x3e, 0x02, "Timeout on logical unit"),
+    (0x3e, 0x03, "Logical unit failed self-test"),
+    (0x3f, 0x01, "Microcode has been changed"),
+    (0x3f, 0x03, "Inquiry data has changed"),
+    (0x3f, 0x0e, "Reported LUNs data has changed"),
+    (0x40, 0x00, "RAM failure"),
+    (0x41, 0x00, "Data path failure"),
+    (0x42, 0x00, "Power-on or self-test failure"),
+    (0x44, 0x00, "Internal target failure"),
+    (0x44, 0x71, "ATA device failed set features"),
+    (0x47, 0x00, "SCSI parity error"),
+    (0x47, 0x01, "Data phase CRC error detected"),
+    (0x48, 0x00, "Initiator detected error message received"),
+    (0x49, 0x00, "Invalid message error"),
+    (0x4b, 0x00, "Data phase error"),
+    (0x4c, 0x00, "Logical unit failed self-configuration"),
+    (0x4e, 0x00, "Overlapped commands attempted"),
+    (0x53, 0x00, "Media load or eject failed"),
+    (0x55, 0x00, "System resource failure"),
+    (0x55, 0x03, "Insufficient resources"),
+    (0x55, 0x04, "Insufficient registration resources"),
+    (0x5d, 0x00, "Failure prediction threshold exceeded"),
+    (
+        0x5d,
+        0x10,
+        "Hardware impending failure general hard drive failure",
+    ),
+    (0x5d, 0xff, "Failure prediction threshold exceeded (false)"),
+    (0x5e, 0x00, "Low power condition on"),
+    (0x5e, 0x01, "Idle condition activated by timer"),
+    (0x5e, 0x03, "Idle condition activated by command"),
+    (0x5e, 0x04, "Standby condition activated by command"),
+    (0x65, 0x00, "Voltage fault"),
+    (0x67, 0x0a, "Set target port groups command failed"),
+    (
+        0x6f,
+        0x00,
+        "Copy protection key exchange failure - authentication failure",
+    ),
+    (0x74, 0x08, "Digital signature validation failure"),
+    (0x74, 0x71, "Logical unit access not authorized"),
+];
+
+/// Name of an ASC/ASCQ pair, None when the pair is not in the table
+pub fn scsi_asc_name(asc: u8, ascq: u8) -> Option<String> {
+    if let Some((_, _, name)) = SCSI_ASC_NAMES
+        .iter()
+        .find(|(a, q, _)| *a == asc && *q == ascq)
+    {
+        return Some(name.to_string());
+    }
+    match (asc, ascq) {
+        (0x40, q) if q >= 0x80 => Some(format!("Diagnostic failure on component {:#04x}", q)),
+        (0x4d, q) => Some(format!("Tagged overlapped commands (task tag {:#04x})", q)),
+        (0x70, q) => Some(format!(
+            "Decompression exception short algorithm id of {:#04x}",
+            q
+        )),
+        (a, _) if a >= 0x80 => Some("Vendor specific".to_string()),
+        _ => None,
+    }
+}
+
+/// Sense-key-specific field, its meaning depends on the sense key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScsiSenseKeySpecific {
+    /// ILLEGAL REQUEST: offending byte (and bit) in the CDB or parameter list
+    FieldPointer {
+        command: bool,
+        bit: Option<u8>,
+        field: u16,
+    },
+    /// RECOVERED, MEDIUM or HARDWARE ERROR: retries the device spent
+    RetryCount(u16),
+    /// NOT READY or NO SENSE: progress of a format, sanitize or self-test, of 65536
+    Progress(u16),
+    /// COPY ABORTED: segment that failed
+    SegmentPointer {
+        descriptor: bool,
+        bit: Option<u8>,
+        field: u16,
+    },
+    /// UNIT ATTENTION: the unit attention queue overflowed
+    UnitAttentionOverflow(bool),
+    Reserved([u8; 3]),
+}
+
+impl ScsiSenseKeySpecific {
+    fn parse(key: ScsiSenseKey, sks: &[u8]) -> Option<Self> {
+        if sks.len() < 3 || sks[0] & 0x80 == 0 {
+            return None;
+        }
+        let value = u16::from_be_bytes([sks[1], sks[2]]);
+        let bit = (sks[0] & 0x08 != 0).then_some(sks[0] & 0x07);
+        use ScsiSenseKey::*;
+        Some(match key {
+            ILLEGAL_REQUEST => ScsiSenseKeySpecific::FieldPointer {
+                command: sks[0] & 0x40 != 0,
+                bit,
+                field: value,
+            },
+            RECOVERED_ERROR | MEDIUM_ERROR | HARDWARE_ERROR => {
+                ScsiSenseKeySpecific::RetryCount(value)
+            }
+            NO_SENSE | NOT_READY => ScsiSenseKeySpecific::Progress(value),
+            COPY_ABORTED => ScsiSenseKeySpecific::SegmentPointer {
+                descriptor: sks[0] & 0x20 != 0,
+                bit,
+                field: value,
+            },
+            UNIT_ATTENTION => ScsiSenseKeySpecific::UnitAttentionOverflow(sks[0] & 0x01 != 0),
+            _ => ScsiSenseKeySpecific::Reserved([sks[0], sks[1], sks[2]]),
+        })
+    }
+}
+
+impl fmt::Display for ScsiSenseKeySpecific {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScsiSenseKeySpecific::FieldPointer {
+                command,
+                bit,
+                field,
+            } => {
+                let place = if *command { "CDB" } else { "parameter list" };
+                write!(f, "invalid field in {} byte {}", place, field)?;
+                if let Some(bit) = bit {
+                    write!(f, " bit {}", bit)?;
+                }
+                Ok(())
+            }
+            ScsiSenseKeySpecific::RetryCount(count) => write!(f, "actual retry count {}", count),
+            ScsiSenseKeySpecific::Progress(progress) => {
+                write!(f, "progress {:.2}%", *progress as f64 * 100.0 / 65536.0)
+            }
+            ScsiSenseKeySpecific::SegmentPointer {
+                descriptor,
+                bit,
+                field,
+            } => {
+                let place = if *descriptor {
+                    "segment descriptor"
+                } else {
+                    "parameter list"
+                };
+                write!(f, "copy aborted at {} byte {}", place, field)?;
+                if let Some(bit) = bit {
+                    write!(f, " bit {}", bit)?;
+                }
+                Ok(())
+            }
+            ScsiSenseKeySpecific::UnitAttentionOverflow(overflow) => {
+                write!(f, "unit attention queue overflow {}", overflow)
+            }
+            ScsiSenseKeySpecific::Reserved(bytes) => write!(f, "sense key specific {:02x?}", bytes),
+        }
+    }
+}
+
+/// Decoded fixed (70h/71h) or descriptor (72h/73h) format sense data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScsiSense {
+    pub response_code: u8,
+    pub descriptor_format: bool,
+    pub deferred: bool,
+    pub sense_key: ScsiSenseKey,
+    pub asc: u8,
+    pub ascq: u8,
+    pub information: Option<u64>,
+    pub command_specific: Option<u64>,
+    pub fru: u8,
+    pub sense_key_specific: Option<ScsiSenseKeySpecific>,
+    pub filemark: bool,
+    pub eom: bool,
+    pub ili: bool,
+    /// Progress indication descriptor: sense key, ASC, ASCQ and progress of
+    /// the operation in progress
+    pub progress: Option<(ScsiSenseKey, u8, u8, u16)>,
+    pub descriptors: Vec<(u8, Vec<u8>)>, // descriptor format types not decoded above
+}
+
+impl ScsiSense {
+    /// Decode a sense buffer; None when it holds no recognised sense data
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let response_code = *data.first()? & 0x7f;
+        match response_code {
+            0x70 | 0x71 => Self::parse_fixed(data),
+            0x72 | 0x73 => Self::parse_descriptor(data),
+            _ => None,
+        }
+    }
+
+    fn empty(response_code: u8) -> Self {
+        Self {
+            response_code,
+            descriptor_format: response_code >= 0x72,
+            deferred: response_code & 1 != 0,
+            sense_key: ScsiSenseKey::NO_SENSE,
+            asc: 0,
+            ascq: 0,
+            information: None,
+            command_specific: None,
+            fru: 0,
+            sense_key_specific: None,
+            filemark: false,
+            eom: false,
+            ili: false,
+            progress: None,
+            descriptors: vec![],
+        }
+    }
+
+    fn parse_fixed(data: &[u8]) -> Option<Self> {
+        if data.len() < 3 {
+            return None;
+        }
+        let byte = |index: usize| data.get(index).copied().unwrap_or(0);
+        let be32 =
+            |at: usize| u32::from_be_bytes([byte(at), byte(at + 1), byte(at + 2), byte(at + 3)]);
+        // only the bytes the device said it returned are meaningful
+        let len = (8 + byte(7) as usize).min(data.len());
+        let mut sense = Self::empty(data[0] & 0x7f);
+        sense.sense_key = ScsiSenseKey::from(data[2]);
+        sense.filemark = data[2] & 0x80 != 0;
+        sense.eom = data[2] & 0x40 != 0;
+        sense.ili = data[2] & 0x20 != 0;
+        if data[0] & 0x80 != 0 {
+            sense.information = Some(be32(3) as u64);
+        }
+        if len >= 12 && be32(8) != 0 {
+            sense.command_specific = Some(be32(8) as u64);
+        }
+        if len >= 14 {
+            sense.asc = byte(12);
+            sense.ascq = byte(13);
+        }
+        if len >= 15 {
+            sense.fru = byte(14);
+        }
+        if len >= 18 {
+            sense.sense_key_specific = ScsiSenseKeySpecific::parse(sense.sense_key, &data[15..18]);
+        }
+        Some(sense)
+    }
+
+    fn parse_descriptor(data: &[u8]) -> Option<Self> {
+        if data.len() < 8 {
+            return None;
+        }
+        let mut sense = Self::empty(data[0] & 0x7f);
+        sense.sense_key = ScsiSenseKey::from(data[1]);
+        sense.asc = data[2];
+        sense.ascq = data[3];
+        let end = (8 + data[7] as usize).min(data.len());
+        let mut at = 8;
+        while at + 2 <= end {
+            let kind = data[at];
+            let len = 2 + data[at + 1] as usize;
+            let desc = &data[at..(at + len).min(end)];
+            let be64 = |from: usize| -> Option<u64> {
+                Some(u64::from_be_bytes(
+                    desc.get(from..from + 8)?.try_into().ok()?,
+                ))
+            };
+            match kind {
+                0x00 if desc.len() >= 12 => {
+                    if desc[2] & 0x80 != 0 {
+                        sense.information = be64(4);
+                    }
+                }
+                0x01 if desc.len() >= 12 => sense.command_specific = be64(4),
+                0x02 if desc.len() >= 7 => {
+                    sense.sense_key_specific =
+                        ScsiSenseKeySpecific::parse(sense.sense_key, &desc[4..7])
+                }
+                0x03 if desc.len() >= 4 => sense.fru = desc[3],
+                0x04 if desc.len() >= 4 => {
+                    sense.filemark = desc[3] & 0x80 != 0;
+                    sense.eom = desc[3] & 0x40 != 0;
+                    sense.ili = desc[3] & 0x20 != 0;
+                }
+                0x05 if desc.len() >= 4 => sense.ili = desc[3] & 0x20 != 0,
+                0x0a if desc.len() >= 8 => {
+                    sense.progress = Some((
+                        ScsiSenseKey::from(desc[2]),
+                        desc[3],
+                        desc[4],
+                        u16::from_be_bytes([desc[6], desc[7]]),
+                    ))
+                }
+                _ => sense.descriptors.push((kind, desc.to_vec())),
+            }
+            at += len;
+        }
+        Some(sense)
+    }
+
+    pub fn asc_name(&self) -> String {
+        scsi_asc_name(self.asc, self.ascq)
+            .unwrap_or_else(|| format!("Unknown ASC/ASCQ {:02x}/{:02x}", self.asc, self.ascq))
+    }
+
+    /// Progress of a long running operation in percent, from either the
+    /// sense key specific field or a progress indication descriptor
+    pub fn progress_percent(&self) -> Option<f64> {
+        let progress = match (self.sense_key_specific, self.progress) {
+            (Some(ScsiSenseKeySpecific::Progress(progress)), _) => progress,
+            (_, Some((_, _, _, progress))) => progress,
+            _ => return None,
+        };
+        Some(progress as f64 * 100.0 / 65536.0)
+    }
+}
+
+impl fmt::Display for ScsiSense {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} (ASC/ASCQ {:02x}/{:02x})",
+            self.sense_key,
+            self.asc_name(),
+            self.asc,
+            self.ascq
+        )?;
+        if self.deferred {
+            write!(f, ", deferred")?;
+        }
+        if let Some(information) = self.information {
+            write!(f, ", information {:#x}", information)?;
+        }
+        if let Some(command_specific) = self.command_specific {
+            write!(f, ", command specific {:#x}", command_specific)?;
+        }
+        if self.fru != 0 {
+            write!(f, ", FRU {:#04x}", self.fru)?;
+        }
+        if let Some(sks) = &self.sense_key_specific {
+            write!(f, ", {}", sks)?;
+        }
+        if let Some((key, asc, ascq, progress)) = self.progress {
+            write!(
+                f,
+                ", {} {:.2}% ({}, {:02x}/{:02x})",
+                scsi_asc_name(asc, ascq).unwrap_or_else(|| "operation".to_string()),
+                progress as f64 * 100.0 / 65536.0,
+                key,
+                asc,
+                ascq
+            )?;
+        }
+        for (flag, name) in [
+            (self.filemark, "FILEMARK"),
+            (self.eom, "EOM"),
+            (self.ili, "ILI"),
+        ] {
+            if flag {
+                write!(f, ", {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A SCSI command that completed with a status other than GOOD. Carried inside
+/// `io::Error` by the passthrough paths; use `ScsiError::from_io` to get it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScsiError {
+    pub opcode: u8,
+    pub status: u8,
+    pub sense: Option<ScsiSense>,
+}
+
+impl ScsiError {
+    pub fn new(opcode: u8, status: u8, sense: &[u8]) -> Self {
+        Self {
+            opcode,
+            status,
+            sense: ScsiSense::parse(sense),
+        }
+    }
+
+    pub fn from_io(error: &std::io::Error) -> Option<&ScsiError> {
+        error.get_ref()?.downcast_ref::<ScsiError>()
+    }
+
+    pub fn sense_key(&self) -> Option<ScsiSenseKey> {
+        self.sense.as_ref().map(|sense| sense.sense_key)
+    }
+}
+
+impl fmt::Display for ScsiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SCSI opcode {:#04x} failed with {} ({:#04x})",
+            self.opcode,
+            scsi_status_name(self.status),
+            self.status
+        )?;
+        if let Some(sense) = &self.sense {
+            write!(f, ": {}", sense)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ScsiError {}
+
+/// Ok for GOOD and CONDITION MET, else the status with its decoded sense data
+pub fn scsi_check_status(opcode: u8, status: u8, sense: &[u8]) -> Result<(), ScsiError> {
+    match status {
+        SCSI_STATUS_GOOD | SCSI_STATUS_CONDITION_MET => Ok(()),
+        _ => Err(ScsiError::new(opcode, status, sense)),
+    }
+}
+
+impl From<ScsiError> for std::io::Error {
+    fn from(error: ScsiError) -> Self {
+        let kind = match error.sense_key() {
+            Some(ScsiSenseKey::ILLEGAL_REQUEST) => std::io::ErrorKind::InvalidInput,
+            Some(ScsiSenseKey::DATA_PROTECT) => std::io::ErrorKind::PermissionDenied,
+            _ => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, error)
+    }
+}
+
+/// ASCII field of INQUIRY or VPD data with the padding removed
+fn scsi_text(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_matches(|c: char| c == ' ' || c == '\0')
+        .to_string()
+}
+
+/// Standard INQUIRY data
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScsiInquiry {
+    pub peripheral_qualifier: u8,
+    pub device_type: u8,
+    pub removable: bool,
+    pub version: u8,
+    pub response_format: u8,
+    pub protect: bool,
+    pub command_queueing: bool,
+    pub vendor: String,
+    pub product: String,
+    pub revision: String,
+}
+
+impl ScsiInquiry {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 36 {
+            return None;
+        }
+        Some(Self {
+            peripheral_qualifier: data[0] >> 5,
+            device_type: data[0] & 0x1f,
+            removable: data[1] & 0x80 != 0,
+            version: data[2],
+            response_format: data[3] & 0x0f,
+            protect: data[5] & 0x01 != 0,
+            command_queueing: data[7] & 0x02 != 0,
+            vendor: scsi_text(&data[8..16]),
+            product: scsi_text(&data[16..32]),
+            revision: scsi_text(&data[32..36]),
+        })
+    }
+
+    pub fn device_type_name(&self) -> &'static str {
+        match self.device_type {
+            0x00 => "Direct access block device",
+            0x01 => "Sequential access device",
+            0x05 => "CD/DVD device",
+            0x07 => "Optical memory device",
+            0x0c => "Storage array controller",
+            0x0d => "Enclosure services device",
+            0x0e => "Simplified direct access device",
+            0x14 => "Host managed zoned block device",
+            0x1f => "Unknown or no device type",
+            _ => "Reserved",
+        }
+    }
+}
+
+impl fmt::Display for ScsiInquiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}: {}, SPC version {:#04x}",
+            self.vendor,
+            self.product,
+            self.revision,
+            self.device_type_name(),
+            self.version
+        )?;
+        for (flag, name) in [
+            (self.removable, "removable"),
+            (self.protect, "protection"),
+            (self.command_queueing, "command queueing"),
+        ] {
+            if flag {
+                write!(f, ", {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Page code and payload of a VPD page, the payload clamped to the page length
+pub fn scsi_vpd_payload(data: &[u8]) -> Option<(u8, &[u8])> {
+    if data.len() < 4 {
+        return None;
+    }
+    let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    Some((data[1], &data[4..(4 + len).min(data.len())]))
+}
+
+/// Supported VPD pages (00h)
+pub fn scsi_vpd_supported_pages(data: &[u8]) -> Vec<u8> {
+    scsi_vpd_payload(data)
+        .map(|(_, pages)| pages.to_vec())
+        .unwrap_or_default()
+}
+
+/// Unit serial number (80h)
+pub fn scsi_vpd_unit_serial(data: &[u8]) -> Option<String> {
+    scsi_vpd_payload(data).map(|(_, serial)| scsi_text(serial))
+}
+
+/// One designation descriptor of the device identification page (83h)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScsiDesignator {
+    pub protocol: Option<u8>, // when PIV is set
+    pub code_set: u8,
+    pub association: u8,
+    pub designator_type: u8,
+    pub identifier: Vec<u8>,
+}
+
+impl ScsiDesignator {
+    /// Device identification (83h) designators
+    pub fn parse_page(data: &[u8]) -> Vec<Self> {
+        let mut designators = vec![];
+        let Some((_, mut body)) = scsi_vpd_payload(data) else {
+            return designators;
+        };
+        while body.len() >= 4 {
+            let len = (4 + body[3] as usize).min(body.len());
+            designators.push(Self {
+                protocol: (body[1] & 0x80 != 0).then_some(body[0] >> 4),
+                code_set: body[0] & 0x0f,
+                association: (body[1] >> 4) & 0x03,
+                designator_type: body[1] & 0x0f,
+                identifier: body[4..len].to_vec(),
+            });
+            body = &body[len..];
+        }
+        designators
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self.designator_type {
+            0x0 => "Vendor specific",
+            0x1 => "T10 vendor ID",
+            0x2 => "EUI-64",
+            0x3 => "NAA",
+            0x4 => "Relative target port",
+            0x5 => "Target port group",
+            0x6 => "Logical unit group",
+            0x7 => "MD5 logical unit",
+            0x8 => "SCSI name string",
+            0x9 => "Protocol specific port",
+            0xa => "UUID",
+            _ => "Reserved",
+        }
+    }
+
+    pub fn association_name(&self) -> &'static str {
+        match self.association {
+            0 => "logical unit",
+            1 => "target port",
+            2 => "target device",
+            _ => "reserved",
+        }
+    }
+}
+
+impl fmt::Display for ScsiDesignator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}): ", self.type_name(), self.association_name())?;
+        // code set 2 is ASCII and 3 is UTF-8, anything else is binary
+        if self.code_set == 2 || self.code_set == 3 {
+            write!(f, "{}", scsi_text(&self.identifier))
+        } else {
+            for byte in &self.identifier {
+                write!(f, "{:02x}", byte)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Block limits (B0h), lengths in logical blocks, 0 when not reported
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScsiBlockLimits {
+    pub max_compare_write: u8,
+    pub optimal_transfer_granularity: u16,
+    pub max_transfer: u32,
+    pub optimal_transfer: u32,
+    pub max_prefetch: u32,
+    pub max_unmap_lba_count: u32,
+    pub max_unmap_descriptors: u32,
+    pub optimal_unmap_granularity: u32,
+    pub unmap_granularity_alignment: Option<u32>, // when UGAVALID is set
+    pub max_write_same: u64,
+}
+
+impl ScsiBlockLimits {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (page, body) = scsi_vpd_payload(data)?;
+        if page != SCSI_VPD_BLOCK_LIMITS || body.len() < 12 {
+            return None;
+        }
+        let byte = |at: usize| body.get(at).copied().unwrap_or(0);
+        let be32 =
+            |at: usize| u32::from_be_bytes([byte(at), byte(at + 1), byte(at + 2), byte(at + 3)]);
+        let ugavalid = byte(28) & 0x80 != 0;
+        Some(Self {
+            max_compare_write: byte(1),
+            optimal_transfer_granularity: u16::from_be_bytes([byte(2), byte(3)]),
+            max_transfer: be32(4),
+            optimal_transfer: be32(8),
+            max_prefetch: be32(12),
+            max_unmap_lba_count: be32(16),
+            max_unmap_descriptors: be32(20),
+            optimal_unmap_granularity: be32(24),
+            unmap_granularity_alignment: ugavalid.then(|| be32(28) & 0x7fff_ffff),
+            max_write_same: (be32(32) as u64) << 32 | be32(36) as u64,
+        })
+    }
+}
+
+impl fmt::Display for ScsiBlockLimits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "max transfer {}, optimal transfer {} (granularity {}), max unmap {} blocks in {} descriptors, unmap granularity {}",
+            self.max_transfer,
+            self.optimal_transfer,
+            self.optimal_transfer_granularity,
+            self.max_unmap_lba_count,
+            self.max_unmap_descriptors,
+            self.optimal_unmap_granularity
+        )?;
+        if let Some(alignment) = self.unmap_granularity_alignment {
+            write!(f, " aligned at {}", alignment)?;
+        }
+        if self.max_write_same != 0 {
+            write!(f, ", max write same {}", self.max_write_same)?;
+        }
+        Ok(())
+    }
+}
+
+/// Block device characteristics (B1h)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScsiBlockCharacteristics {
+    pub rotation_rate: u16, // 1 for non-rotating media, else RPM
+    pub product_type: u8,
+    pub form_factor: u8,
+    pub zoned: u8,
+}
+
+impl ScsiBlockCharacteristics {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (page, body) = scsi_vpd_payload(data)?;
+        if page != SCSI_VPD_BLOCK_DEVICE_CHARACTERISTICS || body.len() < 4 {
+            return None;
+        }
+        Some(Self {
+            rotation_rate: u16::from_be_bytes([body[0], body[1]]),
+            product_type: body[2],
+            form_factor: body[3] & 0x0f,
+            zoned: (body[4..].first().copied().unwrap_or(0) >> 4) & 0x03,
+        })
+    }
+
+    pub fn form_factor_name(&self) -> &'static str {
+        match self.form_factor {
+            0x0 => "not reported",
+            0x1 => "5.25 inch",
+            0x2 => "3.5 inch",
+            0x3 => "2.5 inch",
+            0x4 => "1.8 inch",
+            0x5 => "less than 1.8 inch",
+            _ => "reserved",
+        }
+    }
+}
+
+impl fmt::Display for ScsiBlockCharacteristics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rotation_rate {
+            0 => write!(f, "rotation rate not reported")?,
+            1 => write!(f, "non-rotating medium")?,
+            rpm => write!(f, "{} rpm", rpm)?,
+        }
+        write!(f, ", form factor {}", self.form_factor_name())?;
+        if self.zoned != 0 {
+            write!(f, ", zoned {}", self.zoned)?;
+        }
+        Ok(())
+    }
+}
+
+/// Logical block provisioning (B2h)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScsiLogicalBlockProvisioning {
+    pub threshold_exponent: u8,
+    pub lbpu: bool,    // UNMAP supported
+    pub lbpws: bool,   // WRITE SAME(16) with UNMAP supported
+    pub lbpws10: bool, // WRITE SAME(10) with UNMAP supported
+    pub lbprz: u8,     // unmapped blocks read as zeros when non-zero
+    pub anc_sup: bool,
+    pub provisioning_type: u8,
+}
+
+impl ScsiLogicalBlockProvisioning {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (page, body) = scsi_vpd_payload(data)?;
+        if page != SCSI_VPD_LOGICAL_BLOCK_PROVISIONING || body.len() < 3 {
+            return None;
+        }
+        Some(Self {
+            threshold_exponent: body[0],
+            lbpu: body[1] & 0x80 != 0,
+            lbpws: body[1] & 0x40 != 0,
+            lbpws10: body[1] & 0x20 != 0,
+            lbprz: (body[1] >> 2) & 0x07,
+            anc_sup: body[1] & 0x02 != 0,
+            provisioning_type: body[2] & 0x07,
+        })
+    }
+
+    pub fn provisioning_type_name(&self) -> &'static str {
+        match self.provisioning_type {
+            0 => "fully provisioned",
+            1 => "resource provisioned",
+            2 => "thin provisioned",
+            _ => "reserved",
+        }
+    }
+}
+
+impl fmt::Display for ScsiLogicalBlockProvisioning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.provisioning_type_name())?;
+        for (flag, name) in [
+            (self.lbpu, "UNMAP"),
+            (self.lbpws, "WRITE SAME(16) unmap"),
+            (self.lbpws10, "WRITE SAME(10) unmap"),
+            (self.lbprz != 0, "unmapped reads zero"),
+            (self.anc_sup, "anchor"),
+        ] {
+            if flag {
+                write!(f, ", {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// READ CAPACITY (16) parameter data, or READ CAPACITY (10) widened to it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScsiReadCapacity {
+    pub last_lba: u64,
+    pub block_length: u32,
+    pub protection_type: Option<u8>, // 1 to 3 when PROT_EN is set
+    pub protection_interval_exponent: u8,
+    pub physical_block_exponent: u8, // logical blocks per physical block exponent
+    pub lbpme: bool,
+    pub lbprz: bool,
+    pub lowest_aligned_lba: u16,
+}
+
+impl ScsiReadCapacity {
+    pub fn parse_16(data: &[u8]) -> Option<Self> {
+        if data.len() < 16 {
+            return None;
+        }
+        Some(Self {
+            last_lba: u64::from_be_bytes(data[0..8].try_into().ok()?),
+            block_length: u32::from_be_bytes(data[8..12].try_into().ok()?),
+            protection_type: (data[12] & 0x01 != 0).then_some(((data[12] >> 1) & 0x07) + 1),
+            protection_interval_exponent: data[13] >> 4,
+            physical_block_exponent: data[13] & 0x0f,
+            lbpme: data[14] & 0x80 != 0,
+            lbprz: data[14] & 0x40 != 0,
+            lowest_aligned_lba: u16::from_be_bytes([data[14] & 0x3f, data[15]]),
+        })
+    }
+
+    /// FFFFFFFFh as the last LBA means the capacity needs READ CAPACITY (16)
+    pub fn parse_10(data: &[u8]) -> Option<Self> {
+        if data.len() < 8 {
+            return None;
+        }
+        Some(Self {
+            last_lba: u32::from_be_bytes(data[0..4].try_into().ok()?) as u64,
+            block_length: u32::from_be_bytes(data[4..8].try_into().ok()?),
+            ..Default::default()
+        })
+    }
+
+    pub fn blocks(&self) -> io::Result<u64> {
+        self.last_lba
+            .checked_add(1)
+            .ok_or_else(|| capacity_overflow(self))
+    }
+
+    pub fn capacity(&self) -> io::Result<u64> {
+        self.blocks()?
+            .checked_mul(self.block_length as u64)
+            .ok_or_else(|| capacity_overflow(self))
+    }
+
+    pub fn physical_block_size(&self) -> u64 {
+        (self.block_length as u64) << self.physical_block_exponent
+    }
+}
+
+fn capacity_overflow(capacity: &ScsiReadCapacity) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "last LBA {} with {} byte blocks overflows the capacity",
+            capacity.last_lba, capacity.block_length
+        ),
+    )
+}
+
+impl fmt::Display for ScsiReadCapacity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.blocks(), self.capacity()) {
+            (Ok(blocks), Ok(capacity)) => write!(
+                f,
+                "{} blocks of {} bytes ({} bytes)",
+                blocks, self.block_length, capacity
+            )?,
+            _ => write!(
+                f,
+                "last LBA {} with {} byte blocks (capacity overflows)",
+                self.last_lba, self.block_length
+            )?,
+        }
+        write!(f, ", physical block {} bytes", self.physical_block_size())?;
+        if self.lowest_aligned_lba != 0 {
+            write!(f, ", lowest aligned LBA {}", self.lowest_aligned_lba)?;
+        }
+        if let Some(protection) = self.protection_type {
+            write!(
+                f,
+                ", protection type {} interval 2^{}",
+                protection, self.protection_interval_exponent
+            )?;
+        }
+        if self.lbpme {
+            write!(f, ", thin provisioning")?;
+            if self.lbprz {
+                write!(f, " (unmapped reads zero)")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_capacity() {
+        let mut data = [0u8; 32];
+        data[0..8].copy_from_slice(&0x1_0000_0000u64.to_be_bytes());
+        data[8..12].copy_from_slice(&512u32.to_be_bytes());
+        data[12] = 0x03; // PROT_EN, type 2
+        data[13] = 0x23; // interval 2^2, 8 logical blocks per physical block
+        data[14] = 0xC0;
+        data[15] = 0x07;
+        let capacity = ScsiReadCapacity::parse_16(&data).unwrap();
+        assert_eq!(capacity.blocks().unwrap(), 0x1_0000_0001);
+        assert_eq!(capacity.capacity().unwrap(), 0x1_0000_0001 * 512);
+        assert_eq!(capacity.physical_block_size(), 4096);
+        assert_eq!(capacity.protection_type, Some(2));
+        assert_eq!(capacity.protection_interval_exponent, 2);
+        assert!(capacity.lbpme && capacity.lbprz);
+        assert_eq!(capacity.lowest_aligned_lba, 7);
+        assert!(ScsiReadCapacity::parse_16(&data[..15]).is_none());
+
+        let capacity = ScsiReadCapacity::parse_10(&[0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 2, 0]).unwrap();
+        assert_eq!(capacity.blocks().unwrap(), 0x1_0000_0000);
+        assert_eq!(capacity.protection_type, None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn pass_through_direct_layout() {
+        let mut sptdwb = SCSI_PASS_THROUGH_DIRECT_WITH_BUFFER::new(SCSI_IOCTL_DATA_IN);
+        let base = &sptdwb as *const _ as usize;
+        assert_eq!(
+            sptdwb.sptd.SenseInfoOffset as usize,
+            sptdwb.ucSenseBuf.as_ptr() as usize - base
+        );
+        assert_eq!(sptdwb.sptd.SenseInfoLength as usize, 32);
+        assert_eq!(
+            sptdwb.sptd.Length as usize,
+            size_of::<SCSI_PASS_THROUGH_DIRECT>()
+        );
+
+        sptdwb.ucSenseBuf[0] = 0x70;
+        sptdwb.sptd.ScsiStatus = SCSI_STATUS_CHECK_CONDITION;
+        let mut data = [0u8; 512];
+        let command =
+            ScsiCommand::from_bytes(&[0x2a, 0, 0, 0, 0, 1, 0, 0, 1, 0], ScsiDataDirection::Out)
+                .with_timeout(Duration::from_millis(1500));
+        sptdwb.set_command(&command, &mut data);
+        assert_eq!(sptdwb.sptd.Cdb, command.cdb);
+        assert_eq!(sptdwb.sptd.CdbLength, 10);
+        assert_eq!(sptdwb.sptd.DataIn, SCSI_IOCTL_DATA_OUT as u8);
+        assert_eq!(sptdwb.sptd.DataBuffer, data.as_mut_ptr() as *mut c_void);
+        assert_eq!(sptdwb.sptd.DataTransferLength, 512);
+        assert_eq!(sptdwb.sptd.TimeOutValue, 2); // rounded up
+        assert_eq!(sptdwb.sptd.ScsiStatus, SCSI_STATUS_GOOD);
+        assert_eq!(sptdwb.ucSenseBuf, SenseBuffer::default());
+
+        let command = ScsiCommand::from_bytes(&[0; 6], ScsiDataDirection::None)
+            .with_timeout(Duration::from_millis(1));
+        sptdwb.set_command(&command, &mut data);
+        assert_eq!(sptdwb.sptd.DataIn, SCSI_IOCTL_DATA_UNSPECIFIED as u8);
+        assert_eq!(sptdwb.sptd.DataTransferLength, 0);
+        assert_eq!(sptdwb.sptd.TimeOutValue, 1);
+    }
+
+    #[test]
+    fn read_capacity_overflow() {
+        let mut capacity = ScsiReadCapacity {
+            last_lba: u64::MAX,
+            block_length: 512,
+            ..Default::default()
+        };
+        assert!(capacity.blocks().is_err());
+        assert!(capacity.capacity().is_err());
+        assert!(capacity.to_string().contains("overflows"));
+
+        capacity.last_lba = u64::MAX / 2;
+        assert!(capacity.blocks().is_ok());
+        assert!(capacity.capacity().is_err());
+    }
+
+    fn descriptor_sense(response_code: u8, key: u8, descriptors: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![response_code, key, 0x04, 0x04, 0, 0, 0, 0];
+        for descriptor in descriptors {
+            data.extend_from_slice(descriptor);
+        }
+        data[7] = (data.len() - 8) as u8;
+        data
+    }
+
+    #[test]
+    fn sense_key_specific() {
+        use ScsiSenseKey::*;
+        let parse = ScsiSenseKeySpecific::parse;
+        assert_eq!(parse(ILLEGAL_REQUEST, &[0x00, 0x00, 0x10]), None); // SKSV clear
+        assert_eq!(parse(ILLEGAL_REQUEST, &[0x80, 0x00]), None);
+        assert_eq!(
+            parse(ILLEGAL_REQUEST, &[0x80, 0x00, 0x10]),
+            Some(ScsiSenseKeySpecific::FieldPointer {
+                command: false,
+                bit: None,
+                field: 16
+            })
+        );
+        let pointer = parse(ILLEGAL_REQUEST, &[0xcb, 0x01, 0x02]).unwrap();
+        assert_eq!(
+            pointer,
+            ScsiSenseKeySpecific::FieldPointer {
+                command: true,
+                bit: Some(3),
+                field: 0x102
+            }
+        );
+        assert_eq!(pointer.to_string(), "invalid field in CDB byte 258 bit 3");
+        for key in [RECOVERED_ERROR, MEDIUM_ERROR, HARDWARE_ERROR] {
+            assert_eq!(
+                parse(key, &[0x80, 0x01, 0x00]),
+                Some(ScsiSenseKeySpecific::RetryCount(256))
+            );
+        }
+        for key in [NO_SENSE, NOT_READY] {
+            assert_eq!(
+                parse(key, &[0x80, 0xc0, 0x00]),
+                Some(ScsiSenseKeySpecific::Progress(0xc000))
+            );
+        }
+        assert_eq!(
+            parse(COPY_ABORTED, &[0xad, 0x00, 0x05]),
+            Some(ScsiSenseKeySpecific::SegmentPointer {
+                descriptor: true,
+                bit: Some(5),
+                field: 5
+            })
+        );
+        assert_eq!(
+            parse(UNIT_ATTENTION, &[0x81, 0x00, 0x00]),
+            Some(ScsiSenseKeySpecific::UnitAttentionOverflow(true))
+        );
+        assert_eq!(
+            parse(DATA_PROTECT, &[0x80, 0x01, 0x02]),
+            Some(ScsiSenseKeySpecific::Reserved([0x80, 0x01, 0x02]))
+        );
+    }
+
+    #[test]
+    fn fixed_sense() {
+        let data = [
+            0xf0, // VALID, current
+            0x00, 0x25, // ILI, ILLEGAL REQUEST
+            0x00, 0x12, 0x34, 0x56, // information
+            10,   // additional length
+            0xde, 0xad, 0xbe, 0xef, // command specific
+            0x24, 0x00, // ASC/ASCQ
+            0x07, // FRU
+            0xcb, 0x00, 0x02, // field pointer: CDB byte 2 bit 3
+        ];
+        let sense = ScsiSense::parse(&data).unwrap();
+        assert_eq!(
+            sense,
+            ScsiSense {
+                sense_key: ScsiSenseKey::ILLEGAL_REQUEST,
+                asc: 0x24,
+                information: Some(0x123456),
+                command_specific: Some(0xdeadbeef),
+                fru: 7,
+                sense_key_specific: Some(ScsiSenseKeySpecific::FieldPointer {
+                    command: true,
+                    bit: Some(3),
+                    field: 2
+                }),
+                ili: true,
+                ..ScsiSense::empty(0x70)
+            }
+        );
+        let text = sense.to_string();
+        assert!(text.contains("information 0x123456"), "{}", text);
+        assert!(
+            text.contains("invalid field in CDB byte 2 bit 3"),
+            "{}",
+            text
+        );
+        assert!(text.ends_with(", ILI"), "{}", text);
+        assert_eq!(sense.progress_percent(), None);
+
+        // deferred, no VALID bit: the information bytes are not meaningful
+        let mut deferred = data;
+        deferred[0] = 0x71;
+        let sense = ScsiSense::parse(&deferred).unwrap();
+        assert!(sense.deferred && !sense.descriptor_format);
+        assert_eq!(sense.information, None);
+
+        // a zero command specific field is left out
+        let mut zero = data;
+        zero[8..12].fill(0);
+        assert_eq!(ScsiSense::parse(&zero).unwrap().command_specific, None);
+
+        let mut progress = [0u8; 18];
+        progress[0] = 0x70;
+        progress[2] = 0x02; // NOT READY
+        progress[7] = 10;
+        progress[15..18].copy_from_slice(&[0x80, 0x40, 0x00]);
+        assert_eq!(
+            ScsiSense::parse(&progress).unwrap().progress_percent(),
+            Some(25.0)
+        );
+    }
+
+    #[test]
+    fn fixed_sense_truncated() {
+        let mut data = [0u8; 18];
+        data[0] = 0x70;
+        data[2] = 0x05;
+        data[8..12].copy_from_slice(&1u32.to_be_bytes());
+        data[12] = 0x24;
+        data[13] = 0x01;
+        data[14] = 0x07;
+        data[15..18].copy_from_slice(&[0xc0, 0x00, 0x01]);
+        let parse = |additional: u8, len: usize| {
+            let mut data = data;
+            data[7] = additional;
+            ScsiSense::parse(&data[..len]).unwrap()
+        };
+
+        // the additional length ends the data at a field boundary, bytes
+        // past it are ignored even when the buffer holds them
+        let sense = parse(0, 18);
+        assert_eq!(
+            (
+                sense.command_specific,
+                sense.asc,
+                sense.fru,
+                sense.sense_key_specific
+            ),
+            (None, 0, 0, None)
+        );
+        assert_eq!(sense.sense_key, ScsiSenseKey::ILLEGAL_REQUEST);
+        let sense = parse(4, 18);
+        assert_eq!((sense.command_specific, sense.asc), (Some(1), 0));
+        let sense = parse(6, 18);
+        assert_eq!((sense.asc, sense.ascq, sense.fru), (0x24, 0x01, 0));
+        let sense = parse(7, 18);
+        assert_eq!((sense.fru, sense.sense_key_specific), (7, None));
+        assert!(parse(10, 18).sense_key_specific.is_some());
+
+        // so does a buffer shorter than the additional length
+        let sense = parse(10, 14);
+        assert_eq!(
+            (sense.asc, sense.fru, sense.sense_key_specific),
+            (0x24, 0, None)
+        );
+        let sense = parse(10, 17);
+        assert_eq!((sense.fru, sense.sense_key_specific), (7, None));
+        let sense = parse(10, 3);
+        assert_eq!((sense.command_specific, sense.asc), (None, 0));
+
+        assert!(ScsiSense::parse(&data[..2]).is_none());
+        assert!(ScsiSense::parse(&[]).is_none());
+        assert!(ScsiSense::parse(&[0x7f, 0, 5, 0, 0, 0, 0, 0]).is_none());
+        assert!(ScsiSense::parse(&[0x00; 18]).is_none());
+    }
+
+    #[test]
+    fn descriptor_sense_all() {
+        let information: &[u8] = &[0x00, 0x0a, 0x80, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+        let command_specific: &[u8] = &[0x01, 0x0a, 0, 0, 0, 0, 0, 0, 0xca, 0xfe, 0, 0];
+        let sks: &[u8] = &[0x02, 0x06, 0, 0, 0x80, 0x40, 0x00, 0];
+        let fru: &[u8] = &[0x03, 0x02, 0, 0x09];
+        let stream: &[u8] = &[0x04, 0x02, 0, 0xe0];
+        let block: &[u8] = &[0x05, 0x02, 0, 0x20];
+        let progress: &[u8] = &[0x0a, 0x06, 0x02, 0x04, 0x04, 0, 0x80, 0x00];
+        let vendor: &[u8] = &[0x80, 0x02, 0xaa, 0xbb];
+        let data = descriptor_sense(
+            0x72,
+            0x02,
+            &[
+                information,
+                command_specific,
+                sks,
+                fru,
+                stream,
+                progress,
+                vendor,
+            ],
+        );
+        let sense = ScsiSense::parse(&data).unwrap();
+        assert_eq!(
+            sense,
+            ScsiSense {
+                sense_key: ScsiSenseKey::NOT_READY,
+                asc: 0x04,
+                ascq: 0x04,
+                information: Some(0x1_0000_0002),
+                command_specific: Some(0xcafe_0000),
+                fru: 9,
+                sense_key_specific: Some(ScsiSenseKeySpecific::Progress(0x4000)),
+                filemark: true,
+                eom: true,
+                ili: true,
+                progress: Some((ScsiSenseKey::NOT_READY, 0x04, 0x04, 0x8000)),
+                descriptors: vec![(0x80, vendor.to_vec())],
+                ..ScsiSense::empty(0x72)
+            }
+        );
+        assert!(sense.descriptor_format && !sense.deferred);
+        // the sense key specific progress wins over the descriptor
+        assert_eq!(sense.progress_percent(), Some(25.0));
+
+        let data = descriptor_sense(0x73, 0x02, &[block, progress]);
+        let sense = ScsiSense::parse(&data).unwrap();
+        assert!(sense.deferred && sense.ili && !sense.eom);
+        assert_eq!(sense.progress_percent(), Some(50.0));
+        assert!(sense.to_string().contains("50.00%"), "{}", sense);
+
+        // information without VALID is not reported
+        let mut invalid = information.to_vec();
+        invalid[2] = 0;
+        let data = descriptor_sense(0x72, 0x03, &[&invalid]);
+        let sense = ScsiSense::parse(&data).unwrap();
+        assert_eq!(sense.information, None);
+        assert!(sense.descriptors.is_empty());
+    }
+
+    #[test]
+    fn descriptor_sense_truncated() {
+        let information: &[u8] = &[0x00, 0x0a, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+        let fru: &[u8] = &[0x03, 0x02, 0, 0x09];
+
+        // the additional length cuts the information descriptor short: it is
+        // kept raw instead of decoded, and nothing after it is read
+        let mut data = descriptor_sense(0x72, 0x03, &[fru, information, fru]);
+        data[7] = 4 + 6;
+        let sense = ScsiSense::parse(&data).unwrap();
+        assert_eq!((sense.fru, sense.information), (9, None));
+        assert_eq!(sense.descriptors, vec![(0x00, information[..6].to_vec())]);
+
+        // as does the end of the buffer
+        let data = descriptor_sense(0x72, 0x03, &[fru, information]);
+        let sense = ScsiSense::parse(&data[..data.len() - 1]).unwrap();
+        assert_eq!(sense.information, None);
+        assert_eq!(sense.descriptors, vec![(0x00, information[..11].to_vec())]);
+
+        // a lone type byte is not a descriptor
+        let mut data = descriptor_sense(0x72, 0x03, &[fru]);
+        data.push(0x00);
+        data[7] += 1;
+        let sense = ScsiSense::parse(&data).unwrap();
+        assert_eq!(sense.fru, 9);
+        assert!(sense.descriptors.is_empty());
+
+        // descriptors shorter than their decoded fields
+        let short: &[&[u8]] = &[
+            &[0x02, 0x02, 0, 0],
+            &[0x0a, 0x04, 2, 4, 4, 0],
+            &[0x03, 0x00],
+        ];
+        let data = descriptor_sense(0x72, 0x02, short);
+        let sense = ScsiSense::parse(&data).unwrap();
+        assert_eq!(
+            (sense.sense_key_specific, sense.progress, sense.fru),
+            (None, None, 0)
+        );
+        let kinds: Vec<u8> = sense.descriptors.iter().map(|d| d.0).collect();
+        assert_eq!(kinds, [0x02, 0x0a, 0x03]);
+
+        assert!(ScsiSense::parse(&[0x72, 0x02, 0x04, 0x04, 0, 0, 0]).is_none());
+        let sense = ScsiSense::parse(&[0x72, 0x02, 0x04, 0x04, 0, 0, 0, 0]).unwrap();
+        assert_eq!(sense.asc_name(), scsi_asc_name(0x04, 0x04).unwrap());
+    }
+}
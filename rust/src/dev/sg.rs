@@ -0,0 +1,243 @@
+// Linux SCSI generic passthrough (SG_IO with the sg_io_hdr v3 interface), which
+// works on both /dev/sdX and /dev/sgN
+use super::scsi::*;
+use std::ffi::c_void;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr::null_mut;
+
+pub const SG_IO: libc::c_ulong = 0x2285;
+pub const SG_INTERFACE_ID_ORIG: i32 = b'S' as i32;
+
+pub const SG_DXFER_NONE: i32 = -1;
+pub const SG_DXFER_TO_DEV: i32 = -2;
+pub const SG_DXFER_FROM_DEV: i32 = -3;
+
+// host_status and driver_status values from the kernel's scsi.h
+pub const SG_DID_OK: u16 = 0x00;
+pub const SG_DID_NO_CONNECT: u16 = 0x01;
+pub const SG_DID_BUS_BUSY: u16 = 0x02;
+pub const SG_DID_TIME_OUT: u16 = 0x03;
+pub const SG_DRIVER_TIMEOUT: u16 = 0x06;
+pub const SG_DRIVER_SENSE: u16 = 0x08;
+
+/// `struct sg_io_hdr` from <scsi/sg.h>
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SgIoHdr {
+    pub interface_id: i32,
+    pub dxfer_direction: i32,
+    pub cmd_len: u8,
+    pub mx_sb_len: u8,
+    pub iovec_count: u16,
+    pub dxfer_len: u32,
+    pub dxferp: *mut c_void,
+    pub cmdp: *mut u8,
+    pub sbp: *mut u8,
+    pub timeout: u32, // milliseconds
+    pub flags: u32,
+    pub pack_id: i32,
+    pub usr_ptr: *mut c_void,
+    pub status: u8,
+    pub masked_status: u8,
+    pub msg_status: u8,
+    pub sb_len_wr: u8,
+    pub host_status: u16,
+    pub driver_status: u16,
+    pub resid: i32,
+    pub duration: u32,
+    pub info: u32,
+}
+
+impl SgIoHdr {
+    /// Header for `command`; the CDB, data and sense buffers must outlive the
+    /// ioctl since the header only points at them
+    pub fn new(command: &mut ScsiCommand, data: &mut [u8], sense: &mut SenseBuffer) -> Self {
+        let (dxfer_direction, data) = match command.direction {
+            ScsiDataDirection::None => (SG_DXFER_NONE, &mut data[..0]),
+            ScsiDataDirection::In => (SG_DXFER_FROM_DEV, data),
+            ScsiDataDirection::Out => (SG_DXFER_TO_DEV, data),
+        };
+        Self {
+            interface_id: SG_INTERFACE_ID_ORIG,
+            dxfer_direction,
+            cmd_len: command.cdb_len,
+            mx_sb_len: sense.len() as u8,
+            iovec_count: 0,
+            dxfer_len: data.len() as u32,
+            dxferp: if data.is_empty() {
+                null_mut()
+            } else {
+                data.as_mut_ptr() as *mut c_void
+            },
+            cmdp: command.cdb.as_mut_ptr(),
+            sbp: sense.as_mut_ptr(),
+            timeout: command.timeout.as_millis().min(u32::MAX as u128) as u32,
+            flags: 0,
+            pack_id: 0,
+            usr_ptr: null_mut(),
+            status: 0,
+            masked_status: 0,
+            msg_status: 0,
+            sb_len_wr: 0,
+            host_status: 0,
+            driver_status: 0,
+            resid: 0,
+            duration: 0,
+            info: 0,
+        }
+    }
+
+    /// Bytes transferred, or the error the completion describes: transport
+    /// failures from the host and driver status first, then the SCSI status
+    /// with its sense data
+    pub fn result(&self, opcode: u8, sense: &[u8]) -> io::Result<usize> {
+        if self.host_status != SG_DID_OK {
+            let kind = match self.host_status {
+                SG_DID_TIME_OUT => io::ErrorKind::TimedOut,
+                SG_DID_NO_CONNECT => io::ErrorKind::NotConnected,
+                SG_DID_BUS_BUSY => io::ErrorKind::ResourceBusy,
+                _ => io::ErrorKind::Other,
+            };
+            return Err(io::Error::new(
+                kind,
+                format!(
+                    "SCSI opcode {:#04x} failed with host status {:#06x}",
+                    opcode, self.host_status
+                ),
+            ));
+        }
+        let driver = self.driver_status & 0x0f;
+        if driver == SG_DRIVER_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("SCSI opcode {:#04x} timed out", opcode),
+            ));
+        }
+        let sense = &sense[..(self.sb_len_wr as usize).min(sense.len())];
+        scsi_check_status(opcode, self.status, sense)?;
+        if driver != 0 && driver != SG_DRIVER_SENSE {
+            return Err(io::Error::other(format!(
+                "SCSI opcode {:#04x} failed with driver status {:#06x}",
+                opcode, self.driver_status
+            )));
+        }
+        Ok((self.dxfer_len as i64 - self.resid.max(0) as i64).max(0) as usize)
+    }
+}
+
+/// Send `command` through SG_IO on `fd`
+pub fn sg_io(fd: RawFd, command: &ScsiCommand, data: &mut [u8]) -> io::Result<usize> {
+    let mut command = *command;
+    let mut sense: SenseBuffer = Default::default();
+    let mut hdr = SgIoHdr::new(&mut command, data, &mut sense);
+    if unsafe { libc::ioctl(fd, SG_IO as _, &mut hdr as *mut SgIoHdr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    hdr.result(command.opcode(), &sense)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn header_fields() {
+        let mut command =
+            ScsiCommand::from_bytes(&[0x28, 0, 0, 0, 0, 8, 0, 0, 2, 0], ScsiDataDirection::In)
+                .with_timeout(Duration::from_millis(1500));
+        let mut data = [0u8; 1024];
+        let mut sense: SenseBuffer = Default::default();
+        let hdr = SgIoHdr::new(&mut command, &mut data, &mut sense);
+        assert_eq!(hdr.interface_id, b'S' as i32);
+        assert_eq!(hdr.dxfer_direction, SG_DXFER_FROM_DEV);
+        assert_eq!(hdr.cmd_len, 10);
+        assert_eq!(hdr.mx_sb_len, 32);
+        assert_eq!(hdr.timeout, 1500);
+        assert_eq!(hdr.dxfer_len, 1024);
+        assert_eq!(hdr.dxferp, data.as_mut_ptr() as *mut c_void);
+        assert_eq!(hdr.cmdp, command.cdb.as_mut_ptr());
+        assert_eq!(hdr.sbp, sense.as_mut_ptr());
+
+        let mut command = ScsiCommand::from_bytes(&[0x2a; 16], ScsiDataDirection::Out);
+        let hdr = SgIoHdr::new(&mut command, &mut data, &mut sense);
+        assert_eq!(hdr.dxfer_direction, SG_DXFER_TO_DEV);
+        assert_eq!(hdr.cmd_len, 16);
+        assert_eq!(hdr.timeout, SCSI_DEFAULT_TIMEOUT.as_millis() as u32);
+
+        // no data phase: nothing is handed to the kernel whatever the buffer
+        let mut command = ScsiCommand::from_bytes(&[0x00; 6], ScsiDataDirection::None);
+        let hdr = SgIoHdr::new(&mut command, &mut data, &mut sense);
+        assert_eq!(hdr.dxfer_direction, SG_DXFER_NONE);
+        assert_eq!(hdr.dxfer_len, 0);
+        assert!(hdr.dxferp.is_null());
+        assert_eq!(hdr.cmd_len, 6);
+    }
+
+    fn completed(status: u8, host_status: u16, driver_status: u16, sb_len_wr: u8) -> SgIoHdr {
+        let mut command = ScsiCommand::from_bytes(&[0x28; 10], ScsiDataDirection::In);
+        let mut sense: SenseBuffer = Default::default();
+        let mut hdr = SgIoHdr::new(&mut command, &mut [0u8; 4096], &mut sense);
+        hdr.status = status;
+        hdr.host_status = host_status;
+        hdr.driver_status = driver_status;
+        hdr.sb_len_wr = sb_len_wr;
+        hdr
+    }
+
+    #[test]
+    fn result_mapping() {
+        let no_sense = [0u8; 32];
+        let mut hdr = completed(SCSI_STATUS_GOOD, SG_DID_OK, 0, 0);
+        hdr.resid = 512;
+        assert_eq!(hdr.result(0x28, &no_sense).unwrap(), 4096 - 512);
+
+        for (host, kind) in [
+            (SG_DID_TIME_OUT, io::ErrorKind::TimedOut),
+            (SG_DID_NO_CONNECT, io::ErrorKind::NotConnected),
+            (SG_DID_BUS_BUSY, io::ErrorKind::ResourceBusy),
+            (0x07, io::ErrorKind::Other),
+        ] {
+            let err = completed(SCSI_STATUS_GOOD, host, 0, 0)
+                .result(0x28, &no_sense)
+                .unwrap_err();
+            assert_eq!(err.kind(), kind, "host status {:#x}", host);
+            assert!(ScsiError::from_io(&err).is_none());
+        }
+
+        let err = completed(SCSI_STATUS_GOOD, SG_DID_OK, SG_DRIVER_TIMEOUT, 0)
+            .result(0x28, &no_sense)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // CHECK CONDITION with MEDIUM ERROR / UNRECOVERED READ ERROR sense
+        let mut sense = [0u8; 32];
+        sense[..18].copy_from_slice(&[
+            0x70, 0, 0x03, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0x11, 0x00, 0, 0, 0, 0,
+        ]);
+        let err = completed(SCSI_STATUS_CHECK_CONDITION, SG_DID_OK, SG_DRIVER_SENSE, 18)
+            .result(0x28, &sense)
+            .unwrap_err();
+        let scsi = ScsiError::from_io(&err).unwrap();
+        assert_eq!(scsi.opcode, 0x28);
+        assert_eq!(scsi.status, SCSI_STATUS_CHECK_CONDITION);
+        assert_eq!(scsi.sense_key(), Some(ScsiSenseKey::MEDIUM_ERROR));
+        assert_eq!(
+            scsi.sense.as_ref().map(|s| (s.asc, s.ascq)),
+            Some((0x11, 0x00))
+        );
+
+        // only the sense bytes the kernel wrote are parsed
+        let err = completed(SCSI_STATUS_CHECK_CONDITION, SG_DID_OK, SG_DRIVER_SENSE, 0)
+            .result(0x28, &sense)
+            .unwrap_err();
+        assert!(ScsiError::from_io(&err).unwrap().sense.is_none());
+
+        // good status with a driver error other than sense
+        let err = completed(SCSI_STATUS_GOOD, SG_DID_OK, 0x04, 0)
+            .result(0x28, &no_sense)
+            .unwrap_err();
+        assert!(err.to_string().contains("driver status"));
+    }
+}
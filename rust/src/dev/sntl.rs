@@ -0,0 +1,896 @@
+// SCSI to NVMe translation (SNTL) after the NVM Express SCSI Translation
+// Reference: SCSI CDBs are executed as NVMe commands on an `NvmeTransport` and
+// NVMe status is mapped back to SCSI status and sense data.
+use crate::dev::nvme_commands::{
+    nvme_dsm_range_bytes, nvme_dsm_split_ranges, NvmeOpcodeType, NvmeTransport, NVME_DSM_MAX_RANGES,
+};
+use crate::dev::nvme_define::NVME_ADMIN_COMMANDS::*;
+use crate::dev::nvme_define::NVME_NVM_COMMANDS::*;
+use crate::dev::nvme_define::*;
+use crate::dev::scsi::*;
+use std::io;
+
+pub const SCSI_OPCODE_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+pub const SCSI_OPCODE_SYNCHRONIZE_CACHE_16: u8 = 0x91;
+pub const SCSI_OPCODE_UNMAP: u8 = 0x42;
+
+const SNTL_VPD_PAGES: [u8; 6] = [
+    SCSI_VPD_SUPPORTED_PAGES,
+    SCSI_VPD_UNIT_SERIAL_NUMBER,
+    SCSI_VPD_DEVICE_IDENTIFICATION,
+    SCSI_VPD_BLOCK_LIMITS,
+    SCSI_VPD_BLOCK_DEVICE_CHARACTERISTICS,
+    SCSI_VPD_LOGICAL_BLOCK_PROVISIONING,
+];
+const SNTL_PAGE_SIZE: u64 = 4096; // CAP.MPSMIN is not visible through passthrough
+
+/// Fixed format sense data, with the field pointer set for ILLEGAL REQUEST
+pub fn scsi_sense_fixed(key: ScsiSenseKey, asc: u8, ascq: u8, field: Option<(u16, u8)>) -> Vec<u8> {
+    let mut sense = vec![0u8; 18];
+    sense[0] = 0x70;
+    sense[2] = key as u8;
+    sense[7] = 10;
+    sense[12] = asc;
+    sense[13] = ascq;
+    if let Some((byte, bit)) = field {
+        sense[15] = 0x80 | 0x40 | 0x08 | (bit & 0x7); // SKSV, C/D = CDB, BPV
+        sense[16..18].copy_from_slice(&byte.to_be_bytes());
+    }
+    sense
+}
+
+fn check_condition(opcode: u8, key: ScsiSenseKey, asc: u8, ascq: u8) -> io::Error {
+    ScsiError::new(
+        opcode,
+        SCSI_STATUS_CHECK_CONDITION,
+        &scsi_sense_fixed(key, asc, ascq, None),
+    )
+    .into()
+}
+
+/// ILLEGAL REQUEST, INVALID FIELD IN CDB pointing at `byte`/`bit`
+fn invalid_field(opcode: u8, byte: u16, bit: u8) -> io::Error {
+    ScsiError::new(
+        opcode,
+        SCSI_STATUS_CHECK_CONDITION,
+        &scsi_sense_fixed(ScsiSenseKey::ILLEGAL_REQUEST, 0x24, 0x00, Some((byte, bit))),
+    )
+    .into()
+}
+
+/// SCSI status and sense key, ASC, ASCQ for an NVMe completion status, Table 13
+/// to 15 of the translation reference. None for successful completion.
+pub fn sntl_status_to_sense(status: &NVME_COMMAND_STATUS) -> Option<(u8, ScsiSenseKey, u8, u8)> {
+    use ScsiSenseKey::*;
+    let check = |key, asc, ascq| Some((SCSI_STATUS_CHECK_CONDITION, key, asc, ascq));
+    match (status.SCT(), status.SC()) {
+        (0, 0x00) => None,
+        (0, 0x01) => check(ILLEGAL_REQUEST, 0x20, 0x00), // Invalid Command Opcode
+        (0, 0x02) => check(ILLEGAL_REQUEST, 0x24, 0x00), // Invalid Field in Command
+        (0, 0x03) => check(ILLEGAL_REQUEST, 0x24, 0x00), // Command ID Conflict
+        (0, 0x04) => check(MEDIUM_ERROR, 0x00, 0x00),    // Data Transfer Error
+        (0, 0x05) => check(ABORTED_COMMAND, 0x00, 0x00), // Aborted due to Power Loss
+        (0, 0x06) => check(HARDWARE_ERROR, 0x44, 0x00),  // Internal Error
+        (0, 0x07) => Some((SCSI_STATUS_TASK_ABORTED, NO_SENSE, 0x00, 0x00)), // Abort Requested
+        (0, 0x08) | (0, 0x09) => Some((SCSI_STATUS_TASK_ABORTED, NO_SENSE, 0x00, 0x00)),
+        (0, 0x0a) => check(ILLEGAL_REQUEST, 0x24, 0x00), // Invalid Field in Command
+        (0, 0x0b) => check(ILLEGAL_REQUEST, 0x25, 0x00), // Invalid Namespace or Format
+        (0, 0x0c) => check(ILLEGAL_REQUEST, 0x2c, 0x00), // Command Sequence Error
+        (0, 0x80) => check(ILLEGAL_REQUEST, 0x21, 0x00), // LBA Out of Range
+        (0, 0x81) => check(MEDIUM_ERROR, 0x00, 0x00),    // Capacity Exceeded
+        (0, 0x82) => check(NOT_READY, 0x04, 0x01),       // Namespace Not Ready
+        (0, 0x83) => Some((SCSI_STATUS_RESERVATION_CONFLICT, NO_SENSE, 0x00, 0x00)),
+        (0, 0x84) => check(NOT_READY, 0x04, 0x04), // Format In Progress
+        (1, 0x0a) => check(ILLEGAL_REQUEST, 0x24, 0x00), // Invalid Format
+        (1, 0x80) => check(ILLEGAL_REQUEST, 0x24, 0x00), // Conflicting Attributes
+        (1, 0x81) => check(ILLEGAL_REQUEST, 0x24, 0x00), // Invalid Protection Information
+        (1, 0x82) => check(DATA_PROTECT, 0x27, 0x00), // Attempted Write to Read Only Range
+        (2, 0x80) => check(MEDIUM_ERROR, 0x03, 0x00), // Write Fault
+        (2, 0x81) => check(MEDIUM_ERROR, 0x11, 0x00), // Unrecovered Read Error
+        (2, 0x82) => check(MEDIUM_ERROR, 0x10, 0x01), // End-to-end Guard Check Error
+        (2, 0x83) => check(MEDIUM_ERROR, 0x10, 0x02), // End-to-end Application Tag Check Error
+        (2, 0x84) => check(MEDIUM_ERROR, 0x10, 0x03), // End-to-end Reference Tag Check Error
+        (2, 0x85) => check(MISCOMPARE, 0x1d, 0x00), // Compare Failure
+        (2, 0x86) => check(ILLEGAL_REQUEST, 0x20, 0x02), // Access Denied
+        (2, 0x87) => check(MEDIUM_ERROR, 0x11, 0x00), // Deallocated or Unwritten Logical Block
+        _ => check(HARDWARE_ERROR, 0x44, 0x00),
+    }
+}
+
+/// Cached identify data the translation needs
+#[derive(Debug, Clone, Default)]
+pub struct SntlIdentity {
+    pub serial: String,
+    pub model: String,
+    pub firmware: String,
+    pub ieee: [u8; 3],
+    pub mdts: u8,
+    pub oncs: u16,
+    pub vwc: bool,
+    pub nsze: u64,
+    pub ncap: u64,
+    pub lba_shift: u8,
+    pub dps: u8,
+    pub dlfeat: u8,
+    pub npwg: u16, // 1's based, 0 when not reported
+    pub npdg: u16,
+    pub noiob: u16,
+    pub nguid: [u8; 16],
+    pub eui64: [u8; 8],
+}
+
+impl SntlIdentity {
+    pub fn parse(ctrl: &[u8], ns: &[u8]) -> Self {
+        let text = |data: &[u8]| String::from_utf8_lossy(data).trim().to_string();
+        let u16_at = |data: &[u8], at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        let u64_at =
+            |data: &[u8], at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+        let flbas = ns[26] & 0x0f | (ns[26] >> 1) & 0x30;
+        let lbaf = 128 + 4 * flbas as usize;
+        let optimal = ns[24] & 0x10 != 0;
+        Self {
+            serial: text(&ctrl[4..24]),
+            model: text(&ctrl[24..64]),
+            firmware: text(&ctrl[64..72]),
+            ieee: [ctrl[73], ctrl[74], ctrl[75]],
+            mdts: ctrl[77],
+            oncs: u16_at(ctrl, 520),
+            vwc: ctrl[525] & 1 != 0,
+            nsze: u64_at(ns, 0),
+            ncap: u64_at(ns, 8),
+            lba_shift: ns[lbaf + 2],
+            dps: ns[29],
+            dlfeat: ns[33],
+            npwg: if optimal { u16_at(ns, 64) + 1 } else { 0 },
+            npdg: if optimal { u16_at(ns, 68) + 1 } else { 0 },
+            noiob: u16_at(ns, 46),
+            nguid: ns[104..120].try_into().unwrap(),
+            eui64: ns[120..128].try_into().unwrap(),
+        }
+    }
+
+    pub fn block_size(&self) -> u32 {
+        1 << self.lba_shift
+    }
+
+    /// Largest transfer in logical blocks, MDTS 0 means no limit
+    pub fn max_transfer_blocks(&self) -> u32 {
+        let nlb_limit = 0x1_0000u64;
+        if self.mdts == 0 {
+            return nlb_limit as u32;
+        }
+        let bytes = SNTL_PAGE_SIZE << self.mdts;
+        (bytes >> self.lba_shift).clamp(1, nlb_limit) as u32
+    }
+
+    pub fn dsm_supported(&self) -> bool {
+        self.oncs & 0x0004 != 0
+    }
+}
+
+/// Translates SCSI commands for one namespace onto an NVMe transport
+pub struct Sntl<T: NvmeTransport> {
+    pub transport: T,
+    pub nsid: u32,
+    identity: Option<SntlIdentity>,
+}
+
+impl<T: NvmeTransport> Sntl<T> {
+    pub fn new(transport: T, nsid: u32) -> Self {
+        Self {
+            transport,
+            nsid,
+            identity: None,
+        }
+    }
+
+    fn nvme_error(opcode: u8, status: &NVME_COMMAND_STATUS) -> io::Error {
+        match sntl_status_to_sense(status) {
+            Some((SCSI_STATUS_CHECK_CONDITION, key, asc, ascq)) => {
+                check_condition(opcode, key, asc, ascq)
+            }
+            Some((scsi_status, _, _, _)) => ScsiError::new(opcode, scsi_status, &[]).into(),
+            None => io::Error::other("successful completion is not an error"),
+        }
+    }
+
+    fn admin(
+        &self,
+        opcode: u8,
+        direction: NvmeOpcodeType,
+        nc: &NVME_COMMAND,
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        let status = self
+            .transport
+            .admin_passthru(direction as u8, nc, data, &mut 0)?;
+        match sntl_status_to_sense(&status) {
+            None => Ok(()),
+            Some(_) => Err(Self::nvme_error(opcode, &status)),
+        }
+    }
+
+    fn io(
+        &self,
+        opcode: u8,
+        direction: NvmeOpcodeType,
+        nc: &NVME_COMMAND,
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        let status = self
+            .transport
+            .io_passthru(direction as u8, nc, data, &mut 0)?;
+        match sntl_status_to_sense(&status) {
+            None => Ok(()),
+            Some(_) => Err(Self::nvme_error(opcode, &status)),
+        }
+    }
+
+    fn identify(&self, opcode: u8, cns: u8, nsid: u32) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; 4096];
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_ADMIN_COMMAND_IDENTIFY as u32)
+            .nsid(nsid)
+            .cdw10(cns as u32);
+        self.admin(opcode, NvmeOpcodeType::READ, &nc, &mut data)?;
+        Ok(data)
+    }
+
+    /// Identify data, fetched once and cached
+    pub fn identity(&mut self, opcode: u8) -> io::Result<&SntlIdentity> {
+        if self.identity.is_none() {
+            let ctrl = self.identify(opcode, 0x01, 0)?;
+            let ns = self.identify(opcode, 0x00, self.nsid)?;
+            self.identity = Some(SntlIdentity::parse(&ctrl, &ns));
+        }
+        Ok(self.identity.as_ref().unwrap())
+    }
+
+    /// Drop cached identify data, e.g. after a format changed the block size
+    pub fn refresh(&mut self) {
+        self.identity = None;
+    }
+
+    /// Execute `cdb`; `data` is the data-in buffer for reads or the data-out
+    /// payload for writes. Returns the bytes transferred; failures come back as
+    /// `ScsiError` inside the `io::Error`.
+    pub fn execute(&mut self, cdb: &[u8], data: &mut [u8]) -> io::Result<usize> {
+        let opcode = *cdb
+            .first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty CDB"))?;
+        let needed = match opcode {
+            0x00 => 6,
+            SCSI_OPCODE_INQUIRY => 6,
+            0x25 | 0x28 | 0x2a | SCSI_OPCODE_SYNCHRONIZE_CACHE_10 | SCSI_OPCODE_UNMAP => 10,
+            0xa2 | 0xb5 => 12,
+            _ => 16,
+        };
+        if cdb.len() < needed {
+            return Err(invalid_field(opcode, cdb.len() as u16, 0));
+        }
+        match opcode {
+            0x00 => self.test_unit_ready(),
+            SCSI_OPCODE_INQUIRY => self.inquiry(cdb, data),
+            0x25 => self.read_capacity_10(data),
+            0x9e if cdb[1] & 0x1f == ScsiOpcode::SCSI_SERVICE_ACTION_READ_CAPACITY_16 as u8 => {
+                self.read_capacity_16(cdb, data)
+            }
+            0x28 | 0x88 => self.read_write(cdb, data, false),
+            0x2a | 0x8a => self.read_write(cdb, data, true),
+            SCSI_OPCODE_SYNCHRONIZE_CACHE_10 | SCSI_OPCODE_SYNCHRONIZE_CACHE_16 => {
+                self.synchronize_cache(opcode)
+            }
+            SCSI_OPCODE_UNMAP => self.unmap(cdb, data),
+            0xa2 => self.security_protocol(cdb, data, false),
+            0xb5 => self.security_protocol(cdb, data, true),
+            0x9e => Err(invalid_field(opcode, 1, 4)),
+            _ => Err(check_condition(
+                opcode,
+                ScsiSenseKey::ILLEGAL_REQUEST,
+                0x20,
+                0x00,
+            )),
+        }
+    }
+
+    fn test_unit_ready(&mut self) -> io::Result<usize> {
+        // the namespace answers Identify when it is attached and ready
+        self.identity(0x00)?;
+        Ok(0)
+    }
+
+    fn respond(data: &mut [u8], response: &[u8], allocation: usize) -> usize {
+        let n = response.len().min(allocation).min(data.len());
+        data[..n].copy_from_slice(&response[..n]);
+        n
+    }
+
+    fn inquiry(&mut self, cdb: &[u8], data: &mut [u8]) -> io::Result<usize> {
+        let evpd = cdb[1] & 0x01 != 0;
+        let page = cdb[2];
+        let allocation = u16::from_be_bytes([cdb[3], cdb[4]]) as usize;
+        if !evpd && page != 0 {
+            return Err(invalid_field(SCSI_OPCODE_INQUIRY, 2, 7));
+        }
+        let nsid = self.nsid;
+        let id = self.identity(SCSI_OPCODE_INQUIRY)?.clone();
+        let response = if evpd {
+            match page {
+                SCSI_VPD_SUPPORTED_PAGES => vpd_page(page, &SNTL_VPD_PAGES),
+                SCSI_VPD_UNIT_SERIAL_NUMBER => vpd_page(page, sntl_serial(&id, nsid).as_bytes()),
+                SCSI_VPD_DEVICE_IDENTIFICATION => vpd_page(page, &sntl_designators(&id, nsid)),
+                SCSI_VPD_BLOCK_LIMITS => vpd_page(page, &sntl_block_limits(&id)),
+                SCSI_VPD_BLOCK_DEVICE_CHARACTERISTICS => {
+                    let mut body = vec![0u8; 0x3c];
+                    body[0..2].copy_from_slice(&1u16.to_be_bytes()); // non-rotating medium
+                    vpd_page(page, &body)
+                }
+                SCSI_VPD_LOGICAL_BLOCK_PROVISIONING => {
+                    let mut body = vec![0u8; 4];
+                    if id.dsm_supported() {
+                        body[1] = 0x80; // LBPU: UNMAP supported
+                        if id.dlfeat & 0x07 == 0x01 {
+                            body[1] |= 0x04; // LBPRZ: unmapped blocks read as zeros
+                        }
+                    }
+                    body[2] = if id.ncap < id.nsze { 0x02 } else { 0x00 }; // thin or full
+                    vpd_page(page, &body)
+                }
+                _ => return Err(invalid_field(SCSI_OPCODE_INQUIRY, 2, 7)),
+            }
+        } else {
+            let mut inquiry = vec![0u8; 36];
+            inquiry[0] = 0x00; // direct access block device
+            inquiry[2] = 0x06; // SPC-4
+            inquiry[3] = 0x02; // response data format
+            inquiry[4] = 31; // additional length
+            inquiry[5] = if id.dps & 0x07 != 0 { 0x01 } else { 0x00 }; // PROTECT
+            inquiry[7] = 0x02; // CMDQUE
+            inquiry[8..16].copy_from_slice(b"NVMe    ");
+            let mut product = [b' '; 16];
+            let model = id.model.as_bytes();
+            product[..model.len().min(16)].copy_from_slice(&model[..model.len().min(16)]);
+            inquiry[16..32].copy_from_slice(&product);
+            let mut revision = [b' '; 4];
+            let firmware = id.firmware.as_bytes();
+            revision[..firmware.len().min(4)].copy_from_slice(&firmware[..firmware.len().min(4)]);
+            inquiry[32..36].copy_from_slice(&revision);
+            inquiry
+        };
+        Ok(Self::respond(data, &response, allocation))
+    }
+
+    fn read_capacity_10(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        let id = self.identity(0x25)?;
+        let last = id.nsze.saturating_sub(1).min(0xffff_ffff) as u32;
+        let mut response = [0u8; 8];
+        response[0..4].copy_from_slice(&last.to_be_bytes());
+        response[4..8].copy_from_slice(&id.block_size().to_be_bytes());
+        Ok(Self::respond(data, &response, 8))
+    }
+
+    fn read_capacity_16(&mut self, cdb: &[u8], data: &mut [u8]) -> io::Result<usize> {
+        let allocation = u32::from_be_bytes(cdb[10..14].try_into().unwrap()) as usize;
+        let id = self.identity(0x9e)?;
+        let mut response = [0u8; 32];
+        response[0..8].copy_from_slice(&id.nsze.saturating_sub(1).to_be_bytes());
+        response[8..12].copy_from_slice(&id.block_size().to_be_bytes());
+        let pi_type = id.dps & 0x07;
+        if pi_type != 0 {
+            response[12] = ((pi_type - 1) << 1) | 0x01; // P_TYPE, PROT_EN
+        }
+        if id.npwg > 1 && id.npwg.is_power_of_two() {
+            response[13] = id.npwg.trailing_zeros() as u8; // logical blocks per physical block exponent
+        }
+        if id.dsm_supported() {
+            response[14] = 0x80; // LBPME
+            if id.dlfeat & 0x07 == 0x01 {
+                response[14] |= 0x40; // LBPRZ
+            }
+        }
+        Ok(Self::respond(data, &response, allocation))
+    }
+
+    fn read_write(&mut self, cdb: &[u8], data: &mut [u8], write: bool) -> io::Result<usize> {
+        let opcode = cdb[0];
+        let (lba, blocks) = if opcode == 0x28 || opcode == 0x2a {
+            (
+                u32::from_be_bytes(cdb[2..6].try_into().unwrap()) as u64,
+                u16::from_be_bytes([cdb[7], cdb[8]]) as u64,
+            )
+        } else {
+            (
+                u64::from_be_bytes(cdb[2..10].try_into().unwrap()),
+                u32::from_be_bytes(cdb[10..14].try_into().unwrap()) as u64,
+            )
+        };
+        let fua = cdb[1] & ScsiCdbFlag::SCSI_FL_FUA as u8 != 0;
+        let id = self.identity(opcode)?.clone();
+        if lba.checked_add(blocks).is_none_or(|end| end > id.nsze) {
+            return Err(check_condition(
+                opcode,
+                ScsiSenseKey::ILLEGAL_REQUEST,
+                0x21,
+                0x00,
+            ));
+        }
+        if blocks == 0 {
+            return Ok(0);
+        }
+        let bs = id.block_size() as usize;
+        let total = blocks as usize * bs;
+        if data.len() < total {
+            return Err(check_condition(
+                opcode,
+                ScsiSenseKey::ILLEGAL_REQUEST,
+                0x24,
+                0x00,
+            ));
+        }
+        // split at MDTS, each NVMe command moves at most 64K blocks
+        let max = id.max_transfer_blocks() as u64;
+        let mut done = 0u64;
+        while done < blocks {
+            let count = (blocks - done).min(max);
+            let slba = lba + done;
+            let mut nc = NVME_COMMAND::default();
+            let nvm_opcode = if write {
+                NVME_NVM_COMMAND_WRITE
+            } else {
+                NVME_NVM_COMMAND_READ
+            };
+            nc.opcode(nvm_opcode as u32)
+                .nsid(self.nsid)
+                .cdw10(slba as u32)
+                .cdw11((slba >> 32) as u32)
+                .cdw12((count - 1) as u32 | (fua as u32) << 30);
+            let chunk = &mut data[done as usize * bs..(done + count) as usize * bs];
+            let direction = if write {
+                NvmeOpcodeType::WRITE
+            } else {
+                NvmeOpcodeType::READ
+            };
+            self.io(opcode, direction, &nc, chunk)?;
+            done += count;
+        }
+        Ok(total)
+    }
+
+    fn synchronize_cache(&mut self, opcode: u8) -> io::Result<usize> {
+        let mut nc = NVME_COMMAND::default();
+        nc.opcode(NVME_NVM_COMMAND_FLUSH as u32).nsid(self.nsid);
+        self.io(opcode, NvmeOpcodeType::NOBUFFER, &nc, &mut [])?;
+        Ok(0)
+    }
+
+    fn unmap(&mut self, cdb: &[u8], data: &mut [u8]) -> io::Result<usize> {
+        let length = u16::from_be_bytes([cdb[7], cdb[8]]) as usize;
+        if length == 0 {
+            return Ok(0);
+        }
+        let id = self.identity(SCSI_OPCODE_UNMAP)?.clone();
+        if !id.dsm_supported() {
+            return Err(check_condition(
+                SCSI_OPCODE_UNMAP,
+                ScsiSenseKey::ILLEGAL_REQUEST,
+                0x20,
+                0x00,
+            ));
+        }
+        let list = &data[..length.min(data.len())];
+        if list.len() < 8 {
+            return Err(check_condition(
+                SCSI_OPCODE_UNMAP,
+                ScsiSenseKey::ILLEGAL_REQUEST,
+                0x1a,
+                0x00,
+            ));
+        }
+        let descriptors =
+            (u16::from_be_bytes([list[2], list[3]]) as usize).min(list.len() - 8) / 16;
+        let mut extents = vec![];
+        for descriptor in list[8..8 + descriptors * 16].chunks_exact(16) {
+            let lba = u64::from_be_bytes(descriptor[0..8].try_into().unwrap());
+            let count = u32::from_be_bytes(descriptor[8..12].try_into().unwrap()) as u64;
+            if lba.checked_add(count).is_none_or(|end| end > id.nsze) {
+                return Err(check_condition(
+                    SCSI_OPCODE_UNMAP,
+                    ScsiSenseKey::ILLEGAL_REQUEST,
+                    0x21,
+                    0x00,
+                ));
+            }
+            if count != 0 {
+                extents.push((lba, count));
+            }
+        }
+        for ranges in nvme_dsm_split_ranges(&extents, u32::MAX as u64, NVME_DSM_MAX_RANGES) {
+            let mut payload = nvme_dsm_range_bytes(&ranges);
+            let mut nc = NVME_COMMAND::default();
+            nc.opcode(NVME_NVM_COMMAND_DATASET_MANAGEMENT as u32)
+                .nsid(self.nsid)
+                .cdw10((ranges.len() - 1) as u32)
+                .cdw11(NVME_CDW11_DATASET_MANAGEMENT::new().with_AD(1).into());
+            self.io(SCSI_OPCODE_UNMAP, NvmeOpcodeType::WRITE, &nc, &mut payload)?;
+        }
+        Ok(length.min(data.len()))
+    }
+
+    fn security_protocol(&mut self, cdb: &[u8], data: &mut [u8], send: bool) -> io::Result<usize> {
+        let opcode = cdb[0];
+        let protocol = cdb[1];
+        let spsp = u16::from_be_bytes([cdb[2], cdb[3]]);
+        let inc_512 = cdb[4] & 0x80 != 0;
+        let mut length = u32::from_be_bytes(cdb[6..10].try_into().unwrap()) as usize;
+        if inc_512 {
+            length *= 512;
+        }
+        if length > data.len() {
+            return Err(invalid_field(opcode, 6, 7));
+        }
+        let mut nc = NVME_COMMAND::default();
+        let cdw10 = (protocol as u32) << 24 | (spsp as u32) << 8;
+        if send {
+            nc.opcode(NVME_ADMIN_COMMAND_SECURITY_SEND as u32)
+                .nsid(self.nsid)
+                .cdw10(cdw10)
+                .cdw11(length as u32);
+            self.admin(opcode, NvmeOpcodeType::WRITE, &nc, &mut data[..length])?;
+        } else {
+            nc.opcode(NVME_ADMIN_COMMAND_SECURITY_RECEIVE as u32)
+                .nsid(self.nsid)
+                .cdw10(cdw10)
+                .cdw11(length as u32);
+            self.admin(opcode, NvmeOpcodeType::READ, &nc, &mut data[..length])?;
+        }
+        Ok(length)
+    }
+}
+
+fn vpd_page(page: u8, body: &[u8]) -> Vec<u8> {
+    let mut response = vec![0u8, page];
+    response.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    response.extend_from_slice(body);
+    response
+}
+
+/// Unit serial number: NGUID, else EUI64, else the controller serial and NSID
+pub fn sntl_serial(id: &SntlIdentity, nsid: u32) -> String {
+    let hex = |bytes: &[u8]| -> String {
+        bytes
+            .chunks(2)
+            .map(|pair| {
+                pair.iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("_")
+    };
+    if id.nguid.iter().any(|&b| b != 0) {
+        format!("{}.", hex(&id.nguid))
+    } else if id.eui64.iter().any(|&b| b != 0) {
+        format!("{}.", hex(&id.eui64))
+    } else {
+        format!("{}_{:08X}", id.serial, nsid)
+    }
+}
+
+/// Device identification designators: EUI-64 (from NGUID or EUI64), SCSI name
+/// string and T10 vendor ID
+fn sntl_designators(id: &SntlIdentity, nsid: u32) -> Vec<u8> {
+    let mut body = vec![];
+    let mut designator = |code_set: u8, kind: u8, value: &[u8]| {
+        body.extend_from_slice(&[code_set, kind, 0, value.len() as u8]);
+        body.extend_from_slice(value);
+    };
+    if id.nguid.iter().any(|&b| b != 0) {
+        designator(0x01, 0x02, &id.nguid); // binary, EUI-64 based 16 byte
+    } else if id.eui64.iter().any(|&b| b != 0) {
+        designator(0x01, 0x02, &id.eui64);
+    }
+    let mut t10 = b"NVMe    ".to_vec();
+    t10.extend_from_slice(format!("{:<40}", id.model).as_bytes());
+    t10.extend_from_slice(format!("{:08X}", nsid).as_bytes());
+    designator(0x02, 0x01, &t10); // ASCII, T10 vendor ID
+    body
+}
+
+fn sntl_block_limits(id: &SntlIdentity) -> Vec<u8> {
+    let mut body = vec![0u8; 0x3c];
+    let max_transfer = id.max_transfer_blocks();
+    if id.npwg > 0 {
+        body[2..4].copy_from_slice(&id.npwg.to_be_bytes()); // optimal transfer length granularity
+    }
+    body[4..8].copy_from_slice(&max_transfer.to_be_bytes()); // maximum transfer length
+    if id.noiob > 0 {
+        body[8..12].copy_from_slice(&(id.noiob as u32).to_be_bytes()); // optimal transfer length
+    }
+    if id.dsm_supported() {
+        body[16..20].copy_from_slice(&u32::MAX.to_be_bytes()); // maximum unmap LBA count
+        body[20..24].copy_from_slice(&(NVME_DSM_MAX_RANGES as u32).to_be_bytes()); // descriptors
+        if id.npdg > 0 {
+            body[24..28].copy_from_slice(&(id.npdg as u32).to_be_bytes()); // unmap granularity
+        }
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev::nvme_sim::{NvmeSimulator, NVME_SIM_NSID};
+
+    const NSZE: u64 = 1 << 20;
+
+    fn sntl() -> Sntl<NvmeSimulator> {
+        Sntl::new(NvmeSimulator::new(NSZE, 9), NVME_SIM_NSID)
+    }
+
+    /// Sense key, ASC and ASCQ of a failed command
+    fn sense(error: &io::Error) -> (u8, Option<(ScsiSenseKey, u8, u8)>) {
+        let error = ScsiError::from_io(error).expect("not a SCSI error");
+        let sense = error
+            .sense
+            .as_ref()
+            .map(|sense| (sense.sense_key, sense.asc, sense.ascq));
+        (error.status, sense)
+    }
+
+    fn inquiry_vpd(sntl: &mut Sntl<NvmeSimulator>, page: u8) -> Vec<u8> {
+        let mut data = vec![0u8; 255];
+        let n = sntl
+            .execute(&[SCSI_OPCODE_INQUIRY, 0x01, page, 0, 255, 0], &mut data)
+            .unwrap();
+        assert_eq!(data[1], page);
+        assert_eq!(u16::from_be_bytes([data[2], data[3]]) as usize + 4, n);
+        data.truncate(n);
+        data
+    }
+
+    #[test]
+    fn inquiry() {
+        let mut sntl = sntl();
+        let mut data = [0u8; 96];
+        let n = sntl
+            .execute(&[SCSI_OPCODE_INQUIRY, 0, 0, 0, 96, 0], &mut data)
+            .unwrap();
+        assert_eq!(n, 36);
+        assert_eq!(data[0], 0x00);
+        assert_eq!(&data[8..16], b"NVMe    ");
+        assert_eq!(&data[16..32], b"NVMe Simulator  ");
+        assert_eq!(&data[32..36], b"1.0 ");
+
+        // the allocation length cuts the response short
+        let n = sntl
+            .execute(&[SCSI_OPCODE_INQUIRY, 0, 0, 0, 8, 0], &mut data)
+            .unwrap();
+        assert_eq!(n, 8);
+
+        // a page code without EVPD
+        let err = sntl
+            .execute(&[SCSI_OPCODE_INQUIRY, 0, 0x80, 0, 96, 0], &mut data)
+            .unwrap_err();
+        assert_eq!(
+            sense(&err),
+            (
+                SCSI_STATUS_CHECK_CONDITION,
+                Some((ScsiSenseKey::ILLEGAL_REQUEST, 0x24, 0x00))
+            )
+        );
+    }
+
+    #[test]
+    fn inquiry_vpd_pages() {
+        let mut sntl = sntl();
+        assert_eq!(
+            &inquiry_vpd(&mut sntl, SCSI_VPD_SUPPORTED_PAGES)[4..],
+            &SNTL_VPD_PAGES
+        );
+
+        let serial = inquiry_vpd(&mut sntl, SCSI_VPD_UNIT_SERIAL_NUMBER);
+        assert_eq!(&serial[4..], b"0011_2233_4455_6677_8899_AABB_CCDD_EEFF.");
+
+        let ids = inquiry_vpd(&mut sntl, SCSI_VPD_DEVICE_IDENTIFICATION);
+        assert_eq!(&ids[4..8], &[0x01, 0x02, 0x00, 16]);
+        assert_eq!(ids[8..24], sntl.transport.nguid);
+        assert_eq!(&ids[24..28], &[0x02, 0x01, 0x00, 56]);
+        assert_eq!(&ids[28..36], b"NVMe    ");
+        assert!(ids.ends_with(b"00000001"));
+
+        let limits = inquiry_vpd(&mut sntl, SCSI_VPD_BLOCK_LIMITS);
+        // MDTS 5 with 4 KiB pages is 128 KiB, 256 blocks of 512 bytes
+        assert_eq!(&limits[6..8], &8u16.to_be_bytes());
+        assert_eq!(&limits[8..12], &256u32.to_be_bytes());
+        assert_eq!(&limits[20..24], &u32::MAX.to_be_bytes());
+        assert_eq!(&limits[24..28], &256u32.to_be_bytes());
+        assert_eq!(&limits[28..32], &8u32.to_be_bytes());
+
+        let characteristics = inquiry_vpd(&mut sntl, SCSI_VPD_BLOCK_DEVICE_CHARACTERISTICS);
+        assert_eq!(&characteristics[4..6], &1u16.to_be_bytes());
+
+        let provisioning = inquiry_vpd(&mut sntl, SCSI_VPD_LOGICAL_BLOCK_PROVISIONING);
+        assert_eq!(provisioning[5], 0x84); // LBPU, LBPRZ
+        assert_eq!(provisioning[6], 0x00); // fully provisioned
+
+        let mut data = [0u8; 64];
+        let err = sntl
+            .execute(&[SCSI_OPCODE_INQUIRY, 0x01, 0x99, 0, 64, 0], &mut data)
+            .unwrap_err();
+        assert_eq!(
+            sense(&err).1,
+            Some((ScsiSenseKey::ILLEGAL_REQUEST, 0x24, 0x00))
+        );
+    }
+
+    #[test]
+    fn read_capacity() {
+        let mut sntl = sntl();
+        let mut data = [0u8; 32];
+        assert_eq!(
+            sntl.execute(&[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0], &mut data)
+                .unwrap(),
+            8
+        );
+        assert_eq!(&data[0..4], &((NSZE - 1) as u32).to_be_bytes());
+        assert_eq!(&data[4..8], &512u32.to_be_bytes());
+
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x9e;
+        cdb[1] = ScsiOpcode::SCSI_SERVICE_ACTION_READ_CAPACITY_16 as u8;
+        cdb[10..14].copy_from_slice(&32u32.to_be_bytes());
+        assert_eq!(sntl.execute(&cdb, &mut data).unwrap(), 32);
+        assert_eq!(&data[0..8], &(NSZE - 1).to_be_bytes());
+        assert_eq!(&data[8..12], &512u32.to_be_bytes());
+        assert_eq!(data[12], 0x00); // no protection
+        assert_eq!(data[13], 3); // NPWG 8 blocks per physical block
+        assert_eq!(data[14], 0xc0); // LBPME, LBPRZ
+
+        // a capacity beyond 32 bits saturates READ CAPACITY(10)
+        let mut big = Sntl::new(NvmeSimulator::new(1 << 33, 12), NVME_SIM_NSID);
+        big.execute(&[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0], &mut data)
+            .unwrap();
+        assert_eq!(&data[0..4], &u32::MAX.to_be_bytes());
+        assert_eq!(&data[4..8], &4096u32.to_be_bytes());
+    }
+
+    #[test]
+    fn read_write_split_at_mdts() {
+        let mut sntl = sntl();
+        let blocks = 600u32;
+        let mut data: Vec<u8> = (0..blocks as usize * 512)
+            .map(|i| (i / 512) as u8)
+            .collect();
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x8a;
+        cdb[2..10].copy_from_slice(&10u64.to_be_bytes());
+        cdb[10..14].copy_from_slice(&blocks.to_be_bytes());
+        assert_eq!(sntl.execute(&cdb, &mut data).unwrap(), data.len());
+
+        let writes: Vec<(u32, u32)> = sntl
+            .transport
+            .history()
+            .iter()
+            .filter(|c| !c.admin && c.opcode == NVME_NVM_COMMAND_WRITE as u8)
+            .map(|c| (c.cdw10, c.cdw12 & 0xffff))
+            .collect();
+        assert_eq!(writes, [(10, 255), (266, 255), (522, 87)]);
+        assert_eq!(sntl.transport.block(10 + 300), vec![300u16 as u8; 512]);
+
+        sntl.transport.clear_history();
+        let mut read = vec![0u8; 300 * 512];
+        let mut cdb = [0x28, 0, 0, 0, 0, 10, 0, 0, 0, 0];
+        cdb[7..9].copy_from_slice(&300u16.to_be_bytes());
+        assert_eq!(sntl.execute(&cdb, &mut read).unwrap(), read.len());
+        assert_eq!(read, data[..read.len()]);
+        assert_eq!(sntl.transport.history().len(), 2);
+
+        // past the end of the namespace
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x88;
+        cdb[2..10].copy_from_slice(&(NSZE - 1).to_be_bytes());
+        cdb[10..14].copy_from_slice(&2u32.to_be_bytes());
+        let err = sntl.execute(&cdb, &mut read).unwrap_err();
+        assert_eq!(
+            sense(&err).1,
+            Some((ScsiSenseKey::ILLEGAL_REQUEST, 0x21, 0x00))
+        );
+    }
+
+    #[test]
+    fn unmap_to_dsm() {
+        let mut sntl = sntl();
+        let mut data = vec![0xa5u8; 4 * 512];
+        sntl.execute(&[0x2a, 0, 0, 0, 0, 100, 0, 0, 4, 0], &mut data)
+            .unwrap();
+        assert_eq!(sntl.transport.block(101), vec![0xa5; 512]);
+        sntl.transport.clear_history();
+
+        let mut list = vec![0u8; 8 + 2 * 16];
+        let length = list.len() as u16;
+        list[0..2].copy_from_slice(&(length - 2).to_be_bytes());
+        list[2..4].copy_from_slice(&32u16.to_be_bytes());
+        list[8..16].copy_from_slice(&100u64.to_be_bytes());
+        list[16..20].copy_from_slice(&2u32.to_be_bytes());
+        list[24..32].copy_from_slice(&102u64.to_be_bytes());
+        list[32..36].copy_from_slice(&1u32.to_be_bytes());
+        let mut cdb = [SCSI_OPCODE_UNMAP, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        cdb[7..9].copy_from_slice(&length.to_be_bytes());
+        sntl.execute(&cdb, &mut list).unwrap();
+
+        let history = sntl.transport.history();
+        assert_eq!(history.len(), 1);
+        let dsm = &history[0];
+        assert_eq!(dsm.opcode, NVME_NVM_COMMAND_DATASET_MANAGEMENT as u8);
+        assert_eq!(dsm.cdw10, 1); // two ranges, 0's based
+        assert_eq!(dsm.cdw11, 0x4); // AD
+        for lba in 100..103 {
+            assert_eq!(sntl.transport.block(lba), vec![0; 512]);
+        }
+        assert_eq!(sntl.transport.block(103), vec![0xa5; 512]);
+
+        // a descriptor past the end of the namespace
+        list[8..16].copy_from_slice(&NSZE.to_be_bytes());
+        let err = sntl.execute(&cdb, &mut list).unwrap_err();
+        assert_eq!(
+            sense(&err).1,
+            Some((ScsiSenseKey::ILLEGAL_REQUEST, 0x21, 0x00))
+        );
+    }
+
+    #[test]
+    fn status_to_sense() {
+        use ScsiSenseKey::*;
+        assert_eq!(sntl_status_to_sense(&NVME_COMMAND_STATUS::new()), None);
+        let cases = [
+            (0, 0x80, (ILLEGAL_REQUEST, 0x21, 0x00)),
+            (0, 0x82, (NOT_READY, 0x04, 0x01)),
+            (0, 0x84, (NOT_READY, 0x04, 0x04)),
+            (1, 0x82, (DATA_PROTECT, 0x27, 0x00)),
+            (2, 0x81, (MEDIUM_ERROR, 0x11, 0x00)),
+            (2, 0x82, (MEDIUM_ERROR, 0x10, 0x01)),
+            (2, 0x85, (MISCOMPARE, 0x1d, 0x00)),
+            (3, 0x00, (HARDWARE_ERROR, 0x44, 0x00)),
+        ];
+        let mut sntl = sntl();
+        let mut data = vec![0u8; 512];
+        for (sct, sc, expected) in cases {
+            sntl.transport
+                .inject_status(false, NVME_NVM_COMMAND_READ as u8, sct, sc);
+            let err = sntl
+                .execute(&[0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0], &mut data)
+                .unwrap_err();
+            assert_eq!(
+                sense(&err),
+                (SCSI_STATUS_CHECK_CONDITION, Some(expected)),
+                "SCT {} SC {:#04x}",
+                sct,
+                sc
+            );
+        }
+
+        // no sense data for TASK ABORTED and RESERVATION CONFLICT
+        sntl.transport
+            .inject_status(false, NVME_NVM_COMMAND_WRITE as u8, 0, 0x07);
+        let err = sntl
+            .execute(&[0x2a, 0, 0, 0, 0, 0, 0, 0, 1, 0], &mut data)
+            .unwrap_err();
+        assert_eq!(sense(&err), (SCSI_STATUS_TASK_ABORTED, None));
+        sntl.transport
+            .inject_status(false, NVME_NVM_COMMAND_FLUSH as u8, 0, 0x83);
+        let err = sntl
+            .execute(
+                &[SCSI_OPCODE_SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                &mut [],
+            )
+            .unwrap_err();
+        assert_eq!(sense(&err), (SCSI_STATUS_RESERVATION_CONFLICT, None));
+
+        // a failed identify surfaces on the first command
+        let mut sntl = self::sntl();
+        sntl.transport
+            .inject_status(true, NVME_ADMIN_COMMAND_IDENTIFY as u8, 0, 0x82);
+        let err = sntl.execute(&[0x00, 0, 0, 0, 0, 0], &mut []).unwrap_err();
+        assert_eq!(sense(&err).1, Some((NOT_READY, 0x04, 0x01)));
+    }
+}
@@ -0,0 +1,614 @@
+// TCG Storage Level 0 Discovery (TCG Storage Architecture Core Spec 3.3.6)
+use std::{fmt, io};
+
+pub const TCG_LEVEL0_HEADER_SIZE: usize = 48;
+pub const TCG_LEVEL0_PROTOCOL: u8 = 0x01;
+pub const TCG_LEVEL0_COMID: u16 = 0x0001;
+
+#[repr(u16)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcgFeatureCode {
+    TCG_FEATURE_TPER = 0x0001,
+    TCG_FEATURE_LOCKING = 0x0002,
+    TCG_FEATURE_GEOMETRY = 0x0003,
+    TCG_FEATURE_ENTERPRISE = 0x0100,
+    TCG_FEATURE_OPAL_V1 = 0x0200,
+    TCG_FEATURE_SINGLE_USER_MODE = 0x0201,
+    TCG_FEATURE_DATASTORE = 0x0202,
+    TCG_FEATURE_OPAL_V2 = 0x0203,
+    TCG_FEATURE_PYRITE_V1 = 0x0302,
+    TCG_FEATURE_PYRITE_V2 = 0x0303,
+    TCG_FEATURE_RUBY = 0x0304,
+    TCG_FEATURE_BLOCK_SID = 0x0402,
+}
+
+fn be16(data: &[u8], offset: usize) -> u16 {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .unwrap_or(0)
+}
+
+fn be32(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+        .unwrap_or(0)
+}
+
+fn be64(data: &[u8], offset: usize) -> u64 {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+        .unwrap_or(0)
+}
+
+fn byte(data: &[u8], offset: usize) -> u8 {
+    data.get(offset).copied().unwrap_or(0)
+}
+
+fn bit(value: u8, bit: u8) -> bool {
+    value & (1 << bit) != 0
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TperFeature {
+    pub sync: bool,
+    pub async_: bool,
+    pub ack_nak: bool,
+    pub buffer_mgmt: bool,
+    pub streaming: bool,
+    pub comid_mgmt: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockingFeature {
+    pub supported: bool,
+    pub enabled: bool,
+    pub locked: bool,
+    pub media_encryption: bool,
+    pub mbr_enabled: bool,
+    pub mbr_done: bool,
+    pub mbr_shadowing_not_supported: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GeometryFeature {
+    pub align: bool,
+    pub logical_block_size: u32,
+    pub alignment_granularity: u64,
+    pub lowest_aligned_lba: u64,
+}
+
+/// Common layout of the SSC descriptors (Opal, Pyrite, Ruby, Enterprise)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SscFeature {
+    pub base_comid: u16,
+    pub num_comids: u16,
+    pub range_crossing: bool,
+    pub admin_authorities: u16,
+    pub user_authorities: u16,
+    pub initial_pin: u8,
+    pub revert_pin: u8,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SingleUserModeFeature {
+    pub locking_objects: u32,
+    pub any: bool,
+    pub all: bool,
+    pub policy: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DataStoreFeature {
+    pub max_tables: u16,
+    pub max_size: u32,
+    pub alignment: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockSidFeature {
+    pub sid_value_state: bool,
+    pub sid_blocked: bool,
+    pub hardware_reset: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum TcgFeature {
+    Tper(TperFeature),
+    Locking(LockingFeature),
+    Geometry(GeometryFeature),
+    Enterprise(SscFeature),
+    OpalV1(SscFeature),
+    OpalV2(SscFeature),
+    SingleUserMode(SingleUserModeFeature),
+    DataStore(DataStoreFeature),
+    PyriteV1(SscFeature),
+    PyriteV2(SscFeature),
+    Ruby(SscFeature),
+    BlockSid(BlockSidFeature),
+    Unknown(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct TcgFeatureDescriptor {
+    pub code: u16,
+    pub version: u8,
+    pub feature: TcgFeature,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TcgLevel0Discovery {
+    pub length: u32,
+    pub major: u16,
+    pub minor: u16,
+    pub vendor: Vec<u8>,
+    pub features: Vec<TcgFeatureDescriptor>,
+}
+
+impl TcgFeature {
+    fn parse(code: u16, data: &[u8]) -> Self {
+        // `data` starts at the descriptor header, so the feature specific bytes begin at 4
+        let ssc = || SscFeature {
+            base_comid: be16(data, 4),
+            num_comids: be16(data, 6),
+            range_crossing: bit(byte(data, 8), 0),
+            admin_authorities: be16(data, 9),
+            user_authorities: be16(data, 11),
+            initial_pin: byte(data, 13),
+            revert_pin: byte(data, 14),
+        };
+        match code {
+            c if c == TcgFeatureCode::TCG_FEATURE_TPER as u16 => {
+                let flags = byte(data, 4);
+                TcgFeature::Tper(TperFeature {
+                    sync: bit(flags, 0),
+                    async_: bit(flags, 1),
+                    ack_nak: bit(flags, 2),
+                    buffer_mgmt: bit(flags, 3),
+                    streaming: bit(flags, 4),
+                    comid_mgmt: bit(flags, 6),
+                })
+            }
+            c if c == TcgFeatureCode::TCG_FEATURE_LOCKING as u16 => {
+                let flags = byte(data, 4);
+                TcgFeature::Locking(LockingFeature {
+                    supported: bit(flags, 0),
+                    enabled: bit(flags, 1),
+                    locked: bit(flags, 2),
+                    media_encryption: bit(flags, 3),
+                    mbr_enabled: bit(flags, 4),
+                    mbr_done: bit(flags, 5),
+                    mbr_shadowing_not_supported: bit(flags, 6),
+                })
+            }
+            c if c == TcgFeatureCode::TCG_FEATURE_GEOMETRY as u16 => {
+                TcgFeature::Geometry(GeometryFeature {
+                    align: bit(byte(data, 4), 0),
+                    logical_block_size: be32(data, 12),
+                    alignment_granularity: be64(data, 16),
+                    lowest_aligned_lba: be64(data, 24),
+                })
+            }
+            c if c == TcgFeatureCode::TCG_FEATURE_ENTERPRISE as u16 => {
+                TcgFeature::Enterprise(SscFeature {
+                    base_comid: be16(data, 4),
+                    num_comids: be16(data, 6),
+                    range_crossing: bit(byte(data, 8), 0),
+                    ..Default::default()
+                })
+            }
+            c if c == TcgFeatureCode::TCG_FEATURE_OPAL_V1 as u16 => {
+                TcgFeature::OpalV1(SscFeature {
+                    base_comid: be16(data, 4),
+                    num_comids: be16(data, 6),
+                    range_crossing: bit(byte(data, 8), 0),
+                    ..Default::default()
+                })
+            }
+            c if c == TcgFeatureCode::TCG_FEATURE_SINGLE_USER_MODE as u16 => {
+                let flags = byte(data, 8);
+                TcgFeature::SingleUserMode(SingleUserModeFeature {
+                    locking_objects: be32(data, 4),
+                    any: bit(flags, 0),
+                    all: bit(flags, 1),
+                    policy: bit(flags, 2),
+                })
+            }
+            c if c == TcgFeatureCode::TCG_FEATURE_DATASTORE as u16 => {
+                TcgFeature::DataStore(DataStoreFeature {
+                    max_tables: be16(data, 6),
+                    max_size: be32(data, 8),
+                    alignment: be32(data, 12),
+                })
+            }
+            c if c == TcgFeatureCode::TCG_FEATURE_OPAL_V2 as u16 => TcgFeature::OpalV2(ssc()),
+            c if c == TcgFeatureCode::TCG_FEATURE_PYRITE_V1 as u16
+                || c == TcgFeatureCode::TCG_FEATURE_PYRITE_V2 as u16 =>
+            {
+                let pyrite = SscFeature {
+                    base_comid: be16(data, 4),
+                    num_comids: be16(data, 6),
+                    initial_pin: byte(data, 13),
+                    revert_pin: byte(data, 14),
+                    ..Default::default()
+                };
+                if c == TcgFeatureCode::TCG_FEATURE_PYRITE_V1 as u16 {
+                    TcgFeature::PyriteV1(pyrite)
+                } else {
+                    TcgFeature::PyriteV2(pyrite)
+                }
+            }
+            c if c == TcgFeatureCode::TCG_FEATURE_RUBY as u16 => TcgFeature::Ruby(ssc()),
+            c if c == TcgFeatureCode::TCG_FEATURE_BLOCK_SID as u16 => {
+                TcgFeature::BlockSid(BlockSidFeature {
+                    sid_value_state: bit(byte(data, 4), 0),
+                    sid_blocked: bit(byte(data, 4), 1),
+                    hardware_reset: bit(byte(data, 5), 0),
+                })
+            }
+            _ => TcgFeature::Unknown(data.get(4..).unwrap_or_default().to_vec()),
+        }
+    }
+}
+
+impl TcgLevel0Discovery {
+    /// Parse a Level 0 Discovery response (as returned by IF-RECV protocol 01h, ComID 0001h)
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if data.len() < TCG_LEVEL0_HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Level 0 discovery header truncated",
+            ));
+        }
+        let length = be32(data, 0);
+        // the length field does not count itself
+        let end = (length as usize + 4).min(data.len());
+        let mut discovery = Self {
+            length,
+            major: be16(data, 4),
+            minor: be16(data, 6),
+            vendor: data[16..TCG_LEVEL0_HEADER_SIZE].to_vec(),
+            features: vec![],
+        };
+
+        let mut offset = TCG_LEVEL0_HEADER_SIZE;
+        while offset < end {
+            if offset + 4 > end {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("feature descriptor header at {} truncated", offset),
+                ));
+            }
+            let code = be16(data, offset);
+            let version = byte(data, offset + 2) >> 4;
+            let desc_end = offset + 4 + byte(data, offset + 3) as usize;
+            if desc_end > end {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("feature descriptor {:#06x} truncated", code),
+                ));
+            }
+            discovery.features.push(TcgFeatureDescriptor {
+                code,
+                version,
+                feature: TcgFeature::parse(code, &data[offset..desc_end]),
+            });
+            offset = desc_end;
+        }
+        Ok(discovery)
+    }
+
+    pub fn feature(&self, code: TcgFeatureCode) -> Option<&TcgFeature> {
+        self.features
+            .iter()
+            .find(|desc| desc.code == code as u16)
+            .map(|desc| &desc.feature)
+    }
+
+    pub fn locking(&self) -> Option<&LockingFeature> {
+        match self.feature(TcgFeatureCode::TCG_FEATURE_LOCKING) {
+            Some(TcgFeature::Locking(locking)) => Some(locking),
+            _ => None,
+        }
+    }
+
+    /// SSC descriptor carrying the ComID range, whatever order the device
+    /// reports them in: Opal v2, Ruby, Pyrite v2, Pyrite v1, Opal v1, then Enterprise
+    pub fn ssc(&self) -> Option<&SscFeature> {
+        self.features
+            .iter()
+            .filter_map(|desc| match &desc.feature {
+                TcgFeature::OpalV2(ssc) => Some((0, ssc)),
+                TcgFeature::Ruby(ssc) => Some((1, ssc)),
+                TcgFeature::PyriteV2(ssc) => Some((2, ssc)),
+                TcgFeature::PyriteV1(ssc) => Some((3, ssc)),
+                TcgFeature::OpalV1(ssc) => Some((4, ssc)),
+                TcgFeature::Enterprise(ssc) => Some((5, ssc)),
+                _ => None,
+            })
+            .min_by_key(|&(rank, _)| rank)
+            .map(|(_, ssc)| ssc)
+    }
+
+    pub fn base_comid(&self) -> Option<u16> {
+        self.ssc().map(|ssc| ssc.base_comid)
+    }
+
+    pub fn num_comids(&self) -> Option<u16> {
+        self.ssc().map(|ssc| ssc.num_comids)
+    }
+}
+
+impl fmt::Display for SscFeature {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "    Base ComID: 0x{:04X}", self.base_comid)?;
+        writeln!(fmt, "    Number of ComIDs: {}", self.num_comids)?;
+        writeln!(fmt, "    Range Crossing: {}", self.range_crossing)?;
+        writeln!(
+            fmt,
+            "    Locking SP Admin Authorities: {}",
+            self.admin_authorities
+        )?;
+        writeln!(
+            fmt,
+            "    Locking SP User Authorities: {}",
+            self.user_authorities
+        )?;
+        writeln!(fmt, "    Initial C_PIN_SID: 0x{:02X}", self.initial_pin)?;
+        writeln!(fmt, "    C_PIN_SID upon Revert: 0x{:02X}", self.revert_pin)
+    }
+}
+
+impl fmt::Display for TcgLevel0Discovery {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            fmt,
+            "Level 0 Discovery: length {}, revision {}.{}",
+            self.length, self.major, self.minor
+        )?;
+        for desc in &self.features {
+            let name = match &desc.feature {
+                TcgFeature::Tper(_) => "TPer",
+                TcgFeature::Locking(_) => "Locking",
+                TcgFeature::Geometry(_) => "Geometry Reporting",
+                TcgFeature::Enterprise(_) => "Enterprise SSC",
+                TcgFeature::OpalV1(_) => "Opal SSC v1",
+                TcgFeature::OpalV2(_) => "Opal SSC v2",
+                TcgFeature::SingleUserMode(_) => "Single User Mode",
+                TcgFeature::DataStore(_) => "DataStore Table",
+                TcgFeature::PyriteV1(_) => "Pyrite SSC v1",
+                TcgFeature::PyriteV2(_) => "Pyrite SSC v2",
+                TcgFeature::Ruby(_) => "Ruby SSC",
+                TcgFeature::BlockSid(_) => "Block SID Authentication",
+                TcgFeature::Unknown(_) => "Unknown",
+            };
+            writeln!(
+                fmt,
+                "  Feature 0x{:04X} {} (version {})",
+                desc.code, name, desc.version
+            )?;
+            match &desc.feature {
+                TcgFeature::Tper(tper) => {
+                    writeln!(fmt, "    Sync: {}, Async: {}", tper.sync, tper.async_)?;
+                    writeln!(fmt, "    ACK/NAK: {}", tper.ack_nak)?;
+                    writeln!(fmt, "    Buffer Management: {}", tper.buffer_mgmt)?;
+                    writeln!(fmt, "    Streaming: {}", tper.streaming)?;
+                    writeln!(fmt, "    ComID Management: {}", tper.comid_mgmt)?;
+                }
+                TcgFeature::Locking(locking) => {
+                    writeln!(fmt, "    Locking Supported: {}", locking.supported)?;
+                    writeln!(fmt, "    Locking Enabled: {}", locking.enabled)?;
+                    writeln!(fmt, "    Locked: {}", locking.locked)?;
+                    writeln!(fmt, "    Media Encryption: {}", locking.media_encryption)?;
+                    writeln!(fmt, "    MBR Enabled: {}", locking.mbr_enabled)?;
+                    writeln!(fmt, "    MBR Done: {}", locking.mbr_done)?;
+                    writeln!(
+                        fmt,
+                        "    MBR Shadowing Not Supported: {}",
+                        locking.mbr_shadowing_not_supported
+                    )?;
+                }
+                TcgFeature::Geometry(geo) => {
+                    writeln!(fmt, "    Align: {}", geo.align)?;
+                    writeln!(fmt, "    Logical Block Size: {}", geo.logical_block_size)?;
+                    writeln!(
+                        fmt,
+                        "    Alignment Granularity: {}",
+                        geo.alignment_granularity
+                    )?;
+                    writeln!(fmt, "    Lowest Aligned LBA: {}", geo.lowest_aligned_lba)?;
+                }
+                TcgFeature::Enterprise(ssc)
+                | TcgFeature::OpalV1(ssc)
+                | TcgFeature::OpalV2(ssc)
+                | TcgFeature::PyriteV1(ssc)
+                | TcgFeature::PyriteV2(ssc)
+                | TcgFeature::Ruby(ssc) => write!(fmt, "{}", ssc)?,
+                TcgFeature::SingleUserMode(sum) => {
+                    writeln!(fmt, "    Locking Objects: {}", sum.locking_objects)?;
+                    writeln!(
+                        fmt,
+                        "    Any: {}, All: {}, Policy: {}",
+                        sum.any, sum.all, sum.policy
+                    )?;
+                }
+                TcgFeature::DataStore(ds) => {
+                    writeln!(fmt, "    Max DataStore Tables: {}", ds.max_tables)?;
+                    writeln!(fmt, "    Max Total Size: {}", ds.max_size)?;
+                    writeln!(fmt, "    Table Size Alignment: {}", ds.alignment)?;
+                }
+                TcgFeature::BlockSid(sid) => {
+                    writeln!(fmt, "    SID Value State: {}", sid.sid_value_state)?;
+                    writeln!(fmt, "    SID Blocked State: {}", sid.sid_blocked)?;
+                    writeln!(fmt, "    Hardware Reset: {}", sid.hardware_reset)?;
+                }
+                TcgFeature::Unknown(data) => writeln!(fmt, "    Data: {:02x?}", data)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(code: u16, version: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = code.to_be_bytes().to_vec();
+        out.push(version << 4);
+        out.push(body.len() as u8);
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn discovery(descriptors: &[Vec<u8>]) -> Vec<u8> {
+        let features: Vec<u8> = descriptors.concat();
+        let mut out = vec![0; TCG_LEVEL0_HEADER_SIZE];
+        let length = (TCG_LEVEL0_HEADER_SIZE - 4 + features.len()) as u32;
+        out[0..4].copy_from_slice(&length.to_be_bytes());
+        out[4..6].copy_from_slice(&0u16.to_be_bytes());
+        out[6..8].copy_from_slice(&1u16.to_be_bytes());
+        out[16..20].copy_from_slice(b"VEND");
+        out.extend_from_slice(&features);
+        // IF-RECV returns a whole 512-byte buffer
+        out.resize(out.len().div_ceil(512) * 512, 0);
+        out
+    }
+
+    fn ssc_body(base_comid: u16, num_comids: u16) -> Vec<u8> {
+        let mut body = vec![0; 16];
+        body[0..2].copy_from_slice(&base_comid.to_be_bytes());
+        body[2..4].copy_from_slice(&num_comids.to_be_bytes());
+        body[4] = 0x01; // range crossing
+        body[5..7].copy_from_slice(&4u16.to_be_bytes());
+        body[7..9].copy_from_slice(&8u16.to_be_bytes());
+        body[9] = 0x00;
+        body[10] = 0xFF;
+        body
+    }
+
+    #[test]
+    fn parse_features() {
+        let mut geometry = vec![0; 28];
+        geometry[0] = 0x01;
+        geometry[8..12].copy_from_slice(&512u32.to_be_bytes());
+        geometry[12..20].copy_from_slice(&8u64.to_be_bytes());
+        geometry[20..28].copy_from_slice(&0u64.to_be_bytes());
+        let mut pyrite = vec![0; 12];
+        pyrite[0..2].copy_from_slice(&0x1000u16.to_be_bytes());
+        pyrite[2..4].copy_from_slice(&1u16.to_be_bytes());
+        pyrite[10] = 0xFF;
+        let data = discovery(&[
+            descriptor(0x0001, 1, &[0x11, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            descriptor(0x0002, 1, &[0x0B, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            descriptor(0x0003, 1, &geometry),
+            descriptor(0x0203, 2, &ssc_body(0x07FE, 1)),
+            descriptor(0x0303, 1, &pyrite),
+            descriptor(0xC001, 1, &[0xDE, 0xAD, 0xBE, 0xEF]),
+        ]);
+        let discovery = TcgLevel0Discovery::parse(&data).unwrap();
+        assert_eq!((discovery.major, discovery.minor), (0, 1));
+        assert_eq!(&discovery.vendor[..4], b"VEND");
+        assert_eq!(discovery.features.len(), 6);
+
+        match discovery.feature(TcgFeatureCode::TCG_FEATURE_TPER) {
+            Some(TcgFeature::Tper(tper)) => {
+                assert!(tper.sync && tper.streaming);
+                assert!(!tper.async_ && !tper.comid_mgmt);
+            }
+            other => panic!("{:?}", other),
+        }
+        let locking = discovery.locking().unwrap();
+        assert!(locking.supported && locking.enabled && locking.media_encryption);
+        assert!(!locking.locked && !locking.mbr_enabled);
+        match discovery.feature(TcgFeatureCode::TCG_FEATURE_GEOMETRY) {
+            Some(TcgFeature::Geometry(geo)) => {
+                assert!(geo.align);
+                assert_eq!(geo.logical_block_size, 512);
+                assert_eq!(geo.alignment_granularity, 8);
+                assert_eq!(geo.lowest_aligned_lba, 0);
+            }
+            other => panic!("{:?}", other),
+        }
+        match discovery.feature(TcgFeatureCode::TCG_FEATURE_OPAL_V2) {
+            Some(TcgFeature::OpalV2(opal)) => {
+                assert!(opal.range_crossing);
+                assert_eq!(opal.admin_authorities, 4);
+                assert_eq!(opal.user_authorities, 8);
+                assert_eq!(opal.revert_pin, 0xFF);
+            }
+            other => panic!("{:?}", other),
+        }
+        match discovery.feature(TcgFeatureCode::TCG_FEATURE_PYRITE_V2) {
+            Some(TcgFeature::PyriteV2(pyrite)) => {
+                assert_eq!(pyrite.base_comid, 0x1000);
+                assert_eq!(pyrite.revert_pin, 0xFF);
+            }
+            other => panic!("{:?}", other),
+        }
+        let unknown = &discovery.features[5];
+        assert_eq!(unknown.code, 0xC001);
+        assert_eq!(unknown.version, 1);
+        assert!(
+            matches!(&unknown.feature, TcgFeature::Unknown(d) if d == &[0xDE, 0xAD, 0xBE, 0xEF])
+        );
+
+        // Opal v2 comes first in the preference order
+        assert_eq!(discovery.base_comid(), Some(0x07FE));
+        assert_eq!(discovery.num_comids(), Some(1));
+    }
+
+    #[test]
+    fn ssc_preference() {
+        // lowest ranked first, each with its own ComID
+        let ranked = [
+            (0x0100, 0x0001), // Enterprise
+            (0x0200, 0x0002), // Opal v1
+            (0x0302, 0x0003), // Pyrite v1
+            (0x0303, 0x0004), // Pyrite v2
+            (0x0304, 0x0005), // Ruby
+            (0x0203, 0x0006), // Opal v2
+        ];
+        for count in 1..=ranked.len() {
+            let descriptors: Vec<Vec<u8>> = ranked[..count]
+                .iter()
+                .map(|&(code, comid)| descriptor(code, 1, &ssc_body(comid, 1)))
+                .collect();
+            let found = TcgLevel0Discovery::parse(&discovery(&descriptors)).unwrap();
+            assert_eq!(found.base_comid(), Some(ranked[count - 1].1));
+
+            let reversed: Vec<Vec<u8>> = descriptors.into_iter().rev().collect();
+            let found = TcgLevel0Discovery::parse(&discovery(&reversed)).unwrap();
+            assert_eq!(found.base_comid(), Some(ranked[count - 1].1));
+        }
+
+        // features without a ComID range do not count
+        let data = discovery(&[descriptor(0x0001, 1, &[0x11; 12])]);
+        assert!(TcgLevel0Discovery::parse(&data).unwrap().ssc().is_none());
+    }
+
+    #[test]
+    fn parse_truncated() {
+        assert!(TcgLevel0Discovery::parse(&[]).is_err());
+        assert!(TcgLevel0Discovery::parse(&[0; TCG_LEVEL0_HEADER_SIZE - 1]).is_err());
+
+        // no descriptors at all is fine
+        let empty = discovery(&[]);
+        assert!(TcgLevel0Discovery::parse(&empty[..TCG_LEVEL0_HEADER_SIZE])
+            .unwrap()
+            .features
+            .is_empty());
+
+        let data = discovery(&[descriptor(0x0203, 2, &ssc_body(0x07FE, 1))]);
+        let full = TCG_LEVEL0_HEADER_SIZE + 4 + 16;
+        // descriptor body cut short
+        assert!(TcgLevel0Discovery::parse(&data[..full - 1]).is_err());
+        // descriptor header cut short
+        assert!(TcgLevel0Discovery::parse(&data[..TCG_LEVEL0_HEADER_SIZE + 2]).is_err());
+        // the descriptor claims more than the length field covers
+        let mut short = data.clone();
+        short[0..4].copy_from_slice(&((full - 4 - 1) as u32).to_be_bytes());
+        assert!(TcgLevel0Discovery::parse(&short).is_err());
+        assert!(TcgLevel0Discovery::parse(&data[..full]).is_ok());
+    }
+}
@@ -0,0 +1,917 @@
+// Write-then-verify data integrity workload. Every block carries a stamp with
+// its LBA, the pass number, the run seed and a CRC so a read back can tell
+// unwritten, stale, misdirected, torn and corrupted blocks apart.
+use crate::bench::{bench_open, pread, pwrite, BenchRng, BENCH_ALIGNMENT};
+use crate::dev::disk::Disk;
+use crate::AlignedBuffer;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::path::Path;
+
+pub const STAMP_MAGIC: u64 = u64::from_le_bytes(*b"NVMESTMP");
+pub const STAMP_SIZE: usize = 40;
+const STAMP_CRC_OFFSET: usize = 32;
+const CHECKPOINT_BLOCKS: u64 = 4096;
+
+/// Target of the workload, positional I/O so files, block devices and `Disk`
+/// can share the same engine
+pub trait BlockIo {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize>;
+    fn capacity(&mut self) -> io::Result<u64>;
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl BlockIo for File {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        pread(self, buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        pwrite(self, buf, offset)
+    }
+
+    fn capacity(&mut self) -> io::Result<u64> {
+        self.seek(SeekFrom::End(0))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl BlockIo for Disk {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        Disk::read_at(self, buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        Disk::write_at(self, buf, offset)
+    }
+
+    fn capacity(&mut self) -> io::Result<u64> {
+        Ok(self.size() as u64)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Disk::sync(self)
+    }
+}
+
+/// CRC-32C (Castagnoli), bitwise table built on first use
+pub fn crc32c(data: &[u8]) -> u32 {
+    static TABLE: once_cell::sync::Lazy<[u32; 256]> = once_cell::sync::Lazy::new(|| {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0x82F6_3B78
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        table
+    });
+    !data.iter().fold(!0u32, |crc, &b| {
+        TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Header written at the start of every block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockStamp {
+    pub lba: u64,
+    pub pass: u32,
+    pub block_size: u32,
+    pub seed: u64,
+    pub crc: u32,
+}
+
+impl fmt::Display for BlockStamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "lba={:#x} pass={} seed={:#x} bs={} crc={:#010x}",
+            self.lba, self.pass, self.seed, self.block_size, self.crc
+        )
+    }
+}
+
+impl BlockStamp {
+    /// Fill `block` with the stamp and its payload, then seal it with the CRC
+    pub fn fill(lba: u64, pass: u32, seed: u64, block: &mut [u8]) -> BlockStamp {
+        let mut rng = BenchRng::new(
+            seed ^ lba.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ ((pass as u64) << 32 | pass as u64),
+        );
+        for chunk in block[STAMP_SIZE..].chunks_mut(8) {
+            let value = rng.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+        let block_size = block.len() as u32;
+        block[0..8].copy_from_slice(&STAMP_MAGIC.to_le_bytes());
+        block[8..16].copy_from_slice(&lba.to_le_bytes());
+        block[16..20].copy_from_slice(&pass.to_le_bytes());
+        block[20..24].copy_from_slice(&block_size.to_le_bytes());
+        block[24..32].copy_from_slice(&seed.to_le_bytes());
+        block[32..40].fill(0);
+        let crc = crc32c(block);
+        block[STAMP_CRC_OFFSET..STAMP_CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+        BlockStamp {
+            lba,
+            pass,
+            block_size,
+            seed,
+            crc,
+        }
+    }
+
+    /// Stamp found in `block`, None without the magic
+    pub fn parse(block: &[u8]) -> Option<BlockStamp> {
+        let u64_at = |at: usize| u64::from_le_bytes(block[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(block[at..at + 4].try_into().unwrap());
+        if block.len() < STAMP_SIZE || u64_at(0) != STAMP_MAGIC {
+            return None;
+        }
+        Some(BlockStamp {
+            lba: u64_at(8),
+            pass: u32_at(16),
+            block_size: u32_at(20),
+            seed: u64_at(24),
+            crc: u32_at(STAMP_CRC_OFFSET),
+        })
+    }
+
+    /// CRC over the block with the CRC field zeroed
+    pub fn compute_crc(block: &[u8]) -> u32 {
+        let mut copy = block.to_vec();
+        copy[STAMP_CRC_OFFSET..STAMP_CRC_OFFSET + 4].fill(0);
+        crc32c(&copy)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MismatchKind {
+    Unwritten,   // all zero, the write never landed
+    NoStamp,     // data without a stamp, e.g. overwritten by someone else
+    Corrupt,     // stamp present but the CRC does not match: bit flips
+    Misdirected, // intact block that belongs to another LBA
+    ForeignSeed, // intact block from another run
+    Stale,       // intact block from an older pass, a lost write
+    Torn,        // stale block inside an I/O whose other blocks are current
+    Newer,       // block from a later pass than expected
+}
+
+impl fmt::Display for MismatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MismatchKind::Unwritten => "unwritten",
+            MismatchKind::NoStamp => "no stamp",
+            MismatchKind::Corrupt => "corrupt",
+            MismatchKind::Misdirected => "misdirected",
+            MismatchKind::ForeignSeed => "foreign seed",
+            MismatchKind::Stale => "stale",
+            MismatchKind::Torn => "torn",
+            MismatchKind::Newer => "newer",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IntegrityMismatch {
+    pub lba: u64,
+    pub kind: MismatchKind,
+    pub expected: BlockStamp,
+    pub actual: Option<BlockStamp>,
+    pub bad_bytes: usize, // bytes differing from the expected block
+    pub first_bad: Option<usize>,
+}
+
+impl fmt::Display for IntegrityMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "LBA {:#x}: {}", self.lba, self.kind)?;
+        writeln!(f, "  expected: {}", self.expected)?;
+        match &self.actual {
+            Some(actual) => writeln!(f, "  actual  : {}", actual)?,
+            None => writeln!(f, "  actual  : <no stamp>")?,
+        }
+        if let Some(first) = self.first_bad {
+            writeln!(
+                f,
+                "  {} bytes differ, first at offset {:#x}",
+                self.bad_bytes, first
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare one block read back against what pass `pass` wrote there
+pub fn check_block(
+    block: &[u8],
+    lba: u64,
+    pass: u32,
+    seed: u64,
+    scratch: &mut [u8],
+) -> Option<IntegrityMismatch> {
+    let expected = BlockStamp::fill(lba, pass, seed, scratch);
+    if block == &scratch[..] {
+        return None;
+    }
+    let bad_bytes = block
+        .iter()
+        .zip(scratch.iter())
+        .filter(|(a, b)| a != b)
+        .count();
+    let first_bad = block.iter().zip(scratch.iter()).position(|(a, b)| a != b);
+    let actual = BlockStamp::parse(block);
+    let kind = match &actual {
+        None if block.iter().all(|&b| b == 0) => MismatchKind::Unwritten,
+        None => MismatchKind::NoStamp,
+        Some(stamp) if stamp.crc != BlockStamp::compute_crc(block) => MismatchKind::Corrupt,
+        Some(stamp) if stamp.lba != lba => MismatchKind::Misdirected,
+        Some(stamp) if stamp.seed != seed => MismatchKind::ForeignSeed,
+        Some(stamp) if stamp.pass < pass => MismatchKind::Stale,
+        Some(stamp) if stamp.pass > pass => MismatchKind::Newer,
+        // intact stamp matching everything but different bytes: block size differs
+        Some(_) => MismatchKind::Corrupt,
+    };
+    Some(IntegrityMismatch {
+        lba,
+        kind,
+        expected,
+        actual,
+        bad_bytes,
+        first_bad,
+    })
+}
+
+/// Progress of a run, saved so an interrupted or later verify-only run can
+/// pick up where it stopped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityState {
+    pub seed: u64,
+    pub pass: u32,
+    pub block_size: usize,
+    pub offset: u64,
+    pub blocks: u64,
+    pub written: u64,  // blocks of `pass` written from the start of the region
+    pub verified: u64, // blocks of `pass` verified from the start of the region
+}
+
+impl IntegrityState {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let mut values = BTreeMap::new();
+        for line in text.lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        let get = |key: &str| -> io::Result<u64> {
+            values
+                .get(key)
+                .and_then(|v| match v.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                    None => v.parse().ok(),
+                })
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: missing or invalid '{}'", path, key),
+                    )
+                })
+        };
+        Ok(Self {
+            seed: get("seed")?,
+            pass: get("pass")? as u32,
+            block_size: get("block_size")? as usize,
+            offset: get("offset")?,
+            blocks: get("blocks")?,
+            written: get("written")?,
+            verified: get("verified")?,
+        })
+    }
+
+    /// Write to a temporary file and rename so a crash keeps the old state
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(
+            &tmp,
+            format!(
+                "seed={:#x}\npass={}\nblock_size={}\noffset={}\nblocks={}\nwritten={}\nverified={}\n",
+                self.seed,
+                self.pass,
+                self.block_size,
+                self.offset,
+                self.blocks,
+                self.written,
+                self.verified
+            ),
+        )?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IntegrityConfig {
+    pub path: String,
+    pub block_size: usize, // stamp granularity, usually the logical block size
+    pub io_size: usize,    // bytes per read or write, a multiple of block_size
+    pub offset: u64,
+    pub range: Option<u64>, // bytes from `offset`, the rest of the target by default
+    pub passes: u32,
+    pub first_pass: u32,
+    pub seed: u64,
+    pub verify_only: bool,
+    pub state: Option<String>, // resume from and checkpoint to this file
+    pub direct: bool,
+    pub max_report: usize, // mismatches kept in the report, all are counted
+    pub stop_on_error: bool,
+}
+
+impl Default for IntegrityConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            block_size: 512,
+            io_size: 128 * 1024,
+            offset: 0,
+            range: None,
+            passes: 1,
+            first_pass: 1,
+            seed: 0x5EED,
+            verify_only: false,
+            state: None,
+            direct: false,
+            max_report: 32,
+            stop_on_error: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityReport {
+    pub state: IntegrityState,
+    pub blocks_written: u64,
+    pub blocks_verified: u64,
+    pub counts: BTreeMap<MismatchKind, u64>,
+    pub mismatches: Vec<IntegrityMismatch>,
+}
+
+impl IntegrityReport {
+    pub fn errors(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Region: offset={:#x} blocks={} bs={} seed={:#x} pass={}",
+            self.state.offset,
+            self.state.blocks,
+            self.state.block_size,
+            self.state.seed,
+            self.state.pass
+        )?;
+        writeln!(
+            f,
+            "Written: {} blocks, verified: {} blocks, mismatches: {}",
+            self.blocks_written,
+            self.blocks_verified,
+            self.errors()
+        )?;
+        for (kind, count) in &self.counts {
+            writeln!(f, "  {:<12}: {}", kind.to_string(), count)?;
+        }
+        for mismatch in &self.mismatches {
+            write!(f, "{}", mismatch)?;
+        }
+        if self.errors() > self.mismatches.len() as u64 {
+            writeln!(
+                f,
+                "... {} more not shown",
+                self.errors() - self.mismatches.len() as u64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn checkpoint(config: &IntegrityConfig, state: &IntegrityState) -> io::Result<()> {
+    match &config.state {
+        Some(path) => state.save(path),
+        None => Ok(()),
+    }
+}
+
+/// Blocks per I/O starting at `block`, never crossing the end of the region
+fn chunk_blocks(config: &IntegrityConfig, state: &IntegrityState, block: u64, end: u64) -> usize {
+    let per_io = (config.io_size / state.block_size).max(1) as u64;
+    per_io.min(end - block) as usize
+}
+
+fn write_pass<T: BlockIo>(
+    target: &mut T,
+    config: &IntegrityConfig,
+    state: &mut IntegrityState,
+    buffer: &mut [u8],
+) -> io::Result<u64> {
+    let bs = state.block_size;
+    let mut written = 0;
+    while state.written < state.blocks {
+        let count = chunk_blocks(config, state, state.written, state.blocks);
+        let data = &mut buffer[..count * bs];
+        for (i, block) in data.chunks_exact_mut(bs).enumerate() {
+            let lba = (state.offset / bs as u64) + state.written + i as u64;
+            BlockStamp::fill(lba, state.pass, state.seed, block);
+        }
+        let offset = state.offset + state.written * bs as u64;
+        let n = target.write_at(data, offset)?;
+        if n != data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!(
+                    "short write at {:#x}: {} of {} bytes",
+                    offset,
+                    n,
+                    data.len()
+                ),
+            ));
+        }
+        state.written += count as u64;
+        written += count as u64;
+        if state.written % CHECKPOINT_BLOCKS < count as u64 {
+            target.sync()?;
+            checkpoint(config, state)?;
+        }
+    }
+    target.sync()?;
+    checkpoint(config, state)?;
+    Ok(written)
+}
+
+fn verify_pass<T: BlockIo>(
+    target: &mut T,
+    config: &IntegrityConfig,
+    state: &mut IntegrityState,
+    buffer: &mut [u8],
+    report: &mut IntegrityReport,
+) -> io::Result<()> {
+    let bs = state.block_size;
+    let mut scratch = vec![0u8; bs];
+    while state.verified < state.written {
+        let count = chunk_blocks(config, state, state.verified, state.written);
+        let data = &mut buffer[..count * bs];
+        let offset = state.offset + state.verified * bs as u64;
+        let n = target.read_at(data, offset)?;
+        if n != data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("short read at {:#x}: {} of {} bytes", offset, n, data.len()),
+            ));
+        }
+        let mut found: Vec<IntegrityMismatch> = data
+            .chunks_exact(bs)
+            .enumerate()
+            .filter_map(|(i, block)| {
+                let lba = (state.offset / bs as u64) + state.verified + i as u64;
+                check_block(block, lba, state.pass, state.seed, &mut scratch)
+            })
+            .collect();
+        // a single write that left some blocks current and others old was torn
+        if !found.is_empty() && found.len() < count {
+            for mismatch in found.iter_mut() {
+                if matches!(mismatch.kind, MismatchKind::Stale | MismatchKind::Unwritten) {
+                    mismatch.kind = MismatchKind::Torn;
+                }
+            }
+        }
+        let stop = config.stop_on_error && !found.is_empty();
+        for mismatch in found {
+            *report.counts.entry(mismatch.kind).or_default() += 1;
+            if report.mismatches.len() < config.max_report {
+                report.mismatches.push(mismatch);
+            }
+        }
+        state.verified += count as u64;
+        report.blocks_verified += count as u64;
+        if state.verified % CHECKPOINT_BLOCKS < count as u64 {
+            checkpoint(config, state)?;
+        }
+        if stop {
+            break;
+        }
+    }
+    checkpoint(config, state)
+}
+
+/// A saved state only resumes the region and run it was written for
+fn check_resume<T: BlockIo>(
+    target: &mut T,
+    config: &IntegrityConfig,
+    state: &IntegrityState,
+) -> io::Result<()> {
+    let path = config.state.as_deref().unwrap_or_default();
+    let mismatch = |what: &str, saved: String, given: String| {
+        Err(invalid(format!(
+            "{}: saved for {} {}, not {}; use the same options or another state file",
+            path, what, saved, given
+        )))
+    };
+    if state.block_size != config.block_size {
+        return mismatch(
+            "block size",
+            state.block_size.to_string(),
+            config.block_size.to_string(),
+        );
+    }
+    if state.offset != config.offset {
+        return mismatch(
+            "offset",
+            format!("{:#x}", state.offset),
+            format!("{:#x}", config.offset),
+        );
+    }
+    if state.seed != config.seed {
+        return mismatch(
+            "seed",
+            format!("{:#x}", state.seed),
+            format!("{:#x}", config.seed),
+        );
+    }
+    if let Some(range) = config.range {
+        let room = target.capacity()?.saturating_sub(config.offset);
+        let blocks = range.min(room) / state.block_size.max(1) as u64;
+        if blocks != state.blocks {
+            return mismatch(
+                "range",
+                format!("{} blocks", state.blocks),
+                format!("{} blocks", blocks),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Run the workload on an already opened target
+pub fn integrity_run_on<T: BlockIo>(
+    target: &mut T,
+    config: &IntegrityConfig,
+) -> io::Result<IntegrityReport> {
+    let resumed = match &config.state {
+        Some(path) if Path::new(path).exists() => Some(IntegrityState::load(path)?),
+        _ => None,
+    };
+    let mut state = match resumed {
+        Some(state) => {
+            check_resume(target, config, &state)?;
+            state
+        }
+        None => {
+            let bs = config.block_size;
+            if bs < STAMP_SIZE || config.io_size < bs || !config.offset.is_multiple_of(bs as u64) {
+                return Err(invalid(format!(
+                    "block size must be at least {} bytes, io size at least one block and offset block aligned",
+                    STAMP_SIZE
+                )));
+            }
+            let capacity = target.capacity()?;
+            let range = config
+                .range
+                .unwrap_or(capacity.saturating_sub(config.offset))
+                .min(capacity.saturating_sub(config.offset));
+            IntegrityState {
+                seed: config.seed,
+                pass: config.first_pass,
+                block_size: bs,
+                offset: config.offset,
+                blocks: range / bs as u64,
+                // without a saved state a verify-only run checks the whole region
+                written: if config.verify_only {
+                    range / bs as u64
+                } else {
+                    0
+                },
+                verified: 0,
+            }
+        }
+    };
+    if state.blocks == 0 {
+        return Err(invalid(format!("{} has no room for a block", config.path)));
+    }
+
+    let io_blocks = (config.io_size / state.block_size).max(1);
+    let mut buffer = AlignedBuffer::new(io_blocks * state.block_size, BENCH_ALIGNMENT);
+    let mut report = IntegrityReport::default();
+
+    if config.verify_only {
+        // a finished verify starts over, an interrupted one resumes
+        if state.verified >= state.written {
+            state.verified = 0;
+        }
+        verify_pass(target, config, &mut state, &mut buffer, &mut report)?;
+    } else {
+        let last_pass = state.pass.max(config.first_pass + config.passes.max(1) - 1);
+        loop {
+            report.blocks_written += write_pass(target, config, &mut state, &mut buffer)?;
+            verify_pass(target, config, &mut state, &mut buffer, &mut report)?;
+            if state.pass >= last_pass || (config.stop_on_error && report.errors() > 0) {
+                break;
+            }
+            state.pass += 1;
+            state.written = 0;
+            state.verified = 0;
+            checkpoint(config, &state)?;
+        }
+    }
+    report.state = state;
+    Ok(report)
+}
+
+/// Open `config.path` and run the workload on it
+pub fn integrity_run(config: &IntegrityConfig) -> io::Result<IntegrityReport> {
+    let mut file = bench_open(&config.path, !config.verify_only, config.direct)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", config.path, e)))?;
+    if !config.verify_only {
+        if let Some(range) = config.range {
+            let end = config.offset + range;
+            if file.metadata()?.is_file() && file.capacity()? < end {
+                file.set_len(end)?;
+            }
+        }
+    }
+    integrity_run_on(&mut file, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BS: usize = 512;
+
+    /// In-memory target
+    struct Memory(Vec<u8>);
+
+    impl BlockIo for Memory {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let start = (offset as usize).min(self.0.len());
+            let n = buf.len().min(self.0.len() - start);
+            buf[..n].copy_from_slice(&self.0[start..start + n]);
+            Ok(n)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            let end = offset as usize + buf.len();
+            if self.0.len() < end {
+                self.0.resize(end, 0);
+            }
+            self.0[offset as usize..end].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn capacity(&mut self) -> io::Result<u64> {
+            Ok(self.0.len() as u64)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn stamped(lba: u64, pass: u32, seed: u64) -> Vec<u8> {
+        let mut block = vec![0u8; BS];
+        BlockStamp::fill(lba, pass, seed, &mut block);
+        block
+    }
+
+    fn kind(block: &[u8]) -> Option<MismatchKind> {
+        let mut scratch = vec![0u8; BS];
+        check_block(block, 7, 3, 0x5EED, &mut scratch).map(|m| m.kind)
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn crc() {
+        // CRC-32C check value
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(&[]), 0);
+    }
+
+    #[test]
+    fn stamp_round_trip() {
+        let mut block = vec![0u8; BS];
+        let stamp = BlockStamp::fill(0x1234, 5, 0xfeed, &mut block);
+        assert_eq!(
+            stamp,
+            BlockStamp {
+                lba: 0x1234,
+                pass: 5,
+                block_size: BS as u32,
+                seed: 0xfeed,
+                crc: stamp.crc,
+            }
+        );
+        assert_eq!(BlockStamp::parse(&block), Some(stamp));
+        assert_eq!(BlockStamp::compute_crc(&block), stamp.crc);
+        // the payload depends on the LBA, the pass and the seed
+        assert_ne!(
+            block[STAMP_SIZE..],
+            stamped(0x1235, 5, 0xfeed)[STAMP_SIZE..]
+        );
+        assert_ne!(
+            block[STAMP_SIZE..],
+            stamped(0x1234, 6, 0xfeed)[STAMP_SIZE..]
+        );
+        assert_ne!(
+            block[STAMP_SIZE..],
+            stamped(0x1234, 5, 0xbeef)[STAMP_SIZE..]
+        );
+
+        for at in [0x10, STAMP_CRC_OFFSET, STAMP_SIZE, BS - 1] {
+            let mut flipped = block.clone();
+            flipped[at] ^= 0x04;
+            let parsed = BlockStamp::parse(&flipped).unwrap();
+            assert_ne!(BlockStamp::compute_crc(&flipped), parsed.crc, "{}", at);
+        }
+        assert_eq!(BlockStamp::parse(&vec![0u8; BS]), None);
+        assert_eq!(BlockStamp::parse(&block[..STAMP_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn classify() {
+        assert_eq!(kind(&stamped(7, 3, 0x5EED)), None);
+        assert_eq!(kind(&vec![0u8; BS]), Some(MismatchKind::Unwritten));
+        assert_eq!(kind(&vec![0xa5u8; BS]), Some(MismatchKind::NoStamp));
+        let mut flipped = stamped(7, 3, 0x5EED);
+        flipped[100] ^= 1;
+        assert_eq!(kind(&flipped), Some(MismatchKind::Corrupt));
+        assert_eq!(
+            kind(&stamped(8, 3, 0x5EED)),
+            Some(MismatchKind::Misdirected)
+        );
+        assert_eq!(kind(&stamped(7, 3, 0xBAD)), Some(MismatchKind::ForeignSeed));
+        assert_eq!(kind(&stamped(7, 2, 0x5EED)), Some(MismatchKind::Stale));
+        assert_eq!(kind(&stamped(7, 4, 0x5EED)), Some(MismatchKind::Newer));
+
+        let mut scratch = vec![0u8; BS];
+        let mismatch = check_block(&flipped, 7, 3, 0x5EED, &mut scratch).unwrap();
+        assert_eq!((mismatch.bad_bytes, mismatch.first_bad), (1, Some(100)));
+        assert_eq!(
+            mismatch.expected,
+            BlockStamp::parse(&stamped(7, 3, 0x5EED)).unwrap()
+        );
+    }
+
+    #[test]
+    fn torn() {
+        let config = IntegrityConfig {
+            block_size: BS,
+            io_size: 4 * BS,
+            range: Some(12 * BS as u64),
+            ..Default::default()
+        };
+        let mut target = Memory(vec![0u8; 12 * BS]);
+        let report = integrity_run_on(&mut target, &config).unwrap();
+        assert_eq!((report.blocks_written, report.errors()), (12, 0));
+
+        // pass 2 lands everywhere but block 1 and the whole second I/O, and
+        // block 10 of the third I/O is lost entirely
+        let mut pass2 = Memory(target.0.clone());
+        for lba in [0, 2, 3, 8, 9, 11] {
+            pass2.0[lba * BS..(lba + 1) * BS].copy_from_slice(&stamped(lba as u64, 2, config.seed));
+        }
+        pass2.0[10 * BS..11 * BS].fill(0);
+        let verify = IntegrityConfig {
+            first_pass: 2,
+            verify_only: true,
+            ..config.clone()
+        };
+        let report = integrity_run_on(&mut pass2, &verify).unwrap();
+        assert_eq!(report.blocks_verified, 12);
+        let counts: Vec<_> = report.counts.into_iter().collect();
+        assert_eq!(counts, [(MismatchKind::Stale, 4), (MismatchKind::Torn, 2)]);
+        let torn: Vec<u64> = report
+            .mismatches
+            .iter()
+            .filter(|m| m.kind == MismatchKind::Torn)
+            .map(|m| m.lba)
+            .collect();
+        assert_eq!(torn, [1, 10]);
+    }
+
+    #[test]
+    fn state_file() {
+        let path = temp_path("integrity-state");
+        let state = IntegrityState {
+            seed: 0xfeed_f00d,
+            pass: 3,
+            block_size: 4096,
+            offset: 1 << 20,
+            blocks: 100,
+            written: 60,
+            verified: 20,
+        };
+        state.save(&path).unwrap();
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        assert_eq!(IntegrityState::load(&path).unwrap(), state);
+
+        std::fs::write(&path, "seed=1\npass=1\nblock_size=512\n").unwrap();
+        let e = IntegrityState::load(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        std::fs::write(
+            &path,
+            "seed=0xzz\npass=1\nblock_size=512\noffset=0\nblocks=1\nwritten=0\nverified=0\n",
+        )
+        .unwrap();
+        assert!(IntegrityState::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resume() {
+        let path = temp_path("integrity-resume");
+        let config = IntegrityConfig {
+            block_size: BS,
+            io_size: 4 * BS,
+            offset: BS as u64,
+            range: Some(8 * BS as u64),
+            state: Some(path.clone()),
+            ..Default::default()
+        };
+        // interrupted after half of the writes
+        IntegrityState {
+            seed: config.seed,
+            pass: 1,
+            block_size: BS,
+            offset: BS as u64,
+            blocks: 8,
+            written: 4,
+            verified: 0,
+        }
+        .save(&path)
+        .unwrap();
+        let mut target = Memory(vec![0u8; 16 * BS]);
+        for (name, changed) in [
+            (
+                "block size",
+                IntegrityConfig {
+                    block_size: 1024,
+                    ..config.clone()
+                },
+            ),
+            (
+                "offset",
+                IntegrityConfig {
+                    offset: 0,
+                    ..config.clone()
+                },
+            ),
+            (
+                "seed",
+                IntegrityConfig {
+                    seed: 1,
+                    ..config.clone()
+                },
+            ),
+            (
+                "range",
+                IntegrityConfig {
+                    range: Some(4 * BS as u64),
+                    ..config.clone()
+                },
+            ),
+        ] {
+            let e = integrity_run_on(&mut target, &changed).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", name);
+            assert!(e.to_string().contains(name), "{}", e);
+        }
+        assert_eq!(target.0, vec![0u8; 16 * BS]);
+
+        // only the second half is written, the first reads back unwritten
+        let report = integrity_run_on(&mut target, &config).unwrap();
+        assert_eq!(report.blocks_written, 4);
+        let counts: Vec<_> = report.counts.into_iter().collect();
+        assert_eq!(counts, [(MismatchKind::Unwritten, 4)]);
+        assert_eq!(&target.0[..5 * BS], &vec![0u8; 5 * BS][..]);
+        assert_eq!(target.0[5 * BS..6 * BS], stamped(5, 1, config.seed));
+        let saved = IntegrityState::load(&path).unwrap();
+        assert_eq!((saved.written, saved.verified), (8, 8));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,9 @@
 use crossterm::event::{self, KeyCode, KeyEvent};
 
+pub mod bench;
 pub mod dev;
+pub mod integrity;
+pub mod trace;
 
 pub const RUNNING: bool = true;
 pub const ENDLOOP: bool = false;
@@ -86,6 +89,51 @@ impl DiskLatency {
     }
 }
 
+/// Zeroed heap buffer with a fixed alignment, as unbuffered/direct I/O requires
+pub struct AlignedBuffer {
+    ptr: std::ptr::NonNull<u8>,
+    layout: std::alloc::Layout,
+    // the allocation is never empty, so the requested length is kept apart
+    len: usize,
+}
+
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn new(size: usize, align: usize) -> Self {
+        let layout = std::alloc::Layout::from_size_align(size.max(1), align)
+            .expect("alignment must be a power of two");
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        let ptr =
+            std::ptr::NonNull::new(ptr).unwrap_or_else(|| std::alloc::handle_alloc_error(layout));
+        Self {
+            ptr,
+            layout,
+            len: size,
+        }
+    }
+}
+
+impl std::ops::Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl std::ops::DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
 use std::fs::File;
 use std::io::Write;
 
@@ -105,3 +153,20 @@ pub fn save_trace(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_buffer() {
+        let buffer = AlignedBuffer::new(4096, 4096);
+        assert_eq!(buffer.len(), 4096);
+        assert!((buffer.as_ptr() as usize).is_multiple_of(4096));
+        assert!(buffer.iter().all(|&b| b == 0));
+
+        let mut empty = AlignedBuffer::new(0, 512);
+        assert!(empty.is_empty());
+        assert!(empty.iter_mut().next().is_none());
+    }
+}
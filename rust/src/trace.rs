@@ -0,0 +1,642 @@
+// Streaming I/O trace writer and offline analyzer for trace CSVs, also reads
+// the shorter io_type,start,end,latency layout written by save_trace
+use crate::bench::{fmt_latency, LatencyHistogram, BENCH_PERCENTILES};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const TRACE_HEADER: &str = "io_type,offset,size,queue,start,end,latency,status";
+const TRACE_FLUSH_BYTES: usize = 64 * 1024;
+
+/// One completed I/O, times are nanoseconds since the start of the run
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TraceRecord {
+    pub io_type: String,
+    pub offset: u64,
+    pub size: u64,
+    pub queue: u32,
+    pub start: u64,
+    pub end: u64,
+    pub status: i32, // 0 on success, OS error code or -1 otherwise
+}
+
+impl TraceRecord {
+    pub fn latency(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{},{},{}",
+            self.io_type,
+            self.offset,
+            self.size,
+            self.queue,
+            self.start,
+            self.end,
+            self.latency(),
+            self.status
+        )
+    }
+}
+
+/// Trace file shared by all workers. Records go to disk as they complete, so
+/// memory use does not grow with the length of the run; lines are in
+/// completion order rather than sorted by start time.
+pub struct TraceWriter {
+    out: Mutex<BufWriter<File>>,
+    start_time: Instant,
+}
+
+impl TraceWriter {
+    pub fn create(filename: &str, start_time: Instant) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(filename)?);
+        writeln!(out, "{}", TRACE_HEADER)?;
+        Ok(Self {
+            out: Mutex::new(out),
+            start_time,
+        })
+    }
+
+    /// Nanoseconds from the start of the run to `instant`
+    pub fn since_start(&self, instant: Instant) -> u64 {
+        instant
+            .saturating_duration_since(self.start_time)
+            .as_nanos() as u64
+    }
+
+    /// Per worker buffer, batches lines to keep lock traffic low
+    pub fn buffer(&self) -> TraceBuffer<'_> {
+        TraceBuffer {
+            writer: self,
+            lines: String::with_capacity(TRACE_FLUSH_BYTES + 256),
+        }
+    }
+
+    fn write_lines(&self, lines: &str) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap();
+        out.write_all(lines.as_bytes())
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.out.lock().unwrap().flush()
+    }
+}
+
+pub struct TraceBuffer<'a> {
+    writer: &'a TraceWriter,
+    lines: String,
+}
+
+impl TraceBuffer<'_> {
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let _ = writeln!(self.lines, "{}", record);
+        if self.lines.len() >= TRACE_FLUSH_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.lines.is_empty() {
+            self.writer.write_lines(&self.lines)?;
+            self.lines.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TraceBuffer<'_> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Column positions taken from the header line
+struct TraceColumns {
+    io_type: usize,
+    offset: Option<usize>,
+    size: Option<usize>,
+    queue: Option<usize>,
+    start: usize,
+    end: usize,
+    status: Option<usize>,
+}
+
+impl TraceColumns {
+    fn from_header(header: &str) -> io::Result<Self> {
+        let names: Vec<&str> = header.trim().split(',').map(str::trim).collect();
+        let find = |name: &str| names.iter().position(|&n| n == name);
+        let required = |name: &str| {
+            find(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("trace header has no '{}' column", name),
+                )
+            })
+        };
+        Ok(Self {
+            io_type: required("io_type")?,
+            offset: find("offset"),
+            size: find("size"),
+            queue: find("queue"),
+            start: required("start")?,
+            end: required("end")?,
+            status: find("status"),
+        })
+    }
+
+    fn parse(&self, line: &str) -> Option<TraceRecord> {
+        let fields: Vec<&str> = line.trim().split(',').collect();
+        let number = |index: Option<usize>| -> Option<u64> {
+            match index {
+                Some(i) => fields.get(i)?.trim().parse().ok(),
+                None => Some(0),
+            }
+        };
+        Some(TraceRecord {
+            io_type: fields.get(self.io_type)?.trim().to_string(),
+            offset: number(self.offset)?,
+            size: number(self.size)?,
+            queue: number(self.queue)? as u32,
+            start: number(Some(self.start))?,
+            end: number(Some(self.end))?,
+            status: match self.status {
+                Some(i) => fields.get(i)?.trim().parse().ok()?,
+                None => 0,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceAnalyzeOptions {
+    pub interval: Duration,     // width of one throughput sample
+    pub outliers: usize,        // slowest I/Os to keep
+    pub threshold: Option<u64>, // also list every I/O slower than this, in ns
+}
+
+impl Default for TraceAnalyzeOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            outliers: 10,
+            threshold: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceTypeStats {
+    pub ios: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub latency: LatencyHistogram,
+}
+
+/// Completions and bytes per I/O type within one interval
+#[derive(Debug, Clone, Default)]
+pub struct TraceSample {
+    pub ios: BTreeMap<String, u64>,
+    pub bytes: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceAnalysis {
+    pub options: TraceAnalyzeOptions,
+    pub records: u64,
+    pub skipped: u64, // lines that could not be parsed
+    pub first_start: u64,
+    pub last_end: u64,
+    pub types: BTreeMap<String, TraceTypeStats>,
+    pub series: BTreeMap<u64, TraceSample>, // keyed by interval index
+    pub outliers: Vec<TraceRecord>,         // slowest first
+    pub over_threshold: Vec<TraceRecord>,
+}
+
+impl TraceAnalysis {
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.last_end.saturating_sub(self.first_start))
+    }
+}
+
+/// Read a trace in one pass, memory grows with the run length only through the
+/// throughput series and the threshold list
+pub fn trace_analyze<R: BufRead>(
+    reader: R,
+    options: &TraceAnalyzeOptions,
+) -> io::Result<TraceAnalysis> {
+    let mut lines = reader.lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty trace"))??;
+    let columns = TraceColumns::from_header(&header)?;
+    let interval = options.interval.as_nanos().max(1) as u64;
+    let mut analysis = TraceAnalysis {
+        options: *options,
+        first_start: u64::MAX,
+        ..Default::default()
+    };
+    let mut slowest = BinaryHeap::new();
+
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(record) = columns.parse(&line) else {
+            analysis.skipped += 1;
+            continue;
+        };
+        analysis.records += 1;
+        analysis.first_start = analysis.first_start.min(record.start);
+        analysis.last_end = analysis.last_end.max(record.end);
+
+        let latency = record.latency();
+        let stats = analysis.types.entry(record.io_type.clone()).or_default();
+        if record.status != 0 {
+            stats.errors += 1;
+        } else {
+            stats.ios += 1;
+            stats.bytes += record.size;
+            stats.latency.record(latency);
+            let sample = analysis.series.entry(record.end / interval).or_default();
+            *sample.ios.entry(record.io_type.clone()).or_default() += 1;
+            *sample.bytes.entry(record.io_type.clone()).or_default() += record.size;
+        }
+
+        if options.threshold.is_some_and(|limit| latency > limit) {
+            analysis.over_threshold.push(record.clone());
+        }
+        if options.outliers > 0 {
+            slowest.push(Reverse((latency, record.start, record)));
+            if slowest.len() > options.outliers {
+                slowest.pop();
+            }
+        }
+    }
+
+    if analysis.records == 0 {
+        analysis.first_start = 0;
+    }
+    analysis.outliers = slowest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((_, _, record))| record)
+        .collect();
+    Ok(analysis)
+}
+
+pub fn trace_analyze_file(
+    filename: &str,
+    options: &TraceAnalyzeOptions,
+) -> io::Result<TraceAnalysis> {
+    trace_analyze(BufReader::new(File::open(filename)?), options)
+}
+
+impl TraceAnalysis {
+    /// Throughput series as CSV: interval start in seconds, then IOPS and MiB/s per type
+    pub fn series_csv(&self) -> String {
+        let secs = self.options.interval.as_secs_f64().max(f64::EPSILON);
+        let mut out = String::from("time");
+        for name in self.types.keys() {
+            let _ = write!(out, ",{0}_iops,{0}_mibs", name);
+        }
+        out.push('\n');
+        let (Some(&first), Some(&last)) = (self.series.keys().next(), self.series.keys().last())
+        else {
+            return out;
+        };
+        for index in first..=last {
+            let sample = self.series.get(&index);
+            let _ = write!(out, "{:.3}", index as f64 * secs);
+            for name in self.types.keys() {
+                let ios = sample.and_then(|s| s.ios.get(name)).copied().unwrap_or(0);
+                let bytes = sample.and_then(|s| s.bytes.get(name)).copied().unwrap_or(0);
+                let _ = write!(
+                    out,
+                    ",{:.0},{:.2}",
+                    ios as f64 / secs,
+                    bytes as f64 / secs / crate::MI_BYTES as f64
+                );
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn fmt_histogram(f: &mut fmt::Formatter, latency: &LatencyHistogram) -> fmt::Result {
+        // power of two buckets from 1us, lower bound inclusive
+        let mut buckets: BTreeMap<u32, u64> = BTreeMap::new();
+        let mut remaining = latency.count;
+        let mut below = 0;
+        let mut bound = 1000u64;
+        let mut exp = 0;
+        while remaining > 0 && exp < 40 {
+            let upto = latency.count_below(bound);
+            let count = upto - below;
+            buckets.insert(exp, count);
+            remaining -= count.min(remaining);
+            below = upto;
+            bound <<= 1;
+            exp += 1;
+        }
+        let peak = buckets.values().copied().max().unwrap_or(0).max(1);
+        let mut lower = 0u64;
+        for (exp, count) in buckets {
+            let upper = 1000u64 << exp;
+            if count > 0 {
+                let bar = "#".repeat(((count * 40).div_ceil(peak)) as usize);
+                writeln!(
+                    f,
+                    "  {:>9} - {:<9} {:>10} {:>6.2}% {}",
+                    fmt_latency(lower),
+                    fmt_latency(upper),
+                    count,
+                    count as f64 * 100.0 / latency.count as f64,
+                    bar
+                )?;
+            }
+            lower = upper;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TraceAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let elapsed = self.elapsed();
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        writeln!(
+            f,
+            "Records: {} (skipped {}), span {:.3}s",
+            self.records,
+            self.skipped,
+            elapsed.as_secs_f64()
+        )?;
+        for (name, stats) in &self.types {
+            let lat = &stats.latency;
+            writeln!(f)?;
+            writeln!(
+                f,
+                "{}: ios={} bytes={} errors={} IOPS={:.0} BW={:.2}MiB/s",
+                name,
+                stats.ios,
+                stats.bytes,
+                stats.errors,
+                stats.ios as f64 / secs,
+                stats.bytes as f64 / secs / crate::MI_BYTES as f64
+            )?;
+            if lat.count == 0 {
+                continue;
+            }
+            writeln!(
+                f,
+                "  lat: min={} avg={} max={}",
+                fmt_latency(lat.min),
+                fmt_latency(lat.mean()),
+                fmt_latency(lat.max)
+            )?;
+            let percentiles: Vec<String> = BENCH_PERCENTILES
+                .iter()
+                .map(|&p| format!("p{}={}", p, fmt_latency(lat.percentile(p))))
+                .collect();
+            writeln!(f, "  {}", percentiles.join(" "))?;
+            Self::fmt_histogram(f, lat)?;
+        }
+
+        if !self.series.is_empty() {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "Throughput per {:.3}s:",
+                self.options.interval.as_secs_f64()
+            )?;
+            for line in self.series_csv().replace(',', "\t").lines() {
+                writeln!(f, "  {}", line)?;
+            }
+        }
+
+        if !self.outliers.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Slowest {} I/Os:", self.outliers.len())?;
+            for record in &self.outliers {
+                fmt_outlier(f, record)?;
+            }
+        }
+        if let Some(threshold) = self.options.threshold {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "{} I/Os over {}:",
+                self.over_threshold.len(),
+                fmt_latency(threshold)
+            )?;
+            for record in &self.over_threshold {
+                fmt_outlier(f, record)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fmt_outlier(f: &mut fmt::Formatter, record: &TraceRecord) -> fmt::Result {
+    writeln!(
+        f,
+        "  {:<6} lat={:<10} at={:.6}s offset={:#x} size={} queue={} status={}",
+        record.io_type,
+        fmt_latency(record.latency()),
+        record.start as f64 / 1e9,
+        record.offset,
+        record.size,
+        record.queue,
+        record.status
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyze(text: &str, options: &TraceAnalyzeOptions) -> TraceAnalysis {
+        trace_analyze(text.as_bytes(), options).unwrap()
+    }
+
+    fn record(io_type: &str, start: u64, end: u64, size: u64) -> TraceRecord {
+        TraceRecord {
+            io_type: io_type.to_string(),
+            offset: start * 8,
+            size,
+            queue: 1,
+            start,
+            end,
+            status: 0,
+        }
+    }
+
+    #[test]
+    fn writer_round_trip() {
+        let path = std::env::temp_dir().join(format!("trace-writer-{}", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let writer = TraceWriter::create(&path, Instant::now()).unwrap();
+        let records = vec![
+            record("read", 1000, 21000, 4096),
+            TraceRecord {
+                status: 5,
+                ..record("write", 2000, 9000, 512)
+            },
+        ];
+        {
+            let mut buffer = writer.buffer();
+            for r in &records {
+                buffer.record(r).unwrap();
+            }
+        }
+        writer.flush().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some(TRACE_HEADER));
+        assert_eq!(lines.next(), Some("read,8000,4096,1,1000,21000,20000,0"));
+
+        let analysis = analyze(&text, &TraceAnalyzeOptions::default());
+        assert_eq!((analysis.records, analysis.skipped), (2, 0));
+        let read = &analysis.types["read"];
+        assert_eq!((read.ios, read.bytes, read.errors), (1, 4096, 0));
+        assert_eq!((read.latency.min, read.latency.max), (20000, 20000));
+        // failed I/Os are counted but not timed
+        let write = &analysis.types["write"];
+        assert_eq!((write.ios, write.bytes, write.errors), (0, 0, 1));
+        assert_eq!(write.latency.count, 0);
+        assert_eq!(analysis.outliers, records);
+    }
+
+    #[test]
+    fn save_trace_layout() {
+        let text = "io_type,start,end,latency\nread,100,1100,1000\nwrite,200,5200,5000\nread,300,2300,2000\n";
+        let analysis = analyze(text, &TraceAnalyzeOptions::default());
+        assert_eq!((analysis.records, analysis.skipped), (3, 0));
+        assert_eq!((analysis.first_start, analysis.last_end), (100, 5200));
+        assert_eq!(analysis.elapsed(), Duration::from_nanos(5100));
+        let read = &analysis.types["read"];
+        assert_eq!((read.ios, read.bytes), (2, 0));
+        assert_eq!((read.latency.min, read.latency.max), (1000, 2000));
+        // columns missing from this layout read as zero
+        assert_eq!(
+            analysis.outliers[0],
+            TraceRecord {
+                io_type: "write".to_string(),
+                start: 200,
+                end: 5200,
+                ..Default::default()
+            }
+        );
+
+        // the columns are found by name, in any order
+        let text = "end, start ,io_type\n50,10,flush\n";
+        let analysis = analyze(text, &TraceAnalyzeOptions::default());
+        assert_eq!(analysis.types["flush"].latency.max, 40);
+    }
+
+    #[test]
+    fn bad_input() {
+        for text in ["", "io_type,offset,size\n", "start,end\n1,2\n"] {
+            let e = trace_analyze(text.as_bytes(), &TraceAnalyzeOptions::default()).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", text);
+        }
+
+        let text = format!(
+            "{}\nread,0,512,1,10,20,10,0\n\n   \nread,0,512\nread,x,512,1,10,20,10,0\nread,0,512,1,10,20,10,EIO\nread,0,512,1,30,50,20,0\n",
+            TRACE_HEADER
+        );
+        let analysis = analyze(&text, &TraceAnalyzeOptions::default());
+        assert_eq!((analysis.records, analysis.skipped), (2, 3));
+        assert_eq!(analysis.types["read"].bytes, 1024);
+
+        // only a header: no records and an empty span
+        let analysis = analyze(TRACE_HEADER, &TraceAnalyzeOptions::default());
+        assert_eq!(
+            (analysis.records, analysis.first_start, analysis.last_end),
+            (0, 0, 0)
+        );
+        assert!(analysis.series.is_empty());
+    }
+
+    #[test]
+    fn outliers() {
+        let mut text = String::from("io_type,start,end,latency\n");
+        for (i, latency) in [300, 100, 500, 200, 500, 400].iter().enumerate() {
+            let start = i as u64 * 1000;
+            let _ = writeln!(text, "read,{},{},{}", start, start + latency, latency);
+        }
+        let options = TraceAnalyzeOptions {
+            outliers: 3,
+            threshold: Some(300),
+            ..Default::default()
+        };
+        let analysis = analyze(&text, &options);
+        // slowest first, ties by start time
+        let slowest: Vec<(u64, u64)> = analysis
+            .outliers
+            .iter()
+            .map(|r| (r.latency(), r.start))
+            .collect();
+        assert_eq!(slowest, [(500, 4000), (500, 2000), (400, 5000)]);
+        // strictly over the threshold, in file order
+        let over: Vec<u64> = analysis.over_threshold.iter().map(|r| r.start).collect();
+        assert_eq!(over, [2000, 4000, 5000]);
+
+        let options = TraceAnalyzeOptions {
+            outliers: 0,
+            ..Default::default()
+        };
+        let analysis = analyze(&text, &options);
+        assert!(analysis.outliers.is_empty() && analysis.over_threshold.is_empty());
+        assert!(!analysis.to_string().contains("I/Os over"));
+    }
+
+    #[test]
+    fn series() {
+        let mib = crate::MI_BYTES as u64;
+        // bucketed by completion time; interval 2 has nothing
+        let text = format!(
+            "{}\nread,0,{mib},0,0,900000,900000,0\nread,0,{mib},0,500000,1100000,600000,0\nwrite,0,{mib},0,1200000,1999000,799000,0\nread,0,{mib},0,2500000,3000000,500000,0\nread,0,{mib},0,2500000,3100000,600000,5\n",
+            TRACE_HEADER,
+            mib = mib
+        );
+        let options = TraceAnalyzeOptions {
+            interval: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let analysis = analyze(&text, &options);
+        assert_eq!(
+            analysis.series.keys().copied().collect::<Vec<_>>(),
+            [0, 1, 3]
+        );
+        let csv = analysis.series_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "time,read_iops,read_mibs,write_iops,write_mibs");
+        assert_eq!(lines[1], "0.000,1000,1000.00,0,0.00");
+        assert_eq!(lines[2], "0.001,1000,1000.00,1000,1000.00");
+        assert_eq!(lines[3], "0.002,0,0.00,0,0.00");
+        assert_eq!(lines[4], "0.003,1000,1000.00,0,0.00");
+        assert_eq!(lines.len(), 5);
+
+        let options = TraceAnalyzeOptions {
+            interval: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let text = format!("{}\nread,0,4096,0,0,1600000000,1,0\n", TRACE_HEADER);
+        let csv = analyze(&text, &options).series_csv();
+        assert_eq!(csv, "time,read_iops,read_mibs\n1.500,2,0.01\n");
+        let empty = analyze(TRACE_HEADER, &options).series_csv();
+        assert_eq!(empty, "time\n");
+    }
+}